@@ -0,0 +1,526 @@
+//! Finding citation keys in Markdown, LaTeX and Typst sources.
+//!
+//! Markdown follows Pandoc: `@key` and `[see @key, p. 3]`. LaTeX takes the keys of every
+//! command with `cite` in its name, `\cite`, `\citep`, `\parencite`, `\nocite` and the
+//! rest. Typst has `@key` and `#cite(<key>)`, where `@label` also refers to labels in the
+//! document itself, which are left alone.
+
+use std::collections::HashSet;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    Markdown,
+    Latex,
+    Typst,
+}
+
+impl Language {
+    /// The language of a document from its LSP language id, else its file extension.
+    pub fn detect(language_id: &str, uri: &str) -> Option<Self> {
+        match language_id.to_ascii_lowercase().as_str() {
+            "markdown" => return Some(Self::Markdown),
+            "latex" | "tex" => return Some(Self::Latex),
+            "typst" => return Some(Self::Typst),
+            _ => {}
+        }
+        let extension = uri.rsplit_once('.')?.1.to_ascii_lowercase();
+        match extension.as_str() {
+            "md" | "markdown" | "qmd" | "rmd" => Some(Self::Markdown),
+            "tex" | "ltx" => Some(Self::Latex),
+            "typ" => Some(Self::Typst),
+            _ => None,
+        }
+    }
+
+    /// Characters a key may have besides letters, digits and `_`. Markdown and Typst only
+    /// allow them between those, so a full stop after `@key.` is not part of the key.
+    fn punctuation(self) -> &'static str {
+        match self {
+            Self::Markdown => ":.#$%&-+?<>~/",
+            Self::Latex => ":.-+/",
+            Self::Typst => ":.-",
+        }
+    }
+}
+
+/// A citation key in a document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Citation {
+    pub key: String,
+    /// Byte range of the key alone, without `@` or braces.
+    pub range: Range<usize>,
+    /// Whether the syntax means nothing but a citation. A bare `@name` in Markdown
+    /// running text may as well be a mention of someone.
+    pub certain: bool,
+}
+
+/// Every citation in `text`, in document order.
+pub fn citations(text: &str, language: Language) -> Vec<Citation> {
+    match language {
+        Language::Markdown => markdown(text),
+        Language::Latex => latex_arguments(text)
+            .into_iter()
+            .flat_map(|argument| split_keys(text, argument))
+            .collect(),
+        Language::Typst => typst(text),
+    }
+}
+
+/// The citation whose key `offset` is in or just after.
+pub fn citation_at(text: &str, language: Language, offset: usize) -> Option<Citation> {
+    citations(text, language)
+        .into_iter()
+        .find(|citation| citation.range.start <= offset && offset <= citation.range.end)
+}
+
+/// Where the key being typed at `offset` starts, if `offset` is somewhere a key goes.
+pub fn completion_start(text: &str, language: Language, offset: usize) -> Option<usize> {
+    let before = text.get(..offset)?;
+    let start = before
+        .trim_end_matches(|c: char| is_word(c) || language.punctuation().contains(c))
+        .len();
+    let prefix = &text[..start];
+    let after_at = prefix.ends_with('@') && starts_reference(text, start - 1);
+    let in_place = match language {
+        Language::Markdown => {
+            (after_at || prefix.ends_with("@{")) && !inside(&markdown_code(text), start - 1)
+        }
+        Language::Latex => latex_arguments(text)
+            .iter()
+            .any(|argument| argument.start <= start && offset <= argument.end),
+        Language::Typst => {
+            let cite_label =
+                prefix.ends_with('<') && prefix[..start - 1].trim_end().ends_with("cite(");
+            (after_at || cite_label) && !inside(&typst_skipped(text), start - 1)
+        }
+    };
+    in_place.then_some(start)
+}
+
+fn markdown(text: &str) -> Vec<Citation> {
+    let code = markdown_code(text);
+    let mut citations = Vec::new();
+    for (at, _) in text.match_indices('@') {
+        if inside(&code, at) || !starts_reference(text, at) {
+            continue;
+        }
+        let rest = &text[at + 1..];
+        let (range, braced) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.find(['}', '\n']) {
+                Some(end) if braced[end..].starts_with('}') => (at + 2..at + 2 + end, true),
+                _ => continue,
+            },
+            None => {
+                let length = key_length(rest, Language::Markdown);
+                (at + 1..at + 1 + length, false)
+            }
+        };
+        if range.is_empty() {
+            continue;
+        }
+        citations.push(Citation {
+            key: text[range.clone()].to_string(),
+            range,
+            certain: braced || bracketed(text, at),
+        });
+    }
+    citations
+}
+
+/// Whether `at` is inside `[...]` on its line, as in `[see @key, p. 3]`, and that is not
+/// the text of a link.
+fn bracketed(text: &str, at: usize) -> bool {
+    let line_start = text[..at].rfind('\n').map_or(0, |index| index + 1);
+    let before = &text[line_start..at];
+    let after = &text[at..line_end(text, at)];
+    let opened = before
+        .rfind('[')
+        .is_some_and(|open| !before[open..].contains(']'));
+    let closed = after
+        .find(']')
+        .is_some_and(|close| !after[..close].contains('[') && !after[close + 1..].starts_with('('));
+    opened && closed
+}
+
+/// Byte ranges of Markdown code: fenced blocks and inline code spans.
+fn markdown_code(text: &str) -> Vec<Range<usize>> {
+    let mut code = Vec::new();
+    // The open fence's character and length, and where it starts.
+    let mut fence: Option<(char, usize, usize)> = None;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        let marker = ['`', '~']
+            .into_iter()
+            .map(|c| (c, run(trimmed, c)))
+            .find(|&(_, length)| length >= 3);
+        match (fence, marker) {
+            (None, Some((c, length))) => fence = Some((c, length, offset)),
+            (Some((c, length, start)), Some((closing, closing_length)))
+                if c == closing
+                    && closing_length >= length
+                    && trimmed[closing_length..].trim().is_empty() =>
+            {
+                code.push(start..offset + line.len());
+                fence = None;
+            }
+            (Some(_), _) => {}
+            (None, None) => code.extend(code_spans(line, offset)),
+        }
+        offset += line.len();
+    }
+    if let Some((_, _, start)) = fence {
+        code.push(start..text.len());
+    }
+    code
+}
+
+/// Inline code spans in a line of Markdown: a run of backticks up to the next run of the
+/// same length.
+fn code_spans(line: &str, offset: usize) -> Vec<Range<usize>> {
+    let mut spans = Vec::new();
+    let mut position = 0;
+    while let Some(start) = line[position..].find('`').map(|index| position + index) {
+        let length = run(&line[start..], '`');
+        let mut search = start + length;
+        let mut end = None;
+        while let Some(next) = line[search..].find('`').map(|index| search + index) {
+            let closing = run(&line[next..], '`');
+            if closing == length {
+                end = Some(next + closing);
+                break;
+            }
+            search = next + closing;
+        }
+        match end {
+            Some(end) => {
+                spans.push(offset + start..offset + end);
+                position = end;
+            }
+            None => position = start + length,
+        }
+    }
+    spans
+}
+
+/// The byte ranges inside the braces of citation commands' key arguments. An argument
+/// left open runs to the end of its line, so a key being typed is inside one.
+fn latex_arguments(text: &str) -> Vec<Range<usize>> {
+    let bytes = text.as_bytes();
+    let mut arguments = Vec::new();
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' => index = line_end(text, index),
+            b'\\' => {
+                let name_start = index + 1;
+                let name_length = text[name_start..]
+                    .find(|c: char| !c.is_ascii_alphabetic())
+                    .unwrap_or(text.len() - name_start);
+                if name_length == 0 {
+                    // An escaped character such as `\%`.
+                    index =
+                        name_start + text[name_start..].chars().next().map_or(0, char::len_utf8);
+                    continue;
+                }
+                index = name_start + name_length;
+                let name = text[name_start..index].to_ascii_lowercase();
+                if name.contains("cite") && name != "citestyle" {
+                    index = cite_arguments(text, index, name.ends_with("cites"), &mut arguments);
+                }
+            }
+            _ => index += 1,
+        }
+    }
+    arguments
+}
+
+/// Reads the arguments of a citation command from `index`, just after its name, and
+/// returns where they end. `\cites` and friends take several key arguments.
+fn cite_arguments(
+    text: &str,
+    mut index: usize,
+    multiple: bool,
+    arguments: &mut Vec<Range<usize>>,
+) -> usize {
+    let bytes = text.as_bytes();
+    let skip_space = |index: usize| text.len() - text[index..].trim_start().len();
+    index = skip_space(index);
+    if bytes.get(index) == Some(&b'*') {
+        index += 1;
+    }
+    loop {
+        index = skip_space(index);
+        match bytes.get(index) {
+            Some(b'[') => index = past(text, index, ']'),
+            Some(b'(') if multiple => index = past(text, index, ')'),
+            Some(b'{') => {
+                let start = index + 1;
+                let stop = text[start..]
+                    .find(['}', '{', '\\'])
+                    .map_or(text.len(), |length| start + length);
+                let paragraph = text[start..]
+                    .find("\n\n")
+                    .map_or(text.len(), |length| start + length);
+                if bytes.get(stop) == Some(&b'}') && stop < paragraph {
+                    arguments.push(start..stop);
+                    index = stop + 1;
+                } else {
+                    let end = line_end(text, start);
+                    arguments.push(start..end);
+                    return end;
+                }
+                if !multiple {
+                    return index;
+                }
+            }
+            _ => return index,
+        }
+    }
+}
+
+/// Where the text after the next `closing` from `index` starts.
+fn past(text: &str, index: usize, closing: char) -> usize {
+    text[index..]
+        .find(closing)
+        .map_or(text.len(), |length| index + length + 1)
+}
+
+/// The comma-separated keys in `argument`.
+fn split_keys(text: &str, argument: Range<usize>) -> Vec<Citation> {
+    let mut keys = Vec::new();
+    let mut start = argument.start;
+    for part in text[argument].split(',') {
+        let key = part.trim();
+        let key_start = start + part.len() - part.trim_start().len();
+        if !key.is_empty() && key != "*" && !key.contains(char::is_whitespace) {
+            keys.push(Citation {
+                key: key.to_string(),
+                range: key_start..key_start + key.len(),
+                certain: true,
+            });
+        }
+        start += part.len() + 1;
+    }
+    keys
+}
+
+fn typst(text: &str) -> Vec<Citation> {
+    let skipped = typst_skipped(text);
+    let mut labels = HashSet::new();
+    let mut citations = Vec::new();
+    for (open, _) in text.match_indices('<') {
+        if inside(&skipped, open) {
+            continue;
+        }
+        let rest = &text[open + 1..];
+        let length = rest
+            .find(|c: char| !(is_word(c) || Language::Typst.punctuation().contains(c)))
+            .unwrap_or(rest.len());
+        if length == 0 || !rest[length..].starts_with('>') {
+            continue;
+        }
+        let key = &rest[..length];
+        if text[..open].trim_end().ends_with("cite(") {
+            citations.push(Citation {
+                key: key.to_string(),
+                range: open + 1..open + 1 + length,
+                certain: true,
+            });
+        } else {
+            labels.insert(key);
+        }
+    }
+    for (at, _) in text.match_indices('@') {
+        if inside(&skipped, at) || !starts_reference(text, at) {
+            continue;
+        }
+        let length = key_length(&text[at + 1..], Language::Typst);
+        let key = &text[at + 1..at + 1 + length];
+        if key.is_empty() || labels.contains(key) {
+            continue;
+        }
+        citations.push(Citation {
+            key: key.to_string(),
+            range: at + 1..at + 1 + length,
+            certain: true,
+        });
+    }
+    citations.sort_by_key(|citation| citation.range.start);
+    citations
+}
+
+/// Byte ranges of Typst comments and raw text.
+fn typst_skipped(text: &str) -> Vec<Range<usize>> {
+    let mut skipped = Vec::new();
+    let mut index = 0;
+    while index < text.len() {
+        let rest = &text[index..];
+        // `//` after a colon is part of a URL.
+        let end = if rest.starts_with("//") && !text[..index].ends_with(':') {
+            line_end(text, index)
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            comment
+                .find("*/")
+                .map_or(text.len(), |length| index + 2 + length + 2)
+        } else if rest.starts_with('`') {
+            let length = run(rest, '`');
+            match length {
+                2 => index + 2,
+                _ => rest[length..]
+                    .find(&rest[..length])
+                    .map_or(text.len(), |found| index + length + found + length),
+            }
+        } else {
+            index += rest.chars().next().map_or(1, char::len_utf8);
+            continue;
+        };
+        skipped.push(index..end);
+        index = end;
+    }
+    skipped
+}
+
+/// Whether an `@` at `at` can start a reference rather than being inside a word, as in
+/// an email address, or a URL.
+fn starts_reference(text: &str, at: usize) -> bool {
+    let before = &text[..at];
+    let in_word = before
+        .chars()
+        .next_back()
+        .is_some_and(|c| is_word(c) || c == '\\');
+    let in_url = before
+        .rsplit(char::is_whitespace)
+        .next()
+        .is_some_and(|word| word.contains("://"));
+    !in_word && !in_url
+}
+
+/// Length of the key at the start of `text`: letters, digits and `_`, with the
+/// language's punctuation allowed between them.
+fn key_length(text: &str, language: Language) -> usize {
+    let mut length = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        let next_is_word = chars.peek().is_some_and(|&(_, next)| is_word(next));
+        let continues =
+            is_word(c) || (length > 0 && language.punctuation().contains(c) && next_is_word);
+        if !continues {
+            break;
+        }
+        length = index + c.len_utf8();
+    }
+    length
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Length in bytes of the run of `c` at the start of `text`.
+fn run(text: &str, c: char) -> usize {
+    text.len() - text.trim_start_matches(c).len()
+}
+
+fn line_end(text: &str, index: usize) -> usize {
+    text[index..]
+        .find('\n')
+        .map_or(text.len(), |length| index + length)
+}
+
+fn inside(ranges: &[Range<usize>], offset: usize) -> bool {
+    ranges.iter().any(|range| range.contains(&offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(text: &str, language: Language) -> Vec<(String, bool)> {
+        citations(text, language)
+            .into_iter()
+            .map(|citation| {
+                assert_eq!(text[citation.range.clone()], citation.key);
+                (citation.key, citation.certain)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_pandoc_citations() {
+        let text = "As @doyle1887study shows [see @doyle1890sign, p. 3; -@austen1813pride].\n\
+                    Mail sherlock@bakerstreet.uk or see [@holmes](https://example.com).\n\
+                    `@code` and @{twain:1876}.\n\
+                    ```\n@fenced\n```\n";
+        assert_eq!(
+            keys(text, Language::Markdown),
+            [
+                ("doyle1887study".to_string(), false),
+                ("doyle1890sign".to_string(), true),
+                ("austen1813pride".to_string(), true),
+                ("holmes".to_string(), false),
+                ("twain:1876".to_string(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_latex_citations() {
+        let text = "\\parencite[see][12]{doyle1887study, doyle1890sign}\n\
+                    % \\cite{commented}\n\
+                    100\\% sure \\citep*{austen1813pride}\\nocite{*}\n\
+                    \\cites[1]{a}[2]{b} \\citestyle{authoryear} \\textcite{unfinished";
+        let found = keys(text, Language::Latex)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            [
+                "doyle1887study",
+                "doyle1890sign",
+                "austen1813pride",
+                "a",
+                "b",
+                "unfinished"
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_typst_citations_but_not_labels() {
+        let text =
+            "= Intro <intro>\nSee @intro and @doyle1887study. Also #cite(<twain1876adventures>).\n\
+                    // @commented\nRaw `@raw` and https://example.com/@user";
+        let found = keys(text, Language::Typst)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        assert_eq!(found, ["doyle1887study", "twain1876adventures"]);
+    }
+
+    #[test]
+    fn knows_where_keys_are_typed() {
+        assert_eq!(
+            completion_start("see [@doy", Language::Markdown, 9),
+            Some(6)
+        );
+        assert_eq!(completion_start("see @", Language::Markdown, 5), Some(5));
+        assert_eq!(completion_start("me@doy", Language::Markdown, 6), None);
+        assert_eq!(completion_start("`@doy`", Language::Markdown, 5), None);
+
+        let latex = "\\cite[p. 3]{austen, doy} \\emph{doy}";
+        assert_eq!(completion_start(latex, Language::Latex, 23), Some(20));
+        assert_eq!(completion_start(latex, Language::Latex, 34), None);
+        assert_eq!(completion_start("\\citep{", Language::Latex, 7), Some(7));
+
+        assert_eq!(completion_start("#cite(<doy", Language::Typst, 10), Some(7));
+        assert_eq!(completion_start("see @", Language::Typst, 5), Some(5));
+
+        assert_eq!(
+            citation_at("[@doyle1887study]", Language::Markdown, 16).map(|citation| citation.key),
+            Some("doyle1887study".to_string())
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Open documents, the line and UTF-16 character positions LSP addresses them by, and the
+//! `file:` URIs it names files by.
+
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::citations::Language;
+
+pub struct Document {
+    pub language: Language,
+    pub text: String,
+}
+
+/// The byte offset of an LSP position, clamped to the text.
+pub fn offset(text: &str, position: &Value) -> usize {
+    let line = position["line"].as_u64().unwrap_or(0) as usize;
+    let character = position["character"].as_u64().unwrap_or(0) as usize;
+    let line_start = if line == 0 {
+        Some(0)
+    } else {
+        text.match_indices('\n')
+            .nth(line - 1)
+            .map(|(index, _)| index + 1)
+    };
+    let Some(line_start) = line_start else {
+        return text.len();
+    };
+    let line_text = text[line_start..].split('\n').next().unwrap_or_default();
+    let mut units = 0;
+    for (index, c) in line_text.char_indices() {
+        if units >= character {
+            return line_start + index;
+        }
+        units += c.len_utf16();
+    }
+    line_start + line_text.len()
+}
+
+/// The LSP position of a byte offset.
+pub fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+    json!({
+        "line": before.matches('\n').count(),
+        "character": before[line_start..].encode_utf16().count(),
+    })
+}
+
+pub fn range(text: &str, range: Range<usize>) -> Value {
+    json!({
+        "start": position(text, range.start),
+        "end": position(text, range.end),
+    })
+}
+
+/// The `file:` URI of an absolute path.
+pub fn path_uri(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut uri = String::from("file://");
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => {
+                uri.push(char::from(byte))
+            }
+            byte => uri.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    uri
+}
+
+/// The path a `file:` URI names, or `None` for other schemes.
+pub fn uri_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = match (byte, tail) {
+            (b'%', [high, low, ..]) => std::str::from_utf8(&[*high, *low])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    let path = String::from_utf8(bytes).ok()?;
+    // `file:///C:/Books` names `C:/Books`.
+    let path = match path.as_bytes() {
+        [b'/', drive, b':', ..] if drive.is_ascii_alphabetic() => path[1..].to_string(),
+        _ => path,
+    };
+    Some(PathBuf::from(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_positions_in_utf16() {
+        let text = "first\n🕵 @doyle1887study\n";
+        let key = text.find("doyle").unwrap();
+        assert_eq!(position(text, key), json!({"line": 1, "character": 4}));
+        assert_eq!(offset(text, &json!({"line": 1, "character": 4})), key);
+        assert_eq!(
+            offset(text, &json!({"line": 1, "character": 99})),
+            text.len() - 1
+        );
+        assert_eq!(
+            offset(text, &json!({"line": 9, "character": 0})),
+            text.len()
+        );
+    }
+
+    #[test]
+    fn converts_file_uris() {
+        let path = Path::new("/home/me/Calibre Library/Doyle/metadata.opf");
+        assert_eq!(
+            path_uri(path),
+            "file:///home/me/Calibre%20Library/Doyle/metadata.opf"
+        );
+        assert_eq!(
+            path_uri(Path::new("C:\\Books\\Émile.bib")),
+            "file:///C:/Books/%C3%89mile.bib"
+        );
+        assert_eq!(
+            uri_path("file:///home/me/Calibre%20Library/Doyle/metadata.opf").unwrap(),
+            path
+        );
+        assert_eq!(
+            uri_path("file:///C:/Books/%C3%89mile.bib").unwrap(),
+            Path::new("C:/Books/Émile.bib")
+        );
+        assert_eq!(uri_path("untitled:Untitled-1"), None);
+    }
+}
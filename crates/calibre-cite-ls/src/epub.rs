@@ -0,0 +1,76 @@
+//! Diagnostics for an unpacked EPUB: the problems `epubcheck` finds in the folder, with
+//! the documents being edited checked as they are in the editor rather than on disk.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use calibre_library::epubcheck::{self, Severity, Source};
+use serde_json::{json, Value};
+
+use crate::document;
+
+/// `DiagnosticSeverity.Error`.
+const ERROR: i64 = 1;
+/// `DiagnosticSeverity.Warning`.
+const WARNING: i64 = 2;
+
+/// The folder of the unpacked book the document at `uri` is in, and its path inside it.
+pub fn locate(uri: &str) -> Option<(PathBuf, String)> {
+    let path = document::uri_path(uri)?;
+    let root = epubcheck::unpacked_root(&path)?;
+    let inside = path
+        .strip_prefix(&root)
+        .ok()?
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    Some((root, inside))
+}
+
+/// The book's diagnostics by file URI, given the texts of its open documents by path
+/// inside the book.
+pub fn diagnostics(root: &Path, open: HashMap<String, String>) -> HashMap<String, Vec<Value>> {
+    let source = Source::Unpacked {
+        root: root.to_path_buf(),
+        open,
+    };
+    let mut texts = HashMap::new();
+    let mut diagnostics: HashMap<String, Vec<Value>> = HashMap::new();
+    for problem in epubcheck::check(&source) {
+        let start = match problem.position {
+            Some((line, column)) => {
+                let text = texts.entry(problem.path.clone()).or_insert_with(|| {
+                    source
+                        .read(&problem.path)
+                        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+                        .unwrap_or_default()
+                });
+                json!({"line": line - 1, "character": character(text, line, column)})
+            }
+            None => json!({"line": 0, "character": 0}),
+        };
+        let uri = document::path_uri(&root.join(&problem.path));
+        diagnostics.entry(uri).or_default().push(json!({
+            "range": {"start": start, "end": start},
+            "severity": match problem.severity {
+                Severity::Error => ERROR,
+                Severity::Warning => WARNING,
+            },
+            "source": "epubcheck",
+            "message": problem.message,
+        }));
+    }
+    diagnostics
+}
+
+/// The UTF-16 character LSP counts for a 1-based line and column in characters.
+fn character(text: &str, line: usize, column: usize) -> usize {
+    text.split('\n')
+        .nth(line - 1)
+        .unwrap_or_default()
+        .chars()
+        .take(column - 1)
+        .map(char::len_utf16)
+        .sum()
+}
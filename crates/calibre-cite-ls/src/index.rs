@@ -0,0 +1,140 @@
+//! The library's books under their citation keys, read again whenever `metadata.db`
+//! changes so books added in Calibre can be cited without restarting the server.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use calibre_library::cite::{self, KeyTemplate, Style};
+use calibre_library::metadata::{self, Book};
+
+pub struct Index {
+    library: PathBuf,
+    template: KeyTemplate,
+    /// Modification time of `metadata.db` when it was read.
+    modified: Option<SystemTime>,
+    /// Sorted by key.
+    entries: Vec<Entry>,
+}
+
+pub struct Entry {
+    pub key: String,
+    pub book: Book,
+}
+
+impl Index {
+    pub fn open(library: &Path, template: KeyTemplate) -> Result<Self, String> {
+        let mut index = Self {
+            library: library.to_path_buf(),
+            template,
+            modified: None,
+            entries: Vec::new(),
+        };
+        index.refresh()?;
+        Ok(index)
+    }
+
+    /// Reads the library again if `metadata.db` changed since it was last read, and says
+    /// whether it did.
+    pub fn refresh(&mut self) -> Result<bool, String> {
+        let modified = fs::metadata(self.library.join("metadata.db"))
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if self.modified.is_some() && modified == self.modified {
+            return Ok(false);
+        }
+        let books = metadata::books(&self.library)?;
+        let mut keys = self.template.keys(&books);
+        let mut entries = books
+            .into_iter()
+            .map(|book| Entry {
+                key: keys.remove(&book.id).unwrap_or_default(),
+                book,
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        self.entries = entries;
+        self.modified = modified;
+        Ok(true)
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Entry> {
+        self.entries
+            .binary_search_by(|entry| entry.key.as_str().cmp(key))
+            .ok()
+            .map(|index| &self.entries[index])
+    }
+
+    /// Where a key is defined: the book's `metadata.opf` if Calibre wrote one, else a
+    /// BibTeX entry for it written to a temporary file.
+    pub fn definition(&self, entry: &Entry) -> Result<PathBuf, String> {
+        let opf = self.library.join(&entry.book.path).join("metadata.opf");
+        if opf.is_file() {
+            return Ok(opf);
+        }
+        let directory = std::env::temp_dir().join("calibre-cite-ls");
+        fs::create_dir_all(&directory)
+            .map_err(|err| format!("failed to create {}: {err}", directory.display()))?;
+        let path = directory.join(format!("{}.bib", file_name(&entry.key)));
+        fs::write(&path, Style::Bibtex.format(&entry.book, &entry.key) + "\n")
+            .map_err(|err| format!("failed to write {}: {err}", path.display()))?;
+        Ok(path)
+    }
+}
+
+impl Entry {
+    /// `A Study in Scarlet — Arthur Conan Doyle (1887)`, for completion lists.
+    pub fn summary(&self) -> String {
+        let mut summary = self.book.title.clone();
+        if !self.book.authors.is_empty() {
+            summary.push_str(" — ");
+            summary.push_str(&self.book.authors.join(", "));
+        }
+        if let Some(year) = cite::year(&self.book) {
+            summary.push_str(&format!(" ({year})"));
+        }
+        summary
+    }
+
+    /// Title, authors, year and the rest of what identifies the book, as Markdown.
+    pub fn hover(&self) -> String {
+        let book = &self.book;
+        let mut byline = book.authors.join(", ");
+        if let Some(year) = cite::year(book) {
+            if !byline.is_empty() {
+                byline.push_str(", ");
+            }
+            byline.push_str(year);
+        }
+        let mut lines = vec![format!("**{}**", book.title)];
+        if !byline.is_empty() {
+            lines.push(byline);
+        }
+        if let Some(series) = &book.series {
+            lines.push(format!("{series} #{}", book.series_index));
+        }
+        if let Some(publisher) = &book.publisher {
+            lines.push(publisher.clone());
+        }
+        let mut footer = format!("`{}` · Calibre book {}", self.key, book.id);
+        if !book.formats.is_empty() {
+            footer.push_str(&format!(" · {}", book.formats.join(", ")));
+        }
+        lines.push(footer);
+        lines.join("\n\n")
+    }
+}
+
+/// A key as a file name; keys may hold `/` and `:`.
+fn file_name(key: &str) -> String {
+    key.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' => '_',
+            c => c,
+        })
+        .collect()
+}
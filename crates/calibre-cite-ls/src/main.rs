@@ -0,0 +1,83 @@
+//! `calibre-cite-ls`, a language server for citing Calibre library books from Markdown,
+//! LaTeX and Typst: it completes citation keys, shows the book behind a key on hover,
+//! goes to its `metadata.opf` or a BibTeX entry, and warns about keys no book has.
+//! Opening a file of an unpacked EPUB, a folder with a `META-INF/container.xml`, checks
+//! the whole book and reports its problems on the files they are in.
+//!
+//! The Zed extension starts it with the library in `CALIBRE_LIBRARY_PATH`, or a directory
+//! of libraries whose first by name is used, and the key template in
+//! `CALIBRE_CITATION_KEY`, the same keys `/calibre-cite` makes. It speaks LSP over stdin
+//! and stdout.
+
+mod citations;
+mod document;
+mod epub;
+mod index;
+mod rpc;
+mod server;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use calibre_library::cite::KeyTemplate;
+
+use crate::index::Index;
+use crate::server::Server;
+
+fn main() -> ExitCode {
+    let mut server = Server::new(open_index());
+    let mut input = io::stdin().lock();
+    let mut output = io::stdout().lock();
+    loop {
+        let message = match rpc::read(&mut input) {
+            Ok(Some(message)) => message,
+            Ok(None) => return ExitCode::FAILURE,
+            Err(err) => {
+                eprintln!("calibre-cite-ls: {err}");
+                return ExitCode::FAILURE;
+            }
+        };
+        for reply in server.handle(message) {
+            if let Err(err) = rpc::write(&mut output, &reply) {
+                eprintln!("calibre-cite-ls: {err}");
+                return ExitCode::FAILURE;
+            }
+        }
+        if let Some(code) = server.exit_code() {
+            return ExitCode::from(code);
+        }
+    }
+}
+
+fn open_index() -> Result<Index, String> {
+    let library = std::env::var_os("CALIBRE_LIBRARY_PATH")
+        .map(PathBuf::from)
+        .ok_or_else(|| "CALIBRE_LIBRARY_PATH is not set".to_string())?;
+    let template = match std::env::var("CALIBRE_CITATION_KEY") {
+        Ok(template) => KeyTemplate::parse(&template)
+            .map_err(|err| format!("invalid CALIBRE_CITATION_KEY: {err}"))?,
+        Err(_) => KeyTemplate::default(),
+    };
+    Index::open(&find_library(&library)?, template)
+}
+
+/// `path` if it is a library, else the first library in it by name.
+fn find_library(path: &Path) -> Result<PathBuf, String> {
+    let is_library = |dir: &Path| dir.join("metadata.db").is_file();
+    if is_library(path) {
+        return Ok(path.to_path_buf());
+    }
+    let mut children = fs::read_dir(path)
+        .map_err(|err| format!("failed to read {}: {err}", path.display()))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|child| is_library(child))
+        .collect::<Vec<_>>();
+    children.sort();
+    children
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("{} is not a Calibre library", path.display()))
+}
@@ -0,0 +1,64 @@
+//! JSON-RPC messages framed the way the Language Server Protocol frames them: a
+//! `Content-Length` header, a blank line, then that many bytes of JSON.
+
+use std::io::{self, BufRead, Write};
+
+use serde_json::Value;
+
+/// Reads the next message, or `None` at the end of the input.
+pub fn read(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "message without Content-Length")
+    })?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+pub fn write(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn frames_messages() {
+        let mut framed = Vec::new();
+        write(
+            &mut framed,
+            &json!({"jsonrpc": "2.0", "id": 1, "result": "é"}),
+        )
+        .unwrap();
+        write(&mut framed, &json!({"jsonrpc": "2.0", "method": "exit"})).unwrap();
+
+        let mut input = io::Cursor::new(framed);
+        let first = read(&mut input).unwrap().unwrap();
+        assert_eq!(first["result"], "é");
+        let second = read(&mut input).unwrap().unwrap();
+        assert_eq!(second["method"], "exit");
+        assert!(read(&mut input).unwrap().is_none());
+    }
+}
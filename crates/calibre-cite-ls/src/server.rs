@@ -0,0 +1,489 @@
+//! The language server's state and its answers to LSP messages: completion of keys,
+//! hover, go to definition, and diagnostics for keys no book has and for the problems of
+//! an unpacked EPUB being edited.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::citations::{self, Language};
+use crate::document::{self, Document};
+use crate::epub;
+use crate::index::Index;
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_REQUEST: i64 = -32600;
+const INTERNAL_ERROR: i64 = -32603;
+/// `CompletionItemKind.Reference`.
+const REFERENCE: i64 = 18;
+/// `DiagnosticSeverity.Warning` and `MessageType.Warning`.
+const WARNING: i64 = 2;
+
+pub struct Server {
+    index: Result<Index, String>,
+    /// Open documents by URI.
+    documents: HashMap<String, Document>,
+    /// Texts of open documents inside an unpacked EPUB, by URI.
+    book_documents: HashMap<String, String>,
+    /// The URIs each unpacked EPUB last had diagnostics for, to clear once fixed.
+    book_diagnostics: HashMap<PathBuf, Vec<String>>,
+    shut_down: bool,
+    exit_code: Option<u8>,
+}
+
+impl Server {
+    /// A server for the books in `index`. Without a library it still answers, with
+    /// nothing, after saying what went wrong.
+    pub fn new(index: Result<Index, String>) -> Self {
+        Self {
+            index,
+            documents: HashMap::new(),
+            book_documents: HashMap::new(),
+            book_diagnostics: HashMap::new(),
+            shut_down: false,
+            exit_code: None,
+        }
+    }
+
+    /// The process exit code once the client has said `exit`.
+    pub fn exit_code(&self) -> Option<u8> {
+        self.exit_code
+    }
+
+    /// Handles a message from the client and returns the messages to send back.
+    pub fn handle(&mut self, message: Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or_default().to_string();
+        let params = &message["params"];
+        let Some(id) = message.get("id").cloned() else {
+            return self.notification(&method, params);
+        };
+        if method.is_empty() {
+            // A response to a request of ours; none are sent.
+            return Vec::new();
+        }
+        let mut replies = match self.refresh() {
+            true => self.all_diagnostics(),
+            false => Vec::new(),
+        };
+        let result = match method.as_str() {
+            "initialize" => Ok(capabilities()),
+            "shutdown" => {
+                self.shut_down = true;
+                Ok(Value::Null)
+            }
+            _ if self.shut_down => Err((INVALID_REQUEST, "the server is shutting down".into())),
+            "textDocument/completion" => Ok(self.completion(params)),
+            "textDocument/hover" => Ok(self.hover(params)),
+            "textDocument/definition" => self.definition(params),
+            _ => Err((METHOD_NOT_FOUND, format!("unsupported method {method}"))),
+        };
+        replies.push(match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": code, "message": message},
+            }),
+        });
+        replies
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        match method {
+            "initialized" => match &self.index {
+                Err(err) => vec![json!({
+                    "jsonrpc": "2.0",
+                    "method": "window/showMessage",
+                    "params": {
+                        "type": WARNING,
+                        "message": format!("Calibre citations are unavailable: {err}"),
+                    },
+                })],
+                Ok(_) => Vec::new(),
+            },
+            "exit" => {
+                self.exit_code = Some(if self.shut_down { 0 } else { 1 });
+                Vec::new()
+            }
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+                let text = document["text"].as_str().unwrap_or_default().to_string();
+                let mut replies = Vec::new();
+                if let Some((root, _)) = epub::locate(&uri) {
+                    self.book_documents.insert(uri.clone(), text.clone());
+                    replies = self.book_diagnostics(&root);
+                }
+                let language = document["languageId"].as_str().unwrap_or_default();
+                if let Some(language) = Language::detect(language, &uri) {
+                    self.documents
+                        .insert(uri.clone(), Document { language, text });
+                    replies.extend(self.diagnostics_after_change(&uri));
+                }
+                replies
+            }
+            "textDocument/didChange" => {
+                // Full sync: the last change holds the whole text.
+                let Some(text) = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str())
+                else {
+                    return Vec::new();
+                };
+                let mut replies = Vec::new();
+                if let Some(book_text) = self.book_documents.get_mut(&uri) {
+                    *book_text = text.to_string();
+                    if let Some((root, _)) = epub::locate(&uri) {
+                        replies = self.book_diagnostics(&root);
+                    }
+                }
+                if let Some(document) = self.documents.get_mut(&uri) {
+                    document.text = text.to_string();
+                    replies.extend(self.diagnostics_after_change(&uri));
+                }
+                replies
+            }
+            "textDocument/didSave" => match epub::locate(&uri) {
+                // Other files of the book may have changed on disk along with it.
+                Some((root, _)) if self.book_documents.contains_key(&uri) => {
+                    self.book_diagnostics(&root)
+                }
+                _ => Vec::new(),
+            },
+            "textDocument/didClose" => {
+                let mut replies = Vec::new();
+                if self.book_documents.remove(&uri).is_some() {
+                    // The book is checked as it is on disk, and still shown.
+                    if let Some((root, _)) = epub::locate(&uri) {
+                        replies = self.book_diagnostics(&root);
+                    }
+                }
+                if self.documents.remove(&uri).is_some() {
+                    replies.push(publish(&uri, Vec::new()));
+                }
+                replies
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Reads the library again if it changed, and says whether it did. A library caught
+    /// halfway through a write by Calibre is tried again on the next message.
+    fn refresh(&mut self) -> bool {
+        self.index
+            .as_mut()
+            .is_ok_and(|index| index.refresh().unwrap_or(false))
+    }
+
+    /// Diagnostics for a document that changed: for all of them if the library did too.
+    fn diagnostics_after_change(&mut self, uri: &str) -> Vec<Value> {
+        match self.refresh() {
+            true => self.all_diagnostics(),
+            false => self.diagnostics(uri),
+        }
+    }
+
+    fn all_diagnostics(&self) -> Vec<Value> {
+        self.documents
+            .keys()
+            .flat_map(|uri| self.diagnostics(uri))
+            .collect()
+    }
+
+    /// A warning for each key no book has. Nothing without a library, which would make
+    /// every key a warning.
+    fn diagnostics(&self, uri: &str) -> Vec<Value> {
+        let (Ok(index), Some(document)) = (&self.index, self.documents.get(uri)) else {
+            return Vec::new();
+        };
+        let diagnostics = citations::citations(&document.text, document.language)
+            .into_iter()
+            .filter(|citation| citation.certain && index.get(&citation.key).is_none())
+            .map(|citation| {
+                json!({
+                    "range": document::range(&document.text, citation.range),
+                    "severity": WARNING,
+                    "source": "calibre",
+                    "message": format!("`{}` matches no book in the Calibre library", citation.key),
+                })
+            })
+            .collect();
+        vec![publish(uri, diagnostics)]
+    }
+
+    /// Diagnostics for every file of the unpacked EPUB at `root`, with its open documents
+    /// as they are in the editor, and empty ones for files whose problems are gone.
+    fn book_diagnostics(&mut self, root: &Path) -> Vec<Value> {
+        let open = self
+            .book_documents
+            .iter()
+            .filter_map(|(uri, text)| match epub::locate(uri) {
+                Some((document_root, path)) if document_root == root => Some((path, text.clone())),
+                _ => None,
+            })
+            .collect();
+        let mut diagnostics = epub::diagnostics(root, open);
+        let previous = self
+            .book_diagnostics
+            .insert(root.to_path_buf(), diagnostics.keys().cloned().collect())
+            .unwrap_or_default();
+        for uri in previous {
+            diagnostics.entry(uri).or_default();
+        }
+        let mut replies = diagnostics
+            .into_iter()
+            .map(|(uri, diagnostics)| publish(&uri, diagnostics))
+            .collect::<Vec<_>>();
+        replies.sort_by(|a, b| {
+            a["params"]["uri"]
+                .as_str()
+                .cmp(&b["params"]["uri"].as_str())
+        });
+        replies
+    }
+
+    /// The document and byte offset a request's `textDocument` and `position` point at.
+    fn located(&self, params: &Value) -> Option<(&Document, usize)> {
+        let document = self
+            .documents
+            .get(params["textDocument"]["uri"].as_str()?)?;
+        let offset = document::offset(&document.text, &params["position"]);
+        Some((document, offset))
+    }
+
+    fn completion(&self, params: &Value) -> Value {
+        let (Ok(index), Some((document, offset))) = (&self.index, self.located(params)) else {
+            return Value::Null;
+        };
+        let Some(start) = citations::completion_start(&document.text, document.language, offset)
+        else {
+            return Value::Null;
+        };
+        let range = document::range(&document.text, start..offset);
+        let items = index
+            .entries()
+            .iter()
+            .map(|entry| {
+                json!({
+                    "label": entry.key,
+                    "kind": REFERENCE,
+                    "detail": entry.summary(),
+                    "documentation": {"kind": "markdown", "value": entry.hover()},
+                    "filterText": format!("{} {}", entry.key, entry.book.title),
+                    "sortText": entry.key,
+                    "textEdit": {"range": range, "newText": entry.key},
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({"isIncomplete": false, "items": items})
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let (Ok(index), Some((document, offset))) = (&self.index, self.located(params)) else {
+            return Value::Null;
+        };
+        let Some(citation) = citations::citation_at(&document.text, document.language, offset)
+        else {
+            return Value::Null;
+        };
+        match index.get(&citation.key) {
+            Some(entry) => json!({
+                "contents": {"kind": "markdown", "value": entry.hover()},
+                "range": document::range(&document.text, citation.range),
+            }),
+            None => Value::Null,
+        }
+    }
+
+    fn definition(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (Ok(index), Some((document, offset))) = (&self.index, self.located(params)) else {
+            return Ok(Value::Null);
+        };
+        let entry = citations::citation_at(&document.text, document.language, offset)
+            .and_then(|citation| index.get(&citation.key));
+        let Some(entry) = entry else {
+            return Ok(Value::Null);
+        };
+        let path = index
+            .definition(entry)
+            .map_err(|err| (INTERNAL_ERROR, err))?;
+        let start = json!({"line": 0, "character": 0});
+        Ok(json!({
+            "uri": document::path_uri(&path),
+            "range": {"start": start, "end": start},
+        }))
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "capabilities": {
+            "textDocumentSync": {"openClose": true, "change": 1, "save": true},
+            "completionProvider": {"triggerCharacters": ["@", "{", ",", "<"]},
+            "hoverProvider": true,
+            "definitionProvider": true,
+        },
+        "serverInfo": {
+            "name": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+    })
+}
+
+fn publish(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": {"uri": uri, "diagnostics": diagnostics},
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use calibre_library::cite::KeyTemplate;
+    use std::path::Path;
+
+    const URI: &str = "file:///notes/holmes.md";
+
+    fn server(text: &str) -> (Server, Vec<Value>) {
+        let library =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../../tests/fixtures/test_library");
+        let mut server = Server::new(Index::open(&library, KeyTemplate::default()));
+        let replies = server.handle(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {
+                "uri": URI, "languageId": "markdown", "version": 1, "text": text,
+            }},
+        }));
+        (server, replies)
+    }
+
+    fn request(server: &mut Server, method: &str, line: u64, character: u64) -> Value {
+        let replies = server.handle(json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": method,
+            "params": {
+                "textDocument": {"uri": URI},
+                "position": {"line": line, "character": character},
+            },
+        }));
+        replies.last().unwrap()["result"].clone()
+    }
+
+    #[test]
+    fn warns_about_keys_no_book_has() {
+        let (_, replies) = server("Both [@doyle1887study; @moriarty1893final] and @lestrade.\n");
+        let diagnostics = &replies[0]["params"]["diagnostics"];
+        assert_eq!(diagnostics.as_array().unwrap().len(), 1);
+        assert_eq!(
+            diagnostics[0]["message"],
+            "`moriarty1893final` matches no book in the Calibre library"
+        );
+        assert_eq!(
+            diagnostics[0]["range"]["start"],
+            json!({"line": 0, "character": 24})
+        );
+    }
+
+    #[test]
+    fn completes_hovers_and_defines_keys() {
+        let (mut server, _) = server("See [@doyle1887study] and [@aus\n");
+
+        let completion = request(&mut server, "textDocument/completion", 0, 31);
+        let items = completion["items"].as_array().unwrap();
+        assert_eq!(items.len(), 4);
+        let austen = items
+            .iter()
+            .find(|item| item["label"] == "austen1813pride")
+            .unwrap();
+        assert_eq!(austen["detail"], "Pride and Prejudice — Jane Austen (1813)");
+        assert_eq!(
+            austen["textEdit"]["range"]["start"],
+            json!({"line": 0, "character": 28})
+        );
+        assert!(request(&mut server, "textDocument/completion", 0, 2).is_null());
+
+        let hover = request(&mut server, "textDocument/hover", 0, 10);
+        let hover = hover["contents"]["value"].as_str().unwrap();
+        assert!(hover.starts_with("**A Study in Scarlet**\n\nArthur Conan Doyle, 1887"));
+        assert!(hover.contains("Sherlock Holmes #1"));
+
+        let definition = request(&mut server, "textDocument/definition", 0, 10);
+        let uri = definition["uri"].as_str().unwrap();
+        assert!(uri.ends_with("/A%20Study%20in%20Scarlet%20%281%29/metadata.opf"));
+    }
+
+    #[test]
+    fn checks_unpacked_epubs_as_edited() {
+        let root = std::env::temp_dir().join(format!("calibre-cite-ls-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let text = r##"<html xmlns="http://www.w3.org/1999/xhtml"><body><nav>
+<a href="#end">End</a></nav></body></html>"##;
+        let files = [
+            ("mimetype", "application/epub+zip"),
+            (
+                "META-INF/container.xml",
+                r#"<container xmlns="urn:oasis:names:tc:opendocument:xmlns:container"><rootfiles>
+<rootfile full-path="content.opf" media-type="application/oebps-package+xml"/>
+</rootfiles></container>"#,
+            ),
+            (
+                "content.opf",
+                r#"<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="id">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:identifier id="id">x</dc:identifier>
+<dc:title>T</dc:title><dc:language>en</dc:language></metadata>
+<manifest><item id="one" href="one.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+</manifest><spine><itemref idref="one"/></spine></package>"#,
+            ),
+            ("one.xhtml", text),
+        ];
+        for (path, text) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+        let uri = document::path_uri(&root.join("one.xhtml"));
+        let mut server = Server::new(Err("no library".into()));
+
+        let replies = server.handle(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {
+                "uri": uri, "languageId": "html", "version": 1, "text": text,
+            }},
+        }));
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0]["params"]["uri"], uri);
+        let diagnostics = &replies[0]["params"]["diagnostics"];
+        assert_eq!(diagnostics.as_array().unwrap().len(), 1);
+        assert_eq!(diagnostics[0]["severity"], 1);
+        assert_eq!(
+            diagnostics[0]["message"],
+            "#end leads to no element: one.xhtml has no id \"end\""
+        );
+        assert_eq!(
+            diagnostics[0]["range"]["start"],
+            json!({"line": 1, "character": 0})
+        );
+
+        let fixed = text.replace("<nav>", "<nav id=\"end\">");
+        let replies = server.handle(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": {"uri": uri, "version": 2},
+                "contentChanges": [{"text": fixed}],
+            },
+        }));
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(replies, [publish(&uri, Vec::new())]);
+    }
+}
@@ -0,0 +1,22 @@
+//! `calibre-commands`, which the Zed extension runs for everything that reads a Calibre
+//! library or Calibre's config: Zed only lets the extension itself see its own work
+//! directory. It answers one request per run, as JSON on stdout; see
+//! `calibre_ebook_manager::native`.
+
+use std::process::ExitCode;
+
+use calibre_ebook_manager::native;
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match native::serve(&args) {
+        Ok(json) => {
+            println!("{json}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("calibre-commands: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,702 @@
+//! Citations of library books: BibTeX, CSL-JSON and RIS records, and APA and Chicago
+//! reference list entries, under citation keys made from a configurable template.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+use serde_json::{json, Map, Value};
+
+use crate::metadata::Book;
+
+/// Key template used when neither `citation_key` setting is set: `doyle1887study`.
+pub const DEFAULT_KEY_TEMPLATE: &str = "{author}{year}{title}";
+
+/// Title words a key skips over, as reference managers do.
+const STOP_WORDS: &[&str] = &[
+    "a", "an", "the", "and", "or", "of", "on", "in", "at", "to", "for", "with", "by", "from", "la",
+    "le", "les", "el", "der", "die", "das",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Bibtex,
+    CslJson,
+    Ris,
+    Apa,
+    Chicago,
+}
+
+impl Style {
+    pub const NAMES: &'static [&'static str] = &["bibtex", "csl-json", "ris", "apa", "chicago"];
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        Ok(match name.trim().to_ascii_lowercase().as_str() {
+            "bibtex" | "bib" => Self::Bibtex,
+            "csl-json" | "csl" | "json" => Self::CslJson,
+            "ris" => Self::Ris,
+            "apa" => Self::Apa,
+            "chicago" => Self::Chicago,
+            other => {
+                return Err(format!(
+                    "unknown citation format \"{other}\"; use one of {}",
+                    Self::NAMES.join(", ")
+                ))
+            }
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Bibtex => "BibTeX",
+            Self::CslJson => "CSL-JSON",
+            Self::Ris => "RIS",
+            Self::Apa => "APA",
+            Self::Chicago => "Chicago",
+        }
+    }
+
+    /// Language of a fenced code block holding the citation; `None` for prose styles.
+    pub fn code_language(self) -> Option<&'static str> {
+        match self {
+            Self::Bibtex => Some("bibtex"),
+            Self::CslJson => Some("json"),
+            Self::Ris => Some("ris"),
+            Self::Apa | Self::Chicago => None,
+        }
+    }
+
+    /// `book` cited in this style under `key`.
+    pub fn format(self, book: &Book, key: &str) -> String {
+        match self {
+            Self::Bibtex => bibtex(book, key),
+            Self::CslJson => serde_json::to_string_pretty(&Value::Array(vec![csl_json(book, key)]))
+                .unwrap_or_default(),
+            Self::Ris => ris(book, key),
+            Self::Apa => apa(book),
+            Self::Chicago => chicago(book),
+        }
+    }
+
+    /// A bibliography file of `entries`, books with their keys: records one after
+    /// another, or a single array for CSL-JSON.
+    pub fn document(self, entries: &[(&Book, &str)]) -> String {
+        match self {
+            Self::CslJson => {
+                let items = entries
+                    .iter()
+                    .map(|(book, key)| csl_json(book, key))
+                    .collect();
+                serde_json::to_string_pretty(&Value::Array(items)).unwrap_or_default() + "\n"
+            }
+            style => {
+                let records = entries
+                    .iter()
+                    .map(|(book, key)| style.format(book, key))
+                    .collect::<Vec<_>>();
+                records.join("\n\n") + "\n"
+            }
+        }
+    }
+}
+
+/// A citation key template such as `{author}{year}{title}`.
+///
+/// Placeholders are `{author}` (the first author's family name), `{authors}` (the first
+/// two, then `etal`), `{year}` (`nd` when unknown), `{title}` (the first title word that
+/// is not a stop word), `{shorttitle}` (the first three) and `{id}` (the Calibre id).
+/// Everything else is copied. Keys are lowercase ASCII, accents folded away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyTemplate {
+    parts: Vec<KeyPart>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum KeyPart {
+    Literal(String),
+    Author,
+    Authors,
+    Year,
+    Title,
+    ShortTitle,
+    Id,
+}
+
+impl Default for KeyTemplate {
+    fn default() -> Self {
+        Self::parse(DEFAULT_KEY_TEMPLATE).unwrap_or(Self { parts: Vec::new() })
+    }
+}
+
+/// The template as written, which `parse` reads back unchanged.
+impl std::fmt::Display for KeyTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for part in &self.parts {
+            f.write_str(match part {
+                KeyPart::Literal(text) => text,
+                KeyPart::Author => "{author}",
+                KeyPart::Authors => "{authors}",
+                KeyPart::Year => "{year}",
+                KeyPart::Title => "{title}",
+                KeyPart::ShortTitle => "{shorttitle}",
+                KeyPart::Id => "{id}",
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl KeyTemplate {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(KeyPart::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed {{ in citation key template \"{template}\""))?;
+            let placeholder = &rest[start + 1..start + end];
+            parts.push(match placeholder {
+                "author" => KeyPart::Author,
+                "authors" => KeyPart::Authors,
+                "year" => KeyPart::Year,
+                "title" => KeyPart::Title,
+                "shorttitle" => KeyPart::ShortTitle,
+                "id" => KeyPart::Id,
+                other => {
+                    return Err(format!(
+                        "unknown placeholder {{{other}}} in citation key template; use \
+                         {{author}}, {{authors}}, {{year}}, {{title}}, {{shorttitle}} or {{id}}"
+                    ))
+                }
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(KeyPart::Literal(rest.to_string()));
+        }
+        if !parts
+            .iter()
+            .any(|part| !matches!(part, KeyPart::Literal(_)))
+        {
+            return Err(format!(
+                "citation key template \"{template}\" has no placeholders, so every book would \
+                 get the same key"
+            ));
+        }
+        Ok(Self { parts })
+    }
+
+    /// The key `book` gets before clashes with other books are resolved.
+    pub fn key(&self, book: &Book) -> String {
+        let families = book.author_sorts.iter().map(|sort| family_name(sort));
+        let words = title_words(&book.title);
+        let mut key = String::new();
+        for part in &self.parts {
+            match part {
+                KeyPart::Literal(text) => key.push_str(text),
+                KeyPart::Author => key.push_str(families.clone().next().unwrap_or("anon")),
+                KeyPart::Authors => {
+                    for family in families.clone().take(2) {
+                        key.push_str(family);
+                    }
+                    if book.author_sorts.len() > 2 {
+                        key.push_str("etal");
+                    }
+                    if book.author_sorts.is_empty() {
+                        key.push_str("anon");
+                    }
+                }
+                KeyPart::Year => key.push_str(year(book).unwrap_or("nd")),
+                KeyPart::Title => {
+                    key.push_str(words.first().map(String::as_str).unwrap_or_default())
+                }
+                KeyPart::ShortTitle => {
+                    key.push_str(&words.iter().take(3).cloned().collect::<String>())
+                }
+                KeyPart::Id => {
+                    let _ = write!(key, "{}", book.id);
+                }
+            }
+        }
+        key_text(&key)
+    }
+
+    /// Keys for all of `books`. Where books would share a key, the first by id keeps it
+    /// and the others get `a`, `b`, … appended, so adding a book never changes the key of
+    /// one already cited.
+    pub fn keys(&self, books: &[Book]) -> HashMap<i64, String> {
+        let mut groups: HashMap<String, Vec<i64>> = HashMap::new();
+        for book in books {
+            groups.entry(self.key(book)).or_default().push(book.id);
+        }
+        let mut keys = HashMap::new();
+        for (key, mut ids) in groups {
+            ids.sort_unstable();
+            for (index, id) in ids.into_iter().enumerate() {
+                let key = match index {
+                    0 => key.clone(),
+                    index => format!("{key}{}", suffix(index - 1)),
+                };
+                keys.insert(id, key);
+            }
+        }
+        keys
+    }
+}
+
+/// `a` … `z`, then `aa`, `ab`, …
+fn suffix(index: usize) -> String {
+    let letter = |index: usize| char::from(b'a' + (index % 26) as u8);
+    match index / 26 {
+        0 => letter(index).to_string(),
+        prefix => format!("{}{}", suffix(prefix - 1), letter(index)),
+    }
+}
+
+/// The family name in a sort name such as `Doyle, Arthur Conan`; a name without a comma
+/// is taken whole.
+fn family_name(sort: &str) -> &str {
+    sort.split_once(',')
+        .map_or(sort, |(family, _)| family)
+        .trim()
+}
+
+fn given_names(sort: &str) -> Option<&str> {
+    sort.split_once(',')
+        .map(|(_, given)| given.trim())
+        .filter(|given| !given.is_empty())
+}
+
+/// Lowercase title words for keys, stop words left out.
+fn title_words(title: &str) -> Vec<String> {
+    title
+        .split(|c: char| !c.is_alphanumeric() && c != '\'' && c != '’')
+        .map(key_text)
+        .filter(|word| !word.is_empty() && !STOP_WORDS.contains(&word.as_str()))
+        .collect()
+}
+
+/// Text reduced to what keys may contain: lowercase ASCII letters, digits, `-`, `_`, `:`.
+fn key_text(text: &str) -> String {
+    let mut key = String::new();
+    for c in text.chars().flat_map(char::to_lowercase) {
+        match c {
+            'a'..='z' | '0'..='9' | '-' | '_' | ':' => key.push(c),
+            c => key.push_str(fold_accent(c)),
+        }
+    }
+    key
+}
+
+/// The ASCII letters a Latin letter with a diacritic is written as, or nothing.
+fn fold_accent(c: char) -> &'static str {
+    match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => "a",
+        'æ' => "ae",
+        'ç' | 'ć' | 'č' => "c",
+        'ď' | 'đ' | 'ð' => "d",
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ė' | 'ę' | 'ě' => "e",
+        'ğ' => "g",
+        'ì' | 'í' | 'î' | 'ï' | 'ī' | 'į' | 'ı' => "i",
+        'ł' | 'ľ' | 'ĺ' => "l",
+        'ñ' | 'ń' | 'ň' => "n",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ő' => "o",
+        'œ' => "oe",
+        'ř' | 'ŕ' => "r",
+        'ś' | 'š' | 'ş' | 'ș' => "s",
+        'ß' => "ss",
+        'ť' | 'ţ' | 'ț' => "t",
+        'þ' => "th",
+        'ù' | 'ú' | 'û' | 'ü' | 'ū' | 'ů' | 'ű' | 'ų' => "u",
+        'ý' | 'ÿ' => "y",
+        'ź' | 'ż' | 'ž' => "z",
+        _ => "",
+    }
+}
+
+/// The year of publication, unless Calibre has it as unknown.
+pub fn year(book: &Book) -> Option<&str> {
+    date_parts(book).map(|(year, _, _)| year)
+}
+
+/// Year, month and day of the publication date.
+fn date_parts(book: &Book) -> Option<(&str, u32, u32)> {
+    let pubdate = book.pubdate.as_deref()?;
+    let year = pubdate
+        .get(..4)
+        .filter(|year| year.bytes().all(|b| b.is_ascii_digit()))?;
+    // Calibre's placeholder for an unknown date is in the year 101.
+    if year == "0101" {
+        return None;
+    }
+    let month = pubdate.get(5..7).and_then(|month| month.parse().ok())?;
+    let day = pubdate.get(8..10).and_then(|day| day.parse().ok())?;
+    Some((year, month, day))
+}
+
+fn identifier<'a>(book: &'a Book, kinds: &[&str]) -> Option<&'a str> {
+    book.identifiers
+        .iter()
+        .find(|(kind, _)| kinds.iter().any(|wanted| kind.eq_ignore_ascii_case(wanted)))
+        .map(|(_, value)| value.as_str())
+}
+
+/// A series index as written: `2`, or `2.5`.
+fn series_number(index: f64) -> String {
+    if index.fract() == 0.0 {
+        format!("{index:.0}")
+    } else {
+        index.to_string()
+    }
+}
+
+/// Escapes characters that are special in BibTeX field values.
+fn latex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' | '%' | '$' | '#' | '_' | '{' | '}' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '~' => escaped.push_str("\\textasciitilde{}"),
+            '^' => escaped.push_str("\\textasciicircum{}"),
+            '\\' => escaped.push_str("\\textbackslash{}"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn bibtex(book: &Book, key: &str) -> String {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+
+    let mut fields: Vec<(&str, String)> = Vec::new();
+    if !book.author_sorts.is_empty() {
+        let authors = book
+            .author_sorts
+            .iter()
+            .map(|sort| match sort.contains(',') {
+                true => latex(sort),
+                // Braces keep a corporate author from being split into names.
+                false => format!("{{{}}}", latex(sort)),
+            })
+            .collect::<Vec<_>>()
+            .join(" and ");
+        fields.push(("author", format!("{{{authors}}}")));
+    }
+    fields.push(("title", format!("{{{}}}", latex(&book.title))));
+    if let Some(series) = &book.series {
+        fields.push(("series", format!("{{{}}}", latex(series))));
+        fields.push((
+            "number",
+            format!("{{{}}}", series_number(book.series_index)),
+        ));
+    }
+    if let Some(publisher) = &book.publisher {
+        fields.push(("publisher", format!("{{{}}}", latex(publisher))));
+    }
+    if let Some((year, month, _)) = date_parts(book) {
+        fields.push(("year", format!("{{{year}}}")));
+        if let Some(month) = month
+            .checked_sub(1)
+            .and_then(|index| MONTHS.get(index as usize))
+        {
+            fields.push(("month", month.to_string()));
+        }
+    }
+    for (field, kinds) in [
+        ("isbn", &["isbn"][..]),
+        ("doi", &["doi"]),
+        ("url", &["url", "uri"]),
+    ] {
+        if let Some(value) = identifier(book, kinds) {
+            fields.push((field, format!("{{{}}}", latex(value))));
+        }
+    }
+
+    let width = fields.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    let mut entry = format!("@book{{{key},\n");
+    for (name, value) in fields {
+        let _ = writeln!(entry, "  {name:width$} = {value},");
+    }
+    entry.push('}');
+    entry
+}
+
+fn csl_json(book: &Book, key: &str) -> Value {
+    let mut item = Map::new();
+    item.insert("id".to_string(), json!(key));
+    item.insert("type".to_string(), json!("book"));
+    item.insert("title".to_string(), json!(book.title));
+    if !book.author_sorts.is_empty() {
+        let authors = book
+            .author_sorts
+            .iter()
+            .map(|sort| match given_names(sort) {
+                Some(given) => json!({ "family": family_name(sort), "given": given }),
+                None => json!({ "literal": sort }),
+            })
+            .collect::<Vec<_>>();
+        item.insert("author".to_string(), Value::Array(authors));
+    }
+    if let Some((year, month, day)) = date_parts(book) {
+        let year = year.parse::<u32>().unwrap_or_default();
+        item.insert(
+            "issued".to_string(),
+            json!({ "date-parts": [[year, month, day]] }),
+        );
+    }
+    if let Some(publisher) = &book.publisher {
+        item.insert("publisher".to_string(), json!(publisher));
+    }
+    if let Some(series) = &book.series {
+        item.insert("collection-title".to_string(), json!(series));
+        item.insert(
+            "collection-number".to_string(),
+            json!(series_number(book.series_index)),
+        );
+    }
+    for (field, kinds) in [
+        ("ISBN", &["isbn"][..]),
+        ("DOI", &["doi"]),
+        ("URL", &["url", "uri"]),
+    ] {
+        if let Some(value) = identifier(book, kinds) {
+            item.insert(field.to_string(), json!(value));
+        }
+    }
+    Value::Object(item)
+}
+
+fn ris(book: &Book, key: &str) -> String {
+    let mut lines = vec![("TY", "BOOK".to_string())];
+    for sort in &book.author_sorts {
+        lines.push(("AU", sort.clone()));
+    }
+    lines.push(("TI", book.title.clone()));
+    if let Some(series) = &book.series {
+        lines.push(("T3", series.clone()));
+        lines.push(("VL", series_number(book.series_index)));
+    }
+    if let Some((year, month, day)) = date_parts(book) {
+        lines.push(("PY", year.to_string()));
+        lines.push(("DA", format!("{year}/{month:02}/{day:02}")));
+    }
+    if let Some(publisher) = &book.publisher {
+        lines.push(("PB", publisher.clone()));
+    }
+    for (tag, kinds) in [
+        ("SN", &["isbn", "issn"][..]),
+        ("DO", &["doi"]),
+        ("UR", &["url", "uri"]),
+    ] {
+        if let Some(value) = identifier(book, kinds) {
+            lines.push((tag, value.to_string()));
+        }
+    }
+    lines.push(("ID", key.to_string()));
+    lines.push(("ER", String::new()));
+
+    lines
+        .into_iter()
+        .map(|(tag, value)| format!("{tag}  - {value}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// `Doyle, A. C.`: a family name with initials.
+fn initials(sort: &str) -> String {
+    match given_names(sort) {
+        Some(given) => {
+            let initials = given
+                .split_whitespace()
+                .map(|name| {
+                    let parts = name
+                        .split('-')
+                        .filter_map(|part| part.chars().next())
+                        .map(|initial| format!("{initial}."))
+                        .collect::<Vec<_>>();
+                    parts.join("-")
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            format!("{}, {initials}", family_name(sort))
+        }
+        None => sort.to_string(),
+    }
+}
+
+/// An APA 7 reference list entry. Titles are kept as Calibre has them, since turning
+/// them into sentence case would lowercase proper nouns.
+fn apa(book: &Book) -> String {
+    let authors = book
+        .author_sorts
+        .iter()
+        .map(|sort| initials(sort))
+        .collect::<Vec<_>>();
+    let authors = match authors.as_slice() {
+        [] => String::new(),
+        [one] => one.clone(),
+        [first, second] => format!("{first}, & {second}"),
+        [rest @ .., last] if authors.len() <= 20 => format!("{}, & {last}", rest.join(", ")),
+        // APA lists the first nineteen, an ellipsis and the last.
+        [rest @ .., last] => format!("{}, … {last}", rest[..19].join(", ")),
+    };
+    let year = year(book).unwrap_or("n.d.");
+
+    let mut entry = match authors.is_empty() {
+        true => format!("*{}*. ({year}).", book.title),
+        false => {
+            let authors = if authors.ends_with('.') {
+                authors
+            } else {
+                format!("{authors}.")
+            };
+            format!("{authors} ({year}). *{}*.", book.title)
+        }
+    };
+    if let Some(publisher) = &book.publisher {
+        let _ = write!(entry, " {publisher}.");
+    }
+    if let Some(doi) = identifier(book, &["doi"]) {
+        let _ = write!(entry, " https://doi.org/{doi}");
+    } else if let Some(url) = identifier(book, &["url", "uri"]) {
+        let _ = write!(entry, " {url}");
+    }
+    entry
+}
+
+/// A Chicago notes and bibliography style bibliography entry.
+fn chicago(book: &Book) -> String {
+    let names = book
+        .author_sorts
+        .iter()
+        .zip(&book.authors)
+        .enumerate()
+        .map(|(index, (sort, name))| match index {
+            0 => sort.clone(),
+            _ => name.clone(),
+        })
+        .collect::<Vec<_>>();
+    let authors = match names.as_slice() {
+        [] => String::new(),
+        [one] => one.clone(),
+        [first, second] => format!("{first}, and {second}"),
+        [rest @ .., last] if names.len() <= 10 => format!("{}, and {last}", rest.join(", ")),
+        // Chicago lists seven authors, then et al.
+        _ => format!("{}, et al", names[..7].join(", ")),
+    };
+
+    let mut entry = String::new();
+    if !authors.is_empty() {
+        entry.push_str(authors.trim_end_matches('.'));
+        entry.push_str(". ");
+    }
+    let _ = write!(entry, "*{}*.", book.title);
+    if let Some(series) = &book.series {
+        let _ = write!(entry, " {series} {}.", series_number(book.series_index));
+    }
+    match (book.publisher.as_deref(), year(book)) {
+        (Some(publisher), Some(year)) => {
+            let _ = write!(entry, " {publisher}, {year}.");
+        }
+        (Some(publisher), None) => {
+            let _ = write!(entry, " {publisher}, n.d.");
+        }
+        (None, Some(year)) => {
+            let _ = write!(entry, " {year}.");
+        }
+        (None, None) => {}
+    }
+    if let Some(doi) = identifier(book, &["doi"]) {
+        let _ = write!(entry, " https://doi.org/{doi}.");
+    } else if let Some(url) = identifier(book, &["url", "uri"]) {
+        let _ = write!(entry, " {url}.");
+    }
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::metadata;
+
+    fn fixture() -> Vec<Book> {
+        let library =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../../tests/fixtures/test_library");
+        metadata::books(&library).unwrap()
+    }
+
+    #[test]
+    fn makes_stable_keys_from_the_template() {
+        let books = fixture();
+        let keys = KeyTemplate::default().keys(&books);
+        assert_eq!(keys[&1], "doyle1887study");
+        assert_eq!(keys[&2], "doyle1890sign");
+        assert_eq!(keys[&3], "austen1813pride");
+        assert_eq!(keys[&4], "twain1876adventures");
+
+        let keys = KeyTemplate::parse("{author}-{shorttitle}")
+            .unwrap()
+            .keys(&books);
+        assert_eq!(keys[&4], "twain-adventurestomsawyer");
+        let keys = KeyTemplate::parse("{author}").unwrap().keys(&books);
+        assert_eq!(keys[&1], "doyle");
+        assert_eq!(keys[&2], "doylea");
+        assert_eq!(keys[&3], "austen");
+
+        assert!(KeyTemplate::parse("{surname}{year}").is_err());
+        assert!(KeyTemplate::parse("paper").is_err());
+        assert_eq!(
+            KeyTemplate::parse("cal:{author}_{id}").unwrap().to_string(),
+            "cal:{author}_{id}"
+        );
+    }
+
+    #[test]
+    fn formats_citations() {
+        let books = fixture();
+        let study = &books[0];
+        assert_eq!(
+            Style::Bibtex.format(study, "doyle1887study"),
+            "@book{doyle1887study,\n\
+             \x20 author    = {Doyle, Arthur Conan},\n\
+             \x20 title     = {A Study in Scarlet},\n\
+             \x20 series    = {Sherlock Holmes},\n\
+             \x20 number    = {1},\n\
+             \x20 publisher = {Ward Lock \\& Co},\n\
+             \x20 year      = {1887},\n\
+             \x20 month     = nov,\n\
+             }"
+        );
+        assert_eq!(
+            Style::Ris.format(&books[2], "austen1813pride"),
+            "TY  - BOOK\nAU  - Austen, Jane\nTI  - Pride and Prejudice\nPY  - 1813\n\
+             DA  - 1813/01/28\nPB  - T. Egerton\nID  - austen1813pride\nER  - "
+        );
+        assert_eq!(
+            Style::Apa.format(study, ""),
+            "Doyle, A. C. (1887). *A Study in Scarlet*. Ward Lock & Co."
+        );
+        assert_eq!(
+            Style::Chicago.format(study, ""),
+            "Doyle, Arthur Conan. *A Study in Scarlet*. Sherlock Holmes 1. Ward Lock & Co, 1887."
+        );
+
+        let csl: Value =
+            serde_json::from_str(&Style::CslJson.format(study, "doyle1887study")).unwrap();
+        assert_eq!(csl[0]["id"], "doyle1887study");
+        assert_eq!(
+            csl[0]["author"][0],
+            json!({ "family": "Doyle", "given": "Arthur Conan" })
+        );
+        assert_eq!(csl[0]["issued"]["date-parts"], json!([[1887, 11, 1]]));
+    }
+}
@@ -0,0 +1,194 @@
+//! DEFLATE compression (RFC 1951), for the entries of repacked EPUBs.
+//!
+//! Greedy LZ77 matching over hash chains, written as one block with the fixed Huffman
+//! codes. Output runs a little larger than zlib's, which builds codes per block, but
+//! markup compresses well either way and this stays small.
+
+use crate::inflate::{DISTANCE_BASE, DISTANCE_EXTRA, LENGTH_BASE, LENGTH_EXTRA};
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+/// Most earlier positions tried when looking for a match.
+const MAX_CHAIN: usize = 128;
+const END_OF_BLOCK: u16 = 256;
+const NONE: usize = usize::MAX;
+
+/// Compresses `data` into raw DEFLATE, as zip stores it.
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut output = BitWriter::default();
+    // BFINAL, then BTYPE 01: fixed codes.
+    output.bits(1, 1);
+    output.bits(1, 2);
+
+    let mut matcher = Matcher {
+        data,
+        head: vec![NONE; 1 << HASH_BITS],
+        previous: vec![NONE; WINDOW],
+    };
+    let mut position = 0;
+    while position < data.len() {
+        let (length, distance) = matcher.longest_match(position);
+        if length >= MIN_MATCH {
+            output.length(length);
+            output.distance(distance);
+            for inserted in position..position + length {
+                matcher.insert(inserted);
+            }
+            position += length;
+        } else {
+            output.symbol(u16::from(data[position]));
+            matcher.insert(position);
+            position += 1;
+        }
+    }
+    output.symbol(END_OF_BLOCK);
+    output.finish()
+}
+
+/// Hash chains over the positions seen so far.
+struct Matcher<'a> {
+    data: &'a [u8],
+    /// The last position each hash was seen at.
+    head: Vec<usize>,
+    /// For each position in the window, the one before it with the same hash.
+    previous: Vec<usize>,
+}
+
+impl Matcher<'_> {
+    fn insert(&mut self, position: usize) {
+        if position + MIN_MATCH <= self.data.len() {
+            let hash = hash(&self.data[position..position + MIN_MATCH]);
+            self.previous[position % WINDOW] = self.head[hash];
+            self.head[hash] = position;
+        }
+    }
+
+    /// The longest earlier match for the bytes at `position`, as `(length, distance)`.
+    fn longest_match(&self, position: usize) -> (usize, usize) {
+        let data = self.data;
+        if position + MIN_MATCH > data.len() {
+            return (0, 0);
+        }
+        let limit = MAX_MATCH.min(data.len() - position);
+        let mut best = (0, 0);
+        let mut candidate = self.head[hash(&data[position..position + MIN_MATCH])];
+        for _ in 0..MAX_CHAIN {
+            // Positions further back than the window have had their slots reused.
+            if candidate == NONE || candidate >= position || position - candidate > WINDOW {
+                break;
+            }
+            let length = data[candidate..]
+                .iter()
+                .zip(&data[position..position + limit])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length > best.0 {
+                best = (length, position - candidate);
+                if length == limit {
+                    break;
+                }
+            }
+            candidate = self.previous[candidate % WINDOW];
+        }
+        best
+    }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let value = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+    (value.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Writes bits least significant first, as DEFLATE packs them.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, count: u32) {
+        self.buffer |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// A Huffman code, which DEFLATE stores most significant bit first.
+    fn code(&mut self, code: u32, length: u32) {
+        self.bits(code.reverse_bits() >> (32 - length), length);
+    }
+
+    /// A literal byte, a match length code or the end of the block, in the fixed code.
+    fn symbol(&mut self, symbol: u16) {
+        let symbol = u32::from(symbol);
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn length(&mut self, length: usize) {
+        let index = LENGTH_BASE
+            .iter()
+            .rposition(|&base| usize::from(base) <= length)
+            .unwrap_or(0);
+        self.symbol(257 + index as u16);
+        let extra = length - usize::from(LENGTH_BASE[index]);
+        self.bits(extra as u32, u32::from(LENGTH_EXTRA[index]));
+    }
+
+    fn distance(&mut self, distance: usize) {
+        let index = DISTANCE_BASE
+            .iter()
+            .rposition(|&base| usize::from(base) <= distance)
+            .unwrap_or(0);
+        self.code(index as u32, 5);
+        let extra = distance - usize::from(DISTANCE_BASE[index]);
+        self.bits(extra as u32, u32::from(DISTANCE_EXTRA[index]));
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inflate::inflate;
+
+    #[test]
+    fn round_trips_through_inflate() {
+        let text = "<p>It is a truth universally acknowledged, that a single man in possession \
+                    of a good fortune, must be in want of a wife.</p>\n"
+            .repeat(400);
+        let mut noise = Vec::new();
+        let mut state = 0x1234_5678u32;
+        for _ in 0..70_000 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            noise.push((state % 7) as u8 + b'a');
+        }
+        let runs = vec![0u8; 100_000];
+
+        for data in [b"".as_slice(), b"ab", text.as_bytes(), &noise, &runs] {
+            let compressed = deflate(data);
+            assert_eq!(inflate(&compressed, data.len()).unwrap(), data);
+        }
+        assert!(deflate(text.as_bytes()).len() < text.len() / 20);
+        assert!(deflate(&runs).len() < 1000);
+    }
+}
@@ -0,0 +1,384 @@
+//! Editing a library book's EPUB outside Calibre: unpacking it into a folder, packing the
+//! folder back into an EPUB, and putting that in the library in place of the old one.
+//!
+//! Replacing a format changes `metadata.db` in place, in a [`Transaction`] that holds
+//! SQLite's exclusive lock, so it waits for nothing and is refused while Calibre reads or
+//! writes the library; where that lock cannot be taken, the caller vouches that Calibre
+//! is closed. A Calibre that has the library open but idle reads the change back, though
+//! it shows it only once restarted. The book is replaced by renaming a synced copy over
+//! it after the database is committed, and the database is put back if it cannot be.
+
+use std::fs::{self, File};
+use std::io::Write as _;
+use std::path::{Component, Path, PathBuf};
+
+use crate::epubcheck::{Source, EPUB_MEDIA_TYPE, MIMETYPE};
+use crate::recover;
+use crate::sqlite::write::Transaction;
+use crate::sqlite::{Row, Value};
+use crate::zip::{Archive, Writer, METHOD_DEFLATED, METHOD_STORED};
+
+/// What the copy of a replaced format is called, e.g. `ORIGINAL_EPUB`.
+pub const ORIGINAL_PREFIX: &str = "ORIGINAL_";
+/// Whether [`replace_format`] can lock the library against Calibre, rather than take the
+/// user's word that Calibre is closed.
+pub const CAN_LOCK: bool = Transaction::LOCKS;
+
+/// Writes the files of the EPUB at `epub` into `folder`, which must not exist yet, and
+/// returns how many there were.
+pub fn unpack(epub: &Path, folder: &Path) -> Result<usize, String> {
+    if folder.exists() {
+        return Err(format!("{} already exists", folder.display()));
+    }
+    let archive = Archive::open(epub)?;
+    let mut count = 0;
+    for entry in archive.entries() {
+        if entry.name.ends_with('/') {
+            continue;
+        }
+        let relative = Path::new(&entry.name);
+        // A name like `../../.bashrc` would write outside the folder.
+        if entry.name.contains('\\')
+            || !relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(format!(
+                "{} has an unsafe file name: {}",
+                epub.display(),
+                entry.name
+            ));
+        }
+        let path = folder.join(relative);
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)
+                .map_err(|err| format!("failed to create {}: {err}", directory.display()))?;
+        }
+        fs::write(&path, archive.read(&entry.name)?)
+            .map_err(|err| format!("failed to write {}: {err}", path.display()))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// The EPUB of the unpacked book in `folder`: `mimetype` first and stored, as reading
+/// systems look for it at a fixed offset, then the other files deflated, in path order.
+/// Hidden files such as `.git` are left out.
+pub fn pack(folder: &Path) -> Result<Vec<u8>, String> {
+    let source = Source::unpacked(folder);
+    let mut writer = Writer::default();
+    writer.add(MIMETYPE, EPUB_MEDIA_TYPE.as_bytes(), METHOD_STORED)?;
+    for path in source.files() {
+        if path != MIMETYPE {
+            writer.add(&path, &source.read(&path)?, METHOD_DEFLATED)?;
+        }
+    }
+    writer.finish()
+}
+
+/// What [`replace_format`] did.
+#[derive(Debug)]
+pub struct Replaced {
+    /// The book's file in the format, now holding the new contents.
+    pub path: PathBuf,
+    /// The copy of the file as it was, when this replacement made it.
+    pub original: Option<PathBuf>,
+}
+
+/// Puts `contents` in the library at `library` as book `book`'s file in `format`, in
+/// place of the one there, and updates `data.uncompressed_size` and `books.last_modified`
+/// in `metadata.db` to match. As Calibre's Polish does, the file being replaced is kept
+/// as `ORIGINAL_<format>` unless the book has one already, so the original is the file
+/// as it was before the first edit. A library another program is using is left alone;
+/// `calibre_closed` is the user's word that Calibre is closed, which is what it goes on
+/// where the database cannot be locked.
+pub fn replace_format(
+    library: &Path,
+    book: i64,
+    format: &str,
+    contents: &[u8],
+    calibre_closed: bool,
+) -> Result<Replaced, String> {
+    let db = library.join("metadata.db");
+    let mut transaction = Transaction::begin(&db, calibre_closed)?;
+    let database = transaction.database();
+
+    let books = database.table("books")?;
+    let directory = books
+        .rows()
+        .find(|row| row.integer("id") == Some(book))
+        .and_then(|row| row.text("path"))
+        .map(|path| library.join(path))
+        .ok_or_else(|| format!("there is no book {book} in the library"))?;
+    let data = database.table("data")?;
+    let format = format.to_ascii_uppercase();
+    let original_format = format!("{ORIGINAL_PREFIX}{format}");
+    let files = data
+        .rows()
+        .filter(|row| row.integer("book") == Some(book))
+        .collect::<Vec<_>>();
+    let file = files
+        .iter()
+        .find(|row| format_of(row) == format)
+        .ok_or_else(|| format!("book {book} has no {format}"))?;
+    let (Some(file_id), Some(name)) = (file.integer("id"), file.text("name")) else {
+        return Err(format!(
+            "the data row of book {book}'s {format} is incomplete"
+        ));
+    };
+    let name = name.to_string();
+    let path = directory.join(format!("{name}.{}", format.to_lowercase()));
+    let keep_original = !files.iter().any(|row| format_of(row) == original_format);
+    let original = directory.join(format!("{name}.{}", original_format.to_lowercase()));
+    let next_id = data
+        .rows()
+        .filter_map(|row| row.integer("id"))
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let columns = database
+        .table_schema("data")
+        .map(|schema| schema.columns.clone())
+        .unwrap_or_default();
+    let has_sequence = database.has_table("sqlite_sequence");
+
+    transaction.update(
+        "data",
+        "uncompressed_size",
+        Value::Integer(contents.len() as i64),
+        |row| row.integer("id") == Some(file_id),
+    )?;
+    transaction.update(
+        "books",
+        "last_modified",
+        Value::Text(recover::now()),
+        |row| row.integer("id") == Some(book),
+    )?;
+    let mut replaced = Vec::new();
+    if keep_original {
+        replaced =
+            fs::read(&path).map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+        let size = replaced.len();
+        let values = columns
+            .iter()
+            .map(|column| match column.to_ascii_lowercase().as_str() {
+                "id" => Value::Integer(next_id),
+                "book" => Value::Integer(book),
+                "format" => Value::Text(original_format.clone()),
+                "uncompressed_size" => Value::Integer(size as i64),
+                "name" => Value::Text(name.clone()),
+                _ => Value::Null,
+            })
+            .collect();
+        transaction.insert("data", values)?;
+        if has_sequence {
+            transaction.update("sqlite_sequence", "seq", Value::Integer(next_id), |row| {
+                row.text("name") == Some("data")
+                    && row.integer("seq").is_some_and(|seq| seq < next_id)
+            })?;
+        }
+    }
+    // Everything that can fail without touching the library comes first.
+    let partial = with_suffix(&path, ".partial");
+    write_synced(&partial, contents)?;
+    if keep_original {
+        if let Err(err) = write_synced(&original, &replaced) {
+            let _ = fs::remove_file(&partial);
+            return Err(err);
+        }
+    }
+
+    let discard = || {
+        let _ = fs::remove_file(&partial);
+        if keep_original {
+            let _ = fs::remove_file(&original);
+        }
+    };
+
+    // The original is on disk before the database lists it, and the database says what
+    // the book holds before the book does.
+    if let Err(err) = transaction.commit() {
+        discard();
+        return Err(err);
+    }
+    if let Err(err) = fs::rename(&partial, &path) {
+        let err = format!("failed to write {}: {err}", path.display());
+        if let Err(rollback) = transaction.undo() {
+            let _ = fs::remove_file(&partial);
+            return Err(format!(
+                "{err}, and putting back {} failed too, so it lists the new file: {rollback}",
+                db.display()
+            ));
+        }
+        discard();
+        return Err(err);
+    }
+    sync_directory(&directory);
+    Ok(Replaced {
+        path,
+        original: keep_original.then_some(original),
+    })
+}
+
+/// `path` with `suffix` added to its file name, e.g. `metadata.db-wal`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Writes a new file and waits until it is on disk.
+fn write_synced(path: &Path, contents: &[u8]) -> Result<(), String> {
+    File::create(path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .map_err(|err| format!("failed to write {}: {err}", path.display()))
+}
+
+/// Makes renames in `directory` durable, where the platform allows it.
+fn sync_directory(directory: &Path) {
+    if let Ok(directory) = File::open(directory) {
+        let _ = directory.sync_all();
+    }
+}
+
+fn format_of(row: &Row<'_>) -> String {
+    row.text("format").unwrap_or_default().to_ascii_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata;
+
+    fn copy_dir(from: &Path, to: &Path) {
+        fs::create_dir_all(to).unwrap();
+        for entry in fs::read_dir(from).unwrap().flatten() {
+            let target = to.join(entry.file_name());
+            if entry.path().is_dir() {
+                copy_dir(&entry.path(), &target);
+            } else {
+                fs::copy(entry.path(), target).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn unpacks_edits_and_repacks_a_library_book() {
+        let scratch = std::env::temp_dir().join(format!("calibre-edit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&scratch);
+        let library = scratch.join("library");
+        copy_dir(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("../../tests/fixtures/test_library"),
+            &library,
+        );
+        let book = metadata::books(&library).unwrap().remove(0);
+        let epub = book.file(&library, "EPUB").unwrap();
+        let before = fs::read(&epub).unwrap();
+
+        let folder = scratch.join("A Study in Scarlet (1)");
+        let count = unpack(&epub, &folder).unwrap();
+        assert_eq!(count, Archive::open(&epub).unwrap().entries().len());
+        assert!(unpack(&epub, &folder)
+            .unwrap_err()
+            .ends_with("already exists"));
+        let page = folder.join("OEBPS/page1.xhtml");
+        let text = fs::read_to_string(&page).unwrap();
+        fs::write(
+            &page,
+            text.replace("</body>", "<p>An added note.</p></body>"),
+        )
+        .unwrap();
+        fs::write(folder.join(".notes"), "left out").unwrap();
+
+        let packed = pack(&folder).unwrap();
+        let archive = Archive::from_bytes(packed.clone()).unwrap();
+        let entries = archive.entries();
+        assert_eq!(
+            (entries[0].name.as_str(), entries[0].method),
+            (MIMETYPE, METHOD_STORED)
+        );
+        assert_eq!(entries.len(), count);
+        assert!(archive
+            .read_text("OEBPS/page1.xhtml")
+            .unwrap()
+            .contains("An added note."));
+
+        let replaced = replace_format(&library, book.id, "epub", &packed, false).unwrap();
+        assert_eq!(replaced.path, epub);
+        assert_eq!(fs::read(&epub).unwrap(), packed);
+        let original = replaced.original.unwrap();
+        assert!(original.ends_with("A Study in Scarlet (1)/1.original_epub"));
+        assert_eq!(fs::read(&original).unwrap(), before);
+
+        let book = metadata::books(&library).unwrap().remove(0);
+        assert_eq!(book.formats, ["EPUB", "ORIGINAL_EPUB", "PDF"]);
+        let size = |book: &metadata::Book, format: &str| {
+            let data = book.files.iter().find(|data| data.format == format);
+            data.unwrap().uncompressed_size as usize
+        };
+        assert_eq!(size(&book, "EPUB"), packed.len());
+        assert_eq!(size(&book, "ORIGINAL_EPUB"), before.len());
+        assert_ne!(book.last_modified.as_deref(), Some("2026-10-15 05:17:45"));
+
+        // A second round keeps the first original.
+        let replaced = replace_format(&library, book.id, "EPUB", &before, false).unwrap();
+        assert_eq!(replaced.original, None);
+        let book = metadata::books(&library).unwrap().remove(0);
+        assert_eq!(size(&book, "EPUB"), before.len());
+        assert_eq!(size(&book, "ORIGINAL_EPUB"), before.len());
+        let listing = |directory: &Path| {
+            let mut names = fs::read_dir(directory)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        let book_files = listing(epub.parent().unwrap());
+        assert_eq!(
+            book_files,
+            ["1.epub", "1.original_epub", "1.pdf", "metadata.opf"]
+        );
+        assert!(!listing(&library)
+            .iter()
+            .any(|name| name.ends_with(".partial")));
+
+        // The database is changed in place, and not while its journal holds a write that
+        // a crash interrupted, which SQLite must roll back first.
+        let db = library.join("metadata.db");
+        let tables = |db: &Path| {
+            let database = crate::sqlite::Database::open(db).unwrap();
+            format!("{:?}", [database.table("books"), database.table("data")])
+        };
+        let journal = library.join("metadata.db-journal");
+        fs::write(&journal, [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7]).unwrap();
+        let err = replace_format(&library, book.id, "EPUB", &packed, false).unwrap_err();
+        assert!(err.contains("holds a write that was interrupted"), "{err}");
+        fs::write(&journal, "").unwrap();
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&fs::metadata(&db).unwrap());
+        replace_format(&library, book.id, "EPUB", &before, false).unwrap();
+        #[cfg(unix)]
+        assert_eq!(
+            std::os::unix::fs::MetadataExt::ino(&fs::metadata(&db).unwrap()),
+            inode
+        );
+        assert!(!journal.exists());
+        let written = tables(&db);
+
+        // A book that cannot be put in place leaves the database as it was.
+        fs::remove_file(&epub).unwrap();
+        fs::create_dir_all(epub.join("in the way")).unwrap();
+        let err = replace_format(&library, book.id, "EPUB", &packed, false).unwrap_err();
+        assert!(err.starts_with(&format!("failed to write {}", epub.display())));
+        assert_eq!(tables(&db), written);
+        assert_eq!(listing(epub.parent().unwrap()), book_files);
+        assert!(!listing(&library)
+            .iter()
+            .any(|name| name.ends_with(".partial") || name.ends_with("-journal")));
+        fs::remove_dir_all(&scratch).unwrap();
+    }
+}
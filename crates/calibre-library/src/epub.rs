@@ -0,0 +1,689 @@
+//! Reading EPUB 2 and 3 books: the package a container points at, its manifest and
+//! spine, the table of contents from the EPUB 3 navigation document or the EPUB 2 NCX,
+//! and the spine's documents as plain text or Markdown.
+
+use std::path::Path;
+
+use crate::xml::{self, Element, Node};
+
+use crate::zip::Archive;
+
+pub const CONTAINER: &str = "META-INF/container.xml";
+pub const NCX_MEDIA_TYPE: &str = "application/x-dtbncx+xml";
+
+/// Elements whose content reads as a paragraph of its own.
+const BLOCKS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "body",
+    "br",
+    "dd",
+    "div",
+    "dt",
+    "figcaption",
+    "figure",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "li",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "td",
+    "th",
+    "tr",
+    "ul",
+];
+
+/// Elements with no readable text.
+const SKIPPED: &[&str] = &["head", "script", "style", "svg", "math"];
+
+pub struct Epub {
+    archive: Archive,
+    /// Archive path of the package document `META-INF/container.xml` points at.
+    pub package_path: String,
+    /// The package's `version`, such as `2.0` or `3.0`.
+    pub version: String,
+    pub manifest: Vec<ManifestItem>,
+    /// Manifest ids of the documents in reading order.
+    pub spine: Vec<String>,
+    pub toc: Vec<TocEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestItem {
+    pub id: String,
+    /// Archive path of the resource.
+    pub path: String,
+    pub media_type: String,
+    pub properties: Vec<String>,
+}
+
+/// A table of contents entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TocEntry {
+    pub title: String,
+    /// Archive path of the document it points at.
+    pub path: String,
+    pub fragment: Option<String>,
+    /// Nesting level, 0 at the top.
+    pub depth: usize,
+}
+
+/// A document of the spine, read as text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chapter {
+    pub title: String,
+    pub path: String,
+    /// Paragraphs separated by blank lines.
+    pub text: String,
+}
+
+impl Epub {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let archive = Archive::open(path)?;
+        let container = parse(&archive, CONTAINER)?;
+        let package_path = container
+            .descendants()
+            .into_iter()
+            .find(|element| element.is("rootfile"))
+            .and_then(|rootfile| rootfile.attribute("full-path"))
+            .ok_or_else(|| format!("{CONTAINER} names no package document"))?
+            .to_string();
+        let package = parse(&archive, &package_path)?;
+        let version = package.attribute("version").unwrap_or_default().to_string();
+
+        let manifest = package
+            .child("manifest")
+            .map(|manifest| {
+                manifest
+                    .children_named("item")
+                    .filter_map(|item| {
+                        Some(ManifestItem {
+                            id: item.attribute("id")?.to_string(),
+                            path: resolve(&archive, &package_path, item.attribute("href")?).0,
+                            media_type: item
+                                .attribute("media-type")
+                                .unwrap_or_default()
+                                .to_string(),
+                            properties: item
+                                .attribute("properties")
+                                .unwrap_or_default()
+                                .split_whitespace()
+                                .map(str::to_string)
+                                .collect(),
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let spine_element = package.child("spine");
+        let spine = spine_element
+            .map(|spine| {
+                spine
+                    .children_named("itemref")
+                    .filter_map(|itemref| itemref.attribute("idref"))
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut epub = Self {
+            archive,
+            package_path,
+            version,
+            manifest,
+            spine,
+            toc: Vec::new(),
+        };
+        // EPUB 3's navigation document, else EPUB 2's NCX. A broken one just leaves the
+        // book without a table of contents.
+        let nav = epub
+            .manifest
+            .iter()
+            .find(|item| item.properties.iter().any(|property| property == "nav"));
+        let ncx = spine_element
+            .and_then(|spine| spine.attribute("toc"))
+            .and_then(|id| epub.item(id))
+            .or_else(|| {
+                epub.manifest
+                    .iter()
+                    .find(|item| item.media_type == NCX_MEDIA_TYPE)
+            });
+        epub.toc = match (nav, ncx) {
+            (Some(nav), _) => epub.nav_toc(&nav.path),
+            (None, Some(ncx)) => epub.ncx_toc(&ncx.path),
+            (None, None) => Ok(Vec::new()),
+        }
+        .unwrap_or_default();
+        Ok(epub)
+    }
+
+    pub fn item(&self, id: &str) -> Option<&ManifestItem> {
+        self.manifest.iter().find(|item| item.id == id)
+    }
+
+    /// The spine's documents, titled by the table of contents, else by their own title or
+    /// first heading.
+    pub fn chapters(&self) -> Result<Vec<Chapter>, String> {
+        self.chapters_as(text)
+    }
+
+    /// The book divided the way its table of contents divides it: each entry gets the
+    /// spine documents from its own up to the next entry's, so a chapter split over several
+    /// files reads as one. Documents before the first entry are front matter. Without a
+    /// table of contents, these are the `chapters`.
+    pub fn sections(&self) -> Result<Vec<Chapter>, String> {
+        self.sections_as(text)
+    }
+
+    /// The `sections`, with their text as Markdown.
+    pub fn markdown_sections(&self) -> Result<Vec<Chapter>, String> {
+        self.sections_as(markdown)
+    }
+
+    fn chapters_as(&self, render: fn(&Element) -> String) -> Result<Vec<Chapter>, String> {
+        let mut chapters = Vec::new();
+        for id in &self.spine {
+            let Some(item) = self.item(id) else {
+                continue;
+            };
+            let document = parse(&self.archive, &item.path)?;
+            let title = self
+                .toc
+                .iter()
+                .find(|entry| entry.path == item.path)
+                .map(|entry| entry.title.clone())
+                .or_else(|| document_title(&document))
+                .unwrap_or_else(|| format!("Section {}", chapters.len() + 1));
+            chapters.push(Chapter {
+                title,
+                path: item.path.clone(),
+                text: render(&document),
+            });
+        }
+        Ok(chapters)
+    }
+
+    fn sections_as(&self, render: fn(&Element) -> String) -> Result<Vec<Chapter>, String> {
+        let chapters = self.chapters_as(render)?;
+        if self.toc.is_empty() {
+            return Ok(chapters);
+        }
+        let mut sections: Vec<Chapter> = Vec::new();
+        for chapter in chapters {
+            let listed = self.toc.iter().any(|entry| entry.path == chapter.path);
+            match sections.last_mut() {
+                Some(section) if !listed => {
+                    if !section.text.is_empty() && !chapter.text.is_empty() {
+                        section.text.push_str("\n\n");
+                    }
+                    section.text.push_str(&chapter.text);
+                }
+                _ => sections.push(Chapter {
+                    title: match listed {
+                        true => chapter.title,
+                        false => "Front matter".to_string(),
+                    },
+                    ..chapter
+                }),
+            }
+        }
+        sections.retain(|section| !section.text.is_empty());
+        Ok(sections)
+    }
+
+    fn ncx_toc(&self, path: &str) -> Result<Vec<TocEntry>, String> {
+        fn walk(epub: &Epub, base: &str, parent: &Element, depth: usize, toc: &mut Vec<TocEntry>) {
+            for point in parent.children_named("navPoint") {
+                let title = point
+                    .child("navLabel")
+                    .map(Element::text)
+                    .unwrap_or_default();
+                if let Some(src) = point
+                    .child("content")
+                    .and_then(|content| content.attribute("src"))
+                {
+                    let (path, fragment) = resolve(&epub.archive, base, src);
+                    toc.push(TocEntry {
+                        title,
+                        path,
+                        fragment,
+                        depth,
+                    });
+                }
+                walk(epub, base, point, depth + 1, toc);
+            }
+        }
+
+        let ncx = parse(&self.archive, path)?;
+        let mut toc = Vec::new();
+        if let Some(map) = ncx.child("navMap") {
+            walk(self, path, map, 0, &mut toc);
+        }
+        Ok(toc)
+    }
+
+    fn nav_toc(&self, path: &str) -> Result<Vec<TocEntry>, String> {
+        fn walk(epub: &Epub, base: &str, list: &Element, depth: usize, toc: &mut Vec<TocEntry>) {
+            for item in list.children_named("li") {
+                if let Some(link) = item.child("a") {
+                    if let Some(href) = link.attribute("href") {
+                        let (path, fragment) = resolve(&epub.archive, base, href);
+                        toc.push(TocEntry {
+                            title: link.text(),
+                            path,
+                            fragment,
+                            depth,
+                        });
+                    }
+                }
+                if let Some(nested) = item.child("ol") {
+                    walk(epub, base, nested, depth + 1, toc);
+                }
+            }
+        }
+
+        let document = parse(&self.archive, path)?;
+        let navs = document
+            .descendants()
+            .into_iter()
+            .filter(|element| element.is("nav"))
+            .collect::<Vec<_>>();
+        let nav = navs
+            .iter()
+            .find(|nav| nav.attribute("epub:type") == Some("toc"))
+            .or(navs.first());
+        let mut toc = Vec::new();
+        if let Some(list) = nav.and_then(|nav| nav.child("ol")) {
+            walk(self, path, list, 0, &mut toc);
+        }
+        Ok(toc)
+    }
+}
+
+fn parse(archive: &Archive, path: &str) -> Result<Element, String> {
+    let source = archive.read_text(path)?;
+    xml::parse(&source).map_err(|err| {
+        let (line, column) = err.line_column(&source);
+        format!("{path}:{line}:{column}: {}", err.message)
+    })
+}
+
+/// The archive path and fragment an `href` in the document at `base` points at.
+///
+/// Some books write hrefs relative to the archive root instead of the document; when the
+/// proper path does not exist but that one does, it is used instead.
+fn resolve(archive: &Archive, base: &str, href: &str) -> (String, Option<String>) {
+    let (href, fragment) = match href.split_once('#') {
+        Some((href, fragment)) => (href, Some(percent_decode(fragment))),
+        None => (href, None),
+    };
+    let href = percent_decode(href);
+    if href.is_empty() {
+        return (base.to_string(), fragment);
+    }
+    let directory = base.rsplit_once('/').map_or("", |(directory, _)| directory);
+    let path = normalize(&format!("{directory}/{href}"));
+    if archive.entry(&path).is_none() {
+        let rooted = normalize(&href);
+        if archive.entry(&rooted).is_some() {
+            return (rooted, fragment);
+        }
+    }
+    (path, fragment)
+}
+
+/// Resolves `.` and `..` segments and drops empty ones.
+pub(crate) fn normalize(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+pub(crate) fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = (bytes[index] == b'%')
+            .then(|| text.get(index + 1..index + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A document's `<title>`, else its first heading.
+fn document_title(document: &Element) -> Option<String> {
+    let title = document.find("title").map(Element::text);
+    title.filter(|title| !title.is_empty()).or_else(|| {
+        document
+            .descendants()
+            .into_iter()
+            .find(|element| {
+                matches!(
+                    element.local_name().to_ascii_lowercase().as_str(),
+                    "h1" | "h2" | "h3" | "h4" | "h5" | "h6"
+                )
+            })
+            .map(Element::text)
+            .filter(|heading| !heading.is_empty())
+    })
+}
+
+/// The readable text of an XHTML document: block elements become paragraphs, separated by
+/// blank lines, and whitespace inside them is collapsed.
+pub fn text(document: &Element) -> String {
+    fn walk(element: &Element, paragraphs: &mut Vec<String>, current: &mut String) {
+        let name = element.local_name().to_ascii_lowercase();
+        if SKIPPED.contains(&name.as_str()) {
+            return;
+        }
+        let block = BLOCKS.contains(&name.as_str());
+        if block {
+            flush(paragraphs, current);
+        }
+        for node in &element.children {
+            match node {
+                Node::Text(text) => current.push_str(text),
+                Node::Element(child) => walk(child, paragraphs, current),
+            }
+        }
+        if block {
+            flush(paragraphs, current);
+        }
+    }
+
+    fn flush(paragraphs: &mut Vec<String>, current: &mut String) {
+        let paragraph = current.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !paragraph.is_empty() {
+            paragraphs.push(paragraph);
+        }
+        current.clear();
+    }
+
+    let mut paragraphs = Vec::new();
+    let mut current = String::new();
+    walk(document, &mut paragraphs, &mut current);
+    flush(&mut paragraphs, &mut current);
+    paragraphs.join("\n\n")
+}
+
+/// An XHTML document as Markdown: headings, paragraphs, emphasis, lists, block quotes,
+/// preformatted text and rules. Links and images are reduced to their text, since what
+/// they point at is inside the book.
+pub fn markdown(document: &Element) -> String {
+    #[derive(Default)]
+    struct Writer {
+        blocks: Vec<String>,
+        current: String,
+        /// Open lists, innermost last: the next item's number, or `None` when unordered.
+        lists: Vec<Option<usize>>,
+        /// The marker the next block starts with, when it is a list item's first.
+        marker: Option<String>,
+        quotes: usize,
+    }
+
+    impl Writer {
+        fn walk(&mut self, element: &Element) {
+            let name = element.local_name().to_ascii_lowercase();
+            match name.as_str() {
+                name if SKIPPED.contains(&name) => {}
+                "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                    self.flush();
+                    let level = usize::from(name.as_bytes()[1] - b'0');
+                    self.current = format!("{} ", "#".repeat(level));
+                    self.children(element);
+                    if self.current.trim_end_matches(' ').ends_with('#') {
+                        self.current.clear();
+                    }
+                    self.flush();
+                }
+                "em" | "i" | "cite" => self.inline(element, "*"),
+                "strong" | "b" => self.inline(element, "**"),
+                "code" | "kbd" | "samp" => self.inline(element, "`"),
+                "br" => self.current.push(LINE_BREAK),
+                "hr" => {
+                    self.flush();
+                    self.blocks.push("---".to_string());
+                }
+                "pre" => {
+                    self.flush();
+                    let mut code = String::new();
+                    raw_text(element, &mut code);
+                    let code = code.trim_matches('\n');
+                    if !code.trim().is_empty() {
+                        let block = self.prefixed(&format!("```\n{code}\n```"));
+                        self.blocks.push(block);
+                    }
+                }
+                "ul" | "ol" => {
+                    self.flush();
+                    let start = element
+                        .attribute("start")
+                        .and_then(|start| start.parse().ok())
+                        .unwrap_or(1);
+                    self.lists.push((name == "ol").then_some(start));
+                    self.children(element);
+                    self.flush();
+                    self.lists.pop();
+                }
+                "li" => {
+                    self.flush();
+                    let indent = "   ".repeat(self.lists.len().saturating_sub(1));
+                    self.marker = Some(match self.lists.last_mut() {
+                        Some(Some(number)) => {
+                            *number += 1;
+                            format!("{indent}{}. ", *number - 1)
+                        }
+                        _ => format!("{indent}- "),
+                    });
+                    self.children(element);
+                    self.flush();
+                    self.marker = None;
+                }
+                "blockquote" => {
+                    self.flush();
+                    self.quotes += 1;
+                    self.children(element);
+                    self.flush();
+                    self.quotes -= 1;
+                }
+                name if BLOCKS.contains(&name) => {
+                    self.flush();
+                    self.children(element);
+                    self.flush();
+                }
+                _ => self.children(element),
+            }
+        }
+
+        fn children(&mut self, element: &Element) {
+            for node in &element.children {
+                match node {
+                    Node::Text(text) => self.current.push_str(&escape_markdown(text)),
+                    Node::Element(child) => self.walk(child),
+                }
+            }
+        }
+
+        /// Wraps the text of `element` in `delimiter`, keeping the whitespace around it
+        /// outside, where Markdown needs it.
+        fn inline(&mut self, element: &Element, delimiter: &str) {
+            let start = self.current.len();
+            self.children(element);
+            let inner = self.current.split_off(start);
+            let trimmed = inner.trim();
+            if trimmed.is_empty() {
+                self.current.push_str(&inner);
+                return;
+            }
+            let leading = &inner[..inner.len() - inner.trim_start().len()];
+            let trailing = &inner[inner.trim_end().len()..];
+            self.current.push_str(leading);
+            self.current.push_str(delimiter);
+            self.current.push_str(trimmed);
+            self.current.push_str(delimiter);
+            self.current.push_str(trailing);
+        }
+
+        /// Ends the block being written: its whitespace collapsed, line breaks kept.
+        fn flush(&mut self) {
+            let mut lines = self
+                .current
+                .split(LINE_BREAK)
+                .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+                .collect::<Vec<_>>();
+            self.current.clear();
+            while lines.last().is_some_and(String::is_empty) {
+                lines.pop();
+            }
+            let first = lines.iter().position(|line| !line.is_empty());
+            let Some(first) = first else {
+                return;
+            };
+            let block = self.prefixed(&lines[first..].join("\\\n"));
+            self.blocks.push(block);
+        }
+
+        /// `block` inside the open block quotes and list items: the first line after the
+        /// item's marker, and every other line indented to match.
+        fn prefixed(&mut self, block: &str) -> String {
+            let quote = "> ".repeat(self.quotes);
+            let indent = match (&self.marker, self.lists.len()) {
+                (Some(marker), _) => " ".repeat(marker.len()),
+                (None, 0) => String::new(),
+                (None, depth) => "   ".repeat(depth),
+            };
+            let first = self.marker.take().unwrap_or_else(|| indent.clone());
+            block
+                .lines()
+                .enumerate()
+                .map(|(index, line)| {
+                    let lead = if index == 0 { &first } else { &indent };
+                    format!("{quote}{lead}{line}").trim_end().to_string()
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+    }
+
+    fn raw_text(element: &Element, text: &mut String) {
+        for node in &element.children {
+            match node {
+                Node::Text(value) => text.push_str(value),
+                Node::Element(child) if child.is("br") => text.push('\n'),
+                Node::Element(child) => raw_text(child, text),
+            }
+        }
+    }
+
+    let mut writer = Writer::default();
+    writer.walk(document);
+    writer.flush();
+    writer.blocks.join("\n\n")
+}
+
+/// Stands for `<br/>` in text until whitespace is collapsed.
+const LINE_BREAK: char = '\u{2028}';
+
+/// Text with the characters Markdown would read as formatting escaped.
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '`' | '[' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_package_spine_and_ncx() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(
+            "../../tests/fixtures/test_library/Arthur Conan Doyle/A Study in Scarlet (1)/1.epub",
+        );
+        let epub = Epub::open(&path).unwrap();
+        assert_eq!(epub.package_path, "OEBPS/content.opf");
+        assert_eq!(epub.version, "2.0");
+        assert_eq!(epub.manifest.len(), 6);
+        assert_eq!(epub.spine, ["page1", "page2", "page3", "page4", "page5"]);
+        assert_eq!(
+            epub.toc[1],
+            TocEntry {
+                title: "Page 2".to_string(),
+                path: "OEBPS/page2.xhtml".to_string(),
+                fragment: None,
+                depth: 0,
+            }
+        );
+
+        let sections = epub.markdown_sections().unwrap();
+        assert_eq!(sections.len(), 5);
+        assert!(sections[2]
+            .text
+            .starts_with("# A Study in Scarlet\n\n## Page 3 of 5\n\nThis is page 3 of"));
+    }
+
+    #[test]
+    fn writes_markdown() {
+        let document = xml::parse(
+            r#"<html><head><title>Skipped</title></head><body>
+              <h2>Chapter <em>One</em></h2>
+              <p>A <i>fine</i> day,<br/>  or so   they <b>said </b>— 2 * 3_4.</p>
+              <blockquote><p>Quoted</p></blockquote>
+              <ol start="3"><li>Three<ul><li>nested</li></ul></li><li><p>Four</p></li></ol>
+              <pre>let x = 1;
+    let y = 2;</pre>
+              <hr/>
+              <p><a href="notes.xhtml#n1">[1]</a></p>
+            </body></html>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            markdown(&document),
+            "## Chapter *One*\n\n\
+             A *fine* day,\\\nor so they **said** — 2 \\* 3\\_4.\n\n\
+             > Quoted\n\n\
+             3. Three\n\n   - nested\n\n4. Four\n\n\
+             ```\nlet x = 1;\n    let y = 2;\n```\n\n\
+             ---\n\n\
+             \\[1\\]"
+        );
+    }
+}
@@ -0,0 +1,726 @@
+//! Checking an EPUB for the problems that make reading apps choke on it, in the manner of
+//! epubcheck: the `mimetype` file, `META-INF/container.xml`, the package's metadata,
+//! manifest and spine, files that are missing or unlisted, links that lead nowhere, and
+//! XML that is not well-formed. A book is checked packed, from its `.epub`, or unpacked
+//! in a folder.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::epub::{normalize, percent_decode, CONTAINER, NCX_MEDIA_TYPE};
+use crate::xml::{self, Element};
+use crate::zip::{Archive, METHOD_STORED};
+
+pub const MIMETYPE: &str = "mimetype";
+pub const EPUB_MEDIA_TYPE: &str = "application/epub+zip";
+const PACKAGE_MEDIA_TYPE: &str = "application/oebps-package+xml";
+const XHTML_MEDIA_TYPE: &str = "application/xhtml+xml";
+const SVG_MEDIA_TYPE: &str = "image/svg+xml";
+
+/// Attributes that point at another file, and the elements that carry them.
+const REFERENCES: &[(&str, &[&str])] = &[
+    ("href", &["a", "area", "link", "image", "use"]),
+    (
+        "src",
+        &[
+            "audio", "content", "embed", "iframe", "img", "input", "script", "source", "track",
+            "video",
+        ],
+    ),
+    ("data", &["object"]),
+    ("poster", &["video"]),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Something wrong with one of the book's files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    /// Path of the file inside the book.
+    pub path: String,
+    /// 1-based line and column, when the problem is at a place in the file.
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match self.position {
+            Some((line, column)) => write!(f, "{}:{line}:{column}: {severity}: ", self.path)?,
+            None => write!(f, "{}: {severity}: ", self.path)?,
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// Where a book's files are read from.
+pub enum Source {
+    Packed(Archive),
+    Unpacked {
+        root: PathBuf,
+        /// Texts to check instead of what is on disk, such as documents being edited, by
+        /// path inside the book.
+        open: HashMap<String, String>,
+    },
+}
+
+impl Source {
+    pub fn packed(path: &Path) -> Result<Self, String> {
+        Ok(Self::Packed(Archive::open(path)?))
+    }
+
+    pub fn unpacked(root: &Path) -> Self {
+        Self::Unpacked {
+            root: root.to_path_buf(),
+            open: HashMap::new(),
+        }
+    }
+
+    /// Paths of the book's files: in archive order when packed, sorted when unpacked,
+    /// where hidden files such as `.git` are left out.
+    pub fn files(&self) -> Vec<String> {
+        fn walk(directory: &Path, prefix: &str, files: &mut Vec<String>) {
+            let Ok(entries) = fs::read_dir(directory) else {
+                return;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with('.') {
+                    continue;
+                }
+                let path = format!("{prefix}{name}");
+                if entry.path().is_dir() {
+                    walk(&entry.path(), &format!("{path}/"), files);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+
+        match self {
+            Self::Packed(archive) => archive
+                .entries()
+                .iter()
+                .filter(|entry| !entry.name.ends_with('/'))
+                .map(|entry| entry.name.clone())
+                .collect(),
+            Self::Unpacked { root, .. } => {
+                let mut files = Vec::new();
+                walk(root, "", &mut files);
+                files.sort();
+                files
+            }
+        }
+    }
+
+    pub fn read(&self, path: &str) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Packed(archive) => archive.read(path),
+            Self::Unpacked { root, open } => match open.get(path) {
+                Some(text) => Ok(text.as_bytes().to_vec()),
+                None => {
+                    fs::read(root.join(path)).map_err(|err| format!("cannot read {path}: {err}"))
+                }
+            },
+        }
+    }
+}
+
+/// The folder of the unpacked book `path` is in, or is: the nearest one holding a
+/// `META-INF/container.xml`.
+pub fn unpacked_root(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .find(|directory| directory.join(CONTAINER).is_file())
+        .map(Path::to_path_buf)
+}
+
+/// Everything wrong with the book, errors first, then by file and place.
+pub fn check(source: &Source) -> Vec<Problem> {
+    let mut checker = Checker {
+        source,
+        files: source.files().into_iter().collect(),
+        problems: Vec::new(),
+    };
+    checker.mimetype();
+    if let Some(package) = checker.container() {
+        checker.package(&package);
+    }
+    let mut problems = checker.problems;
+    problems
+        .sort_by(|a, b| (a.severity, &a.path, a.position).cmp(&(b.severity, &b.path, b.position)));
+    problems
+}
+
+struct Checker<'a> {
+    source: &'a Source,
+    files: HashSet<String>,
+    problems: Vec<Problem>,
+}
+
+/// A manifest item.
+struct Item<'a> {
+    id: &'a str,
+    path: String,
+    media_type: &'a str,
+    element: &'a Element,
+}
+
+/// A parsed XML file of the book: its text, root, and the ids it defines.
+struct Document {
+    source: String,
+    root: Element,
+    ids: HashSet<String>,
+}
+
+impl Checker<'_> {
+    fn report(
+        &mut self,
+        severity: Severity,
+        path: &str,
+        position: Option<(usize, usize)>,
+        message: String,
+    ) {
+        self.problems.push(Problem {
+            severity,
+            path: path.to_string(),
+            position,
+            message,
+        });
+    }
+
+    fn error_at(&mut self, path: &str, source: &str, element: &Element, message: String) {
+        let position = xml::line_column(source, element.offset);
+        self.report(Severity::Error, path, Some(position), message);
+    }
+
+    /// `mimetype` holds exactly the EPUB media type, and comes first in the archive,
+    /// uncompressed, so that the media type sits at a fixed offset in the file.
+    fn mimetype(&mut self) {
+        if let Source::Packed(archive) = self.source {
+            match archive.entries().first() {
+                Some(first) if first.name == MIMETYPE && first.method != METHOD_STORED => {
+                    let message = "mimetype must be stored, not compressed".to_string();
+                    self.report(Severity::Error, MIMETYPE, None, message);
+                }
+                Some(first) if first.name == MIMETYPE => {}
+                _ if self.files.contains(MIMETYPE) => {
+                    let message = "mimetype must be the first file in the archive".to_string();
+                    self.report(Severity::Error, MIMETYPE, None, message);
+                }
+                _ => {}
+            }
+        }
+        match self.source.read(MIMETYPE) {
+            _ if !self.files.contains(MIMETYPE) => {
+                let message = "the book has no mimetype file".to_string();
+                self.report(Severity::Error, MIMETYPE, None, message);
+            }
+            Ok(contents) if contents == EPUB_MEDIA_TYPE.as_bytes() => {}
+            Ok(_) => {
+                let message = format!(
+                    "mimetype must contain exactly `{EPUB_MEDIA_TYPE}`, without spaces or a line break"
+                );
+                self.report(Severity::Error, MIMETYPE, Some((1, 1)), message);
+            }
+            Err(err) => self.report(Severity::Error, MIMETYPE, None, err),
+        }
+    }
+
+    /// The package document `container.xml` names, if it is in the book.
+    fn container(&mut self) -> Option<String> {
+        if !self.files.contains(CONTAINER) {
+            let message = format!("the book has no {CONTAINER}");
+            self.report(Severity::Error, CONTAINER, None, message);
+            return None;
+        }
+        let document = self.parse(CONTAINER)?;
+        let Some(rootfile) = document
+            .root
+            .descendants()
+            .into_iter()
+            .find(|element| element.is("rootfile"))
+        else {
+            let message = "no <rootfile> names the package document".to_string();
+            self.error_at(CONTAINER, &document.source, &document.root, message);
+            return None;
+        };
+        let Some(path) = rootfile
+            .attribute("full-path")
+            .filter(|path| !path.is_empty())
+        else {
+            let message = "the <rootfile> has no full-path".to_string();
+            self.error_at(CONTAINER, &document.source, rootfile, message);
+            return None;
+        };
+        let path = normalize(path);
+        if rootfile.attribute("media-type") != Some(PACKAGE_MEDIA_TYPE) {
+            let position = xml::line_column(&document.source, rootfile.offset);
+            let message = format!("the <rootfile> media-type should be {PACKAGE_MEDIA_TYPE}");
+            self.report(Severity::Warning, CONTAINER, Some(position), message);
+        }
+        if !self.files.contains(&path) {
+            let message = format!("the package document {path} is not in the book");
+            self.error_at(CONTAINER, &document.source, rootfile, message);
+            return None;
+        }
+        Some(path)
+    }
+
+    fn package(&mut self, path: &str) {
+        let Some(document) = self.parse(path) else {
+            return;
+        };
+        let (source, package) = (&document.source, &document.root);
+        if !package.is("package") {
+            let message = format!("the root element is <{}>, not <package>", package.name);
+            self.error_at(path, source, package, message);
+            return;
+        }
+        let version = package.attribute("version").unwrap_or_default();
+        let epub3 = version.starts_with('3');
+        if !matches!(version, "2.0" | "3.0" | "3.1" | "3.2" | "3.3") {
+            let message = format!("the package version is \"{version}\", not 2.0 or 3.0");
+            self.error_at(path, source, package, message);
+        }
+        self.metadata(path, source, package);
+
+        let Some(manifest) = package.child("manifest") else {
+            self.error_at(
+                path,
+                source,
+                package,
+                "the package has no <manifest>".into(),
+            );
+            return;
+        };
+        let directory = path.rsplit_once('/').map_or("", |(directory, _)| directory);
+        let mut items: Vec<Item> = Vec::new();
+        for element in manifest.children_named("item") {
+            let (Some(id), Some(href)) = (element.attribute("id"), element.attribute("href"))
+            else {
+                let message = "a manifest <item> needs both an id and an href".to_string();
+                self.error_at(path, source, element, message);
+                continue;
+            };
+            let media_type = element.attribute("media-type").unwrap_or_default();
+            if media_type.is_empty() {
+                let message = format!("manifest item \"{id}\" has no media-type");
+                self.error_at(path, source, element, message);
+            }
+            if items.iter().any(|item| item.id == id) {
+                let message = format!("two manifest items have the id \"{id}\"");
+                self.error_at(path, source, element, message);
+                continue;
+            }
+            if is_remote(href) {
+                continue;
+            }
+            let resource = resolve(directory, href);
+            if items.iter().any(|item| item.path == resource) {
+                let message = format!("{resource} is in the manifest twice");
+                self.error_at(path, source, element, message);
+                continue;
+            }
+            if !self.files.contains(&resource) {
+                let message = format!("{resource}, manifest item \"{id}\", is not in the book");
+                self.error_at(path, source, element, message);
+            }
+            items.push(Item {
+                id,
+                path: resource,
+                media_type,
+                element,
+            });
+        }
+
+        let navs = items
+            .iter()
+            .filter(|item| {
+                item.element
+                    .attribute("properties")
+                    .is_some_and(|properties| properties.split_whitespace().any(|p| p == "nav"))
+            })
+            .count();
+        if epub3 && navs != 1 {
+            let message = format!(
+                "an EPUB 3 manifest needs exactly one item with properties=\"nav\", not {navs}"
+            );
+            self.error_at(path, source, manifest, message);
+        }
+        self.spine(path, source, package, &items, epub3);
+
+        let listed = items
+            .iter()
+            .map(|item| item.path.as_str())
+            .collect::<HashSet<_>>();
+        let mut unlisted = self
+            .files
+            .iter()
+            .filter(|file| {
+                file.as_str() != MIMETYPE
+                    && file.as_str() != path
+                    && !file.starts_with("META-INF/")
+                    && !listed.contains(file.as_str())
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        unlisted.sort();
+        for file in unlisted {
+            let message = format!("{file} is in the book but not in the manifest");
+            self.report(Severity::Warning, &file, None, message);
+        }
+
+        // Every XML file must be well-formed; links are checked once all are read.
+        let mut documents = HashMap::new();
+        for item in &items {
+            let xml = item.media_type == XHTML_MEDIA_TYPE
+                || item.media_type == NCX_MEDIA_TYPE
+                || item.media_type.ends_with("+xml");
+            if !xml || !self.files.contains(&item.path) || documents.contains_key(&item.path) {
+                continue;
+            }
+            if let Some(document) = self.parse(&item.path) {
+                if item.media_type == XHTML_MEDIA_TYPE && !document.root.is("html") {
+                    let message =
+                        format!("the root element is <{}>, not <html>", document.root.name);
+                    self.error_at(&item.path, &document.source, &document.root, message);
+                }
+                documents.insert(item.path.clone(), document);
+            }
+        }
+        let mut paths = documents.keys().cloned().collect::<Vec<_>>();
+        paths.sort();
+        for path in paths {
+            self.links(&path, &documents);
+        }
+    }
+
+    /// The package has a title, an identifier and a language, and its unique identifier
+    /// is one of its identifiers.
+    fn metadata(&mut self, path: &str, source: &str, package: &Element) {
+        let Some(metadata) = package.child("metadata") else {
+            self.error_at(
+                path,
+                source,
+                package,
+                "the package has no <metadata>".into(),
+            );
+            return;
+        };
+        for name in ["title", "identifier", "language"] {
+            if metadata.child(name).is_none() {
+                let message = format!("the package metadata has no dc:{name}");
+                self.error_at(path, source, metadata, message);
+            }
+        }
+        if let Some(id) = package.attribute("unique-identifier") {
+            let found = metadata
+                .children_named("identifier")
+                .any(|identifier| identifier.attribute("id") == Some(id));
+            if !found {
+                let message = format!("unique-identifier \"{id}\" names no dc:identifier");
+                self.error_at(path, source, package, message);
+            }
+        }
+    }
+
+    /// Every spine entry is a manifest item, listed once, that reading systems can show,
+    /// and an EPUB 2 spine names its NCX.
+    fn spine(&mut self, path: &str, source: &str, package: &Element, items: &[Item], epub3: bool) {
+        let Some(spine) = package.child("spine") else {
+            self.error_at(path, source, package, "the package has no <spine>".into());
+            return;
+        };
+        let mut seen = HashSet::new();
+        let mut count = 0;
+        for itemref in spine.children_named("itemref") {
+            count += 1;
+            let Some(idref) = itemref.attribute("idref") else {
+                self.error_at(path, source, itemref, "an <itemref> needs an idref".into());
+                continue;
+            };
+            if !seen.insert(idref) {
+                let message = format!("\"{idref}\" is in the spine twice");
+                self.error_at(path, source, itemref, message);
+                continue;
+            }
+            let Some(item) = items.iter().find(|item| item.id == idref) else {
+                let message = format!("the spine lists \"{idref}\", which is not in the manifest");
+                self.error_at(path, source, itemref, message);
+                continue;
+            };
+            let readable = item.media_type == XHTML_MEDIA_TYPE
+                || (epub3 && item.media_type == SVG_MEDIA_TYPE)
+                || item.element.attribute("fallback").is_some();
+            if !readable {
+                let message = format!(
+                    "spine item \"{idref}\" is {}, which needs a fallback to XHTML",
+                    item.media_type
+                );
+                self.error_at(path, source, itemref, message);
+            }
+        }
+        if count == 0 {
+            self.error_at(path, source, spine, "the spine lists no documents".into());
+        }
+        match spine.attribute("toc") {
+            Some(id) => match items.iter().find(|item| item.id == id) {
+                Some(item) if item.media_type == NCX_MEDIA_TYPE => {}
+                Some(_) => {
+                    let message = format!("the spine's toc \"{id}\" is not an NCX");
+                    self.error_at(path, source, spine, message);
+                }
+                None => {
+                    let message = format!("the spine's toc \"{id}\" is not in the manifest");
+                    self.error_at(path, source, spine, message);
+                }
+            },
+            None if !epub3 => {
+                let message = "an EPUB 2 spine needs a toc attribute naming the NCX".to_string();
+                self.error_at(path, source, spine, message);
+            }
+            None => {}
+        }
+    }
+
+    /// Links and embedded resources of the document at `path` lead to files in the book,
+    /// and links with a fragment to an element with that id.
+    fn links(&mut self, path: &str, documents: &HashMap<String, Document>) {
+        let document = &documents[path];
+        let directory = path.rsplit_once('/').map_or("", |(directory, _)| directory);
+        for element in document.root.descendants() {
+            let name = element.local_name();
+            for (attribute, elements) in REFERENCES {
+                if !elements.contains(&name) {
+                    continue;
+                }
+                let Some(reference) = element.attribute(attribute) else {
+                    continue;
+                };
+                let reference = reference.trim();
+                if reference.is_empty() || is_remote(reference) {
+                    continue;
+                }
+                let (target, fragment) = match reference.split_once('#') {
+                    Some((target, fragment)) => (target, Some(percent_decode(fragment))),
+                    None => (reference, None),
+                };
+                let target = match target {
+                    "" => path.to_string(),
+                    target => resolve(directory, target),
+                };
+                if !self.files.contains(&target) {
+                    let message =
+                        format!("{reference} leads to {target}, which is not in the book");
+                    self.error_at(path, &document.source, element, message);
+                    continue;
+                }
+                let (Some(fragment), Some(linked)) = (fragment, documents.get(&target)) else {
+                    continue;
+                };
+                if !fragment.is_empty()
+                    && !linked.ids.contains(&fragment)
+                    && !is_fragment_scheme(&fragment)
+                {
+                    let message = format!(
+                        "{reference} leads to no element: {target} has no id \"{fragment}\""
+                    );
+                    self.error_at(path, &document.source, element, message);
+                }
+            }
+        }
+    }
+
+    /// Reads and parses an XML file, reporting why when it cannot be.
+    fn parse(&mut self, path: &str) -> Option<Document> {
+        let source = match self.source.read(path) {
+            Ok(bytes) => {
+                let bytes = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(&bytes);
+                match String::from_utf8(bytes.to_vec()) {
+                    Ok(source) => source,
+                    Err(_) => {
+                        let message = "the file is not UTF-8".to_string();
+                        self.report(Severity::Error, path, None, message);
+                        return None;
+                    }
+                }
+            }
+            Err(err) => {
+                self.report(Severity::Error, path, None, err);
+                return None;
+            }
+        };
+        match xml::parse(&source) {
+            Ok(root) => {
+                let ids = root
+                    .descendants()
+                    .into_iter()
+                    .filter_map(|element| element.attribute("id"))
+                    .map(str::to_string)
+                    .collect();
+                Some(Document { source, root, ids })
+            }
+            Err(err) => {
+                let position = err.line_column(&source);
+                let message = format!("not well-formed: {}", err.message);
+                self.report(Severity::Error, path, Some(position), message);
+                None
+            }
+        }
+    }
+}
+
+/// The path inside the book an href in a file of `directory` points at.
+fn resolve(directory: &str, href: &str) -> String {
+    let href = href.split(['?', '#']).next().unwrap_or_default();
+    normalize(&format!("{directory}/{}", percent_decode(href)))
+}
+
+/// Whether a reference leaves the book: it has a scheme, such as `https:` or `mailto:`,
+/// or names a host.
+fn is_remote(reference: &str) -> bool {
+    if reference.starts_with("//") {
+        return true;
+    }
+    let scheme = reference
+        .split([':', '/', '?', '#'])
+        .next()
+        .unwrap_or_default();
+    reference.len() > scheme.len()
+        && reference[scheme.len()..].starts_with(':')
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+}
+
+/// Fragments that select rather than name, such as EPUB CFIs and media fragments.
+fn is_fragment_scheme(fragment: &str) -> bool {
+    fragment.starts_with("epubcfi(") || fragment.starts_with("t=") || fragment.starts_with("xywh=")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join(
+            "../../tests/fixtures/test_library/Arthur Conan Doyle/A Study in Scarlet (1)/1.epub",
+        )
+    }
+
+    fn messages(problems: &[Problem]) -> Vec<String> {
+        problems.iter().map(Problem::to_string).collect()
+    }
+
+    #[test]
+    fn checks_a_packed_book() {
+        let problems = check(&Source::packed(&fixture()).unwrap());
+        // The fixture lacks a dc:language, and its NCX links are rooted at the archive.
+        assert_eq!(
+            messages(&problems[..2]),
+            [
+                "OEBPS/content.opf:3:3: error: the package metadata has no dc:language",
+                "OEBPS/toc.ncx:11:7: error: OEBPS/page1.xhtml leads to OEBPS/OEBPS/page1.xhtml, \
+                 which is not in the book",
+            ]
+        );
+        assert!(problems
+            .iter()
+            .all(|problem| problem.message != "mimetype must be the first file in the archive"));
+    }
+
+    #[test]
+    fn checks_an_unpacked_book() {
+        let root = std::env::temp_dir().join(format!("calibre-epubcheck-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let files = [
+            (MIMETYPE, "application/epub+zip\n"),
+            (
+                CONTAINER,
+                r#"<container xmlns="urn:oasis:names:tc:opendocument:xmlns:container"><rootfiles>
+<rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+</rootfiles></container>"#,
+            ),
+            (
+                "OEBPS/content.opf",
+                r#"<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="id">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:identifier id="id">x</dc:identifier>
+<dc:title>T</dc:title><dc:language>en</dc:language></metadata>
+<manifest>
+<item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+<item id="one" href="one.xhtml" media-type="application/xhtml+xml"/>
+<item id="cover" href="cover.jpg" media-type="image/jpeg"/>
+</manifest>
+<spine><itemref idref="one"/><itemref idref="cover"/><itemref idref="two"/></spine>
+</package>"#,
+            ),
+            (
+                "OEBPS/nav.xhtml",
+                r##"<html xmlns="http://www.w3.org/1999/xhtml"><body><nav><ol>
+<li><a href="one.xhtml#start">One</a></li><li><a href="one.xhtml#end">End</a></li>
+</ol></nav></body></html>"##,
+            ),
+            (
+                "OEBPS/one.xhtml",
+                r#"<html xmlns="http://www.w3.org/1999/xhtml"><body><p id="start">Hi
+<img src="images/map.png"/></p></body></html>"#,
+            ),
+            ("OEBPS/notes.txt", "stray"),
+        ];
+        for (path, text) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        assert_eq!(
+            unpacked_root(&root.join("OEBPS/one.xhtml")),
+            Some(root.clone())
+        );
+
+        let mut source = Source::unpacked(&root);
+        let problems = messages(&check(&source));
+        assert_eq!(
+            problems,
+            [
+                "OEBPS/content.opf:7:1: error: OEBPS/cover.jpg, manifest item \"cover\", is not \
+                 in the book",
+                "OEBPS/content.opf:9:30: error: spine item \"cover\" is image/jpeg, which needs a \
+                 fallback to XHTML",
+                "OEBPS/content.opf:9:54: error: the spine lists \"two\", which is not in the \
+                 manifest",
+                "OEBPS/nav.xhtml:2:47: error: one.xhtml#end leads to no element: OEBPS/one.xhtml \
+                 has no id \"end\"",
+                "OEBPS/one.xhtml:2:1: error: images/map.png leads to OEBPS/images/map.png, which \
+                 is not in the book",
+                "mimetype:1:1: error: mimetype must contain exactly `application/epub+zip`, \
+                 without spaces or a line break",
+                "OEBPS/notes.txt: warning: OEBPS/notes.txt is in the book but not in the manifest",
+            ]
+        );
+
+        // Open documents are checked as they are in the editor.
+        if let Source::Unpacked { open, .. } = &mut source {
+            open.insert(
+                "OEBPS/one.xhtml".to_string(),
+                "<html><body><p>Hi</body></html>".to_string(),
+            );
+        }
+        let problems = check(&source);
+        assert!(messages(&problems).contains(
+            &"OEBPS/one.xhtml:1:18: error: not well-formed: end tag </body> does not match <p>"
+                .to_string()
+        ));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
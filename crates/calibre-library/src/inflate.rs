@@ -0,0 +1,302 @@
+//! DEFLATE decompression (RFC 1951), for zip entries and zlib-compressed PDF streams.
+//!
+//! A straightforward canonical-Huffman decoder in the manner of zlib's `puff.c`: slow next
+//! to a table-driven inflater, but small, and books are read one chapter at a time.
+
+const MAX_BITS: usize = 15;
+const MAX_LITERAL_CODES: usize = 286;
+const MAX_DISTANCE_CODES: usize = 30;
+const FIXED_LITERAL_CODES: usize = 288;
+
+pub(crate) const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+pub(crate) const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+pub(crate) const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+pub(crate) const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order in which code length code lengths are stored in a dynamic block header.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Decompresses raw DEFLATE data. `size_hint` is the expected output size, if known.
+pub fn inflate(data: &[u8], size_hint: usize) -> Result<Vec<u8>, String> {
+    let mut inflater = Inflater {
+        input: BitReader::new(data),
+        output: Vec::with_capacity(size_hint),
+    };
+    inflater.run()?;
+    Ok(inflater.output)
+}
+
+/// Decompresses a zlib stream (RFC 1950), as PDF's `FlateDecode` filter uses. The trailing
+/// checksum is not verified, since damaged PDFs often get it wrong.
+pub fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    let [cmf, flg, ..] = data else {
+        return Err("zlib stream is truncated".to_string());
+    };
+    if cmf & 0x0f != 8 || (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 != 0 {
+        return Err("not a zlib stream".to_string());
+    }
+    if flg & 0x20 != 0 {
+        return Err("zlib preset dictionaries are not supported".to_string());
+    }
+    inflate(&data[2..], data.len() * 4)
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    bit_buffer: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            position: 0,
+            bit_buffer: 0,
+            bit_count: 0,
+        }
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32, String> {
+        while self.bit_count < count {
+            let byte = *self
+                .data
+                .get(self.position)
+                .ok_or("deflate stream is truncated")?;
+            self.position += 1;
+            self.bit_buffer |= u32::from(byte) << self.bit_count;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buffer & ((1u32 << count) - 1);
+        self.bit_buffer >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    /// Drops the bits left in the current byte, before a stored block.
+    fn align(&mut self) {
+        self.bit_buffer = 0;
+        self.bit_count = 0;
+    }
+}
+
+/// A canonical Huffman code: how many codes there are of each length, and the symbols in
+/// code order.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, String> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        if counts[0] as usize == lengths.len() {
+            // No codes at all; valid for a distance code of a block without matches.
+            return Ok(Self {
+                counts,
+                symbols: Vec::new(),
+            });
+        }
+
+        let mut left: i32 = 1;
+        for count in &counts[1..] {
+            left = (left << 1) - i32::from(*count);
+            if left < 0 {
+                return Err("over-subscribed Huffman code".to_string());
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for length in 1..MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, input: &mut BitReader<'_>) -> Result<u16, String> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for length in 1..=MAX_BITS {
+            code |= input.bits(1)? as i32;
+            let count = i32::from(self.counts[length]);
+            if code - count < first {
+                return self
+                    .symbols
+                    .get((index + code - first) as usize)
+                    .copied()
+                    .ok_or_else(|| "invalid Huffman code".to_string());
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        Err("invalid Huffman code".to_string())
+    }
+}
+
+struct Inflater<'a> {
+    input: BitReader<'a>,
+    output: Vec<u8>,
+}
+
+impl Inflater<'_> {
+    fn run(&mut self) -> Result<(), String> {
+        loop {
+            let last = self.input.bits(1)? == 1;
+            match self.input.bits(2)? {
+                0 => self.stored()?,
+                1 => self.fixed()?,
+                2 => self.dynamic()?,
+                _ => return Err("invalid deflate block type".to_string()),
+            }
+            if last {
+                return Ok(());
+            }
+        }
+    }
+
+    fn stored(&mut self) -> Result<(), String> {
+        self.input.align();
+        let data = self.input.data;
+        let position = self.input.position;
+        let header = data
+            .get(position..position + 4)
+            .ok_or("deflate stream is truncated")?;
+        let length = u16::from_le_bytes([header[0], header[1]]);
+        let complement = u16::from_le_bytes([header[2], header[3]]);
+        if length != !complement {
+            return Err("stored block length does not match its complement".to_string());
+        }
+        let start = position + 4;
+        let block = data
+            .get(start..start + length as usize)
+            .ok_or("deflate stream is truncated")?;
+        self.output.extend_from_slice(block);
+        self.input.position = start + length as usize;
+        Ok(())
+    }
+
+    fn fixed(&mut self) -> Result<(), String> {
+        let mut lengths = [0u8; FIXED_LITERAL_CODES];
+        for (symbol, length) in lengths.iter_mut().enumerate() {
+            *length = match symbol {
+                0..=143 => 8,
+                144..=255 => 9,
+                256..=279 => 7,
+                _ => 8,
+            };
+        }
+        let literals = Huffman::new(&lengths)?;
+        let distances = Huffman::new(&[5; MAX_DISTANCE_CODES])?;
+        self.codes(&literals, &distances)
+    }
+
+    fn dynamic(&mut self) -> Result<(), String> {
+        let literal_count = self.input.bits(5)? as usize + 257;
+        let distance_count = self.input.bits(5)? as usize + 1;
+        let code_length_count = self.input.bits(4)? as usize + 4;
+        if literal_count > MAX_LITERAL_CODES || distance_count > MAX_DISTANCE_CODES {
+            return Err("too many codes in a dynamic deflate block".to_string());
+        }
+
+        let mut code_lengths = [0u8; 19];
+        for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+            code_lengths[index] = self.input.bits(3)? as u8;
+        }
+        let code_length_code = Huffman::new(&code_lengths)?;
+
+        let mut lengths = vec![0u8; literal_count + distance_count];
+        let mut index = 0;
+        while index < lengths.len() {
+            let symbol = code_length_code.decode(&mut self.input)?;
+            let (value, repeat) = match symbol {
+                0..=15 => {
+                    lengths[index] = symbol as u8;
+                    index += 1;
+                    continue;
+                }
+                16 => {
+                    let previous = *index
+                        .checked_sub(1)
+                        .and_then(|previous| lengths.get(previous))
+                        .ok_or("repeat with no previous code length")?;
+                    (previous, 3 + self.input.bits(2)? as usize)
+                }
+                17 => (0, 3 + self.input.bits(3)? as usize),
+                _ => (0, 11 + self.input.bits(7)? as usize),
+            };
+            if index + repeat > lengths.len() {
+                return Err("code lengths overflow the dynamic block header".to_string());
+            }
+            lengths[index..index + repeat].fill(value);
+            index += repeat;
+        }
+        if lengths[256] == 0 {
+            return Err("dynamic deflate block has no end-of-block code".to_string());
+        }
+
+        let literals = Huffman::new(&lengths[..literal_count])?;
+        let distances = Huffman::new(&lengths[literal_count..])?;
+        self.codes(&literals, &distances)
+    }
+
+    fn codes(&mut self, literals: &Huffman, distances: &Huffman) -> Result<(), String> {
+        loop {
+            let symbol = literals.decode(&mut self.input)? as usize;
+            match symbol {
+                0..=255 => self.output.push(symbol as u8),
+                256 => return Ok(()),
+                _ => {
+                    let index = symbol - 257;
+                    if index >= LENGTH_BASE.len() {
+                        return Err("invalid deflate length code".to_string());
+                    }
+                    let length = LENGTH_BASE[index] as usize
+                        + self.input.bits(u32::from(LENGTH_EXTRA[index]))? as usize;
+
+                    let index = distances.decode(&mut self.input)? as usize;
+                    if index >= DISTANCE_BASE.len() {
+                        return Err("invalid deflate distance code".to_string());
+                    }
+                    let distance = DISTANCE_BASE[index] as usize
+                        + self.input.bits(u32::from(DISTANCE_EXTRA[index]))? as usize;
+                    if distance > self.output.len() {
+                        return Err("deflate distance reaches before the start".to_string());
+                    }
+
+                    // Byte by byte, since a match may overlap the bytes it produces.
+                    let start = self.output.len() - distance;
+                    for offset in 0..length {
+                        let byte = self.output[start + offset];
+                        self.output.push(byte);
+                    }
+                }
+            }
+        }
+    }
+}
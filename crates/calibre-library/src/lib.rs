@@ -0,0 +1,16 @@
+//! Calibre library access shared by the Zed extension, which runs as WebAssembly, and the
+//! native tools built alongside it. Nothing here links C code or calls Calibre.
+
+pub mod cite;
+pub mod deflate;
+pub mod edit;
+pub mod epub;
+pub mod epubcheck;
+pub mod inflate;
+pub mod metadata;
+pub mod opf;
+pub mod pdf;
+pub mod recover;
+pub mod sqlite;
+pub mod xml;
+pub mod zip;
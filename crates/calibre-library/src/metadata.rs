@@ -0,0 +1,700 @@
+//! Calibre's `metadata.db`, read straight from the file into typed rows.
+//!
+//! The row types follow the SQLAlchemy models in `src/calibre_mcp/db/models.py`. Dates are
+//! kept as the text Calibre stores, e.g. `2023-04-01 12:00:00+00:00`. Everything goes
+//! through [`crate::sqlite`], so this works in the extension's WebAssembly sandbox as
+//! long as the library directory is readable.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::sqlite::{Database, Row, Table, Value};
+
+/// A row of `books`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BookRecord {
+    pub id: i64,
+    pub title: String,
+    pub sort: Option<String>,
+    pub timestamp: Option<String>,
+    pub pubdate: Option<String>,
+    pub series_index: f64,
+    pub author_sort: Option<String>,
+    /// Directory of the book's files, relative to the library.
+    pub path: String,
+    pub uuid: Option<String>,
+    pub has_cover: bool,
+    pub last_modified: Option<String>,
+}
+
+/// A row of `authors`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Author {
+    pub id: i64,
+    pub name: String,
+    pub sort: Option<String>,
+    pub link: String,
+}
+
+/// A row of `series`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Series {
+    pub id: i64,
+    pub name: String,
+    pub sort: Option<String>,
+}
+
+/// A row of `tags`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Tag {
+    pub id: i64,
+    pub name: String,
+}
+
+/// A row of `publishers`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Publisher {
+    pub id: i64,
+    pub name: String,
+    pub sort: Option<String>,
+}
+
+/// A row of `ratings`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rating {
+    pub id: i64,
+    /// Stars times two, 0 to 10.
+    pub rating: i64,
+}
+
+/// A row of `comments`, the book's description.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Comment {
+    pub id: i64,
+    pub book: i64,
+    pub text: String,
+}
+
+/// A row of `data`: one format of a book.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Data {
+    pub id: i64,
+    pub book: i64,
+    /// Upper-case format name such as `EPUB`.
+    pub format: String,
+    pub uncompressed_size: i64,
+    /// File name without the extension, inside the book's directory.
+    pub name: String,
+}
+
+/// A row of `identifiers`, such as an ISBN.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Identifier {
+    pub id: i64,
+    pub book: i64,
+    pub kind: String,
+    pub val: String,
+}
+
+/// A row of `custom_columns`: a user-defined column whose values live in
+/// `custom_column_<id>`, linked through `books_custom_column_<id>_link` when `normalized`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CustomColumn {
+    pub id: i64,
+    /// Lookup name, used as `#label` in searches.
+    pub label: String,
+    /// Heading Calibre shows.
+    pub name: String,
+    /// `text`, `comments`, `series`, `enumeration`, `int`, `float`, `rating`, `datetime`,
+    /// `bool` or `composite`.
+    pub datatype: String,
+    pub is_multiple: bool,
+    pub normalized: bool,
+}
+
+/// A row of one of the `books_*_link` tables: `books_authors_link` links books to
+/// authors, `books_series_link` to series, and so on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Link {
+    pub id: i64,
+    pub book: i64,
+    /// Id of the author, series, tag, publisher or rating.
+    pub item: i64,
+}
+
+/// An open `metadata.db`.
+pub struct MetadataDb {
+    db: Database,
+}
+
+impl MetadataDb {
+    /// Opens the `metadata.db` of the library at `library`.
+    pub fn open(library: &Path) -> Result<Self, String> {
+        Ok(Self {
+            db: Database::open(&library.join("metadata.db"))?,
+        })
+    }
+
+    /// The `books` table, which unlike the others must be there: a library without it
+    /// is an error rather than an empty library.
+    pub fn books(&self) -> Result<Vec<BookRecord>, String> {
+        if !self.db.has_table("books") {
+            return Err("metadata.db has no `books` table".to_string());
+        }
+        self.rows("books", |row| {
+            Some(BookRecord {
+                id: row.integer("id")?,
+                title: text(row, "title"),
+                sort: optional_text(row, "sort"),
+                timestamp: optional_text(row, "timestamp"),
+                pubdate: optional_text(row, "pubdate"),
+                series_index: row.real("series_index").unwrap_or(1.0),
+                author_sort: optional_text(row, "author_sort"),
+                path: text(row, "path"),
+                uuid: optional_text(row, "uuid"),
+                has_cover: row.integer("has_cover").unwrap_or(0) != 0,
+                last_modified: optional_text(row, "last_modified"),
+            })
+        })
+    }
+
+    pub fn authors(&self) -> Result<Vec<Author>, String> {
+        self.rows("authors", |row| {
+            Some(Author {
+                id: row.integer("id")?,
+                name: text(row, "name"),
+                sort: optional_text(row, "sort"),
+                link: text(row, "link"),
+            })
+        })
+    }
+
+    pub fn series(&self) -> Result<Vec<Series>, String> {
+        self.rows("series", |row| {
+            Some(Series {
+                id: row.integer("id")?,
+                name: text(row, "name"),
+                sort: optional_text(row, "sort"),
+            })
+        })
+    }
+
+    pub fn tags(&self) -> Result<Vec<Tag>, String> {
+        self.rows("tags", |row| {
+            Some(Tag {
+                id: row.integer("id")?,
+                name: text(row, "name"),
+            })
+        })
+    }
+
+    pub fn publishers(&self) -> Result<Vec<Publisher>, String> {
+        self.rows("publishers", |row| {
+            Some(Publisher {
+                id: row.integer("id")?,
+                name: text(row, "name"),
+                sort: optional_text(row, "sort"),
+            })
+        })
+    }
+
+    pub fn ratings(&self) -> Result<Vec<Rating>, String> {
+        self.rows("ratings", |row| {
+            Some(Rating {
+                id: row.integer("id")?,
+                rating: row.integer("rating").unwrap_or(0),
+            })
+        })
+    }
+
+    pub fn comments(&self) -> Result<Vec<Comment>, String> {
+        self.rows("comments", |row| {
+            Some(Comment {
+                id: row.integer("id")?,
+                book: row.integer("book")?,
+                text: text(row, "text"),
+            })
+        })
+    }
+
+    pub fn data(&self) -> Result<Vec<Data>, String> {
+        self.rows("data", |row| {
+            Some(Data {
+                id: row.integer("id")?,
+                book: row.integer("book")?,
+                format: text(row, "format").to_ascii_uppercase(),
+                uncompressed_size: row.integer("uncompressed_size").unwrap_or(0),
+                name: text(row, "name"),
+            })
+        })
+    }
+
+    pub fn identifiers(&self) -> Result<Vec<Identifier>, String> {
+        self.rows("identifiers", |row| {
+            Some(Identifier {
+                id: row.integer("id")?,
+                book: row.integer("book")?,
+                kind: text(row, "type"),
+                val: text(row, "val"),
+            })
+        })
+    }
+
+    /// Custom columns not marked for deletion.
+    pub fn custom_columns(&self) -> Result<Vec<CustomColumn>, String> {
+        self.rows("custom_columns", |row| {
+            if row.integer("mark_for_delete").unwrap_or(0) != 0 {
+                return None;
+            }
+            Some(CustomColumn {
+                id: row.integer("id")?,
+                label: text(row, "label"),
+                name: text(row, "name"),
+                datatype: text(row, "datatype"),
+                is_multiple: row.integer("is_multiple").unwrap_or(0) != 0,
+                normalized: row.integer("normalized").unwrap_or(0) != 0,
+            })
+        })
+    }
+
+    /// A custom column's values by book, as text. Booleans read `Yes` or `No`, ratings
+    /// their stars, and a series value carries its index, e.g. `Discworld [3]`.
+    pub fn custom_values(
+        &self,
+        column: &CustomColumn,
+    ) -> Result<HashMap<i64, Vec<String>>, String> {
+        let table = format!("custom_column_{}", column.id);
+        let mut values: HashMap<i64, Vec<String>> = HashMap::new();
+        if !column.normalized {
+            for (book, value) in self.rows(&table, |row| {
+                Some((
+                    row.integer("book")?,
+                    custom_value(column, row.get("value"))?,
+                ))
+            })? {
+                values.entry(book).or_default().push(value);
+            }
+            return Ok(values);
+        }
+
+        let names: HashMap<i64, String> = self
+            .rows(&table, |row| {
+                Some((row.integer("id")?, custom_value(column, row.get("value"))?))
+            })?
+            .into_iter()
+            .collect();
+        let link_table = format!("books_custom_column_{}_link", column.id);
+        for (book, value, extra) in self.rows(&link_table, |row| {
+            Some((
+                row.integer("book")?,
+                row.integer("value")?,
+                row.real("extra"),
+            ))
+        })? {
+            if let Some(name) = names.get(&value) {
+                let value = match extra {
+                    Some(index) if column.datatype == "series" => format!("{name} [{index}]"),
+                    _ => name.clone(),
+                };
+                values.entry(book).or_default().push(value);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Saved searches as `(name, query)` pairs, from the `saved_searches` preference.
+    pub fn saved_searches(&self) -> Result<Vec<(String, String)>, String> {
+        let value = self
+            .rows("preferences", |row| {
+                (row.text("key")? == "saved_searches").then(|| text(row, "val"))
+            })?
+            .pop();
+        let Some(value) = value else {
+            return Ok(Vec::new());
+        };
+        let searches: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&value)
+            .map_err(|err| format!("invalid saved_searches preference: {err}"))?;
+        Ok(searches
+            .into_iter()
+            .filter_map(|(name, query)| Some((name, query.as_str()?.to_string())))
+            .collect())
+    }
+
+    pub fn books_authors_link(&self) -> Result<Vec<Link>, String> {
+        self.links("books_authors_link", "author")
+    }
+
+    pub fn books_series_link(&self) -> Result<Vec<Link>, String> {
+        self.links("books_series_link", "series")
+    }
+
+    pub fn books_tags_link(&self) -> Result<Vec<Link>, String> {
+        self.links("books_tags_link", "tag")
+    }
+
+    pub fn books_publishers_link(&self) -> Result<Vec<Link>, String> {
+        self.links("books_publishers_link", "publisher")
+    }
+
+    pub fn books_ratings_link(&self) -> Result<Vec<Link>, String> {
+        self.links("books_ratings_link", "rating")
+    }
+
+    /// Every book joined with its metadata, by id.
+    pub fn library(&self) -> Result<Vec<Book>, String> {
+        let author_rows = self.authors()?;
+        // Authors without a sort name sort by their name, as in Calibre.
+        let author_sorts = author_rows
+            .iter()
+            .map(|author| {
+                let sort = author.sort.as_deref().filter(|sort| !sort.is_empty());
+                (author.id, sort.unwrap_or(&author.name).to_string())
+            })
+            .collect::<HashMap<_, _>>();
+        let authors = by_id(author_rows, |author| (author.id, author.name));
+        let series = by_id(self.series()?, |series| (series.id, series.name));
+        let tags = by_id(self.tags()?, |tag| (tag.id, tag.name));
+        let publishers = by_id(self.publishers()?, |publisher| {
+            (publisher.id, publisher.name)
+        });
+        let ratings = by_id(self.ratings()?, |rating| (rating.id, rating.rating));
+
+        let author_links = self.books_authors_link()?;
+        let book_author_sorts = linked(author_links.clone(), &author_sorts);
+        let book_authors = linked(author_links, &authors);
+        let book_series = linked(self.books_series_link()?, &series);
+        let book_tags = linked(self.books_tags_link()?, &tags);
+        let book_publishers = linked(self.books_publishers_link()?, &publishers);
+        let book_ratings = linked(self.books_ratings_link()?, &ratings);
+
+        let mut formats: HashMap<i64, Vec<Data>> = HashMap::new();
+        for data in self.data()? {
+            formats.entry(data.book).or_default().push(data);
+        }
+        let mut comments: HashMap<i64, String> = self
+            .comments()?
+            .into_iter()
+            .map(|comment| (comment.book, comment.text))
+            .collect();
+        let mut identifiers: HashMap<i64, Vec<(String, String)>> = HashMap::new();
+        for identifier in self.identifiers()? {
+            identifiers
+                .entry(identifier.book)
+                .or_default()
+                .push((identifier.kind, identifier.val));
+        }
+
+        let mut books = self
+            .books()?
+            .into_iter()
+            .map(|record| {
+                let id = record.id;
+                let mut tags = book_tags.get(&id).cloned().unwrap_or_default();
+                tags.sort_by_key(|tag| tag.to_lowercase());
+                let mut files = formats.remove(&id).unwrap_or_default();
+                files.sort_by(|a, b| a.format.cmp(&b.format));
+                Book {
+                    authors: book_authors.get(&id).cloned().unwrap_or_default(),
+                    author_sorts: book_author_sorts.get(&id).cloned().unwrap_or_default(),
+                    series: first(&book_series, id),
+                    tags,
+                    publisher: first(&book_publishers, id),
+                    rating: first(&book_ratings, id),
+                    formats: files.iter().map(|data| data.format.clone()).collect(),
+                    files,
+                    comments: comments.remove(&id),
+                    identifiers: identifiers.remove(&id).unwrap_or_default(),
+                    record,
+                }
+            })
+            .collect::<Vec<_>>();
+        books.sort_by_key(|book| book.id);
+        Ok(books)
+    }
+
+    /// Reads a table's rows, skipping those without the required columns. Older and
+    /// hand-made libraries lack some tables besides `books`, which then read as empty.
+    fn rows<T>(&self, table: &str, read: impl Fn(Row<'_>) -> Option<T>) -> Result<Vec<T>, String> {
+        let table = if self.db.has_table(table) {
+            self.db.table(table)?
+        } else {
+            Table::default()
+        };
+        Ok(table.rows().filter_map(read).collect())
+    }
+
+    fn links(&self, table: &str, column: &str) -> Result<Vec<Link>, String> {
+        self.rows(table, |row| {
+            Some(Link {
+                id: row.integer("id")?,
+                book: row.integer("book")?,
+                item: row.integer(column)?,
+            })
+        })
+    }
+}
+
+/// A book with the metadata Calibre shows for it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Book {
+    pub record: BookRecord,
+    /// Authors in the order Calibre lists them.
+    pub authors: Vec<String>,
+    /// Sort names of `authors`, such as `Doyle, Arthur Conan`, in the same order.
+    pub author_sorts: Vec<String>,
+    pub series: Option<String>,
+    /// Tags sorted by name.
+    pub tags: Vec<String>,
+    pub publisher: Option<String>,
+    /// Stars times two, 0 to 10.
+    pub rating: Option<i64>,
+    /// Format names such as `EPUB`, sorted.
+    pub formats: Vec<String>,
+    /// The `data` rows behind `formats`, in the same order.
+    pub files: Vec<Data>,
+    pub comments: Option<String>,
+    /// `(type, value)` pairs such as `("isbn", "9780141439518")`.
+    pub identifiers: Vec<(String, String)>,
+}
+
+impl Book {
+    /// Where the book's file in `format`, such as `EPUB`, is in the library at `library`.
+    pub fn file(&self, library: &Path, format: &str) -> Option<PathBuf> {
+        self.files
+            .iter()
+            .find(|data| data.format.eq_ignore_ascii_case(format))
+            .map(|data| {
+                library.join(&self.path).join(format!(
+                    "{}.{}",
+                    data.name,
+                    data.format.to_lowercase()
+                ))
+            })
+    }
+}
+
+impl std::ops::Deref for Book {
+    type Target = BookRecord;
+
+    fn deref(&self) -> &BookRecord {
+        &self.record
+    }
+}
+
+/// Every book in the library at `library`, joined with its metadata. When `metadata.db`
+/// cannot be read, the books come from the `metadata.opf` sidecars in their directories.
+pub fn books(library: &Path) -> Result<Vec<Book>, String> {
+    let error = match MetadataDb::open(library).and_then(|db| db.library()) {
+        Ok(books) => return Ok(books),
+        Err(error) => error,
+    };
+    match crate::opf::books(library) {
+        Ok(books) if !books.is_empty() => Ok(books),
+        _ => Err(error),
+    }
+}
+
+fn custom_value(column: &CustomColumn, value: &Value) -> Option<String> {
+    let value = match (column.datatype.as_str(), value) {
+        (_, Value::Null) => return None,
+        ("bool", Value::Integer(value)) => if *value != 0 { "Yes" } else { "No" }.to_string(),
+        ("rating", Value::Integer(value)) => format!("{} stars", *value as f64 / 2.0),
+        (_, Value::Integer(value)) => value.to_string(),
+        (_, Value::Real(value)) => value.to_string(),
+        (_, Value::Text(value)) => value.clone(),
+        (_, Value::Blob(_)) => return None,
+    };
+    Some(value)
+}
+
+fn text(row: Row<'_>, column: &str) -> String {
+    row.text(column).unwrap_or_default().to_string()
+}
+
+fn optional_text(row: Row<'_>, column: &str) -> Option<String> {
+    row.text(column).map(str::to_string)
+}
+
+fn by_id<T, V>(rows: Vec<T>, entry: impl Fn(T) -> (i64, V)) -> HashMap<i64, V> {
+    rows.into_iter().map(entry).collect()
+}
+
+/// Each book's linked values, in link order.
+fn linked<V: Clone>(links: Vec<Link>, values: &HashMap<i64, V>) -> HashMap<i64, Vec<V>> {
+    let mut linked: HashMap<i64, Vec<V>> = HashMap::new();
+    for link in links {
+        if let Some(value) = values.get(&link.item) {
+            linked.entry(link.book).or_default().push(value.clone());
+        }
+    }
+    linked
+}
+
+fn first<V: Clone>(linked: &HashMap<i64, Vec<V>>, book: i64) -> Option<V> {
+    linked.get(&book).and_then(|values| values.first().cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The fixture library, generated by `scripts/create_test_db.py`.
+    fn fixture() -> MetadataDb {
+        let library =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../../tests/fixtures/test_library");
+        MetadataDb::open(&library).expect(
+            "tests/fixtures/test_library/metadata.db is missing; run scripts/create_test_db.py",
+        )
+    }
+
+    #[test]
+    fn reads_books() {
+        let books = fixture().books().unwrap();
+        assert_eq!(books.len(), 4);
+        let study = &books[0];
+        assert_eq!(study.id, 1);
+        assert_eq!(study.title, "A Study in Scarlet");
+        assert_eq!(study.sort.as_deref(), Some("Study in Scarlet, A"));
+        assert_eq!(study.author_sort.as_deref(), Some("Doyle, Arthur Conan"));
+        assert_eq!(study.path, "Arthur Conan Doyle/A Study in Scarlet (1)");
+        assert_eq!(study.uuid.as_deref(), Some("test-uuid-1"));
+        assert_eq!(study.series_index, 1.0);
+        assert!(study.pubdate.as_deref().unwrap().starts_with("1887-11-01"));
+        assert!(!study.has_cover);
+    }
+
+    #[test]
+    fn reads_lookup_tables() {
+        let db = fixture();
+        let authors = db.authors().unwrap();
+        assert_eq!(
+            authors.iter().map(|a| a.name.as_str()).collect::<Vec<_>>(),
+            ["Arthur Conan Doyle", "Jane Austen", "Mark Twain"]
+        );
+        assert_eq!(authors[1].sort.as_deref(), Some("Austen, Jane"));
+        assert_eq!(db.series().unwrap()[0].name, "Sherlock Holmes");
+        assert_eq!(db.tags().unwrap().len(), 5);
+        assert_eq!(db.publishers().unwrap()[2].name, "T. Egerton");
+        assert_eq!(
+            db.ratings()
+                .unwrap()
+                .iter()
+                .map(|r| r.rating)
+                .collect::<Vec<_>>(),
+            [1, 2, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn reads_per_book_tables() {
+        let db = fixture();
+        let comments = db.comments().unwrap();
+        assert_eq!(comments[1].book, 2);
+        assert_eq!(comments[1].text, "The second Sherlock Holmes novel.");
+
+        let data = db.data().unwrap();
+        assert_eq!(data.len(), 6);
+        assert_eq!(
+            (
+                data[1].book,
+                data[1].format.as_str(),
+                data[1].uncompressed_size
+            ),
+            (1, "PDF", 656)
+        );
+
+        let identifiers = db.identifiers().unwrap();
+        assert_eq!(
+            (
+                identifiers[2].book,
+                identifiers[2].kind.as_str(),
+                identifiers[2].val.as_str()
+            ),
+            (3, "gutenberg", "1342")
+        );
+
+        assert_eq!(
+            db.books_authors_link().unwrap()[3],
+            Link {
+                id: 4,
+                book: 4,
+                item: 3
+            }
+        );
+    }
+
+    #[test]
+    fn data_points_at_files_in_the_library() {
+        let db = fixture();
+        let books = db.books().unwrap();
+        let library =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../../tests/fixtures/test_library");
+        for data in db.data().unwrap() {
+            let book = books.iter().find(|book| book.id == data.book).unwrap();
+            let file = library.join(&book.path).join(format!(
+                "{}.{}",
+                data.name,
+                data.format.to_lowercase()
+            ));
+            assert!(file.is_file(), "{} is missing", file.display());
+        }
+    }
+
+    #[test]
+    fn joins_books_with_their_metadata() {
+        let books = fixture().library().unwrap();
+        let sign = &books[1];
+        assert_eq!(sign.title, "The Sign of the Four");
+        assert_eq!(sign.authors, ["Arthur Conan Doyle"]);
+        assert_eq!(sign.series.as_deref(), Some("Sherlock Holmes"));
+        assert_eq!(sign.series_index, 2.0);
+        assert_eq!(sign.tags, ["classic", "detective", "mystery"]);
+        assert_eq!(sign.publisher.as_deref(), Some("Spencer Blackett"));
+        assert_eq!(sign.rating, Some(4));
+        assert_eq!(sign.formats, ["EPUB"]);
+        assert_eq!(
+            sign.identifiers,
+            [("gutenberg".to_string(), "2097".to_string())]
+        );
+
+        let sawyer = &books[3];
+        assert_eq!(sawyer.formats, ["CBZ", "EPUB"]);
+        assert_eq!(sawyer.series, None);
+    }
+
+    #[test]
+    fn missing_tables_read_as_empty() {
+        let db = fixture();
+        assert!(db
+            .rows("no_such_table", |row| row.integer("id"))
+            .unwrap()
+            .is_empty());
+
+        // Except `books`, as the library would look empty without it.
+        let mut builder = crate::sqlite::write::Builder::default();
+        builder
+            .table("CREATE TABLE authors (id INTEGER PRIMARY KEY, name TEXT)")
+            .unwrap();
+        let db = MetadataDb {
+            db: Database::from_bytes(builder.finish().unwrap()).unwrap(),
+        };
+        assert_eq!(
+            db.library().unwrap_err(),
+            "metadata.db has no `books` table"
+        );
+    }
+
+    #[test]
+    fn reads_changes_still_in_the_wal() {
+        let library =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../../tests/fixtures/wal_library");
+        let books = books(&library).unwrap();
+        assert_eq!(books.len(), 202);
+        assert_eq!(books[0].title, "Title From The WAL");
+        assert_eq!(books[0].authors, ["Ann Author"]);
+        assert_eq!(
+            (books[1].title.as_str(), books[1].authors.as_slice()),
+            ("Added In The WAL", ["Bea Writer".to_string()].as_slice())
+        );
+    }
+}
@@ -0,0 +1,949 @@
+//! OPF package documents, versions 2.0 and 3.0: the `metadata.opf` Calibre keeps beside
+//! each book's files, and the package inside an EPUB.
+//!
+//! Dublin Core metadata and Calibre's `calibre:` extensions are read into [`Metadata`].
+//! Everything else, unrecognised metadata included, is kept as parsed, so
+//! [`Package::write`] gives back an equivalent document in the same OPF version. The
+//! sidecars are enough to list a library's books when `metadata.db` cannot be read.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::metadata::{Book, BookRecord, Data};
+use crate::xml::{self, Element, Node};
+
+/// File name of the sidecar in each book's directory.
+pub const SIDECAR: &str = "metadata.opf";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Opf2,
+    Opf3,
+}
+
+impl Version {
+    fn attribute(self) -> &'static str {
+        match self {
+            Version::Opf2 => "2.0",
+            Version::Opf3 => "3.0",
+        }
+    }
+}
+
+/// A parsed `<package>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Package {
+    pub version: Version,
+    /// Id of the `dc:identifier` that identifies the package.
+    pub unique_identifier: Option<String>,
+    /// The other attributes of `<package>`, such as namespace declarations and `prefix`.
+    pub attributes: Vec<(String, String)>,
+    /// The attributes of `<metadata>`, usually namespace declarations.
+    pub metadata_attributes: Vec<(String, String)>,
+    pub metadata: Metadata,
+    /// What follows `<metadata>`: `<manifest>`, `<spine>`, `<guide>` and so on.
+    pub sections: Vec<Element>,
+}
+
+/// What `<metadata>` says about a book.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub identifiers: Vec<Identifier>,
+    pub title: Option<String>,
+    /// The title's `id`, which OPF 3 refinements point at.
+    pub title_id: Option<String>,
+    /// `calibre:title_sort` in OPF 2, the title's `file-as` in OPF 3.
+    pub title_sort: Option<String>,
+    /// `dc:creator`s, who are authors unless a role says otherwise.
+    pub creators: Vec<Creator>,
+    pub contributors: Vec<Creator>,
+    /// Publication date, such as `1813-01-28T00:00:00+00:00`.
+    pub date: Option<String>,
+    pub publisher: Option<String>,
+    /// The book's description, HTML in Calibre's sidecars.
+    pub description: Option<String>,
+    pub languages: Vec<String>,
+    /// Tags.
+    pub subjects: Vec<String>,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    /// Stars times two, 0 to 10.
+    pub rating: Option<f64>,
+    /// When the book was added to the library.
+    pub timestamp: Option<String>,
+    /// `calibre:author_link_map`: a link for each author.
+    pub author_link_map: Option<Value>,
+    /// `calibre:user_categories`: each user category's items, as `[item, field]` pairs.
+    pub user_categories: Option<Value>,
+    pub custom_columns: Vec<CustomColumn>,
+    /// Metadata elements not covered above, in document order.
+    pub other: Vec<Element>,
+}
+
+/// A `dc:identifier`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Identifier {
+    pub id: Option<String>,
+    /// The `opf:scheme` attribute.
+    pub scheme: Option<String>,
+    /// The text, which in OPF 3 carries the scheme, as in `urn:uuid:…` or `isbn:…`.
+    pub value: String,
+}
+
+impl Identifier {
+    /// The lowercase scheme and the value without it: from `opf:scheme` when there is
+    /// one, otherwise from a `urn:scheme:` or `scheme:` prefix.
+    pub fn parts(&self) -> (Option<String>, &str) {
+        if let Some(scheme) = &self.scheme {
+            return (Some(scheme.to_lowercase()), &self.value);
+        }
+        let value = self.value.strip_prefix("urn:").unwrap_or(&self.value);
+        match value.split_once(':') {
+            Some((scheme, rest))
+                if !scheme.is_empty()
+                    && !rest.starts_with("//")
+                    && scheme
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-') =>
+            {
+                (Some(scheme.to_lowercase()), rest)
+            }
+            _ => (None, &self.value),
+        }
+    }
+}
+
+/// A `dc:creator` or `dc:contributor`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Creator {
+    pub id: Option<String>,
+    pub name: String,
+    /// A MARC relator code such as `aut` or `edt`.
+    pub role: Option<String>,
+    /// The sort name, such as `Austen, Jane`.
+    pub file_as: Option<String>,
+}
+
+/// A custom column's definition and the book's value in it, as Calibre stores them in
+/// `calibre:user_metadata`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CustomColumn {
+    /// The lookup name, `#` included.
+    pub label: String,
+    pub definition: Value,
+}
+
+impl CustomColumn {
+    pub fn value(&self) -> Option<&Value> {
+        self.definition
+            .get("#value#")
+            .filter(|value| !value.is_null())
+    }
+}
+
+impl Package {
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let source = fs::read_to_string(path)
+            .map_err(|err| format!("cannot read {}: {err}", path.display()))?;
+        Self::parse(&source).map_err(|err| format!("{}: {err}", path.display()))
+    }
+
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let root = xml::parse(source).map_err(|err| {
+            let (line, column) = err.line_column(source);
+            format!("{} at line {line}, column {column}", err.message)
+        })?;
+        if !root.is("package") {
+            return Err(format!(
+                "the root element is <{}>, not <package>",
+                root.name
+            ));
+        }
+        let metadata = root.child("metadata");
+        Ok(Self {
+            version: match root.attribute("version") {
+                Some(version) if version.starts_with('3') => Version::Opf3,
+                _ => Version::Opf2,
+            },
+            unique_identifier: root.attribute("unique-identifier").map(str::to_string),
+            attributes: root
+                .attributes
+                .iter()
+                .filter(|(key, _)| key != "version" && key != "unique-identifier")
+                .cloned()
+                .collect(),
+            metadata_attributes: metadata
+                .map(|metadata| metadata.attributes.clone())
+                .unwrap_or_default(),
+            metadata: metadata.map(Metadata::read).unwrap_or_default(),
+            sections: root
+                .elements()
+                .filter(|element| !element.is("metadata"))
+                .map(tidy)
+                .collect(),
+        })
+    }
+
+    /// The document, in the layout Calibre writes.
+    pub fn write(&self) -> String {
+        let mut package = Element::new("package");
+        package.attributes = self.attributes.clone();
+        if let Some(id) = &self.unique_identifier {
+            package
+                .attributes
+                .push(("unique-identifier".to_string(), id.clone()));
+        }
+        package
+            .attributes
+            .push(("version".to_string(), self.version.attribute().to_string()));
+        let mut metadata = Element::new("metadata");
+        metadata.attributes = self.metadata_attributes.clone();
+        metadata.children = self
+            .metadata
+            .elements(self.version)
+            .into_iter()
+            .map(Node::Element)
+            .collect();
+        package.children = std::iter::once(metadata)
+            .chain(self.sections.iter().cloned())
+            .map(Node::Element)
+            .collect();
+
+        let mut out = "<?xml version='1.0' encoding='utf-8'?>\n".to_string();
+        package.write(&mut out, 0);
+        out
+    }
+
+    /// The book whose directory in the library at `library` is `path`, as far as the
+    /// package and the files beside it tell. Its id is the `calibre` identifier, or else
+    /// the number Calibre ends the directory name with.
+    pub fn book(&self, library: &Path, path: &str) -> Option<Book> {
+        let metadata = &self.metadata;
+        let id = metadata
+            .identifier("calibre")
+            .and_then(|id| id.parse().ok())
+            .or_else(|| path.strip_suffix(')')?.rsplit_once('(')?.1.parse().ok())?;
+        let directory = library.join(path);
+
+        let mut files = Vec::new();
+        for file in fs::read_dir(&directory).into_iter().flatten().flatten() {
+            let file = file.path();
+            let name = file.file_name().and_then(|name| name.to_str());
+            if !file.is_file() || matches!(name, Some(SIDECAR | "cover.jpg")) {
+                continue;
+            }
+            let (Some(name), Some(format)) = (
+                file.file_stem().and_then(|stem| stem.to_str()),
+                file.extension().and_then(|extension| extension.to_str()),
+            ) else {
+                continue;
+            };
+            files.push(Data {
+                id: 0,
+                book: id,
+                format: format.to_uppercase(),
+                uncompressed_size: fs::metadata(&file).map_or(0, |file| file.len() as i64),
+                name: name.to_string(),
+            });
+        }
+        files.sort_by(|a, b| a.format.cmp(&b.format));
+
+        let authors = metadata.authors().collect::<Vec<_>>();
+        let author_sorts = authors
+            .iter()
+            .map(|author| {
+                author
+                    .file_as
+                    .clone()
+                    .unwrap_or_else(|| author.name.clone())
+            })
+            .collect::<Vec<_>>();
+        let mut tags = metadata.subjects.clone();
+        tags.sort_by_key(|tag| tag.to_lowercase());
+        Some(Book {
+            record: BookRecord {
+                id,
+                title: metadata
+                    .title
+                    .clone()
+                    .unwrap_or_else(|| "Unknown".to_string()),
+                sort: metadata.title_sort.clone(),
+                timestamp: metadata.timestamp.clone(),
+                pubdate: metadata.date.clone(),
+                series_index: metadata.series_index.unwrap_or(1.0),
+                author_sort: (!author_sorts.is_empty()).then(|| author_sorts.join(" & ")),
+                path: path.to_string(),
+                uuid: metadata.identifier("uuid").map(str::to_string),
+                has_cover: directory.join("cover.jpg").is_file(),
+                last_modified: None,
+            },
+            authors: authors.iter().map(|author| author.name.clone()).collect(),
+            author_sorts,
+            series: metadata.series.clone(),
+            tags,
+            publisher: metadata.publisher.clone(),
+            rating: metadata.rating.map(|rating| rating.round() as i64),
+            formats: files.iter().map(|data| data.format.clone()).collect(),
+            files,
+            comments: metadata.description.clone(),
+            identifiers: metadata
+                .identifiers
+                .iter()
+                .filter_map(|identifier| {
+                    let (scheme, value) = identifier.parts();
+                    let scheme = scheme.filter(|scheme| scheme != "calibre" && scheme != "uuid")?;
+                    Some((scheme, value.to_string()))
+                })
+                .collect(),
+        })
+    }
+}
+
+impl Metadata {
+    /// The value of the first identifier with `scheme`, such as `uuid` or `isbn`.
+    pub fn identifier(&self, scheme: &str) -> Option<&str> {
+        self.identifiers.iter().find_map(|identifier| {
+            let (found, value) = identifier.parts();
+            found
+                .filter(|found| found.eq_ignore_ascii_case(scheme))
+                .map(|_| value)
+        })
+    }
+
+    /// The creators without a role, or with the role `aut`.
+    pub fn authors(&self) -> impl Iterator<Item = &Creator> {
+        self.creators
+            .iter()
+            .filter(|creator| creator.role.as_deref().is_none_or(|role| role == "aut"))
+    }
+
+    fn read(element: &Element) -> Self {
+        let mut refinements = Refinements::new(element);
+        let mut metadata = Metadata::default();
+        for index in 0..refinements.elements.len() {
+            let element = refinements.elements[index];
+            if refinements.used[index] || element.attribute("refines").is_some() {
+                continue;
+            }
+            let text = content(element);
+            let dublin_core = !element.is("meta");
+            let read = match element.local_name() {
+                "identifier" if dublin_core => {
+                    metadata.identifiers.push(Identifier {
+                        id: element.attribute("id").map(str::to_string),
+                        scheme: element.attribute("scheme").map(str::to_string),
+                        value: text,
+                    });
+                    true
+                }
+                "title" if dublin_core && metadata.title.is_none() => {
+                    metadata.title_sort = refinements.take(element, "file-as");
+                    metadata.title_id = element.attribute("id").map(str::to_string);
+                    metadata.title = Some(text);
+                    true
+                }
+                "creator" | "contributor" if dublin_core => {
+                    let creator = Creator {
+                        id: element.attribute("id").map(str::to_string),
+                        role: match element.attribute("role") {
+                            Some(role) => Some(role.to_string()),
+                            None => refinements.take(element, "role"),
+                        },
+                        file_as: match element.attribute("file-as") {
+                            Some(file_as) => Some(file_as.to_string()),
+                            None => refinements.take(element, "file-as"),
+                        },
+                        name: text,
+                    };
+                    match element.local_name() {
+                        "creator" => metadata.creators.push(creator),
+                        _ => metadata.contributors.push(creator),
+                    }
+                    true
+                }
+                "date" if dublin_core => {
+                    let publication = element
+                        .attribute("event")
+                        .is_none_or(|event| event == "publication");
+                    publication && set(&mut metadata.date, Some(text))
+                }
+                "publisher" if dublin_core => set(&mut metadata.publisher, Some(text)),
+                "description" if dublin_core => set(&mut metadata.description, Some(text)),
+                "language" if dublin_core => {
+                    metadata.languages.push(text);
+                    true
+                }
+                "subject" if dublin_core => {
+                    metadata.subjects.push(text);
+                    true
+                }
+                "meta" if element.attribute("property") == Some("belongs-to-collection") => {
+                    let series = refinements
+                        .find(element, "collection-type")
+                        .is_some_and(|kind| content(kind) == "series");
+                    series && metadata.series.is_none() && {
+                        refinements.take(element, "collection-type");
+                        metadata.series = Some(text);
+                        let position = refinements.find(element, "group-position");
+                        if let Some(index) =
+                            position.and_then(|position| content(position).parse().ok())
+                        {
+                            refinements.take(element, "group-position");
+                            metadata.series_index = Some(index);
+                        }
+                        true
+                    }
+                }
+                "meta" => metadata.read_calibre(element, text),
+                _ => false,
+            };
+            refinements.used[index] = read;
+        }
+
+        for (element, used) in refinements.elements.iter().zip(&refinements.used) {
+            if !used {
+                metadata.other.push(tidy(element));
+            }
+        }
+        metadata
+    }
+
+    /// Reads a Calibre `<meta>`, OPF 2's `name` and `content` or OPF 3's `property` and
+    /// text. False when it is some other meta, repeats one already read, or does not parse.
+    fn read_calibre(&mut self, element: &Element, text: String) -> bool {
+        let (name, value) = match (element.attribute("name"), element.attribute("property")) {
+            (Some(name), _) => (
+                name,
+                element.attribute("content").unwrap_or_default().to_string(),
+            ),
+            (None, Some(property)) => (property, text),
+            (None, None) => return false,
+        };
+        let Some(name) = name.strip_prefix("calibre:") else {
+            return false;
+        };
+        let json = || serde_json::from_str::<Value>(&value).ok();
+        match name {
+            "series" => set(&mut self.series, Some(value.clone())),
+            "series_index" => set(&mut self.series_index, value.parse().ok()),
+            "rating" => set(&mut self.rating, value.parse().ok()),
+            "timestamp" => set(&mut self.timestamp, Some(value.clone())),
+            "title_sort" => set(&mut self.title_sort, Some(value.clone())),
+            "author_link_map" => set(&mut self.author_link_map, json()),
+            "user_categories" => set(&mut self.user_categories, json()),
+            "user_metadata" => match json() {
+                Some(Value::Object(columns)) if self.custom_columns.is_empty() => {
+                    self.custom_columns = columns
+                        .into_iter()
+                        .map(|(label, definition)| CustomColumn { label, definition })
+                        .collect();
+                    true
+                }
+                _ => false,
+            },
+            _ => match (name.strip_prefix("user_metadata:"), json()) {
+                (Some(label), Some(definition))
+                    if !self
+                        .custom_columns
+                        .iter()
+                        .any(|column| column.label == label) =>
+                {
+                    self.custom_columns.push(CustomColumn {
+                        label: label.to_string(),
+                        definition,
+                    });
+                    true
+                }
+                _ => false,
+            },
+        }
+    }
+
+    /// The children of `<metadata>` in `version`, in the order Calibre writes them.
+    fn elements(&self, version: Version) -> Vec<Element> {
+        let opf3 = version == Version::Opf3;
+        let mut elements = Vec::new();
+        for identifier in &self.identifiers {
+            let mut attributes = Vec::new();
+            if let Some(scheme) = &identifier.scheme {
+                attributes.push(("opf:scheme", scheme.as_str()));
+            }
+            if let Some(id) = &identifier.id {
+                attributes.push(("id", id.as_str()));
+            }
+            elements.push(element("dc:identifier", &attributes, &identifier.value));
+        }
+
+        if let Some(title) = &self.title {
+            let id = match (&self.title_id, &self.title_sort) {
+                (Some(id), _) => Some(id.as_str()),
+                (None, Some(_)) if opf3 => Some("title"),
+                (None, _) => None,
+            };
+            let attributes = id.map(|id| ("id", id));
+            elements.push(element("dc:title", attributes.as_slice(), title));
+            if let (Some(id), Some(sort), true) = (id, &self.title_sort, opf3) {
+                elements.push(refinement(id, "file-as", sort));
+            }
+        }
+
+        for (name, prefix, creators) in [
+            ("dc:creator", "create", &self.creators),
+            ("dc:contributor", "contrib", &self.contributors),
+        ] {
+            for (number, creator) in creators.iter().enumerate() {
+                if !opf3 {
+                    let mut attributes = Vec::new();
+                    if let Some(file_as) = &creator.file_as {
+                        attributes.push(("opf:file-as", file_as.as_str()));
+                    }
+                    if let Some(role) = &creator.role {
+                        attributes.push(("opf:role", role.as_str()));
+                    }
+                    if let Some(id) = &creator.id {
+                        attributes.push(("id", id.as_str()));
+                    }
+                    elements.push(element(name, &attributes, &creator.name));
+                    continue;
+                }
+
+                let refined = creator.role.is_some() || creator.file_as.is_some();
+                let id = match &creator.id {
+                    Some(id) => Some(id.clone()),
+                    None => refined.then(|| format!("{prefix}{}", number + 1)),
+                };
+                let attributes = id.as_deref().map(|id| ("id", id));
+                elements.push(element(name, attributes.as_slice(), &creator.name));
+                if let Some(id) = &id {
+                    if let Some(role) = &creator.role {
+                        let mut role = refinement(id, "role", role);
+                        role.attributes
+                            .push(("scheme".to_string(), "marc:relators".to_string()));
+                        elements.push(role);
+                    }
+                    if let Some(file_as) = &creator.file_as {
+                        elements.push(refinement(id, "file-as", file_as));
+                    }
+                }
+            }
+        }
+
+        for (name, value) in [
+            ("dc:date", &self.date),
+            ("dc:description", &self.description),
+            ("dc:publisher", &self.publisher),
+        ] {
+            if let Some(value) = value {
+                elements.push(element(name, &[], value));
+            }
+        }
+        for language in &self.languages {
+            elements.push(element("dc:language", &[], language));
+        }
+        for subject in &self.subjects {
+            elements.push(element("dc:subject", &[], subject));
+        }
+
+        let meta = |name: &str, value: &str| match opf3 {
+            true => element("meta", &[("property", &format!("calibre:{name}"))], value),
+            false => element(
+                "meta",
+                &[("name", &format!("calibre:{name}")), ("content", value)],
+                "",
+            ),
+        };
+        if let Some(map) = &self.author_link_map {
+            elements.push(meta("author_link_map", &map.to_string()));
+        }
+        match (&self.series, opf3) {
+            (Some(series), true) => {
+                let id = "series";
+                elements.push(element(
+                    "meta",
+                    &[("property", "belongs-to-collection"), ("id", id)],
+                    series,
+                ));
+                elements.push(refinement(id, "collection-type", "series"));
+                if let Some(index) = self.series_index {
+                    elements.push(refinement(id, "group-position", &index.to_string()));
+                }
+            }
+            (Some(series), false) => elements.push(meta("series", series)),
+            (None, _) => {}
+        }
+        if let Some(index) = self.series_index.filter(|_| !opf3 || self.series.is_none()) {
+            elements.push(meta("series_index", &index.to_string()));
+        }
+        if let Some(rating) = self.rating {
+            elements.push(meta("rating", &rating.to_string()));
+        }
+        if let Some(timestamp) = &self.timestamp {
+            elements.push(meta("timestamp", timestamp));
+        }
+        if let Some(sort) = self
+            .title_sort
+            .as_ref()
+            .filter(|_| !opf3 || self.title.is_none())
+        {
+            elements.push(meta("title_sort", sort));
+        }
+        if let Some(categories) = &self.user_categories {
+            elements.push(meta("user_categories", &categories.to_string()));
+        }
+        if opf3 && !self.custom_columns.is_empty() {
+            let columns = self
+                .custom_columns
+                .iter()
+                .map(|column| (column.label.clone(), column.definition.clone()))
+                .collect::<serde_json::Map<_, _>>();
+            elements.push(meta("user_metadata", &Value::Object(columns).to_string()));
+        } else {
+            for column in &self.custom_columns {
+                elements.push(meta(
+                    &format!("user_metadata:{}", column.label),
+                    &column.definition.to_string(),
+                ));
+            }
+        }
+
+        elements.extend(self.other.iter().cloned());
+        elements
+    }
+}
+
+/// The children of `<metadata>`, with the OPF 3 refinements among them by the id they
+/// refine, and which have been read.
+struct Refinements<'a> {
+    elements: Vec<&'a Element>,
+    by_id: HashMap<&'a str, Vec<usize>>,
+    used: Vec<bool>,
+}
+
+impl<'a> Refinements<'a> {
+    fn new(metadata: &'a Element) -> Self {
+        let elements = metadata.elements().collect::<Vec<_>>();
+        let mut by_id: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (index, element) in elements.iter().enumerate() {
+            if let Some(id) = element.attribute("refines").filter(|_| element.is("meta")) {
+                by_id
+                    .entry(id.trim_start_matches('#'))
+                    .or_default()
+                    .push(index);
+            }
+        }
+        let used = vec![false; elements.len()];
+        Self {
+            elements,
+            by_id,
+            used,
+        }
+    }
+
+    fn position(&self, element: &Element, property: &str) -> Option<usize> {
+        let id = element.attribute("id")?;
+        self.by_id.get(id)?.iter().copied().find(|index| {
+            !self.used[*index] && self.elements[*index].attribute("property") == Some(property)
+        })
+    }
+
+    /// The first unread refinement of `element` with `property`.
+    fn find(&self, element: &Element, property: &str) -> Option<&'a Element> {
+        self.position(element, property)
+            .map(|index| self.elements[index])
+    }
+
+    /// The value of the first unread refinement of `element` with `property`, which is
+    /// then read.
+    fn take(&mut self, element: &Element, property: &str) -> Option<String> {
+        let index = self.position(element, property)?;
+        self.used[index] = true;
+        Some(content(self.elements[index]))
+    }
+}
+
+/// A book's directory relative to the library, with its sidecar or why it could not be
+/// read.
+pub type Sidecar = (String, Result<Package, String>);
+
+/// The sidecars in the library at `library`, one directory below each author's.
+pub fn sidecars(library: &Path) -> Result<Vec<Sidecar>, String> {
+    let mut found = Vec::new();
+    for author in directories(library)? {
+        for book in directories(&author)? {
+            let sidecar = book.join(SIDECAR);
+            if !sidecar.is_file() {
+                continue;
+            }
+            let path = [&author, &book]
+                .iter()
+                .filter_map(|directory| directory.file_name())
+                .map(|name| name.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            found.push((path, Package::read(&sidecar)));
+        }
+    }
+    Ok(found)
+}
+
+/// Every book in the library at `library` with a readable sidecar, by id.
+pub fn books(library: &Path) -> Result<Vec<Book>, String> {
+    let mut books = sidecars(library)?
+        .into_iter()
+        .filter_map(|(path, package)| package.ok()?.book(library, &path))
+        .collect::<Vec<_>>();
+    books.sort_by_key(|book| book.id);
+    Ok(books)
+}
+
+/// Subdirectories of `directory`, sorted, leaving out hidden ones such as Calibre's
+/// `.caltrash`.
+pub(crate) fn directories(directory: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(directory)
+        .map_err(|err| format!("cannot read {}: {err}", directory.display()))?;
+    let mut directories = entries
+        .flatten()
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    directories.sort();
+    Ok(directories)
+}
+
+/// Fills an empty `slot`. False when it was already full or there is nothing to put in it.
+fn set<T>(slot: &mut Option<T>, value: Option<T>) -> bool {
+    if slot.is_some() || value.is_none() {
+        return false;
+    }
+    *slot = value;
+    true
+}
+
+/// The text inside an element, trimmed.
+fn content(element: &Element) -> String {
+    let mut text = String::new();
+    for node in &element.children {
+        match node {
+            Node::Text(value) => text.push_str(value),
+            Node::Element(child) => text.push_str(&content(child)),
+        }
+    }
+    text.trim().to_string()
+}
+
+fn element(name: &str, attributes: &[(&str, &str)], text: &str) -> Element {
+    let mut element = Element::new(name);
+    element.attributes = attributes
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    if !text.is_empty() {
+        element.children.push(Node::Text(text.to_string()));
+    }
+    element
+}
+
+/// An OPF 3 `<meta>` giving `property` of the element with `id`.
+fn refinement(id: &str, property: &str, value: &str) -> Element {
+    element(
+        "meta",
+        &[("refines", &format!("#{id}")), ("property", property)],
+        value,
+    )
+}
+
+/// `element` without offsets into its document, or the whitespace between its child
+/// elements, so that it compares equal to itself written out and parsed again.
+fn tidy(element: &Element) -> Element {
+    let mixed = element.elements().next().is_some();
+    Element {
+        name: element.name.clone(),
+        attributes: element.attributes.clone(),
+        children: element
+            .children
+            .iter()
+            .filter_map(|node| match node {
+                Node::Element(child) => Some(Node::Element(tidy(child))),
+                Node::Text(text) if mixed => {
+                    let text = text.trim();
+                    (!text.is_empty()).then(|| Node::Text(text.to_string()))
+                }
+                Node::Text(text) => Some(Node::Text(text.clone())),
+            })
+            .collect(),
+        offset: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPF2: &str = r##"<?xml version='1.0' encoding='utf-8'?>
+<package xmlns="http://www.idpf.org/2007/opf" unique-identifier="uuid_id" version="2.0">
+    <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+        <dc:identifier opf:scheme="calibre" id="calibre_id">7</dc:identifier>
+        <dc:identifier opf:scheme="uuid" id="uuid_id">0b8c4c2e-1b0f-4a8e-9d55-6a3d3c9e0b7a</dc:identifier>
+        <dc:title>Emma</dc:title>
+        <dc:creator opf:file-as="Austen, Jane" opf:role="aut">Jane Austen</dc:creator>
+        <dc:contributor opf:file-as="calibre" opf:role="bkp">calibre (7.2.0) [https://calibre-ebook.com]</dc:contributor>
+        <dc:date>1815-12-23T00:00:00+00:00</dc:date>
+        <dc:description>&lt;p&gt;Emma Woodhouse, handsome, clever, &amp;amp; rich.&lt;/p&gt;</dc:description>
+        <dc:publisher>John Murray</dc:publisher>
+        <dc:identifier opf:scheme="ISBN">9780141439587</dc:identifier>
+        <dc:language>eng</dc:language>
+        <dc:subject>Classic</dc:subject>
+        <dc:subject>Romance</dc:subject>
+        <meta name="calibre:author_link_map" content="{&quot;Jane Austen&quot;: &quot;&quot;}"/>
+        <meta name="calibre:series" content="Austen Novels"/>
+        <meta name="calibre:series_index" content="4.5"/>
+        <meta name="calibre:rating" content="8.0"/>
+        <meta name="calibre:timestamp" content="2024-03-02T10:15:00+00:00"/>
+        <meta name="calibre:title_sort" content="Emma"/>
+        <meta name="calibre:user_categories" content="{&quot;Favourites&quot;: [[&quot;Jane Austen&quot;, &quot;authors&quot;]]}"/>
+        <meta name="calibre:user_metadata:#read" content="{&quot;datatype&quot;: &quot;bool&quot;, &quot;name&quot;: &quot;Read&quot;, &quot;#value#&quot;: true}"/>
+        <meta name="cover" content="cover"/>
+    </metadata>
+    <guide>
+        <reference type="cover" title="Cover" href="cover.jpg"/>
+    </guide>
+</package>
+"##;
+
+    const OPF3: &str = r##"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid" prefix="calibre: https://calibre-ebook.com">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:uuid:5d1f7a3e-2b1c-4c4e-8f2a-1e9b7c3d2a10</dc:identifier>
+    <dc:identifier>isbn:9780199535569</dc:identifier>
+    <dc:title id="t1">Persuasion</dc:title>
+    <meta refines="#t1" property="file-as">Persuasion</meta>
+    <meta refines="#t1" property="title-type">main</meta>
+    <dc:creator id="c1">Jane Austen</dc:creator>
+    <meta refines="#c1" property="role" scheme="marc:relators">aut</meta>
+    <meta refines="#c1" property="file-as">Austen, Jane</meta>
+    <dc:language>en</dc:language>
+    <meta property="dcterms:modified">2024-01-01T00:00:00Z</meta>
+    <meta property="belongs-to-collection" id="c02">Austen Novels</meta>
+    <meta refines="#c02" property="collection-type">series</meta>
+    <meta refines="#c02" property="group-position">6</meta>
+    <meta property="calibre:rating">10</meta>
+    <meta property="calibre:user_metadata">{"#genre": {"datatype": "text", "is_multiple": {}, "#value#": "Romance"}}</meta>
+  </metadata>
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="c1" href="chapter1.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine>
+    <itemref idref="c1"/>
+  </spine>
+</package>
+"##;
+
+    #[test]
+    fn round_trips_opf2() {
+        let package = Package::parse(OPF2).unwrap();
+        assert_eq!(package.version, Version::Opf2);
+        let metadata = &package.metadata;
+        assert_eq!(metadata.title.as_deref(), Some("Emma"));
+        assert_eq!(metadata.identifier("calibre"), Some("7"));
+        assert_eq!(metadata.identifier("isbn"), Some("9780141439587"));
+        let authors = metadata.authors().collect::<Vec<_>>();
+        assert_eq!(authors.len(), 1);
+        assert_eq!(authors[0].file_as.as_deref(), Some("Austen, Jane"));
+        assert_eq!(metadata.contributors[0].role.as_deref(), Some("bkp"));
+        assert_eq!(
+            metadata.description.as_deref(),
+            Some("<p>Emma Woodhouse, handsome, clever, &amp; rich.</p>")
+        );
+        assert_eq!(metadata.series.as_deref(), Some("Austen Novels"));
+        assert_eq!(metadata.series_index, Some(4.5));
+        assert_eq!(metadata.rating, Some(8.0));
+        assert_eq!(
+            metadata.user_categories.as_ref().unwrap()["Favourites"][0][1],
+            "authors"
+        );
+        assert_eq!(metadata.custom_columns[0].label, "#read");
+        assert_eq!(metadata.custom_columns[0].value(), Some(&Value::Bool(true)));
+        assert_eq!(metadata.other.len(), 1);
+        assert_eq!(metadata.other[0].attribute("name"), Some("cover"));
+
+        let written = package.write();
+        assert!(written.contains(
+            "        <dc:creator opf:file-as=\"Austen, Jane\" opf:role=\"aut\">Jane Austen</dc:creator>\n"
+        ));
+        assert!(written.contains("        <meta name=\"calibre:series_index\" content=\"4.5\"/>\n"));
+        assert!(written.contains(
+            "    <guide>\n        <reference type=\"cover\" title=\"Cover\" href=\"cover.jpg\"/>\n    </guide>\n"
+        ));
+        assert_eq!(Package::parse(&written).unwrap(), package);
+    }
+
+    #[test]
+    fn round_trips_opf3() {
+        let package = Package::parse(OPF3).unwrap();
+        assert_eq!(package.version, Version::Opf3);
+        assert_eq!(package.unique_identifier.as_deref(), Some("uid"));
+        let metadata = &package.metadata;
+        assert_eq!(
+            metadata.identifier("uuid"),
+            Some("5d1f7a3e-2b1c-4c4e-8f2a-1e9b7c3d2a10")
+        );
+        assert_eq!(metadata.identifier("isbn"), Some("9780199535569"));
+        assert_eq!(metadata.title_sort.as_deref(), Some("Persuasion"));
+        assert_eq!(metadata.creators[0].role.as_deref(), Some("aut"));
+        assert_eq!(
+            metadata.creators[0].file_as.as_deref(),
+            Some("Austen, Jane")
+        );
+        assert_eq!(metadata.series.as_deref(), Some("Austen Novels"));
+        assert_eq!(metadata.series_index, Some(6.0));
+        assert_eq!(metadata.rating, Some(10.0));
+        assert_eq!(
+            metadata.custom_columns[0].value(),
+            Some(&Value::from("Romance"))
+        );
+        // The title type is a refinement nothing reads, so it is kept.
+        let other = metadata
+            .other
+            .iter()
+            .map(|element| element.attribute("property").unwrap_or_default())
+            .collect::<Vec<_>>();
+        assert_eq!(other, ["title-type", "dcterms:modified"]);
+        assert_eq!(package.sections.len(), 2);
+
+        let written = package.write();
+        assert!(written
+            .contains("        <meta refines=\"#series\" property=\"group-position\">6</meta>\n"));
+        assert_eq!(Package::parse(&written).unwrap(), package);
+    }
+
+    #[test]
+    fn reads_books_from_sidecars() {
+        let library =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../../tests/fixtures/test_library");
+        let from_sidecars = books(&library).unwrap();
+        let from_db = crate::metadata::books(&library).unwrap();
+        assert_eq!(from_sidecars.len(), from_db.len());
+        for (sidecar, db) in from_sidecars.iter().zip(&from_db) {
+            assert_eq!(sidecar.id, db.id);
+            assert_eq!(sidecar.title, db.title);
+            assert_eq!(sidecar.sort, db.sort);
+            assert_eq!(sidecar.path, db.path);
+            assert_eq!(sidecar.uuid, db.uuid);
+            assert_eq!(sidecar.series_index, db.series_index);
+            assert_eq!(sidecar.authors, db.authors);
+            assert_eq!(sidecar.author_sorts, db.author_sorts);
+            assert_eq!(sidecar.series, db.series);
+            assert_eq!(sidecar.tags, db.tags);
+            assert_eq!(sidecar.publisher, db.publisher);
+            assert_eq!(sidecar.rating, db.rating);
+            assert_eq!(sidecar.formats, db.formats);
+            assert_eq!(sidecar.comments, db.comments);
+            assert_eq!(sidecar.identifiers, db.identifiers);
+        }
+    }
+}
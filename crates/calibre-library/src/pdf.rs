@@ -0,0 +1,1302 @@
+//! Text from PDF files, page by page.
+//!
+//! Objects are found by scanning the file rather than trusting its cross-reference table,
+//! which damaged and incrementally updated files often get wrong; object streams are
+//! unpacked too. Text comes from the text-showing operators of each page's content, decoded
+//! through the font's `ToUnicode` map where there is one and WinAnsi otherwise. Layout is
+//! approximated: a move to another line starts a new line, and a wide gap becomes a space.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::inflate;
+
+/// How deep references and page trees are followed before giving up on a cycle.
+const MAX_DEPTH: usize = 32;
+/// A `TJ` adjustment, in thousandths of an em, wide enough to stand for a space.
+const WORD_GAP: f64 = 200.0;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Object {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    Real(f64),
+    String(Vec<u8>),
+    Name(String),
+    Array(Vec<Object>),
+    Dictionary(Dictionary),
+    Stream(Dictionary, Vec<u8>),
+    Reference(u32),
+    /// A bare keyword: an operator in content streams, `stream` or `endobj` elsewhere.
+    Keyword(String),
+}
+
+type Dictionary = HashMap<String, Object>;
+
+impl Object {
+    fn number(&self) -> Option<f64> {
+        match self {
+            Self::Integer(value) => Some(*value as f64),
+            Self::Real(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        match self {
+            Self::Name(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    fn dictionary(&self) -> Option<&Dictionary> {
+        match self {
+            Self::Dictionary(dictionary) | Self::Stream(dictionary, _) => Some(dictionary),
+            _ => None,
+        }
+    }
+}
+
+/// Text of each page, in page order.
+pub fn pages(path: &Path) -> Result<Vec<String>, String> {
+    let data = fs::read(path).map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+    let document = Document::parse(&data).map_err(|err| format!("{}: {err}", path.display()))?;
+    document.pages()
+}
+
+struct Document {
+    objects: HashMap<u32, Object>,
+    /// Trailer dictionaries and cross-reference streams, last one first.
+    trailers: Vec<Dictionary>,
+}
+
+impl Document {
+    fn parse(data: &[u8]) -> Result<Self, String> {
+        if !data.starts_with(b"%PDF-") && find(data, b"%PDF-", 0).is_none_or(|start| start > 1024) {
+            return Err("not a PDF file".to_string());
+        }
+        let mut document = Self {
+            objects: HashMap::new(),
+            trailers: Vec::new(),
+        };
+
+        // Later definitions replace earlier ones, as incremental updates intend.
+        let mut position = 0;
+        while let Some(found) = find(data, b"obj", position) {
+            position = found + 3;
+            let Some(number) = object_number(data, found) else {
+                continue;
+            };
+            let mut lexer = Lexer::new(data, position);
+            let Some(Ok(object)) = lexer.object() else {
+                continue;
+            };
+            let object = match (object, lexer.keyword_follows("stream")) {
+                (Object::Dictionary(dictionary), true) => {
+                    let (contents, end) = stream_contents(data, lexer.position, &dictionary);
+                    position = end;
+                    Object::Stream(dictionary, contents)
+                }
+                (object, _) => {
+                    position = lexer.position;
+                    object
+                }
+            };
+            if let Object::Stream(dictionary, _) = &object {
+                if dictionary.get("Type").and_then(Object::name) == Some("XRef") {
+                    document.trailers.push(dictionary.clone());
+                }
+            }
+            document.objects.insert(number, object);
+        }
+
+        let mut position = 0;
+        while let Some(found) = find(data, b"trailer", position) {
+            position = found + 7;
+            if let Some(Ok(Object::Dictionary(trailer))) = Lexer::new(data, position).object() {
+                document.trailers.push(trailer);
+            }
+        }
+        document.trailers.reverse();
+        if document
+            .trailers
+            .iter()
+            .any(|trailer| trailer.contains_key("Encrypt"))
+        {
+            return Err("the PDF is encrypted".to_string());
+        }
+
+        document.unpack_object_streams();
+        Ok(document)
+    }
+
+    /// Adds the objects packed in object streams, unless defined directly.
+    fn unpack_object_streams(&mut self) {
+        let streams = self
+            .objects
+            .values()
+            .filter(|object| {
+                object
+                    .dictionary()
+                    .and_then(|dictionary| dictionary.get("Type"))
+                    .and_then(Object::name)
+                    == Some("ObjStm")
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for stream in streams {
+            let Object::Stream(dictionary, _) = &stream else {
+                continue;
+            };
+            let Ok(data) = self.decode(&stream) else {
+                continue;
+            };
+            let count = self.number(dictionary.get("N")).unwrap_or(0.0) as usize;
+            let first = self.number(dictionary.get("First")).unwrap_or(0.0) as usize;
+            let mut header = Lexer::new(&data, 0);
+            for _ in 0..count {
+                let (Some(Ok(Object::Integer(number))), Some(Ok(Object::Integer(offset)))) =
+                    (header.object(), header.object())
+                else {
+                    break;
+                };
+                let Some(Ok(object)) = Lexer::new(&data, first + offset as usize).object() else {
+                    continue;
+                };
+                self.objects.entry(number as u32).or_insert(object);
+            }
+        }
+    }
+
+    /// Follows references to the object they point at.
+    fn resolve<'a>(&'a self, object: &'a Object) -> &'a Object {
+        let mut object = object;
+        for _ in 0..MAX_DEPTH {
+            match object {
+                Object::Reference(number) => {
+                    object = self.objects.get(number).unwrap_or(&Object::Null);
+                }
+                object => return object,
+            }
+        }
+        &Object::Null
+    }
+
+    fn get<'a>(&'a self, dictionary: &'a Dictionary, key: &str) -> Option<&'a Object> {
+        dictionary
+            .get(key)
+            .map(|object| self.resolve(object))
+            .filter(|object| **object != Object::Null)
+    }
+
+    fn number(&self, object: Option<&Object>) -> Option<f64> {
+        object.and_then(|object| self.resolve(object).number())
+    }
+
+    /// A stream's data with its filters undone.
+    fn decode(&self, stream: &Object) -> Result<Vec<u8>, String> {
+        let Object::Stream(dictionary, data) = stream else {
+            return Err("not a stream".to_string());
+        };
+        let filters = match self.get(dictionary, "Filter") {
+            Some(Object::Name(name)) => vec![name.clone()],
+            Some(Object::Array(names)) => names
+                .iter()
+                .filter_map(|name| self.resolve(name).name().map(str::to_string))
+                .collect(),
+            _ => Vec::new(),
+        };
+        if let Some(parameters) = self.get(dictionary, "DecodeParms") {
+            let predicted = |parameters: &Object| {
+                self.resolve(parameters)
+                    .dictionary()
+                    .and_then(|parameters| self.number(parameters.get("Predictor")))
+                    .is_some_and(|predictor| predictor > 1.0)
+            };
+            let uses_predictor = match parameters {
+                Object::Array(parameters) => parameters.iter().any(predicted),
+                parameters => predicted(parameters),
+            };
+            if uses_predictor {
+                return Err("stream predictors are not supported".to_string());
+            }
+        }
+
+        let mut data = data.clone();
+        for filter in filters {
+            data = match filter.as_str() {
+                "FlateDecode" | "Fl" => inflate::zlib_decompress(&data)
+                    .or_else(|_| inflate::inflate(&data, data.len() * 4))?,
+                "ASCIIHexDecode" | "AHx" => ascii_hex_decode(&data),
+                "ASCII85Decode" | "A85" => ascii85_decode(&data)?,
+                filter => return Err(format!("unsupported stream filter {filter}")),
+            };
+        }
+        Ok(data)
+    }
+
+    fn catalog(&self) -> Option<&Dictionary> {
+        self.trailers
+            .iter()
+            .find_map(|trailer| self.get(trailer, "Root"))
+            .and_then(Object::dictionary)
+            .or_else(|| {
+                self.objects.values().find_map(|object| {
+                    object.dictionary().filter(|dictionary| {
+                        dictionary.get("Type").and_then(Object::name) == Some("Catalog")
+                    })
+                })
+            })
+    }
+
+    fn pages(&self) -> Result<Vec<String>, String> {
+        let root = self
+            .catalog()
+            .and_then(|catalog| self.get(catalog, "Pages"))
+            .and_then(Object::dictionary)
+            .ok_or("the PDF has no page tree")?;
+        let mut pages = Vec::new();
+        self.collect_pages(root, None, 0, &mut pages);
+        Ok(pages
+            .into_iter()
+            .map(|(page, resources)| self.page_text(page, resources))
+            .collect())
+    }
+
+    /// Pages under a page tree node, each with the resources it has or inherits.
+    fn collect_pages<'a>(
+        &'a self,
+        node: &'a Dictionary,
+        inherited: Option<&'a Dictionary>,
+        depth: usize,
+        pages: &mut Vec<(&'a Dictionary, Option<&'a Dictionary>)>,
+    ) {
+        if depth > MAX_DEPTH {
+            return;
+        }
+        let resources = self
+            .get(node, "Resources")
+            .and_then(Object::dictionary)
+            .or(inherited);
+        match self.get(node, "Kids") {
+            Some(Object::Array(kids)) => {
+                for kid in kids {
+                    if let Some(kid) = self.resolve(kid).dictionary() {
+                        self.collect_pages(kid, resources, depth + 1, pages);
+                    }
+                }
+            }
+            _ => pages.push((node, resources)),
+        }
+    }
+
+    fn page_text(&self, page: &Dictionary, resources: Option<&Dictionary>) -> String {
+        let streams = match self.get(page, "Contents") {
+            Some(Object::Array(streams)) => {
+                streams.iter().map(|stream| self.resolve(stream)).collect()
+            }
+            Some(stream) => vec![stream],
+            None => Vec::new(),
+        };
+        let mut content = Vec::new();
+        for stream in streams {
+            // A stream that cannot be decoded leaves its text out rather than failing
+            // the book.
+            if let Ok(data) = self.decode(stream) {
+                content.extend_from_slice(&data);
+                content.push(b'\n');
+            }
+        }
+
+        let fonts = resources
+            .and_then(|resources| self.get(resources, "Font"))
+            .and_then(Object::dictionary)
+            .map(|fonts| {
+                fonts
+                    .iter()
+                    .filter_map(|(name, font)| {
+                        Some((name.clone(), self.font(self.resolve(font).dictionary()?)))
+                    })
+                    .collect::<HashMap<_, _>>()
+            })
+            .unwrap_or_default();
+        TextExtractor::new(&fonts).run(&content)
+    }
+
+    fn font(&self, font: &Dictionary) -> Font {
+        let to_unicode = self
+            .get(font, "ToUnicode")
+            .and_then(|stream| self.decode(stream).ok())
+            .map(|data| CMap::parse(&data));
+        let composite = self.get(font, "Subtype").and_then(Object::name) == Some("Type0");
+        let mut differences = HashMap::new();
+        if let Some(Object::Array(entries)) = self
+            .get(font, "Encoding")
+            .and_then(Object::dictionary)
+            .and_then(|encoding| self.get(encoding, "Differences"))
+        {
+            let mut code = 0u32;
+            for entry in entries {
+                match self.resolve(entry) {
+                    Object::Integer(start) => code = *start as u32,
+                    Object::Name(glyph) => {
+                        if let Some(c) = glyph_char(glyph) {
+                            differences.insert(code, c);
+                        }
+                        code += 1;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Font {
+            to_unicode,
+            composite,
+            differences,
+        }
+    }
+}
+
+/// The object number before `N G obj` ending just before `keyword`, if that is what is
+/// there.
+fn object_number(data: &[u8], keyword: usize) -> Option<u32> {
+    let mut index = keyword;
+    let mut numbers = Vec::new();
+    for _ in 0..2 {
+        let end = index;
+        while index > 0 && is_whitespace(data[index - 1]) {
+            index -= 1;
+        }
+        if index == end {
+            return None;
+        }
+        let digits_end = index;
+        while index > 0 && data[index - 1].is_ascii_digit() {
+            index -= 1;
+        }
+        if index == digits_end {
+            return None;
+        }
+        numbers.push(
+            std::str::from_utf8(&data[index..digits_end])
+                .ok()?
+                .parse::<u32>()
+                .ok()?,
+        );
+    }
+    if index > 0 && !is_whitespace(data[index - 1]) && !is_delimiter(data[index - 1]) {
+        return None;
+    }
+    numbers.pop()
+}
+
+/// A stream's raw data starting after the `stream` keyword at `start`, and where the
+/// object goes on after it. `/Length` is trusted when `endstream` follows it.
+fn stream_contents(data: &[u8], start: usize, dictionary: &Dictionary) -> (Vec<u8>, usize) {
+    let mut start = start;
+    if data.get(start) == Some(&b'\r') {
+        start += 1;
+    }
+    if data.get(start) == Some(&b'\n') {
+        start += 1;
+    }
+    if let Some(Object::Integer(length)) = dictionary.get("Length") {
+        let end = start + (*length).max(0) as usize;
+        let after = data.get(end..).unwrap_or_default();
+        let trimmed = after
+            .iter()
+            .position(|byte| !is_whitespace(*byte))
+            .map_or(after, |skip| &after[skip..]);
+        if trimmed.starts_with(b"endstream") {
+            return (data[start..end].to_vec(), end);
+        }
+    }
+    let end = find(data, b"endstream", start).unwrap_or(data.len());
+    let mut contents_end = end;
+    if contents_end > start && data[contents_end - 1] == b'\n' {
+        contents_end -= 1;
+    }
+    if contents_end > start && data[contents_end - 1] == b'\r' {
+        contents_end -= 1;
+    }
+    (data[start..contents_end].to_vec(), end)
+}
+
+fn find(data: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    data.get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|offset| from + offset)
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b'\0' | b'\t' | b'\n' | b'\x0c' | b'\r' | b' ')
+}
+
+fn is_delimiter(byte: u8) -> bool {
+    matches!(
+        byte,
+        b'(' | b')' | b'<' | b'>' | b'[' | b']' | b'{' | b'}' | b'/' | b'%'
+    )
+}
+
+/// Reads objects, and the keywords between them, from PDF syntax.
+struct Lexer<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(data: &'a [u8], position: usize) -> Self {
+        Self { data, position }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(byte) = self.peek() {
+            if is_whitespace(byte) {
+                self.position += 1;
+            } else if byte == b'%' {
+                while self
+                    .peek()
+                    .is_some_and(|byte| byte != b'\n' && byte != b'\r')
+                {
+                    self.position += 1;
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Whether the next token is `keyword`, consuming it if so.
+    fn keyword_follows(&mut self, keyword: &str) -> bool {
+        let start = self.position;
+        if let Some(Ok(Object::Keyword(found))) = self.object() {
+            if found == keyword {
+                return true;
+            }
+        }
+        self.position = start;
+        false
+    }
+
+    /// The next object or keyword; `None` at the end of the data.
+    fn object(&mut self) -> Option<Result<Object, String>> {
+        self.skip_whitespace();
+        let byte = self.peek()?;
+        Some(match byte {
+            b'/' => {
+                self.position += 1;
+                Ok(Object::Name(self.name()))
+            }
+            b'(' => {
+                self.position += 1;
+                self.literal_string()
+            }
+            b'<' if self.data.get(self.position + 1) == Some(&b'<') => {
+                self.position += 2;
+                self.dictionary()
+            }
+            b'<' => {
+                self.position += 1;
+                self.hex_string()
+            }
+            b'[' => {
+                self.position += 1;
+                self.array()
+            }
+            b']' | b'>' | b')' | b'{' | b'}' => {
+                self.position += 1;
+                Ok(Object::Keyword((byte as char).to_string()))
+            }
+            b'0'..=b'9' | b'+' | b'-' | b'.' => self.number(),
+            _ => {
+                let word = self.word();
+                Ok(match word.as_str() {
+                    "true" => Object::Boolean(true),
+                    "false" => Object::Boolean(false),
+                    "null" => Object::Null,
+                    _ => Object::Keyword(word),
+                })
+            }
+        })
+    }
+
+    fn word(&mut self) -> String {
+        let start = self.position;
+        while self
+            .peek()
+            .is_some_and(|byte| !is_whitespace(byte) && !is_delimiter(byte))
+        {
+            self.position += 1;
+        }
+        if self.position == start {
+            // A stray delimiter; step over it so reading always makes progress.
+            self.position += 1;
+        }
+        String::from_utf8_lossy(&self.data[start..self.position]).into_owned()
+    }
+
+    fn name(&mut self) -> String {
+        let start = self.position;
+        while self
+            .peek()
+            .is_some_and(|byte| !is_whitespace(byte) && !is_delimiter(byte))
+        {
+            self.position += 1;
+        }
+        let raw = &self.data[start..self.position];
+        let mut name = Vec::with_capacity(raw.len());
+        let mut index = 0;
+        while index < raw.len() {
+            let escaped = (raw[index] == b'#')
+                .then(|| raw.get(index + 1..index + 3))
+                .flatten()
+                .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+            match escaped {
+                Some(byte) => {
+                    name.push(byte);
+                    index += 3;
+                }
+                None => {
+                    name.push(raw[index]);
+                    index += 1;
+                }
+            }
+        }
+        String::from_utf8_lossy(&name).into_owned()
+    }
+
+    /// A number, or a reference `N G R` starting with one.
+    fn number(&mut self) -> Result<Object, String> {
+        let start = self.position;
+        let word = self.word();
+        if let Ok(integer) = word.parse::<i64>() {
+            let after = self.position;
+            if let (Some(Ok(Object::Integer(_))), true) =
+                (self.plain_integer(), self.keyword_follows("R"))
+            {
+                return Ok(Object::Reference(integer as u32));
+            }
+            self.position = after;
+            return Ok(Object::Integer(integer));
+        }
+        word.parse::<f64>()
+            .map(Object::Real)
+            .map_err(|_| format!("invalid number {word} at byte {start}"))
+    }
+
+    fn plain_integer(&mut self) -> Option<Result<Object, String>> {
+        self.skip_whitespace();
+        if !self.peek()?.is_ascii_digit() {
+            return None;
+        }
+        let word = self.word();
+        Some(
+            word.parse::<i64>()
+                .map(Object::Integer)
+                .map_err(|err| err.to_string()),
+        )
+    }
+
+    fn literal_string(&mut self) -> Result<Object, String> {
+        let mut bytes = Vec::new();
+        let mut depth = 0;
+        while let Some(byte) = self.peek() {
+            self.position += 1;
+            match byte {
+                b'(' => {
+                    depth += 1;
+                    bytes.push(byte);
+                }
+                b')' if depth == 0 => return Ok(Object::String(bytes)),
+                b')' => {
+                    depth -= 1;
+                    bytes.push(byte);
+                }
+                b'\\' => {
+                    let Some(escaped) = self.peek() else {
+                        break;
+                    };
+                    self.position += 1;
+                    match escaped {
+                        b'n' => bytes.push(b'\n'),
+                        b'r' => bytes.push(b'\r'),
+                        b't' => bytes.push(b'\t'),
+                        b'b' => bytes.push(b'\x08'),
+                        b'f' => bytes.push(b'\x0c'),
+                        b'0'..=b'7' => {
+                            let mut value = u32::from(escaped - b'0');
+                            for _ in 0..2 {
+                                match self.peek() {
+                                    Some(digit @ b'0'..=b'7') => {
+                                        value = value * 8 + u32::from(digit - b'0');
+                                        self.position += 1;
+                                    }
+                                    _ => break,
+                                }
+                            }
+                            bytes.push(value as u8);
+                        }
+                        // A backslash at the end of a line continues the string.
+                        b'\r' => {
+                            if self.peek() == Some(b'\n') {
+                                self.position += 1;
+                            }
+                        }
+                        b'\n' => {}
+                        escaped => bytes.push(escaped),
+                    }
+                }
+                byte => bytes.push(byte),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+
+    fn hex_string(&mut self) -> Result<Object, String> {
+        let start = self.position;
+        let end = find(self.data, b">", start).ok_or("unterminated hex string")?;
+        self.position = end + 1;
+        Ok(Object::String(ascii_hex_decode(&self.data[start..end])))
+    }
+
+    fn array(&mut self) -> Result<Object, String> {
+        let mut items = Vec::new();
+        loop {
+            match self.object() {
+                None => return Err("unterminated array".to_string()),
+                Some(Ok(Object::Keyword(keyword))) if keyword == "]" => {
+                    return Ok(Object::Array(items))
+                }
+                Some(item) => items.push(item?),
+            }
+        }
+    }
+
+    fn dictionary(&mut self) -> Result<Object, String> {
+        let mut dictionary = Dictionary::new();
+        loop {
+            self.skip_whitespace();
+            if self.data[self.position..].starts_with(b">>") {
+                self.position += 2;
+                return Ok(Object::Dictionary(dictionary));
+            }
+            let key = match self.object() {
+                None => return Err("unterminated dictionary".to_string()),
+                Some(Ok(Object::Name(key))) => key,
+                Some(Ok(other)) => return Err(format!("dictionary key is not a name: {other:?}")),
+                Some(Err(err)) => return Err(err),
+            };
+            let value = self.object().ok_or("unterminated dictionary")??;
+            dictionary.insert(key, value);
+        }
+    }
+
+    /// Skips an inline image's data, after its `ID` operator, up to and including `EI`.
+    fn skip_inline_image(&mut self) {
+        let mut index = self.position;
+        while let Some(found) = find(self.data, b"EI", index) {
+            let before = found.checked_sub(1).map(|index| self.data[index]);
+            let after = self.data.get(found + 2).copied();
+            if before.is_some_and(is_whitespace) && after.is_none_or(is_whitespace) {
+                self.position = found + 2;
+                return;
+            }
+            index = found + 2;
+        }
+        self.position = self.data.len();
+    }
+}
+
+fn ascii_hex_decode(data: &[u8]) -> Vec<u8> {
+    let digits = data
+        .iter()
+        .take_while(|byte| **byte != b'>')
+        .filter_map(|byte| (*byte as char).to_digit(16))
+        .collect::<Vec<_>>();
+    digits
+        .chunks(2)
+        .map(|pair| (pair[0] * 16 + pair.get(1).copied().unwrap_or(0)) as u8)
+        .collect()
+}
+
+fn ascii85_decode(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoded = Vec::new();
+    let mut group = Vec::with_capacity(5);
+    let data = data.strip_prefix(b"<~").unwrap_or(data);
+    for &byte in data {
+        match byte {
+            b'~' => break,
+            b'z' if group.is_empty() => decoded.extend_from_slice(&[0; 4]),
+            b'!'..=b'u' => {
+                group.push(u32::from(byte - b'!'));
+                if group.len() == 5 {
+                    let value = group
+                        .iter()
+                        .fold(0u64, |value, digit| value * 85 + u64::from(*digit));
+                    decoded.extend_from_slice(&(value as u32).to_be_bytes());
+                    group.clear();
+                }
+            }
+            byte if is_whitespace(byte) => {}
+            _ => return Err("invalid ASCII85 data".to_string()),
+        }
+    }
+    if !group.is_empty() {
+        let length = group.len();
+        group.resize(5, 84);
+        let value = group
+            .iter()
+            .fold(0u64, |value, digit| value * 85 + u64::from(*digit));
+        decoded.extend_from_slice(&(value as u32).to_be_bytes()[..length - 1]);
+    }
+    Ok(decoded)
+}
+
+/// How a font's character codes become text.
+struct Font {
+    to_unicode: Option<CMap>,
+    /// Type 0 fonts use two-byte codes unless their `ToUnicode` map says otherwise.
+    composite: bool,
+    differences: HashMap<u32, char>,
+}
+
+impl Font {
+    fn decode(&self, bytes: &[u8], text: &mut String) {
+        let width = match &self.to_unicode {
+            Some(cmap) if cmap.code_width > 0 => cmap.code_width,
+            _ if self.composite => 2,
+            _ => 1,
+        };
+        for code in bytes.chunks(width) {
+            let code = code
+                .iter()
+                .fold(0u32, |code, byte| code << 8 | u32::from(*byte));
+            if let Some(mapped) = self
+                .to_unicode
+                .as_ref()
+                .and_then(|cmap| cmap.map.get(&code))
+            {
+                text.push_str(mapped);
+            } else if let Some(c) = self.differences.get(&code) {
+                text.push(*c);
+            } else if width == 1 {
+                text.push(win_ansi(code as u8));
+            }
+        }
+    }
+}
+
+/// A `ToUnicode` character map.
+struct CMap {
+    /// Bytes per code, from the code space; 0 when it does not say.
+    code_width: usize,
+    map: HashMap<u32, String>,
+}
+
+impl CMap {
+    fn parse(data: &[u8]) -> Self {
+        let mut cmap = Self {
+            code_width: 0,
+            map: HashMap::new(),
+        };
+        let mut lexer = Lexer::new(data, 0);
+        let mut operands = Vec::new();
+        let mut section = String::new();
+        while let Some(object) = lexer.object() {
+            let Ok(object) = object else {
+                break;
+            };
+            match object {
+                Object::Keyword(keyword) => {
+                    match keyword.as_str() {
+                        "begincodespacerange" | "beginbfchar" | "beginbfrange" => {
+                            section = keyword.clone();
+                        }
+                        "endcodespacerange" => {
+                            if let Some(Object::String(low)) = operands.first() {
+                                cmap.code_width = low.len();
+                            }
+                        }
+                        "endbfchar" => {
+                            for pair in operands.chunks(2) {
+                                if let [Object::String(code), Object::String(unicode)] = pair {
+                                    cmap.map.insert(code_value(code), utf16(unicode));
+                                }
+                            }
+                        }
+                        "endbfrange" => {
+                            for range in operands.chunks(3) {
+                                cmap.insert_range(range);
+                            }
+                        }
+                        _ => {}
+                    }
+                    if keyword.starts_with("end") || section.is_empty() {
+                        section.clear();
+                        operands.clear();
+                    }
+                }
+                object if !section.is_empty() => operands.push(object),
+                _ => {}
+            }
+        }
+        cmap
+    }
+
+    fn insert_range(&mut self, range: &[Object]) {
+        let [Object::String(low), Object::String(high), destination] = range else {
+            return;
+        };
+        let (low, high) = (code_value(low), code_value(high));
+        // A malformed range could claim billions of codes.
+        if high < low || high - low > 0xffff {
+            return;
+        }
+        match destination {
+            Object::String(start) => {
+                let mut units = start
+                    .chunks(2)
+                    .map(|pair| u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]))
+                    .collect::<Vec<_>>();
+                for code in low..=high {
+                    self.map.insert(code, String::from_utf16_lossy(&units));
+                    if let Some(last) = units.last_mut() {
+                        *last = last.wrapping_add(1);
+                    }
+                }
+            }
+            Object::Array(destinations) => {
+                for (code, destination) in (low..=high).zip(destinations) {
+                    if let Object::String(unicode) = destination {
+                        self.map.insert(code, utf16(unicode));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn code_value(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0u32, |code, byte| code << 8 | u32::from(*byte))
+}
+
+fn utf16(bytes: &[u8]) -> String {
+    let units = bytes
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]))
+        .collect::<Vec<_>>();
+    String::from_utf16_lossy(&units)
+}
+
+/// WinAnsiEncoding, which is Latin-1 except for 0x80–0x9f.
+fn win_ansi(byte: u8) -> char {
+    const HIGH: [char; 32] = [
+        '€', '\u{fffd}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{fffd}', 'Ž',
+        '\u{fffd}', '\u{fffd}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ',
+        '\u{fffd}', 'ž', 'Ÿ',
+    ];
+    match byte {
+        0x80..=0x9f => HIGH[(byte - 0x80) as usize],
+        byte => byte as char,
+    }
+}
+
+/// The character a glyph name in an encoding's `/Differences` stands for.
+fn glyph_char(glyph: &str) -> Option<char> {
+    if let Some(hex) = glyph.strip_prefix("uni").filter(|hex| hex.len() == 4) {
+        return u32::from_str_radix(hex, 16).ok().and_then(char::from_u32);
+    }
+    let mut chars = glyph.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(c);
+    }
+    Some(match glyph {
+        "space" => ' ',
+        "quoteleft" => '‘',
+        "quoteright" => '’',
+        "quotedblleft" => '“',
+        "quotedblright" => '”',
+        "quotesingle" => '\'',
+        "quotedbl" => '"',
+        "endash" => '–',
+        "emdash" => '—',
+        "hyphen" => '-',
+        "period" => '.',
+        "comma" => ',',
+        "colon" => ':',
+        "semicolon" => ';',
+        "exclam" => '!',
+        "question" => '?',
+        "parenleft" => '(',
+        "parenright" => ')',
+        "ellipsis" => '…',
+        "bullet" => '•',
+        "fi" => 'ﬁ',
+        "fl" => 'ﬂ',
+        _ => return None,
+    })
+}
+
+/// Runs a page's content, collecting the text it shows.
+struct TextExtractor<'a> {
+    fonts: &'a HashMap<String, Font>,
+    font: Option<&'a Font>,
+    text: String,
+    /// Vertical position of the current line, to tell a new line from a move along it.
+    line_y: Option<f64>,
+}
+
+impl<'a> TextExtractor<'a> {
+    fn new(fonts: &'a HashMap<String, Font>) -> Self {
+        Self {
+            fonts,
+            font: None,
+            text: String::new(),
+            line_y: None,
+        }
+    }
+
+    fn run(mut self, content: &[u8]) -> String {
+        let mut lexer = Lexer::new(content, 0);
+        let mut operands: Vec<Object> = Vec::new();
+        while let Some(object) = lexer.object() {
+            let operator = match object {
+                Ok(Object::Keyword(operator)) => operator,
+                Ok(operand) => {
+                    operands.push(operand);
+                    continue;
+                }
+                // Skip what cannot be read and carry on with the rest.
+                Err(_) => {
+                    operands.clear();
+                    continue;
+                }
+            };
+            match operator.as_str() {
+                "Tf" => {
+                    self.font = operands
+                        .first()
+                        .and_then(Object::name)
+                        .and_then(|name| self.fonts.get(name));
+                }
+                "Tj" => self.show(operands.last()),
+                "'" => {
+                    self.newline();
+                    self.show(operands.last());
+                }
+                "\"" => {
+                    self.newline();
+                    self.show(operands.last());
+                }
+                "TJ" => {
+                    if let Some(Object::Array(items)) = operands.last() {
+                        for item in items {
+                            match item {
+                                Object::String(_) => self.show(Some(item)),
+                                item if item.number().is_some_and(|gap| gap < -WORD_GAP) => {
+                                    self.space();
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+                "Td" | "TD" => {
+                    let x = operands.first().and_then(Object::number).unwrap_or(0.0);
+                    let y = operands.get(1).and_then(Object::number).unwrap_or(0.0);
+                    if y.abs() > f64::EPSILON {
+                        self.newline();
+                        self.line_y = Some(self.line_y.unwrap_or(0.0) + y);
+                    } else if x.abs() > f64::EPSILON {
+                        self.space();
+                    }
+                }
+                "Tm" => {
+                    let y = operands.get(5).and_then(Object::number);
+                    match (self.line_y, y) {
+                        (Some(line_y), Some(y)) if (line_y - y).abs() < 1.0 => self.space(),
+                        _ => self.newline(),
+                    }
+                    self.line_y = y;
+                }
+                "T*" => self.newline(),
+                "ET" => self.space(),
+                "ID" => lexer.skip_inline_image(),
+                _ => {}
+            }
+            operands.clear();
+        }
+
+        self.text
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn show(&mut self, string: Option<&Object>) {
+        let Some(Object::String(bytes)) = string else {
+            return;
+        };
+        match self.font {
+            Some(font) => font.decode(bytes, &mut self.text),
+            None => self.text.extend(bytes.iter().map(|byte| win_ansi(*byte))),
+        }
+    }
+
+    fn space(&mut self) {
+        if !self.text.is_empty() && !self.text.ends_with(char::is_whitespace) {
+            self.text.push(' ');
+        }
+    }
+
+    fn newline(&mut self) {
+        if !self.text.is_empty() && !self.text.ends_with('\n') {
+            self.text.push('\n');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deflate;
+
+    /// A stream object with `content`, compressed when `flate` is set.
+    fn stream(dictionary: &str, content: &[u8], flate: bool) -> Vec<u8> {
+        let (content, filter) = if flate {
+            let mut zlib = vec![0x78, 0x01];
+            zlib.extend(deflate::deflate(content));
+            (zlib, "/Filter /FlateDecode ")
+        } else {
+            (content.to_vec(), "")
+        };
+        let mut object = format!(
+            "<< {filter}/Length {} {dictionary} >>\nstream\n",
+            content.len()
+        )
+        .into_bytes();
+        object.extend(content);
+        object.extend(b"\nendstream");
+        object
+    }
+
+    /// A PDF of numbered objects, 1 being the catalog unless `trailer` names another root.
+    fn pdf(objects: &[Vec<u8>], trailer: &str) -> Vec<u8> {
+        let mut data = b"%PDF-1.7\n".to_vec();
+        for (index, object) in objects.iter().enumerate() {
+            data.extend(format!("{} 0 obj\n", index + 1).bytes());
+            data.extend(object);
+            data.extend(b"\nendobj\n");
+        }
+        data.extend(format!("trailer\n<< {trailer} >>\n%%EOF\n").bytes());
+        data
+    }
+
+    fn pages_of(data: &[u8]) -> Result<Vec<String>, String> {
+        Document::parse(data)?.pages()
+    }
+
+    /// A catalog, a page tree whose `Resources` its pages inherit, and a page per content;
+    /// `extra` objects are numbered from `3 + 2 * contents.len()`.
+    fn document(fonts: &str, contents: &[&[u8]], extra: Vec<Vec<u8>>) -> Vec<u8> {
+        let kids = (0..contents.len())
+            .map(|index| format!("{} 0 R", 3 + 2 * index))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut objects = vec![
+            b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+            format!("<< /Type /Pages /Kids [{kids}] /Resources << /Font << {fonts} >> >> >>")
+                .into_bytes(),
+        ];
+        for (index, content) in contents.iter().enumerate() {
+            objects.push(format!("<< /Type /Page /Contents {} 0 R >>", 4 + 2 * index).into_bytes());
+            objects.push(stream("", content, index % 2 == 1));
+        }
+        objects.extend(extra);
+        pdf(&objects, "/Root 1 0 R")
+    }
+
+    #[test]
+    fn decodes_text_through_fonts_and_to_unicode_maps() {
+        let cmap = b"/CIDInit /ProcSet findresource begin 12 dict begin begincmap\n\
+            1 begincodespacerange <0000> <ffff> endcodespacerange\n\
+            2 beginbfchar <0001> <0048> <0005> <d835dc9c> endbfchar\n\
+            2 beginbfrange <0002> <0003> <0069> <0004> <0004> [<00660069>] endbfrange\n\
+            endcmap end end";
+        let single = b"1 begincodespacerange <00> <ff> endcodespacerange \
+            1 beginbfchar <41> <0051> endbfchar";
+        let fonts = "/F1 11 0 R /F2 12 0 R /F3 14 0 R /F4 15 0 R";
+        let extra = vec![
+            b"<< /Type /Font /Subtype /Type0 /ToUnicode 13 0 R >>".to_vec(),
+            b"<< /Type /Font /Subtype /Type1 /Encoding << /Differences [65 /quoteright /uni00E9 /x] >> >>".to_vec(),
+            stream("", cmap, true),
+            b"<< /Type /Font /Subtype /Type0 >>".to_vec(),
+            b"<< /Type /Font /ToUnicode 16 0 R >>".to_vec(),
+            stream("", single, false),
+        ];
+        let data = document(
+            fonts,
+            &[
+                b"BT /F1 12 Tf <00010002000300040005> Tj ET",
+                b"BT /F2 12 Tf (ABCD\\223quoted\\224) Tj ET",
+                b"BT /F3 12 Tf <00410042> Tj /F4 9 Tf (AB) Tj ET",
+                b"BT (no font \\(set\\)) Tj ET",
+            ],
+            extra,
+        );
+        assert_eq!(
+            pages_of(&data).unwrap(),
+            [
+                "Hijfi\u{1d49c}",
+                // `x` is itself a character; unknown codes fall back to WinAnsi.
+                "’éxD“quoted”",
+                // A Type 0 font without a map reads two-byte codes and drops them.
+                "QB",
+                "no font (set)",
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_objects_in_object_streams_and_updates() {
+        let packed = [
+            (1, "<< /Type /Catalog /Pages 2 0 R >>"),
+            (2, "<< /Type /Pages /Kids [3 0 R 5 0 R] >>"),
+            (3, "<< /Type /Page /Contents 4 0 R >>"),
+            (5, "<< /Type /Page /Contents 99 0 R >>"),
+        ];
+        let mut header = String::new();
+        let mut body = String::new();
+        for (number, object) in packed {
+            header += &format!("{number} {} ", body.len());
+            body += object;
+            body += " ";
+        }
+        let object_stream = stream(
+            &format!("/Type /ObjStm /N {} /First {}", packed.len(), header.len()),
+            (header.clone() + &body).as_bytes(),
+            true,
+        );
+        let mut data = b"%PDF-1.5\n".to_vec();
+        for (number, object) in [
+            (4, stream("", b"BT (first) Tj ET", false)),
+            (7, object_stream),
+            (8, stream("/Type /XRef /Root 1 0 R /Size 9", b"", false)),
+            // An incremental update replacing a packed page and a content stream.
+            (5, b"<< /Type /Page /Contents 6 0 R >>".to_vec()),
+            (6, stream("", b"BT (second) Tj ET", true)),
+            (
+                4,
+                b"<< /Length 999 >>\nstream\nBT (updated) Tj ET\nendstream".to_vec(),
+            ),
+        ] {
+            data.extend(format!("{number} 0 obj\n").bytes());
+            data.extend(object);
+            data.extend(b"\nendobj\n");
+        }
+        assert_eq!(pages_of(&data).unwrap(), ["updated", "second"]);
+
+        // Without a trailer naming the root, the catalog is found by its type.
+        let data = document("", &[b"BT (found) Tj ET"], Vec::new());
+        let end = find(&data, b"trailer", 0).unwrap();
+        assert_eq!(pages_of(&data[..end]).unwrap(), ["found"]);
+    }
+
+    #[test]
+    fn spaces_words_by_kerning_and_lines_by_position() {
+        let data = document(
+            "",
+            &[
+                b"BT [(Hel) -20 (lo) -250 (world)] TJ 0 -14 Td (next) Tj 30 0 Td (line) Tj ET\n\
+                BT 1 0 0 1 72 700 Tm (a) Tj 1 0 0 1 100 700.5 Tm (b) Tj\n\
+                1 0 0 1 72 680 Tm (c) Tj T* (d) Tj (e) ' 0 1 (f) \" ET\n\
+                q BI /W 2 /H 1 /BPC 8 /CS /G ID (( EI Q BT <6869> Tj ET",
+            ],
+            Vec::new(),
+        );
+        assert_eq!(
+            pages_of(&data).unwrap(),
+            ["Hello world\nnext line\na b\nc\nd\ne\nf hi"]
+        );
+    }
+
+    #[test]
+    fn refuses_files_it_cannot_read() {
+        assert_eq!(pages_of(b"hello").unwrap_err(), "not a PDF file");
+        assert_eq!(
+            pages(Path::new("/no/such.pdf")).unwrap_err(),
+            "failed to read /no/such.pdf: No such file or directory (os error 2)"
+        );
+        let encrypted = pdf(
+            &[
+                b"<< /Type /Catalog >>".to_vec(),
+                b"<< /Filter /Standard >>".to_vec(),
+            ],
+            "/Root 1 0 R /Encrypt 2 0 R",
+        );
+        assert_eq!(pages_of(&encrypted).unwrap_err(), "the PDF is encrypted");
+        let pageless = pdf(&[b"<< /Type /Catalog >>".to_vec()], "/Root 1 0 R");
+        assert_eq!(pages_of(&pageless).unwrap_err(), "the PDF has no page tree");
+
+        // A stream that cannot be decoded leaves its page empty.
+        let unsupported = [
+            stream("/Filter /DCTDecode", b"BT (image) Tj ET", false),
+            stream(
+                "/Filter /FlateDecode /DecodeParms << /Predictor 12 >>",
+                b"x",
+                false,
+            ),
+            stream(
+                "/Filter [/ASCIIHexDecode /ASCII85Decode]",
+                b"3C7E3837635552445A7E3E",
+                false,
+            ),
+        ];
+        let document = Document::parse(&pdf(&unsupported, "")).unwrap();
+        let decoded = (1..=3)
+            .map(|number| document.decode(&document.objects[&number]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            decoded,
+            [
+                Err("unsupported stream filter DCTDecode".to_string()),
+                Err("stream predictors are not supported".to_string()),
+                Ok(b"Hello".to_vec()),
+            ]
+        );
+        let data = document_with_filter("/Filter /JBIG2Decode");
+        assert_eq!(pages_of(&data).unwrap(), ["", "kept"]);
+
+        assert_eq!(ascii_hex_decode(b"48 65 6c 6C 6f7>"), b"Hellop");
+        assert_eq!(ascii85_decode(b"<~87cURDZ~>").unwrap(), b"Hello");
+        assert_eq!(ascii85_decode(b"z").unwrap(), [0; 4]);
+        assert_eq!(ascii85_decode(b"87{").unwrap_err(), "invalid ASCII85 data");
+    }
+
+    fn document_with_filter(filter: &str) -> Vec<u8> {
+        pdf(
+            &[
+                b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+                b"<< /Type /Pages /Kids [3 0 R 4 0 R] >>".to_vec(),
+                b"<< /Type /Page /Contents 5 0 R >>".to_vec(),
+                b"<< /Type /Page /Contents [5 0 R 6 0 R] >>".to_vec(),
+                stream(filter, b"BT (lost) Tj ET", false),
+                stream("", b"BT (kept) Tj ET", false),
+            ],
+            "/Root 1 0 R",
+        )
+    }
+}
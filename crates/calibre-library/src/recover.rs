@@ -0,0 +1,466 @@
+//! Rebuilding a lost or damaged `metadata.db` from what Calibre keeps beside each book:
+//! the `metadata.opf` sidecar and the format files in its `Author/Title (id)/` directory.
+//!
+//! The new database has the tables the SQLAlchemy models in
+//! `src/calibre_mcp/db/models.py` read, in Calibre's own layout, plus languages. Custom
+//! column values, user categories and Calibre's triggers are not rebuilt. The original
+//! database is only ever read by the caller, never written: the output must be a new file.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write as _;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::metadata::Book;
+use crate::opf::{self, Package, SIDECAR};
+use crate::sqlite::write::Builder;
+use crate::sqlite::Value;
+
+/// The schema, in creation order: Calibre's tables for what the models cover, and the
+/// models' indexes.
+const SCHEMA: &[&str] = &[
+    "CREATE TABLE books ( id INTEGER PRIMARY KEY AUTOINCREMENT, title TEXT NOT NULL DEFAULT 'Unknown' COLLATE NOCASE, sort TEXT COLLATE NOCASE, timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP, pubdate TIMESTAMP DEFAULT CURRENT_TIMESTAMP, series_index REAL NOT NULL DEFAULT 1.0, author_sort TEXT COLLATE NOCASE, isbn TEXT DEFAULT '' COLLATE NOCASE, lccn TEXT DEFAULT '' COLLATE NOCASE, path TEXT NOT NULL DEFAULT '', flags INTEGER NOT NULL DEFAULT 1, uuid TEXT, has_cover BOOL DEFAULT 0, last_modified TIMESTAMP NOT NULL DEFAULT '2000-01-01 00:00:00+00:00')",
+    "CREATE TABLE sqlite_sequence(name,seq)",
+    "CREATE TABLE authors ( id INTEGER PRIMARY KEY, name TEXT NOT NULL COLLATE NOCASE, sort TEXT COLLATE NOCASE, link TEXT NOT NULL DEFAULT '', UNIQUE(name))",
+    "CREATE TABLE books_authors_link ( id INTEGER PRIMARY KEY, book INTEGER NOT NULL, author INTEGER NOT NULL, UNIQUE(book, author))",
+    "CREATE TABLE series ( id INTEGER PRIMARY KEY, name TEXT NOT NULL COLLATE NOCASE, sort TEXT COLLATE NOCASE, link TEXT NOT NULL DEFAULT '', UNIQUE (name))",
+    "CREATE TABLE books_series_link ( id INTEGER PRIMARY KEY, book INTEGER NOT NULL, series INTEGER NOT NULL, UNIQUE(book))",
+    "CREATE TABLE tags ( id INTEGER PRIMARY KEY, name TEXT NOT NULL COLLATE NOCASE, link TEXT NOT NULL DEFAULT '', UNIQUE (name))",
+    "CREATE TABLE books_tags_link ( id INTEGER PRIMARY KEY, book INTEGER NOT NULL, tag INTEGER NOT NULL, UNIQUE(book, tag))",
+    "CREATE TABLE publishers ( id INTEGER PRIMARY KEY, name TEXT NOT NULL COLLATE NOCASE, sort TEXT COLLATE NOCASE, link TEXT NOT NULL DEFAULT '', UNIQUE(name))",
+    "CREATE TABLE books_publishers_link ( id INTEGER PRIMARY KEY, book INTEGER NOT NULL, publisher INTEGER NOT NULL, UNIQUE(book))",
+    "CREATE TABLE ratings ( id INTEGER PRIMARY KEY, rating INTEGER CHECK(rating > -1 AND rating < 11), link TEXT NOT NULL DEFAULT '', UNIQUE (rating))",
+    "CREATE TABLE books_ratings_link ( id INTEGER PRIMARY KEY, book INTEGER NOT NULL, rating INTEGER NOT NULL, UNIQUE(book, rating))",
+    "CREATE TABLE languages ( id INTEGER PRIMARY KEY, lang_code TEXT NOT NULL COLLATE NOCASE, link TEXT NOT NULL DEFAULT '', UNIQUE(lang_code))",
+    "CREATE TABLE books_languages_link ( id INTEGER PRIMARY KEY, book INTEGER NOT NULL, lang_code INTEGER NOT NULL, item_order INTEGER NOT NULL DEFAULT 0, UNIQUE(book, lang_code))",
+    "CREATE TABLE comments ( id INTEGER PRIMARY KEY, book INTEGER NOT NULL, text TEXT NOT NULL COLLATE NOCASE, UNIQUE(book))",
+    "CREATE TABLE data ( id INTEGER PRIMARY KEY, book INTEGER NOT NULL, format TEXT NOT NULL COLLATE NOCASE, uncompressed_size INTEGER NOT NULL, name TEXT NOT NULL, UNIQUE(book, format))",
+    "CREATE TABLE identifiers ( id INTEGER PRIMARY KEY, book INTEGER NOT NULL, type TEXT NOT NULL DEFAULT 'isbn' COLLATE NOCASE, val TEXT NOT NULL COLLATE NOCASE, UNIQUE(book, type))",
+    "CREATE INDEX idx_books_title ON books (title)",
+    "CREATE INDEX idx_books_author_sort ON books (author_sort)",
+    "CREATE INDEX idx_books_pubdate ON books (pubdate)",
+    "CREATE INDEX idx_authors_name ON authors (name)",
+    "CREATE INDEX idx_series_name ON series (name)",
+    "CREATE INDEX idx_tags_name ON tags (name)",
+    "CREATE INDEX idx_publishers_name ON publishers (name)",
+    "CREATE INDEX books_authors_link_aidx ON books_authors_link (author)",
+    "CREATE INDEX books_authors_link_bidx ON books_authors_link (book)",
+    "CREATE INDEX books_series_link_bidx ON books_series_link (book)",
+    "CREATE INDEX books_tags_link_bidx ON books_tags_link (book)",
+    "CREATE INDEX books_publishers_link_bidx ON books_publishers_link (book)",
+    "CREATE INDEX books_ratings_link_bidx ON books_ratings_link (book)",
+    "CREATE INDEX books_languages_link_bidx ON books_languages_link (book)",
+    "CREATE INDEX comments_idx ON comments (book)",
+    "CREATE INDEX data_idx ON data (book)",
+];
+
+/// What a rebuild did.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Books written to the new database.
+    pub books: usize,
+    /// Book directories, relative to the library, that could not be rebuilt, with why.
+    pub failures: Vec<(String, String)>,
+    /// Custom columns the sidecars carry values for, which the new database lacks.
+    pub custom_columns: Vec<String>,
+}
+
+/// Writes a new database to `output` from the sidecars and files of the library at
+/// `library`. Fails without writing anything when `output` exists already.
+pub fn rebuild(library: &Path, output: &Path) -> Result<Report, String> {
+    let original = library.join("metadata.db");
+    if output.exists() || same_file(output, &original) {
+        return Err(format!(
+            "{} already exists; choose a new file for the rebuilt database",
+            output.display()
+        ));
+    }
+
+    let mut report = Report::default();
+    let mut books: Vec<(Book, Package, String)> = Vec::new();
+    let mut ids: HashMap<i64, String> = HashMap::new();
+    for author in opf::directories(library)? {
+        for directory in opf::directories(&author)? {
+            let path = relative(library, &directory);
+            let sidecar = directory.join(SIDECAR);
+            if !sidecar.is_file() {
+                report.failures.push((path, format!("no {SIDECAR}")));
+                continue;
+            }
+            let package = match Package::read(&sidecar) {
+                Ok(package) => package,
+                Err(err) => {
+                    report.failures.push((path, err));
+                    continue;
+                }
+            };
+            let Some(book) = package.book(library, &path) else {
+                let reason = "no calibre identifier, and the directory name does not end in an id";
+                report.failures.push((path, reason.to_string()));
+                continue;
+            };
+            if let Some(other) = ids.get(&book.id) {
+                let reason = format!("book id {} is already taken by {other}", book.id);
+                report.failures.push((path, reason));
+                continue;
+            }
+            ids.insert(book.id, path);
+            let modified = fs::metadata(&sidecar)
+                .and_then(|file| file.modified())
+                .map_or_else(|_| now(), timestamp);
+            books.push((book, package, modified));
+        }
+    }
+    books.sort_by_key(|(book, _, _)| book.id);
+
+    for (_, package, _) in &books {
+        for column in &package.metadata.custom_columns {
+            if !report.custom_columns.contains(&column.label) {
+                report.custom_columns.push(column.label.clone());
+            }
+        }
+    }
+    report.custom_columns.sort();
+    report.books = books.len();
+
+    let database = database(&books)?;
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(output)
+        .map_err(|err| format!("cannot create {}: {err}", output.display()))?;
+    file.write_all(&database)
+        .map_err(|err| format!("cannot write {}: {err}", output.display()))?;
+    Ok(report)
+}
+
+/// The database file for `books`, by id, each with its sidecar and `last_modified`.
+fn database(books: &[(Book, Package, String)]) -> Result<Vec<u8>, String> {
+    let mut builder = Builder::default();
+    for sql in SCHEMA {
+        if sql.starts_with("CREATE TABLE") {
+            builder.table(sql)?;
+        } else {
+            builder.index(sql)?;
+        }
+    }
+
+    let mut authors = Items::default();
+    let mut series = Items::default();
+    let mut tags = Items::default();
+    let mut publishers = Items::default();
+    let mut ratings = Items::default();
+    let mut languages = Items::default();
+    let mut links: HashMap<&str, i64> = HashMap::new();
+    let mut link = |builder: &mut Builder, table: &'static str, row: Vec<Value>| {
+        let id = links.entry(table).or_default();
+        *id += 1;
+        let mut values = vec![Value::Integer(*id)];
+        values.extend(row);
+        builder.insert(table, values)
+    };
+
+    for (book, package, last_modified) in books {
+        let metadata = &package.metadata;
+        let isbn = book
+            .identifiers
+            .iter()
+            .find(|(kind, _)| kind == "isbn")
+            .map_or("", |(_, value)| value.as_str());
+        builder.insert(
+            "books",
+            vec![
+                Value::Integer(book.id),
+                Value::Text(book.title.clone()),
+                text(book.sort.as_ref().unwrap_or(&book.title)),
+                optional_date(book.timestamp.as_deref()),
+                optional_date(book.pubdate.as_deref()),
+                Value::Real(book.series_index),
+                optional_text(book.author_sort.as_deref()),
+                text(isbn),
+                text(""),
+                text(&book.path),
+                Value::Integer(1),
+                optional_text(book.uuid.as_deref()),
+                Value::Integer(book.has_cover as i64),
+                text(last_modified),
+            ],
+        )?;
+
+        let author_links = metadata
+            .author_link_map
+            .as_ref()
+            .and_then(|map| map.as_object());
+        let mut linked = Vec::new();
+        for (name, sort) in book.authors.iter().zip(&book.author_sorts) {
+            let url = author_links
+                .and_then(|map| map.get(name)?.as_str())
+                .unwrap_or_default();
+            let author = authors.id(name, || vec![text(name), text(sort), text(url)]);
+            if !linked.contains(&author) {
+                linked.push(author);
+                link(&mut builder, "books_authors_link", ids(book.id, author))?;
+            }
+        }
+        if let Some(name) = &book.series {
+            let item = series.id(name, || vec![text(name), text(name), text("")]);
+            link(&mut builder, "books_series_link", ids(book.id, item))?;
+        }
+        let mut linked = Vec::new();
+        for name in &book.tags {
+            let tag = tags.id(name, || vec![text(name), text("")]);
+            if !linked.contains(&tag) {
+                linked.push(tag);
+                link(&mut builder, "books_tags_link", ids(book.id, tag))?;
+            }
+        }
+        if let Some(name) = &book.publisher {
+            let item = publishers.id(name, || vec![text(name), text(name), text("")]);
+            link(&mut builder, "books_publishers_link", ids(book.id, item))?;
+        }
+        if let Some(rating) = book.rating.filter(|rating| (1..=10).contains(rating)) {
+            let item = ratings.id(&rating.to_string(), || {
+                vec![Value::Integer(rating), text("")]
+            });
+            link(&mut builder, "books_ratings_link", ids(book.id, item))?;
+        }
+        let mut linked = Vec::new();
+        for code in &metadata.languages {
+            let language = languages.id(code, || vec![text(code), text("")]);
+            if !linked.contains(&language) {
+                let mut row = ids(book.id, language);
+                row.push(Value::Integer(linked.len() as i64));
+                linked.push(language);
+                link(&mut builder, "books_languages_link", row)?;
+            }
+        }
+        if let Some(comments) = &book.comments {
+            link(
+                &mut builder,
+                "comments",
+                vec![Value::Integer(book.id), text(comments)],
+            )?;
+        }
+        let mut formats = Vec::new();
+        for data in &book.files {
+            if !formats.contains(&data.format) {
+                formats.push(data.format.clone());
+                let row = vec![
+                    Value::Integer(book.id),
+                    text(&data.format),
+                    Value::Integer(data.uncompressed_size),
+                    text(&data.name),
+                ];
+                link(&mut builder, "data", row)?;
+            }
+        }
+        let mut kinds = Vec::new();
+        for (kind, value) in &book.identifiers {
+            if !kinds.contains(kind) {
+                kinds.push(kind.clone());
+                let row = vec![Value::Integer(book.id), text(kind), text(value)];
+                link(&mut builder, "identifiers", row)?;
+            }
+        }
+    }
+
+    for (table, items) in [
+        ("authors", authors),
+        ("series", series),
+        ("tags", tags),
+        ("publishers", publishers),
+        ("ratings", ratings),
+        ("languages", languages),
+    ] {
+        for (id, row) in items.rows.into_iter().enumerate() {
+            let mut values = vec![Value::Integer(id as i64 + 1)];
+            values.extend(row);
+            builder.insert(table, values)?;
+        }
+    }
+    if let Some((last, _, _)) = books.last() {
+        builder.insert(
+            "sqlite_sequence",
+            vec![text("books"), Value::Integer(last.id)],
+        )?;
+    }
+    builder.finish()
+}
+
+/// Rows of a table of names, such as `tags`, numbered from 1 as they are first seen.
+/// Names differing only in case are one row, as Calibre's `NOCASE` columns have it.
+#[derive(Default)]
+struct Items {
+    ids: HashMap<String, i64>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl Items {
+    fn id(&mut self, name: &str, row: impl FnOnce() -> Vec<Value>) -> i64 {
+        let key = name.to_ascii_lowercase();
+        if let Some(id) = self.ids.get(&key) {
+            return *id;
+        }
+        self.rows.push(row());
+        let id = self.rows.len() as i64;
+        self.ids.insert(key, id);
+        id
+    }
+}
+
+fn ids(book: i64, item: i64) -> Vec<Value> {
+    vec![Value::Integer(book), Value::Integer(item)]
+}
+
+fn text(text: &str) -> Value {
+    Value::Text(text.to_string())
+}
+
+fn optional_text(text: Option<&str>) -> Value {
+    text.map_or(Value::Null, |text| Value::Text(text.to_string()))
+}
+
+/// An OPF date, `2023-04-01T12:00:00+00:00`, as Calibre stores it in the database.
+fn optional_date(date: Option<&str>) -> Value {
+    match date {
+        Some(date) if date.as_bytes().get(10) == Some(&b'T') => {
+            Value::Text(format!("{} {}", &date[..10], &date[11..]))
+        }
+        date => optional_text(date),
+    }
+}
+
+fn relative(library: &Path, directory: &Path) -> String {
+    directory
+        .strip_prefix(library)
+        .unwrap_or(directory)
+        .iter()
+        .map(|part| part.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// The current time, as [`timestamp`] writes it.
+pub(crate) fn now() -> String {
+    timestamp(SystemTime::now())
+}
+
+/// `time` as Calibre writes timestamps, `2023-04-01 12:00:00+00:00`.
+fn timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as i64);
+    let (days, seconds) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400));
+    // Days since 1970-01-01 to a civil date, after Howard Hinnant's `civil_from_days`.
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}+00:00",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use crate::metadata::{self, MetadataDb};
+
+    fn library() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../../tests/fixtures/test_library")
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("calibre-recover-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn rebuilds_the_library_from_its_sidecars() {
+        let output = scratch("fixture");
+        let report = rebuild(&library(), &output.join("metadata.db")).unwrap();
+        assert_eq!(report.books, 4);
+        assert!(report.failures.is_empty(), "{:?}", report.failures);
+
+        let rebuilt = MetadataDb::open(&output).unwrap().library().unwrap();
+        let original = metadata::books(&library()).unwrap();
+        assert_eq!(rebuilt.len(), original.len());
+        for (rebuilt, original) in rebuilt.iter().zip(&original) {
+            assert_eq!(rebuilt.id, original.id);
+            assert_eq!(rebuilt.title, original.title);
+            assert_eq!(rebuilt.path, original.path);
+            assert_eq!(rebuilt.uuid, original.uuid);
+            assert_eq!(rebuilt.series_index, original.series_index);
+            assert_eq!(rebuilt.authors, original.authors);
+            assert_eq!(rebuilt.author_sorts, original.author_sorts);
+            assert_eq!(rebuilt.series, original.series);
+            assert_eq!(rebuilt.tags, original.tags);
+            assert_eq!(rebuilt.publisher, original.publisher);
+            assert_eq!(rebuilt.rating, original.rating);
+            assert_eq!(rebuilt.formats, original.formats);
+            assert_eq!(rebuilt.comments, original.comments);
+            assert_eq!(rebuilt.identifiers, original.identifiers);
+        }
+
+        // The output is never overwritten, and neither is the original.
+        assert!(rebuild(&library(), &output.join("metadata.db")).is_err());
+        assert!(rebuild(&library(), &library().join("metadata.db")).is_err());
+        fs::remove_dir_all(output).unwrap();
+    }
+
+    #[test]
+    fn reports_books_it_cannot_rebuild() {
+        let library = scratch("broken");
+        let unreadable = library.join("Anonymous/Beowulf (7)");
+        fs::create_dir_all(&unreadable).unwrap();
+        fs::write(unreadable.join(SIDECAR), "<package>").unwrap();
+        fs::create_dir_all(library.join("Anonymous/The Seafarer (8)")).unwrap();
+        fs::create_dir_all(library.join(".caltrash/b")).unwrap();
+        let output = library.join("metadata.recovered.db");
+
+        let report = rebuild(&library, &output).unwrap();
+        assert_eq!(report.books, 0);
+        let failures = report
+            .failures
+            .iter()
+            .map(|(path, _)| path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            failures,
+            ["Anonymous/Beowulf (7)", "Anonymous/The Seafarer (8)"]
+        );
+        assert_eq!(report.failures[1].1, "no metadata.opf");
+        assert!(output.is_file());
+        fs::remove_dir_all(library).unwrap();
+    }
+
+    #[test]
+    fn formats_timestamps_like_calibre() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_792_041_465);
+        assert_eq!(timestamp(time), "2026-10-15 05:17:45+00:00");
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01 00:00:00+00:00");
+    }
+}
@@ -0,0 +1,598 @@
+//! A read-only reader for the SQLite file format.
+//!
+//! The extension runs as WebAssembly, where linking the SQLite C library is not an option,
+//! and it only ever needs to scan whole tables of a Calibre `metadata.db`. This walks the
+//! tables' b-trees directly, `WITHOUT ROWID` ones included: no SQL, no indexes, no writes.
+//! Transactions a WAL-mode database has not checkpointed yet are read from its `-wal`
+//! file, as SQLite would.
+//! Writing a whole new database, as library recovery does, is [`write`]'s job.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+mod wal;
+pub mod write;
+
+const HEADER_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+const HEADER_SIZE: usize = 100;
+
+const INTERIOR_INDEX_PAGE: u8 = 2;
+const INTERIOR_TABLE_PAGE: u8 = 5;
+const LEAF_INDEX_PAGE: u8 = 10;
+const LEAF_TABLE_PAGE: u8 = 13;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+/// A table's columns, as declared in its `CREATE TABLE` statement.
+#[derive(Debug, Clone)]
+pub struct TableSchema {
+    pub name: String,
+    pub root_page: u32,
+    pub columns: Vec<String>,
+    /// Column declared `INTEGER PRIMARY KEY`, which SQLite stores as the rowid.
+    rowid_column: Option<usize>,
+    /// Primary key columns of a `WITHOUT ROWID` table.
+    without_rowid_key: Option<Vec<usize>>,
+}
+
+/// Callback for each cell of a b-tree, with its rowid, 0 in an index b-tree, and payload.
+type Visit<'a> = dyn FnMut(i64, &[u8]) -> Result<(), String> + 'a;
+
+/// All rows of a table, in rowid order.
+#[derive(Debug, Clone, Default)]
+pub struct Table {
+    columns: Vec<String>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl Table {
+    pub fn rows(&self) -> impl Iterator<Item = Row<'_>> {
+        self.rows.iter().map(|values| Row {
+            columns: &self.columns,
+            values,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Row<'a> {
+    columns: &'a [String],
+    values: &'a [Value],
+}
+
+impl<'a> Row<'a> {
+    /// The value of a column; unknown columns read as NULL, like columns added by
+    /// `ALTER TABLE` that older rows do not store.
+    pub fn get(&self, column: &str) -> &'a Value {
+        self.columns
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(column))
+            .and_then(|index| self.values.get(index))
+            .unwrap_or(&Value::Null)
+    }
+
+    pub fn integer(&self, column: &str) -> Option<i64> {
+        match self.get(column) {
+            Value::Integer(value) => Some(*value),
+            Value::Real(value) => Some(*value as i64),
+            Value::Text(value) => value.trim().parse().ok(),
+            Value::Null | Value::Blob(_) => None,
+        }
+    }
+
+    pub fn real(&self, column: &str) -> Option<f64> {
+        match self.get(column) {
+            Value::Integer(value) => Some(*value as f64),
+            Value::Real(value) => Some(*value),
+            Value::Text(value) => value.trim().parse().ok(),
+            Value::Null | Value::Blob(_) => None,
+        }
+    }
+
+    pub fn text(&self, column: &str) -> Option<&'a str> {
+        match self.get(column) {
+            Value::Text(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+pub struct Database {
+    data: Vec<u8>,
+    page_size: usize,
+    usable_size: usize,
+    tables: Vec<TableSchema>,
+}
+
+impl Database {
+    /// Opens the database at `path` as of its last committed transaction, including those
+    /// still in its write-ahead log.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let mut data =
+            fs::read(path).map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+        let wal_path = wal_path(path);
+        match fs::read(&wal_path) {
+            Ok(wal) => wal::apply(&mut data, &wal)
+                .map_err(|err| format!("{}: {err}", wal_path.display()))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(format!(
+                    "failed to read {}, which may hold changes to {}: {err}",
+                    wal_path.display(),
+                    path.display()
+                ))
+            }
+        }
+        Self::from_bytes(data).map_err(|err| format!("{}: {err}", path.display()))
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, String> {
+        if data.len() < HEADER_SIZE || &data[..16] != HEADER_MAGIC {
+            return Err("not a SQLite database".to_string());
+        }
+        let page_size = match u16::from_be_bytes([data[16], data[17]]) {
+            1 => 65536,
+            size if size >= 512 && size.is_power_of_two() => size as usize,
+            size => return Err(format!("invalid page size {size}")),
+        };
+        let text_encoding = u32::from_be_bytes([data[56], data[57], data[58], data[59]]);
+        if text_encoding > 1 {
+            return Err("only UTF-8 databases are supported".to_string());
+        }
+
+        let mut database = Self {
+            usable_size: page_size - data[20] as usize,
+            page_size,
+            data,
+            tables: Vec::new(),
+        };
+
+        let mut tables = Vec::new();
+        for record in database.scan(1)? {
+            let [kind, name, _, root_page, sql] = record.as_slice() else {
+                continue;
+            };
+            if let (Value::Text(kind), Value::Text(name), Value::Integer(root_page)) =
+                (kind, name, root_page)
+            {
+                if kind != "table" || *root_page <= 0 {
+                    continue;
+                }
+                let sql = match sql {
+                    Value::Text(sql) => sql.as_str(),
+                    _ => "",
+                };
+                let (columns, rowid_column) = parse_columns(sql);
+                tables.push(TableSchema {
+                    name: name.clone(),
+                    root_page: *root_page as u32,
+                    without_rowid_key: without_rowid_key(sql, &columns),
+                    columns,
+                    rowid_column,
+                });
+            }
+        }
+        database.tables = tables;
+        Ok(database)
+    }
+
+    pub fn table_schema(&self, name: &str) -> Option<&TableSchema> {
+        self.tables
+            .iter()
+            .find(|table| table.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn has_table(&self, name: &str) -> bool {
+        self.table_schema(name).is_some()
+    }
+
+    /// Reads a whole table. The rowid is filled into an `INTEGER PRIMARY KEY` column.
+    pub fn table(&self, name: &str) -> Result<Table, String> {
+        let schema = self
+            .table_schema(name)
+            .ok_or_else(|| format!("no such table: {name}"))?;
+        let order = stored_order(schema.without_rowid_key.as_deref(), schema.columns.len());
+        let rows = self
+            .scan_with_rowids(schema.root_page)?
+            .into_iter()
+            .map(|(rowid, mut values)| {
+                if schema.without_rowid_key.is_some() {
+                    let mut row = vec![Value::Null; schema.columns.len()];
+                    for (column, value) in order.iter().zip(values) {
+                        row[*column] = value;
+                    }
+                    return row;
+                }
+                values.resize(schema.columns.len().max(values.len()), Value::Null);
+                if let Some(index) = schema.rowid_column {
+                    values[index] = Value::Integer(rowid);
+                }
+                values
+            })
+            .collect();
+        Ok(Table {
+            columns: schema.columns.clone(),
+            rows,
+        })
+    }
+
+    /// Counts a table's rows without decoding them.
+    pub fn row_count(&self, name: &str) -> Result<usize, String> {
+        let schema = self
+            .table_schema(name)
+            .ok_or_else(|| format!("no such table: {name}"))?;
+        let mut count = 0;
+        self.walk(schema.root_page, &mut |_, _| {
+            count += 1;
+            Ok(())
+        })?;
+        Ok(count)
+    }
+
+    fn scan(&self, root_page: u32) -> Result<Vec<Vec<Value>>, String> {
+        Ok(self
+            .scan_with_rowids(root_page)?
+            .into_iter()
+            .map(|(_, values)| values)
+            .collect())
+    }
+
+    fn scan_with_rowids(&self, root_page: u32) -> Result<Vec<(i64, Vec<Value>)>, String> {
+        let mut rows = Vec::new();
+        self.walk(root_page, &mut |rowid, payload| {
+            rows.push((rowid, decode_record(payload)?));
+            Ok(())
+        })?;
+        Ok(rows)
+    }
+
+    /// Visits every cell of a table b-tree in rowid order, or of an index b-tree in key
+    /// order, with its assembled payload.
+    fn walk(&self, root_page: u32, visit: &mut Visit<'_>) -> Result<(), String> {
+        /// An index b-tree's interior pages hold entries of their own, visited between
+        /// the children on either side of them.
+        enum Step {
+            Page(u32),
+            Entry(Vec<u8>),
+        }
+
+        let page_count = self.data.len() / self.page_size;
+        let mut visited = 0;
+        let mut stack = vec![Step::Page(root_page)];
+
+        while let Some(step) = stack.pop() {
+            let page_number = match step {
+                Step::Page(number) => number,
+                Step::Entry(payload) => {
+                    visit(0, &payload)?;
+                    continue;
+                }
+            };
+            visited += 1;
+            if visited > page_count {
+                return Err("b-tree has a cycle".to_string());
+            }
+
+            let page = self.page(page_number)?;
+            let header = if page_number == 1 { HEADER_SIZE } else { 0 };
+            let kind = *page.get(header).ok_or("truncated page")?;
+            let cell_count = read_u16(page, header + 3)? as usize;
+
+            match kind {
+                LEAF_TABLE_PAGE => {
+                    for index in 0..cell_count {
+                        let offset = read_u16(page, header + 8 + index * 2)? as usize;
+                        let (payload_size, read) = read_varint(page, offset)?;
+                        let (rowid, read_rowid) = read_varint(page, offset + read)?;
+                        let start = offset + read + read_rowid;
+                        let payload = self.payload(page, start, payload_size as usize, false)?;
+                        visit(rowid as i64, &payload)?;
+                    }
+                }
+                INTERIOR_TABLE_PAGE => {
+                    // Pushed in reverse so children pop off the stack in rowid order.
+                    stack.push(Step::Page(read_u32(page, header + 8)?));
+                    for index in (0..cell_count).rev() {
+                        let offset = read_u16(page, header + 12 + index * 2)? as usize;
+                        stack.push(Step::Page(read_u32(page, offset)?));
+                    }
+                }
+                LEAF_INDEX_PAGE => {
+                    for index in 0..cell_count {
+                        let offset = read_u16(page, header + 8 + index * 2)? as usize;
+                        let (payload_size, read) = read_varint(page, offset)?;
+                        let payload =
+                            self.payload(page, offset + read, payload_size as usize, true)?;
+                        visit(0, &payload)?;
+                    }
+                }
+                INTERIOR_INDEX_PAGE => {
+                    stack.push(Step::Page(read_u32(page, header + 8)?));
+                    for index in (0..cell_count).rev() {
+                        let offset = read_u16(page, header + 12 + index * 2)? as usize;
+                        let (payload_size, read) = read_varint(page, offset + 4)?;
+                        let start = offset + 4 + read;
+                        stack.push(Step::Entry(self.payload(
+                            page,
+                            start,
+                            payload_size as usize,
+                            true,
+                        )?));
+                        stack.push(Step::Page(read_u32(page, offset)?));
+                    }
+                }
+                kind => return Err(format!("page {page_number} is not a b-tree page ({kind})")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Assembles a cell payload, following overflow pages when it does not fit locally,
+    /// which for an index b-tree is sooner.
+    fn payload(
+        &self,
+        page: &[u8],
+        start: usize,
+        size: usize,
+        index: bool,
+    ) -> Result<Vec<u8>, String> {
+        let usable = self.usable_size;
+        let local = local_size(usable, size, index);
+        let mut payload = page
+            .get(start..start + local)
+            .ok_or("cell runs past the end of its page")?
+            .to_vec();
+        let mut next = if local < size {
+            read_u32(page, start + local)?
+        } else {
+            0
+        };
+        while payload.len() < size {
+            if next == 0 {
+                return Err("overflow chain ends early".to_string());
+            }
+            let overflow = self.page(next)?;
+            next = read_u32(overflow, 0)?;
+            let take = (size - payload.len()).min(usable - 4);
+            payload.extend_from_slice(overflow.get(4..4 + take).ok_or("truncated overflow page")?);
+        }
+        Ok(payload)
+    }
+
+    fn page(&self, number: u32) -> Result<&[u8], String> {
+        let start = (number as usize)
+            .checked_sub(1)
+            .ok_or("page 0 does not exist")?
+            * self.page_size;
+        self.data
+            .get(start..start + self.page_size)
+            .ok_or_else(|| format!("page {number} is past the end of the file"))
+    }
+}
+
+/// How much of a cell's payload of `size` bytes is kept on its page, the rest going to
+/// overflow pages; an index b-tree's cells spill sooner than a table's.
+fn local_size(usable: usize, size: usize, index: bool) -> usize {
+    let max_local = if index {
+        (usable - 12) * 64 / 255 - 23
+    } else {
+        usable - 35
+    };
+    if size <= max_local {
+        return size;
+    }
+    let min_local = (usable - 12) * 32 / 255 - 23;
+    let local = min_local + (size - min_local) % (usable - 4);
+    if local <= max_local {
+        local
+    } else {
+        min_local
+    }
+}
+
+/// The write-ahead log of the database at `path`, e.g. `metadata.db-wal`.
+pub fn wal_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push("-wal");
+    PathBuf::from(name)
+}
+
+fn decode_record(payload: &[u8]) -> Result<Vec<Value>, String> {
+    let (header_size, mut header_pos) = read_varint(payload, 0)?;
+    let header_size = header_size as usize;
+    let mut body_pos = header_size;
+    let mut values = Vec::new();
+
+    while header_pos < header_size {
+        let (serial_type, read) = read_varint(payload, header_pos)?;
+        header_pos += read;
+
+        let size = match serial_type {
+            0 | 8 | 9 => 0,
+            1..=4 => serial_type as usize,
+            5 => 6,
+            6 | 7 => 8,
+            10 | 11 => return Err("reserved serial type".to_string()),
+            n => (n as usize - 12) / 2,
+        };
+        let bytes = payload
+            .get(body_pos..body_pos + size)
+            .ok_or("record body is truncated")?;
+        body_pos += size;
+
+        values.push(match serial_type {
+            0 => Value::Null,
+            1..=6 => {
+                // Big-endian two's complement of 1 to 8 bytes.
+                let mut value = if bytes[0] & 0x80 != 0 { -1i64 } else { 0 };
+                for byte in bytes {
+                    value = (value << 8) | *byte as i64;
+                }
+                Value::Integer(value)
+            }
+            7 => Value::Real(f64::from_be_bytes(bytes.try_into().unwrap_or_default())),
+            8 => Value::Integer(0),
+            9 => Value::Integer(1),
+            n if n % 2 == 0 => Value::Blob(bytes.to_vec()),
+            _ => Value::Text(String::from_utf8_lossy(bytes).into_owned()),
+        });
+    }
+    Ok(values)
+}
+
+/// Column names from a `CREATE TABLE` statement, and which of them aliases the rowid.
+fn parse_columns(sql: &str) -> (Vec<String>, Option<usize>) {
+    let (Some(open), Some(close)) = (sql.find('('), sql.rfind(')')) else {
+        return (Vec::new(), None);
+    };
+    if close <= open {
+        return (Vec::new(), None);
+    }
+
+    let mut columns = Vec::new();
+    let mut rowid_column = None;
+    for definition in split_top_level(&sql[open + 1..close]) {
+        let definition = definition.trim();
+        let upper = definition.to_ascii_uppercase();
+        if ["CONSTRAINT", "PRIMARY", "UNIQUE", "CHECK", "FOREIGN"]
+            .iter()
+            .any(|keyword| upper.starts_with(keyword))
+        {
+            continue;
+        }
+
+        let mut tokens = definition.split_whitespace();
+        let Some(name) = tokens.next() else {
+            continue;
+        };
+        let rest = tokens.collect::<Vec<_>>().join(" ").to_ascii_uppercase();
+        if rest.starts_with("INTEGER PRIMARY KEY") && !is_without_rowid(sql) {
+            rowid_column = Some(columns.len());
+        }
+        columns.push(unquote(name));
+    }
+    (columns, rowid_column)
+}
+
+fn is_without_rowid(sql: &str) -> bool {
+    let options = sql.rfind(')').map_or("", |close| &sql[close + 1..]);
+    let words = options
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+    words.windows(2).any(|pair| {
+        pair[0].eq_ignore_ascii_case("WITHOUT") && pair[1].eq_ignore_ascii_case("ROWID")
+    })
+}
+
+/// The primary key of a `WITHOUT ROWID` table, which orders its b-tree; `None` for a
+/// table with rowids.
+fn without_rowid_key(sql: &str, columns: &[String]) -> Option<Vec<usize>> {
+    if !is_without_rowid(sql) {
+        return None;
+    }
+    let open = sql.find('(')?;
+    let close = sql.rfind(')')?;
+    let position = |name: &str| {
+        let name = unquote(name);
+        columns
+            .iter()
+            .position(|column| column.eq_ignore_ascii_case(&name))
+    };
+    for definition in split_top_level(sql.get(open + 1..close)?) {
+        let definition = definition.trim();
+        let upper = definition.to_ascii_uppercase();
+        let Some(at) = upper.find("PRIMARY KEY") else {
+            continue;
+        };
+        if !matches!(
+            upper.split_whitespace().next(),
+            Some("PRIMARY" | "CONSTRAINT")
+        ) {
+            return Some(vec![position(definition.split_whitespace().next()?)?]);
+        }
+        let list = &definition[at..];
+        let (open, close) = (list.find('(')?, list.rfind(')')?);
+        return split_top_level(list.get(open + 1..close)?)
+            .into_iter()
+            .map(|term| position(term.split_whitespace().next()?))
+            .collect();
+    }
+    None
+}
+
+/// The order a table's columns are stored in: as declared, or for a `WITHOUT ROWID`
+/// table, its primary key and then the other columns.
+fn stored_order(without_rowid_key: Option<&[usize]>, count: usize) -> Vec<usize> {
+    let key = without_rowid_key.unwrap_or_default();
+    key.iter()
+        .copied()
+        .chain((0..count).filter(|column| !key.contains(column)))
+        .collect()
+}
+
+fn unquote(name: &str) -> String {
+    name.trim()
+        .trim_matches(['"', '`', '[', ']', '\''])
+        .to_string()
+}
+
+fn split_top_level(list: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+    for (index, c) in list.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"' | '`') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                parts.push(&list[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&list[start..]);
+    parts
+}
+
+fn read_varint(bytes: &[u8], offset: usize) -> Result<(u64, usize), String> {
+    let mut value = 0u64;
+    for index in 0..9 {
+        let byte = *bytes.get(offset + index).ok_or("truncated varint")?;
+        if index == 8 {
+            return Ok(((value << 8) | byte as u64, 9));
+        }
+        value = (value << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Ok((value, index + 1));
+        }
+    }
+    unreachable!("a varint is at most nine bytes")
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, String> {
+    bytes
+        .get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| "truncated page".to_string())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, String> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| "truncated page".to_string())
+}
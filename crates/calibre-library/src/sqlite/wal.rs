@@ -0,0 +1,173 @@
+//! Reading a write-ahead log, so that transactions a WAL-mode database has committed but
+//! not yet checkpointed are seen.
+//!
+//! The log is a 32-byte header followed by frames, each a 24-byte header and one page.
+//! A frame belongs to the log while its salts match the header's and the running
+//! checksum over the frames so far holds; a frame whose header gives the database size
+//! commits the transaction it ends. Frames after the last commit are a transaction still
+//! being written, or left over from before the log was restarted, and are ignored, as
+//! SQLite itself does when it recovers the log.
+
+use super::read_u32;
+
+const HEADER_SIZE: usize = 32;
+const FRAME_HEADER_SIZE: usize = 24;
+/// The magic number, whose lowest bit says whether checksums read words big-endian.
+const MAGIC: u32 = 0x377f_0682;
+const FORMAT_VERSION: u32 = 3_007_000;
+
+/// Brings `data`, the main database file, up to the last transaction committed to `wal`.
+/// A log without a valid header holds nothing, and leaves `data` as it is.
+pub fn apply(data: &mut Vec<u8>, wal: &[u8]) -> Result<(), String> {
+    if wal.len() < HEADER_SIZE
+        || read_u32(wal, 0)? & !1 != MAGIC
+        || read_u32(wal, 4)? != FORMAT_VERSION
+    {
+        return Ok(());
+    }
+    let big_endian = read_u32(wal, 0)? & 1 == 1;
+    let page_size = match read_u32(wal, 8)? {
+        size if (512..=65536).contains(&size) && size.is_power_of_two() => size as usize,
+        size => return Err(format!("the WAL has an invalid page size {size}")),
+    };
+    let salts = &wal[16..24];
+    let mut checksum = checksum_of((0, 0), &wal[..24], big_endian);
+    if checksum != (read_u32(wal, 24)?, read_u32(wal, 28)?) {
+        return Ok(());
+    }
+
+    let mut pending = Vec::new();
+    let mut offset = HEADER_SIZE;
+    while let Some(frame) = wal.get(offset..offset + FRAME_HEADER_SIZE + page_size) {
+        let (header, page) = frame.split_at(FRAME_HEADER_SIZE);
+        if &header[8..16] != salts {
+            break;
+        }
+        checksum = checksum_of(
+            checksum_of(checksum, &header[..8], big_endian),
+            page,
+            big_endian,
+        );
+        if checksum != (read_u32(header, 16)?, read_u32(header, 20)?) {
+            break;
+        }
+        let page_number = read_u32(header, 0)? as usize;
+        if page_number == 0 {
+            break;
+        }
+        pending.push((page_number, page));
+
+        let database_pages = read_u32(header, 4)? as usize;
+        if database_pages > 0 {
+            commit(data, page_size, &pending, database_pages)?;
+            pending.clear();
+        }
+        offset += frame.len();
+    }
+    Ok(())
+}
+
+/// Writes a committed transaction's pages into the file and sizes it as the commit says.
+fn commit(
+    data: &mut Vec<u8>,
+    page_size: usize,
+    pages: &[(usize, &[u8])],
+    database_pages: usize,
+) -> Result<(), String> {
+    if !data.is_empty() && !data.len().is_multiple_of(page_size) {
+        return Err(format!(
+            "the WAL's page size {page_size} does not match the database's"
+        ));
+    }
+    data.resize(database_pages * page_size, 0);
+    for (number, page) in pages {
+        // Pages past the end were dropped from the database later in the transaction.
+        if let Some(target) = data.get_mut((number - 1) * page_size..number * page_size) {
+            target.copy_from_slice(page);
+        }
+    }
+    Ok(())
+}
+
+/// SQLite's WAL checksum: two running sums over pairs of 32-bit words.
+fn checksum_of((mut s0, mut s1): (u32, u32), bytes: &[u8], big_endian: bool) -> (u32, u32) {
+    let word = |chunk: &[u8]| {
+        let bytes = [chunk[0], chunk[1], chunk[2], chunk[3]];
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    for pair in bytes.chunks_exact(8) {
+        s0 = s0.wrapping_add(word(&pair[..4])).wrapping_add(s1);
+        s1 = s1.wrapping_add(word(&pair[4..])).wrapping_add(s0);
+    }
+    (s0, s1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+    use crate::sqlite::Database;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../../tests/fixtures/wal_library")
+            .join(name);
+        fs::read(&path).unwrap_or_else(|err| {
+            panic!(
+                "{}: {err}; run scripts/create_wal_fixture.py",
+                path.display()
+            )
+        })
+    }
+
+    #[test]
+    fn applies_committed_transactions_only() {
+        let main = fixture("metadata.db");
+        let wal = fixture("metadata.db-wal");
+        let titles = |wal: &[u8]| {
+            let mut data = main.clone();
+            apply(&mut data, wal).unwrap();
+            let books = Database::from_bytes(data).unwrap().table("books").unwrap();
+            books
+                .rows()
+                .map(|row| row.text("title").unwrap_or_default().to_string())
+                .collect::<Vec<_>>()
+        };
+        let checkpointed = ["Checkpointed Title"];
+        let first = ["Title From The WAL", "Added In The WAL"];
+
+        assert_eq!(titles(&[]), checkpointed);
+        let all = titles(&wal);
+        assert_eq!(all.len(), 202);
+        assert_eq!(all[..2], first);
+        assert!(all[201].starts_with("Book 202 "));
+
+        // The first transaction is frames 0 to 4, the second 5 to 13; a transaction
+        // without its commit frame is still being written.
+        let frame = FRAME_HEADER_SIZE + 4096;
+        assert_eq!(wal.len(), HEADER_SIZE + 14 * frame);
+        assert_eq!(titles(&wal[..wal.len() - 1]), first);
+        assert_eq!(titles(&wal[..HEADER_SIZE + 5 * frame]), first);
+        assert_eq!(titles(&wal[..HEADER_SIZE + 5 * frame - 1]), checkpointed);
+
+        // A frame failing its checksum ends the log, as do salts from before a restart.
+        let mut corrupt = wal.clone();
+        corrupt[HEADER_SIZE + 6 * frame + 100] ^= 1;
+        assert_eq!(titles(&corrupt), first);
+        let mut stale = wal.clone();
+        stale[HEADER_SIZE + 5 * frame + 8] ^= 1;
+        assert_eq!(titles(&stale), first);
+
+        // A log with a bad header holds nothing.
+        let mut header = wal.clone();
+        header[24] ^= 1;
+        assert_eq!(titles(&header), checkpointed);
+        assert_eq!(titles(&[0; 64]), checkpointed);
+    }
+}
@@ -0,0 +1,1026 @@
+//! Writing a SQLite database in one go: tables with their rows, `WITHOUT ROWID` ones
+//! included, and the indexes that `UNIQUE` constraints and `CREATE INDEX` imply.
+//!
+//! Statements are stored in `sqlite_master` as given, and only parsed for column names,
+//! collations and keys. Page size is 4096, encoding UTF-8, and there is no journal.
+//!
+//! A database in use is changed in place instead, row by row, in a [`Transaction`].
+
+use std::cmp::Ordering;
+
+use super::{parse_columns, split_top_level, unquote, Value};
+use super::{stored_order, without_rowid_key, HEADER_MAGIC, HEADER_SIZE};
+use super::{INTERIOR_INDEX_PAGE, INTERIOR_TABLE_PAGE, LEAF_INDEX_PAGE, LEAF_TABLE_PAGE};
+
+mod transaction;
+
+pub use transaction::Transaction;
+
+const PAGE_SIZE: usize = 4096;
+/// The SQLite release the header says last wrote the file.
+const SQLITE_VERSION_NUMBER: u32 = 3_045_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Collation {
+    Binary,
+    NoCase,
+}
+
+struct Table {
+    name: String,
+    sql: String,
+    columns: Vec<String>,
+    collations: Vec<Collation>,
+    rowid_column: Option<usize>,
+    /// Primary key columns of a `WITHOUT ROWID` table.
+    without_rowid_key: Option<Vec<usize>>,
+    /// Rows by rowid, with the rowid column stored as NULL as SQLite does. A `WITHOUT
+    /// ROWID` table numbers them in the order they were added.
+    rows: Vec<(i64, Vec<Value>)>,
+}
+
+struct Index {
+    name: String,
+    table: String,
+    /// `None` for the indexes behind `UNIQUE` constraints.
+    sql: Option<String>,
+    /// Indexed columns of the table, with the collation each is compared in.
+    columns: Vec<(usize, Collation)>,
+    unique: bool,
+}
+
+/// A database being put together. Tables and indexes go into `sqlite_master` in the order
+/// they are added.
+#[derive(Default)]
+pub struct Builder {
+    tables: Vec<Table>,
+    indexes: Vec<Index>,
+    /// `(is table, position)` in creation order.
+    order: Vec<(bool, usize)>,
+}
+
+impl Builder {
+    /// Adds a table from its `CREATE TABLE` statement, along with the indexes for its
+    /// `UNIQUE` constraints.
+    pub fn table(&mut self, sql: &str) -> Result<(), String> {
+        let name = object_name(sql, "TABLE")?;
+        let (columns, rowid_column) = parse_columns(sql);
+        if columns.is_empty() {
+            return Err(format!("no columns in {sql}"));
+        }
+        let collations = column_collations(sql, columns.len());
+        let without_rowid_key = without_rowid_key(sql, &columns);
+
+        // Constraints are numbered in order, and a `WITHOUT ROWID` table's primary key
+        // takes a number without an index of its own.
+        let mut autoindexes = Vec::new();
+        let mut number = 0;
+        for definition in definitions(sql) {
+            let upper = definition.to_ascii_uppercase();
+            if without_rowid_key.is_some() && upper.contains("PRIMARY KEY") {
+                number += 1;
+                continue;
+            }
+            let unique_columns = if upper.starts_with("UNIQUE") {
+                let open = definition.find('(').unwrap_or(definition.len());
+                let close = definition.rfind(')').unwrap_or(definition.len());
+                definition
+                    .get(open + 1..close)
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(unquote)
+                    .collect::<Vec<_>>()
+            } else if upper
+                .split_whitespace()
+                .skip(1)
+                .any(|word| word == "UNIQUE")
+            {
+                definition.split_whitespace().take(1).map(unquote).collect()
+            } else {
+                continue;
+            };
+            let columns = unique_columns
+                .iter()
+                .map(|column| {
+                    let position = position(&columns, column)
+                        .ok_or_else(|| format!("{name} has no column {column}"))?;
+                    Ok((position, collations[position]))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            number += 1;
+            autoindexes.push((number, columns));
+        }
+
+        self.order.push((true, self.tables.len()));
+        self.tables.push(Table {
+            name: name.clone(),
+            sql: sql.to_string(),
+            columns,
+            collations,
+            rowid_column,
+            without_rowid_key,
+            rows: Vec::new(),
+        });
+        for (number, columns) in autoindexes {
+            self.order.push((false, self.indexes.len()));
+            self.indexes.push(Index {
+                name: format!("sqlite_autoindex_{name}_{number}"),
+                table: name.clone(),
+                sql: None,
+                columns,
+                unique: true,
+            });
+        }
+        Ok(())
+    }
+
+    /// Adds an index from its `CREATE [UNIQUE] INDEX … ON table (column [COLLATE …], …)`
+    /// statement. The table must already be added.
+    pub fn index(&mut self, sql: &str) -> Result<(), String> {
+        let name = object_name(sql, "INDEX")?;
+        let upper = sql.to_ascii_uppercase();
+        let on = upper
+            .find(" ON ")
+            .ok_or_else(|| format!("no table in {sql}"))?;
+        let open = sql
+            .find('(')
+            .ok_or_else(|| format!("no columns in {sql}"))?;
+        let close = sql.rfind(')').unwrap_or(sql.len());
+        let table_name = unquote(&sql[on + 4..open]);
+        let table = self
+            .tables
+            .iter()
+            .find(|table| table.name.eq_ignore_ascii_case(&table_name))
+            .ok_or_else(|| format!("no table {table_name} for index {name}"))?;
+
+        let mut columns = Vec::new();
+        for term in split_top_level(&sql[open + 1..close]) {
+            let words = term.split_whitespace().collect::<Vec<_>>();
+            let Some(column) = words.first() else {
+                return Err(format!("empty column in {sql}"));
+            };
+            let column = unquote(column);
+            let position = position(&table.columns, &column)
+                .ok_or_else(|| format!("{} has no column {column}", table.name))?;
+            let collation = match words
+                .iter()
+                .position(|word| word.eq_ignore_ascii_case("COLLATE"))
+            {
+                Some(at) => collation(words.get(at + 1).copied().unwrap_or_default())?,
+                None => table.collations[position],
+            };
+            if words.iter().any(|word| word.eq_ignore_ascii_case("DESC")) {
+                return Err(format!("descending index columns are not supported: {sql}"));
+            }
+            columns.push((position, collation));
+        }
+
+        self.order.push((false, self.indexes.len()));
+        self.indexes.push(Index {
+            name,
+            table: table.name.clone(),
+            sql: Some(sql.to_string()),
+            columns,
+            unique: upper.trim_start().starts_with("CREATE UNIQUE"),
+        });
+        Ok(())
+    }
+
+    /// Adds a row to `table`, with a value for each column. An `INTEGER PRIMARY KEY`
+    /// column must hold the row's id; other tables number their rows from 1.
+    pub fn insert(&mut self, table: &str, values: Vec<Value>) -> Result<(), String> {
+        let table = self
+            .tables
+            .iter_mut()
+            .find(|candidate| candidate.name.eq_ignore_ascii_case(table))
+            .ok_or_else(|| format!("no such table: {table}"))?;
+        if values.len() != table.columns.len() {
+            return Err(format!(
+                "{} has {} columns but {} values were given",
+                table.name,
+                table.columns.len(),
+                values.len()
+            ));
+        }
+        let mut values = values;
+        let rowid = match table.rowid_column {
+            Some(column) => match std::mem::replace(&mut values[column], Value::Null) {
+                Value::Integer(rowid) => rowid,
+                _ => return Err(format!("{} needs an integer id", table.name)),
+            },
+            None => table.rows.last().map_or(1, |(rowid, _)| rowid + 1),
+        };
+        table.rows.push((rowid, values));
+        Ok(())
+    }
+
+    /// The database file.
+    pub fn finish(mut self) -> Result<Vec<u8>, String> {
+        let mut pages = Pages::default();
+        // Page 1 holds the file header and the root of `sqlite_master`.
+        pages.allocate();
+
+        let mut master = Vec::new();
+        for (is_table, position) in self.order.clone() {
+            let (kind, name, table_name, sql, root) = if is_table {
+                let table = &mut self.tables[position];
+                table.rows.sort_by_key(|(rowid, _)| *rowid);
+                if let Some(pair) = table.rows.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+                    return Err(format!(
+                        "UNIQUE constraint failed: {} row {} appears twice",
+                        table.name, pair[0].0
+                    ));
+                }
+                let table = &self.tables[position];
+                let root = match &table.without_rowid_key {
+                    Some(key) => {
+                        let mut columns = key
+                            .iter()
+                            .map(|column| (Some(*column), table.collations[*column]))
+                            .collect::<Vec<_>>();
+                        columns.extend(
+                            stored_order(Some(key), table.columns.len())[key.len()..]
+                                .iter()
+                                .map(|column| (Some(*column), Collation::Binary)),
+                        );
+                        index_tree(&mut pages, sorted_records(table, &columns, key.len())?)
+                    }
+                    None => {
+                        let cells = table
+                            .rows
+                            .iter()
+                            .map(|(rowid, values)| (*rowid, record(values)))
+                            .collect();
+                        table_tree(&mut pages, cells, false)
+                    }
+                };
+                ("table", &table.name, &table.name, Some(&table.sql), root)
+            } else {
+                let index = &self.indexes[position];
+                let table = self
+                    .tables
+                    .iter()
+                    .find(|table| table.name == index.table)
+                    .ok_or_else(|| format!("no table {}", index.table))?;
+                let root = index_tree(&mut pages, index_entries(index, table)?);
+                ("index", &index.name, &index.table, index.sql.as_ref(), root)
+            };
+            master.push((
+                master.len() as i64 + 1,
+                record(&[
+                    Value::Text(kind.to_string()),
+                    Value::Text(name.clone()),
+                    Value::Text(table_name.clone()),
+                    Value::Integer(root as i64),
+                    sql.map_or(Value::Null, |sql| Value::Text(sql.clone())),
+                ]),
+            ));
+        }
+        table_tree(&mut pages, master, true);
+
+        let mut data = pages.data;
+        let page_count = (data.len() / PAGE_SIZE) as u32;
+        data[..16].copy_from_slice(HEADER_MAGIC);
+        data[16..18].copy_from_slice(&(PAGE_SIZE as u16).to_be_bytes());
+        // File format versions (legacy), reserved bytes, and payload fractions.
+        data[18..24].copy_from_slice(&[1, 1, 0, 64, 32, 32]);
+        data[24..28].copy_from_slice(&1u32.to_be_bytes());
+        data[28..32].copy_from_slice(&page_count.to_be_bytes());
+        // Schema cookie, schema format 4, and UTF-8.
+        data[40..44].copy_from_slice(&1u32.to_be_bytes());
+        data[44..48].copy_from_slice(&4u32.to_be_bytes());
+        data[56..60].copy_from_slice(&1u32.to_be_bytes());
+        data[92..96].copy_from_slice(&1u32.to_be_bytes());
+        data[96..100].copy_from_slice(&SQLITE_VERSION_NUMBER.to_be_bytes());
+        Ok(data)
+    }
+}
+
+/// The pages written so far, numbered from 1.
+#[derive(Default)]
+struct Pages {
+    data: Vec<u8>,
+}
+
+impl Pages {
+    fn allocate(&mut self) -> u32 {
+        self.data.resize(self.data.len() + PAGE_SIZE, 0);
+        (self.data.len() / PAGE_SIZE) as u32
+    }
+
+    fn page(&mut self, number: u32) -> &mut [u8] {
+        let start = (number as usize - 1) * PAGE_SIZE;
+        &mut self.data[start..start + PAGE_SIZE]
+    }
+
+    /// Writes a b-tree page of `kind` holding `cells`, in order.
+    fn write(&mut self, number: u32, kind: u8, cells: &[Vec<u8>], right_child: Option<u32>) {
+        let header = if number == 1 { HEADER_SIZE } else { 0 };
+        let page = self.page(number);
+        page[header] = kind;
+        page[header + 3..header + 5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+        let pointers = header + if right_child.is_some() { 12 } else { 8 };
+        if let Some(child) = right_child {
+            page[header + 8..header + 12].copy_from_slice(&child.to_be_bytes());
+        }
+        let mut content = PAGE_SIZE;
+        for (index, cell) in cells.iter().enumerate() {
+            content -= cell.len();
+            page[content..content + cell.len()].copy_from_slice(cell);
+            let pointer = pointers + index * 2;
+            page[pointer..pointer + 2].copy_from_slice(&(content as u16).to_be_bytes());
+        }
+        page[header + 5..header + 7].copy_from_slice(&(content as u16).to_be_bytes());
+    }
+
+    /// The part of `payload` kept in its cell, followed by the first overflow page when
+    /// the rest spills onto a chain of them.
+    fn spill(&mut self, payload: &[u8], max_local: usize) -> Vec<u8> {
+        let usable = PAGE_SIZE;
+        let local = if payload.len() <= max_local {
+            payload.len()
+        } else {
+            let min_local = (usable - 12) * 32 / 255 - 23;
+            let local = min_local + (payload.len() - min_local) % (usable - 4);
+            if local <= max_local {
+                local
+            } else {
+                min_local
+            }
+        };
+        let mut cell = payload[..local].to_vec();
+        let chunks = payload[local..].chunks(usable - 4).collect::<Vec<_>>();
+        let numbers = chunks.iter().map(|_| self.allocate()).collect::<Vec<_>>();
+        for (index, chunk) in chunks.iter().enumerate() {
+            let next = numbers.get(index + 1).copied().unwrap_or(0);
+            let page = self.page(numbers[index]);
+            page[..4].copy_from_slice(&next.to_be_bytes());
+            page[4..4 + chunk.len()].copy_from_slice(chunk);
+        }
+        if let Some(first) = numbers.first() {
+            cell.extend_from_slice(&first.to_be_bytes());
+        }
+        cell
+    }
+}
+
+/// Room for cells and their pointers on a page, leaving out the page header and, for
+/// `sqlite_master`, whose root is page 1, the file header.
+fn capacity(header: usize, master: bool) -> usize {
+    PAGE_SIZE - header - if master { HEADER_SIZE } else { 0 }
+}
+
+/// Writes a table b-tree of `(rowid, record)` cells in rowid order, and returns its root
+/// page: page 1 for `sqlite_master`.
+fn table_tree(pages: &mut Pages, rows: Vec<(i64, Vec<u8>)>, master: bool) -> u32 {
+    let max_local = PAGE_SIZE - 35;
+    let cells = rows
+        .into_iter()
+        .map(|(rowid, payload)| {
+            let mut cell = varint(payload.len() as u64);
+            cell.extend(varint(rowid as u64));
+            cell.extend(pages.spill(&payload, max_local));
+            (rowid, cell)
+        })
+        .collect::<Vec<_>>();
+
+    // Leaves, each with the largest rowid in it.
+    let mut groups = pack(&cells, |(_, cell)| cell.len() + 2, capacity(8, master));
+    if groups.len() == 1 {
+        let root = if master { 1 } else { pages.allocate() };
+        let cells = groups[0]
+            .iter()
+            .map(|(_, cell)| cell.clone())
+            .collect::<Vec<_>>();
+        pages.write(root, LEAF_TABLE_PAGE, &cells, None);
+        return root;
+    }
+    let mut children = Vec::new();
+    for group in groups.drain(..) {
+        let number = pages.allocate();
+        let cells = group
+            .iter()
+            .map(|(_, cell)| cell.clone())
+            .collect::<Vec<_>>();
+        pages.write(number, LEAF_TABLE_PAGE, &cells, None);
+        children.push((number, group.last().map_or(0, |(rowid, _)| *rowid)));
+    }
+
+    // Interior levels: a cell for each child but the last, keyed by its largest rowid.
+    loop {
+        let groups = pack(&children, |_| 4 + 9 + 2, capacity(12, master));
+        let top = groups.len() == 1;
+        let mut parents = Vec::new();
+        for group in groups {
+            let number = if top && master { 1 } else { pages.allocate() };
+            let (last, rest) = group.split_last().expect("groups are never empty");
+            let cells = rest
+                .iter()
+                .map(|(child, key)| {
+                    let mut cell = child.to_be_bytes().to_vec();
+                    cell.extend(varint(*key as u64));
+                    cell
+                })
+                .collect::<Vec<_>>();
+            pages.write(number, INTERIOR_TABLE_PAGE, &cells, Some(last.0));
+            parents.push((number, last.1));
+        }
+        if top {
+            return parents[0].0;
+        }
+        children = parents;
+    }
+}
+
+/// Writes an index b-tree of records in index order, and returns its root page. Unlike
+/// a table's, an interior page holds entries of its own, one between each two children.
+fn index_tree(pages: &mut Pages, entries: Vec<Vec<u8>>) -> u32 {
+    let max_local = (PAGE_SIZE - 12) * 64 / 255 - 23;
+    let cell = |pages: &mut Pages, payload: &[u8]| {
+        let mut cell = varint(payload.len() as u64);
+        cell.extend(pages.spill(payload, max_local));
+        cell
+    };
+    let cells = entries
+        .iter()
+        .map(|entry| cell(pages, entry))
+        .collect::<Vec<_>>();
+
+    let (groups, mut dividers) = split(cells, capacity(8, false));
+    let mut children = Vec::new();
+    for group in &groups {
+        let number = pages.allocate();
+        pages.write(number, LEAF_INDEX_PAGE, group, None);
+        children.push(number);
+    }
+    while children.len() > 1 {
+        // A divider on an interior page carries its left child.
+        let cells = dividers
+            .into_iter()
+            .zip(&children)
+            .map(|(divider, child)| {
+                let mut cell = child.to_be_bytes().to_vec();
+                cell.extend(divider);
+                cell
+            })
+            .collect::<Vec<_>>();
+        let last_child = *children.last().expect("there are children");
+        let (groups, next_dividers) = split(cells, capacity(12, false));
+        let mut parents = Vec::new();
+        // Each group's right child is the left child of the divider after it, or the
+        // last child for the last group.
+        for (index, group) in groups.iter().enumerate() {
+            let right = match next_dividers.get(index) {
+                Some(divider) => {
+                    u32::from_be_bytes([divider[0], divider[1], divider[2], divider[3]])
+                }
+                None => last_child,
+            };
+            let number = pages.allocate();
+            pages.write(number, INTERIOR_INDEX_PAGE, group, Some(right));
+            parents.push(number);
+        }
+        children = parents;
+        dividers = next_dividers
+            .into_iter()
+            .map(|mut divider| divider.split_off(4))
+            .collect();
+    }
+    match children.first() {
+        Some(root) => *root,
+        None => {
+            let root = pages.allocate();
+            pages.write(root, LEAF_INDEX_PAGE, &[], None);
+            root
+        }
+    }
+}
+
+/// Greedily packs `items` into pages of `room` bytes, none empty.
+fn pack<T: Clone>(items: &[T], size: impl Fn(&T) -> usize, room: usize) -> Vec<Vec<T>> {
+    let mut groups: Vec<Vec<T>> = vec![Vec::new()];
+    let mut used = 0;
+    for item in items {
+        let item_size = size(item);
+        let current = groups.last_mut().expect("there is a group");
+        if used + item_size > room && !current.is_empty() {
+            groups.push(Vec::new());
+            used = 0;
+        }
+        used += item_size;
+        groups
+            .last_mut()
+            .expect("there is a group")
+            .push(item.clone());
+    }
+    // An interior page needs two children; borrow one for a lonely last group when it
+    // fits. A leaf, where it may not, is fine with one cell.
+    let count = groups.len();
+    if count > 1 && groups[count - 1].len() == 1 && groups[count - 2].len() > 1 {
+        let moved = groups[count - 2].last().expect("the group has items");
+        if used + size(moved) <= room {
+            let moved = groups[count - 2].pop().expect("the group has items");
+            groups[count - 1].insert(0, moved);
+        }
+    }
+    groups
+}
+
+/// Packs `cells` into pages of `room` bytes with one cell between each two pages, which
+/// goes up a level: the pages, and the cells between them.
+fn split(cells: Vec<Vec<u8>>, room: usize) -> (Vec<Vec<Vec<u8>>>, Vec<Vec<u8>>) {
+    let mut groups = vec![Vec::new()];
+    let mut dividers = Vec::new();
+    let mut used = 0;
+    let count = cells.len();
+    for (index, cell) in cells.into_iter().enumerate() {
+        let current = groups.last_mut().expect("there is a group");
+        let size = cell.len() + 2;
+        if used + size <= room || current.is_empty() {
+            used += size;
+            current.push(cell);
+            continue;
+        }
+        // The page is full, so this cell goes up, unless it is the last one, which must
+        // not leave an empty page behind it.
+        if index + 1 == count {
+            if let Some(previous) = current.pop() {
+                dividers.push(previous);
+            }
+            groups.push(vec![cell]);
+        } else {
+            dividers.push(cell);
+            groups.push(Vec::new());
+            used = 0;
+        }
+    }
+    (groups, dividers)
+}
+
+/// The index's records, `(columns…, rowid)`, sorted; an error when a unique index would
+/// hold the same key twice. In a `WITHOUT ROWID` table, the primary key columns that are
+/// not indexed already stand in for the rowid.
+fn index_entries(index: &Index, table: &Table) -> Result<Vec<Vec<u8>>, String> {
+    let mut columns = index
+        .columns
+        .iter()
+        .map(|(column, collation)| (Some(*column), *collation))
+        .collect::<Vec<_>>();
+    match &table.without_rowid_key {
+        Some(key) => columns.extend(
+            key.iter()
+                .filter(|column| !index.columns.iter().any(|(indexed, _)| indexed == *column))
+                .map(|column| (Some(*column), table.collations[*column])),
+        ),
+        None => columns.push((None, Collation::Binary)),
+    }
+    let unique = if index.unique { index.columns.len() } else { 0 };
+    sorted_records(table, &columns, unique)
+}
+
+/// Records of `columns` of each row of `table`, `None` standing for the rowid, sorted by
+/// them in their collations; an error when two rows share the values of the first
+/// `unique` columns and none of them is NULL.
+fn sorted_records(
+    table: &Table,
+    columns: &[(Option<usize>, Collation)],
+    unique: usize,
+) -> Result<Vec<Vec<u8>>, String> {
+    let mut entries = table
+        .rows
+        .iter()
+        .map(|(rowid, values)| {
+            columns
+                .iter()
+                .map(|(column, _)| match column {
+                    Some(column) if Some(*column) != table.rowid_column => values[*column].clone(),
+                    _ => Value::Integer(*rowid),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let compare = |a: &[Value], b: &[Value], count: usize| {
+        (0..count)
+            .map(|column| compare(&a[column], &b[column], columns[column].1))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    };
+    entries.sort_by(|a, b| compare(a, b, a.len()));
+
+    if unique > 0 {
+        for pair in entries.windows(2) {
+            let has_null = pair[0][..unique].contains(&Value::Null);
+            if !has_null && compare(&pair[0], &pair[1], unique).is_eq() {
+                let names = columns[..unique]
+                    .iter()
+                    .filter_map(|(column, _)| *column)
+                    .map(|column| format!("{}.{}", table.name, table.columns[column]))
+                    .collect::<Vec<_>>();
+                return Err(format!("UNIQUE constraint failed: {}", names.join(", ")));
+            }
+        }
+    }
+    Ok(entries.iter().map(|entry| record(entry)).collect())
+}
+
+/// SQLite's ordering of values: NULLs, then numbers, then text in `collation`, then blobs.
+fn compare(a: &Value, b: &Value, collation: Collation) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Integer(_) | Value::Real(_) => 1,
+            Value::Text(_) => 2,
+            Value::Blob(_) => 3,
+        }
+    }
+    match (a, b) {
+        (Value::Integer(a), Value::Integer(b)) => a.cmp(b),
+        (Value::Integer(a), Value::Real(b)) => (*a as f64).total_cmp(b),
+        (Value::Real(a), Value::Integer(b)) => a.total_cmp(&(*b as f64)),
+        (Value::Real(a), Value::Real(b)) => a.total_cmp(b),
+        (Value::Text(a), Value::Text(b)) => match collation {
+            Collation::Binary => a.as_bytes().cmp(b.as_bytes()),
+            Collation::NoCase => a
+                .bytes()
+                .map(|byte| byte.to_ascii_lowercase())
+                .cmp(b.bytes().map(|byte| byte.to_ascii_lowercase())),
+        },
+        (Value::Blob(a), Value::Blob(b)) => a.cmp(b),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// A record: a header of serial types, then the values.
+fn record(values: &[Value]) -> Vec<u8> {
+    let mut types = Vec::new();
+    let mut body = Vec::new();
+    for value in values {
+        let serial_type = match value {
+            Value::Null => 0,
+            Value::Integer(0) => 8,
+            Value::Integer(1) => 9,
+            Value::Integer(value) => {
+                let (serial_type, size) = match *value {
+                    -0x80..=0x7f => (1, 1),
+                    -0x8000..=0x7fff => (2, 2),
+                    -0x80_0000..=0x7f_ffff => (3, 3),
+                    -0x8000_0000..=0x7fff_ffff => (4, 4),
+                    -0x8000_0000_0000..=0x7fff_ffff_ffff => (5, 6),
+                    _ => (6, 8),
+                };
+                body.extend_from_slice(&value.to_be_bytes()[8 - size..]);
+                serial_type
+            }
+            Value::Real(value) => {
+                body.extend_from_slice(&value.to_be_bytes());
+                7
+            }
+            Value::Text(text) => {
+                body.extend_from_slice(text.as_bytes());
+                text.len() as u64 * 2 + 13
+            }
+            Value::Blob(blob) => {
+                body.extend_from_slice(blob);
+                blob.len() as u64 * 2 + 12
+            }
+        };
+        types.extend(varint(serial_type));
+    }
+    // The header's size counts its own varint.
+    let mut header_size = types.len() + 1;
+    while varint(header_size as u64).len() + types.len() > header_size {
+        header_size += 1;
+    }
+    let mut record = varint(header_size as u64);
+    record.extend(types);
+    record.extend(body);
+    record
+}
+
+fn varint(mut value: u64) -> Vec<u8> {
+    if value > 0x00ff_ffff_ffff_ffff {
+        // Nine bytes: eight of seven bits, then a full one.
+        let mut bytes = vec![(value & 0xff) as u8];
+        value >>= 8;
+        for _ in 0..8 {
+            bytes.push((value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+        bytes.reverse();
+        return bytes;
+    }
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.reverse();
+    bytes
+}
+
+/// The name after `CREATE [UNIQUE] <kind> [IF NOT EXISTS]`.
+fn object_name(sql: &str, kind: &str) -> Result<String, String> {
+    let words = sql
+        .split(|c: char| c.is_whitespace() || c == '(')
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+    let at = words
+        .iter()
+        .position(|word| word.eq_ignore_ascii_case(kind))
+        .ok_or_else(|| format!("not a CREATE {kind} statement: {sql}"))?;
+    let mut rest = words[at + 1..].iter();
+    let mut name = rest.next();
+    if name.is_some_and(|word| word.eq_ignore_ascii_case("IF")) {
+        name = rest.nth(2);
+    }
+    name.map(|name| unquote(name))
+        .ok_or_else(|| format!("no name in {sql}"))
+}
+
+/// The column definitions and table constraints between the outer parentheses.
+fn definitions(sql: &str) -> Vec<&str> {
+    let (Some(open), Some(close)) = (sql.find('('), sql.rfind(')')) else {
+        return Vec::new();
+    };
+    split_top_level(&sql[open + 1..close])
+        .into_iter()
+        .map(str::trim)
+        .collect()
+}
+
+/// The definitions that are columns rather than table constraints.
+fn column_definitions(sql: &str) -> Vec<&str> {
+    definitions(sql)
+        .into_iter()
+        .filter(|definition| {
+            let upper = definition.to_ascii_uppercase();
+            !["CONSTRAINT", "PRIMARY", "UNIQUE", "CHECK", "FOREIGN"]
+                .iter()
+                .any(|keyword| upper.starts_with(keyword))
+        })
+        .collect()
+}
+
+/// Each column's declared collation, in order.
+fn column_collations(sql: &str, count: usize) -> Vec<Collation> {
+    let mut collations = column_definitions(sql)
+        .into_iter()
+        .map(|definition| {
+            let words = definition.split_whitespace().collect::<Vec<_>>();
+            words
+                .iter()
+                .position(|word| word.eq_ignore_ascii_case("COLLATE"))
+                .and_then(|at| collation(words.get(at + 1)?).ok())
+                .unwrap_or(Collation::Binary)
+        })
+        .collect::<Vec<_>>();
+    collations.resize(count, Collation::Binary);
+    collations
+}
+
+/// Each column's declared default when it is a constant, which is what a row stored
+/// before the column was added reads as; `None` for expressions.
+fn column_defaults(sql: &str, count: usize) -> Vec<Option<Value>> {
+    let mut defaults = column_definitions(sql)
+        .into_iter()
+        .map(|definition| {
+            let upper = definition.to_ascii_uppercase();
+            let Some(at) = upper
+                .match_indices("DEFAULT")
+                .map(|(at, _)| at)
+                .find(|&at| upper[..at].ends_with(char::is_whitespace))
+            else {
+                return Some(Value::Null);
+            };
+            let rest = definition[at + "DEFAULT".len()..].trim_start();
+            if let Some(quoted) = rest.strip_prefix('\'') {
+                let mut text = String::new();
+                let mut chars = quoted.chars().peekable();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\'' if chars.peek() == Some(&'\'') => {
+                            chars.next();
+                            text.push('\'');
+                        }
+                        '\'' => return Some(Value::Text(text)),
+                        c => text.push(c),
+                    }
+                }
+                return None;
+            }
+            let word = rest.split_whitespace().next().unwrap_or_default();
+            match word.to_ascii_uppercase().as_str() {
+                "NULL" => Some(Value::Null),
+                "TRUE" => Some(Value::Integer(1)),
+                "FALSE" => Some(Value::Integer(0)),
+                _ => word
+                    .parse()
+                    .map(Value::Integer)
+                    .or_else(|_| word.parse().map(Value::Real))
+                    .ok(),
+            }
+        })
+        .collect::<Vec<_>>();
+    defaults.resize(count, Some(Value::Null));
+    defaults
+}
+
+fn collation(name: &str) -> Result<Collation, String> {
+    match name.trim_end_matches(',').to_ascii_uppercase().as_str() {
+        "BINARY" => Ok(Collation::Binary),
+        "NOCASE" => Ok(Collation::NoCase),
+        _ => Err(format!("unsupported collation {name}")),
+    }
+}
+
+fn position(columns: &[String], name: &str) -> Option<usize> {
+    columns
+        .iter()
+        .position(|column| column.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite::Database;
+
+    #[test]
+    fn writes_tables_that_read_back() {
+        let mut builder = Builder::default();
+        builder
+            .table(
+                "CREATE TABLE tags ( id INTEGER PRIMARY KEY, name TEXT NOT NULL COLLATE NOCASE, \
+                 note TEXT, UNIQUE (name))",
+            )
+            .unwrap();
+        builder
+            .index("CREATE INDEX tags_note_idx ON tags (note)")
+            .unwrap();
+        // Enough rows for interior pages, and a note long enough to overflow.
+        let long = "x".repeat(10_000);
+        for id in 1..=2000 {
+            let note = if id == 7 {
+                long.clone()
+            } else {
+                format!("note {id}")
+            };
+            builder
+                .insert(
+                    "tags",
+                    vec![
+                        Value::Integer(id),
+                        Value::Text(format!("Tag {id:05}")),
+                        Value::Text(note),
+                    ],
+                )
+                .unwrap();
+        }
+
+        let database = Database::from_bytes(builder.finish().unwrap()).unwrap();
+        let table = database.table("tags").unwrap();
+        let rows = table.rows().collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2000);
+        assert_eq!(rows[1999].integer("id"), Some(2000));
+        assert_eq!(rows[1999].text("name"), Some("Tag 02000"));
+        assert_eq!(rows[6].text("note"), Some(long.as_str()));
+        assert!(database.table_schema("tags").is_some());
+    }
+
+    #[test]
+    fn writes_rows_near_the_page_size() {
+        // Leaves of a cell each, too full to share, as FTS5 segments in `_data` make.
+        assert_eq!(
+            pack(&[4064, 2411], |size| *size, capacity(8, false)),
+            vec![vec![4064], vec![2411]]
+        );
+        assert_eq!(
+            pack(&[15; 3], |size| *size, 30),
+            vec![vec![15], vec![15; 2]]
+        );
+
+        let mut builder = Builder::default();
+        builder
+            .table("CREATE TABLE segments ( id INTEGER PRIMARY KEY, block BLOB)")
+            .unwrap();
+        let blocks = [1000, 2402, 4055].map(|size| vec![7u8; size]);
+        for (id, block) in blocks.iter().enumerate() {
+            builder
+                .insert(
+                    "segments",
+                    vec![Value::Integer(id as i64 + 1), Value::Blob(block.clone())],
+                )
+                .unwrap();
+        }
+        let database = Database::from_bytes(builder.finish().unwrap()).unwrap();
+        let table = database.table("segments").unwrap();
+        let rows = table.rows().collect::<Vec<_>>();
+        assert_eq!(rows.len(), 3);
+        for (row, block) in rows.iter().zip(&blocks) {
+            assert_eq!(row.get("block"), &Value::Blob(block.clone()));
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_unique_keys() {
+        let mut builder = Builder::default();
+        builder
+            .table("CREATE TABLE tags ( id INTEGER PRIMARY KEY, name TEXT COLLATE NOCASE UNIQUE)")
+            .unwrap();
+        for (id, name) in [(1, "Fiction"), (2, "fiction")] {
+            builder
+                .insert(
+                    "tags",
+                    vec![Value::Integer(id), Value::Text(name.to_string())],
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            builder.finish().unwrap_err(),
+            "UNIQUE constraint failed: tags.name"
+        );
+    }
+
+    #[test]
+    fn writes_without_rowid_tables() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../../tests/fixtures/fts_library/metadata.db");
+        let original = Database::open(&path).unwrap();
+        let mut builder = Builder::default();
+        let mut names = Vec::new();
+        for record in original.scan(1).unwrap() {
+            if let [Value::Text(kind), Value::Text(name), _, _, Value::Text(sql)] = &record[..] {
+                if kind == "table" && sql.to_ascii_uppercase().contains("WITHOUT ROWID") {
+                    builder.table(sql).unwrap();
+                    for row in original.table(name).unwrap().rows {
+                        builder.insert(name, row).unwrap();
+                    }
+                    names.push(name.clone());
+                }
+            }
+        }
+        let copy = Database::from_bytes(builder.finish().unwrap()).unwrap();
+        assert_eq!(names.len(), 4);
+        for name in &names {
+            assert_eq!(
+                format!("{:?}", copy.table(name).unwrap()),
+                format!("{:?}", original.table(name).unwrap()),
+                "{name}"
+            );
+        }
+        // Full-text search keeps its term index and settings in `WITHOUT ROWID` tables.
+        let index = copy.table("annotations_fts_idx").unwrap();
+        let keys = index
+            .rows()
+            .map(|row| match row.get("term") {
+                Value::Blob(term) => (row.integer("segid").unwrap(), term.clone()),
+                term => panic!("a term is a blob, not {term:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(keys.len(), 1477);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        let config = copy.table("annotations_fts_config").unwrap();
+        let version = config.rows().find(|row| row.text("k") == Some("version"));
+        assert_eq!(version.and_then(|row| row.integer("v")), Some(4));
+        assert_eq!(copy.row_count("annotations_fts_stemmed_idx").unwrap(), 300);
+
+        // The primary key is numbered among the constraints, and must be unique.
+        let mut builder = Builder::default();
+        builder
+            .table(
+                "CREATE TABLE t (a UNIQUE, b INTEGER PRIMARY KEY, c, UNIQUE (c, a)) WITHOUT ROWID",
+            )
+            .unwrap();
+        let names = builder
+            .indexes
+            .iter()
+            .map(|index| index.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["sqlite_autoindex_t_1", "sqlite_autoindex_t_3"]);
+        for (a, b) in [("x", 2), ("y", 1), ("z", 2)] {
+            let values = vec![Value::Text(a.into()), Value::Integer(b), Value::Null];
+            builder.insert("t", values).unwrap();
+        }
+        assert_eq!(
+            builder.finish().unwrap_err(),
+            "UNIQUE constraint failed: t.b"
+        );
+    }
+
+    #[test]
+    fn reads_constant_column_defaults() {
+        let sql = "CREATE TABLE t (a INTEGER PRIMARY KEY, b TEXT DEFAULT 'it''s', \
+                   c REAL NOT NULL DEFAULT -1.5, d TIMESTAMP DEFAULT CURRENT_TIMESTAMP, e, \
+                   UNIQUE(b))";
+        assert_eq!(
+            column_defaults(sql, 5),
+            [
+                Some(Value::Null),
+                Some(Value::Text("it's".to_string())),
+                Some(Value::Real(-1.5)),
+                None,
+                Some(Value::Null),
+            ]
+        );
+    }
+}
@@ -0,0 +1,1059 @@
+//! Changing rows of a database in place, the way SQLite itself commits a transaction:
+//! under the locks `BEGIN EXCLUSIVE` takes, with the pages about to change copied to a
+//! rollback journal first. A crash leaves that journal behind, hot, and SQLite rolls the
+//! file back from it the next time it opens the database. Connections that have the
+//! file open, such as Calibre's, see the file change counter move on and read it again.
+//!
+//! Only what editing a library takes is here: setting a column no index covers, and
+//! adding a row to a rowid table and its indexes, splitting pages as they fill up.
+//! Triggers do not run. A database in WAL mode, or with auto-vacuum, is refused.
+//!
+//! On Unix the locks are the `fcntl` byte-range locks SQLite uses. Elsewhere they are
+//! not taken, and a transaction only begins on the caller's word that nothing else has
+//! the database open.
+
+use std::cmp::Ordering;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read as _, Seek as _, SeekFrom, Write as _};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::SQLITE_VERSION_NUMBER;
+use super::{column_defaults, compare, pack, record, split, varint, Builder, Collation};
+use crate::sqlite::{decode_record, local_size, read_u16, read_u32, read_varint, wal_path};
+use crate::sqlite::{Database, Row, TableSchema, Value, HEADER_SIZE};
+use crate::sqlite::{INTERIOR_INDEX_PAGE, INTERIOR_TABLE_PAGE, LEAF_INDEX_PAGE, LEAF_TABLE_PAGE};
+
+/// SQLite's locks are on bytes past the first gigabyte, in a page it never uses: one each
+/// for the PENDING and RESERVED locks, then a range readers share.
+const PENDING_BYTE: u64 = 0x4000_0000;
+#[cfg(unix)]
+const RESERVED_BYTE: u64 = PENDING_BYTE + 1;
+#[cfg(unix)]
+const SHARED_FIRST: u64 = PENDING_BYTE + 2;
+#[cfg(unix)]
+const SHARED_SIZE: u64 = 510;
+
+const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+/// The journal header is padded to a sector; SQLite reads the size from the header.
+const SECTOR_SIZE: usize = 512;
+/// How deep a b-tree is followed before it is taken to have a cycle.
+const MAX_DEPTH: usize = 64;
+
+/// Changes to a database, made in memory until [`Transaction::commit`] writes them.
+///
+/// The locks are held until the transaction is dropped. POSIX drops a process's locks on
+/// a file when it closes any descriptor of it, so the database must not be opened again
+/// meanwhile; [`Transaction::database`] reads it instead.
+pub struct Transaction {
+    path: PathBuf,
+    file: File,
+    /// The database with the changes made so far.
+    database: Database,
+    /// The file as it is on disk, and as it was when the transaction began.
+    written: Vec<u8>,
+    original: Vec<u8>,
+}
+
+/// A b-tree page taken apart: its kind, its cells as stored, and its right child.
+struct Node {
+    kind: u8,
+    cells: Vec<Vec<u8>>,
+    right_child: Option<u32>,
+}
+
+/// An index of a table, with the columns of its entries, `None` standing for the rowid,
+/// and how many of them must be unique.
+struct Index {
+    name: String,
+    root: u32,
+    columns: Vec<(Option<usize>, Collation)>,
+    unique: usize,
+}
+
+/// The pages from a b-tree's root down to a page: each parent, and which of its children,
+/// counting the right child last, the way leads through.
+type Trail = Vec<(u32, usize)>;
+
+impl Transaction {
+    /// Whether the database is locked, which it is on Unix.
+    pub const LOCKS: bool = cfg!(unix);
+
+    /// Opens the database at `path` for changing and takes SQLite's exclusive lock on it,
+    /// without waiting; an error when another connection is reading or writing it. Where
+    /// the lock cannot be taken, `unlocked` goes on without it.
+    pub fn begin(path: &Path, unlocked: bool) -> Result<Self, String> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|err| format!("failed to open {}: {err}", path.display()))?;
+        if !lock(&file, path)? && !unlocked {
+            return Err(format!(
+                "{} cannot be locked on this platform, so it is not known whether Calibre \
+                 or another program has it open",
+                path.display()
+            ));
+        }
+
+        // With the lock held, a journal is one a crashed write left behind.
+        let journal = journal_path(path);
+        let mut magic = [0; 8];
+        if File::open(&journal).is_ok_and(|mut file| file.read_exact(&mut magic).is_ok())
+            && magic == JOURNAL_MAGIC
+        {
+            return Err(format!(
+                "{} holds a write that was interrupted; open the library in Calibre to roll \
+                 it back, and try again",
+                journal.display()
+            ));
+        }
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+        let database = Database::from_bytes(data.clone())
+            .map_err(|err| format!("{}: {err}", path.display()))?;
+        if data[18] == 2 || data[19] == 2 || fs::metadata(wal_path(path)).is_ok() {
+            return Err(format!(
+                "{} is in WAL mode, and cannot be changed in place",
+                path.display()
+            ));
+        }
+        if read_u32(&data, 52)? != 0 {
+            return Err(format!(
+                "{} uses auto-vacuum, and cannot be changed in place",
+                path.display()
+            ));
+        }
+        if data.len() % database.page_size != 0 {
+            return Err(format!("{} is not a whole number of pages", path.display()));
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            database,
+            written: data.clone(),
+            original: data,
+        })
+    }
+
+    /// The database with the changes made so far.
+    pub fn database(&self) -> &Database {
+        &self.database
+    }
+
+    /// Sets `column` to `value` in the rows of `table` that `matches`, which sees each
+    /// row with its rowid filled in, and returns how many rows it changed.
+    pub fn update(
+        &mut self,
+        table: &str,
+        column: &str,
+        value: Value,
+        matches: impl Fn(Row<'_>) -> bool,
+    ) -> Result<usize, String> {
+        let schema = self.rowid_table(table)?;
+        let position = schema
+            .columns
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(column))
+            .ok_or_else(|| format!("{} has no column {column}", schema.name))?;
+        if Some(position) == schema.rowid_column {
+            return Err(format!("the rowid of {} cannot be changed", schema.name));
+        }
+        if let Some(index) = self.indexes(&schema)?.iter().find(|index| {
+            index
+                .columns
+                .iter()
+                .any(|(column, _)| *column == Some(position))
+        }) {
+            return Err(format!(
+                "{}.{column} is in index {}, and cannot be changed in place",
+                schema.name, index.name
+            ));
+        }
+        let defaults = column_defaults(&self.sql(&schema.name)?, schema.columns.len());
+
+        let mut changed = 0;
+        for (rowid, mut values) in self.database.scan_with_rowids(schema.root_page)? {
+            let mut row = values.clone();
+            row.resize(schema.columns.len().max(row.len()), Value::Null);
+            if let Some(column) = schema.rowid_column {
+                row[column] = Value::Integer(rowid);
+            }
+            let row = Row {
+                columns: &schema.columns,
+                values: &row,
+            };
+            if !matches(row) {
+                continue;
+            }
+            // Rows older than an `ALTER TABLE … ADD COLUMN` leave it out.
+            for default in defaults.iter().take(position + 1).skip(values.len()) {
+                values.push(default.clone().ok_or_else(|| {
+                    format!(
+                        "{} row {rowid} lacks a column with no constant default",
+                        schema.name
+                    )
+                })?);
+            }
+            values[position] = value.clone();
+
+            let (path, page, mut node, at, _) = self.find_row(schema.root_page, rowid)?;
+            self.free_overflow(&node.cells[at])?;
+            node.cells[at] = self.table_cell(rowid, &record(&values));
+            self.place(path, page, node)?;
+            changed += 1;
+        }
+        Ok(changed)
+    }
+
+    /// Adds a row to `table` and its indexes, with a value for each column. An `INTEGER
+    /// PRIMARY KEY` column must hold the row's id; other tables number it after the last.
+    pub fn insert(&mut self, table: &str, values: Vec<Value>) -> Result<(), String> {
+        let schema = self.rowid_table(table)?;
+        if values.len() != schema.columns.len() {
+            return Err(format!(
+                "{} has {} columns but {} values were given",
+                schema.name,
+                schema.columns.len(),
+                values.len()
+            ));
+        }
+        let mut values = values;
+        let rowid = match schema.rowid_column {
+            Some(column) => match std::mem::replace(&mut values[column], Value::Null) {
+                Value::Integer(rowid) => rowid,
+                _ => return Err(format!("{} needs an integer id", schema.name)),
+            },
+            None => {
+                let mut last = 0;
+                self.database.walk(schema.root_page, &mut |rowid, _| {
+                    last = rowid;
+                    Ok(())
+                })?;
+                last + 1
+            }
+        };
+
+        // Every constraint is checked before anything changes.
+        let (path, page, mut node, at, exists) = self.find_row(schema.root_page, rowid)?;
+        if exists {
+            return Err(format!(
+                "UNIQUE constraint failed: {} row {rowid} exists",
+                schema.name
+            ));
+        }
+        let indexes = self.indexes(&schema)?;
+        let mut entries = Vec::new();
+        for index in &indexes {
+            let entry = index
+                .columns
+                .iter()
+                .map(|(column, _)| match column {
+                    Some(column) if Some(*column) != schema.rowid_column => values[*column].clone(),
+                    _ => Value::Integer(rowid),
+                })
+                .collect::<Vec<_>>();
+            if index.unique > 0 && !entry[..index.unique].contains(&Value::Null) {
+                let mut clash = false;
+                self.database.walk(index.root, &mut |_, payload| {
+                    let key = decode_record(payload)?;
+                    clash |= compare_keys(&entry, &key, &index.columns[..index.unique]).is_eq();
+                    Ok(())
+                })?;
+                if clash {
+                    let names = index.columns[..index.unique]
+                        .iter()
+                        .filter_map(|(column, _)| *column)
+                        .map(|column| format!("{}.{}", schema.name, schema.columns[column]))
+                        .collect::<Vec<_>>();
+                    return Err(format!("UNIQUE constraint failed: {}", names.join(", ")));
+                }
+            }
+            entries.push(entry);
+        }
+
+        let cell = self.table_cell(rowid, &record(&values));
+        node.cells.insert(at, cell);
+        self.place(path, page, node)?;
+        for (index, entry) in indexes.iter().zip(entries) {
+            let (path, page, mut node, at) = self.find_entry(index, &entry)?;
+            let cell = self.index_cell(&record(&entry));
+            node.cells.insert(at, cell);
+            self.place(path, page, node)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the changes into the file: the pages they touch go to the journal first,
+    /// then the new ones over them, and deleting the journal commits them. The lock is
+    /// kept, and so is the file as it was, for [`Transaction::undo`].
+    pub fn commit(&mut self) -> Result<(), String> {
+        let data = self.database.data.clone();
+        self.write(data)
+    }
+
+    /// Puts the file back as it was when the transaction began, committing that the same
+    /// way.
+    pub fn undo(&mut self) -> Result<(), String> {
+        self.write(self.original.clone())?;
+        self.database = Database::from_bytes(self.written.clone())?;
+        Ok(())
+    }
+
+    fn write(&mut self, mut data: Vec<u8>) -> Result<(), String> {
+        let page_size = self.database.page_size;
+        let written_pages = self.written.len() / page_size;
+        // The change counter tells other connections their cached pages are stale, and
+        // the page count in the header holds for as long as it matches.
+        let counter = read_u32(&self.written, 24)?.wrapping_add(1);
+        let page_count = (data.len() / page_size) as u32;
+        data[24..28].copy_from_slice(&counter.to_be_bytes());
+        data[28..32].copy_from_slice(&page_count.to_be_bytes());
+        data[92..96].copy_from_slice(&counter.to_be_bytes());
+        data[96..100].copy_from_slice(&SQLITE_VERSION_NUMBER.to_be_bytes());
+
+        let changed = (0..written_pages)
+            .filter(|index| {
+                page(&self.written, *index, page_size) != page(&data, *index, page_size)
+            })
+            .collect::<Vec<_>>();
+
+        // The journal: a header padded to a sector, then each page as it was, with its
+        // number and a checksum over a sample of its bytes, salted with a nonce.
+        let nonce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.subsec_nanos());
+        let mut journal = JOURNAL_MAGIC.to_vec();
+        for field in [
+            changed.len() as u32,
+            nonce,
+            written_pages as u32,
+            SECTOR_SIZE as u32,
+            page_size as u32,
+        ] {
+            journal.extend_from_slice(&field.to_be_bytes());
+        }
+        journal.resize(SECTOR_SIZE, 0);
+        for index in &changed {
+            let contents = page(&self.written, *index, page_size).expect("the page is in the file");
+            journal.extend_from_slice(&(*index as u32 + 1).to_be_bytes());
+            journal.extend_from_slice(contents);
+            let checksum = (1..)
+                .map(|step| page_size as isize - 200 * step)
+                .take_while(|offset| *offset > 0)
+                .fold(nonce, |sum, offset| {
+                    sum.wrapping_add(contents[offset as usize] as u32)
+                });
+            journal.extend_from_slice(&checksum.to_be_bytes());
+        }
+        let journal_path = journal_path(&self.path);
+        File::create(&journal_path)
+            .and_then(|mut file| {
+                file.write_all(&journal)?;
+                file.sync_all()
+            })
+            .map_err(|err| format!("failed to write {}: {err}", journal_path.display()))?;
+        sync_directory(&self.path);
+
+        // Pages past the new end of the file are only in the journal, for rolling back.
+        let result = changed
+            .iter()
+            .copied()
+            .filter(|index| *index < page_count as usize)
+            .chain(written_pages..page_count as usize)
+            .try_for_each(|index| {
+                self.file
+                    .seek(SeekFrom::Start((index * page_size) as u64))?;
+                self.file
+                    .write_all(page(&data, index, page_size).expect("the page is in the data"))
+            })
+            .and_then(|()| self.file.set_len(data.len() as u64))
+            .and_then(|()| self.file.sync_all());
+        if let Err(err) = result {
+            // The journal stays, so that SQLite rolls back what was written of this.
+            return Err(format!("failed to write {}: {err}", self.path.display()));
+        }
+        fs::remove_file(&journal_path)
+            .map_err(|err| format!("failed to delete {}: {err}", journal_path.display()))?;
+        sync_directory(&self.path);
+        self.written = data;
+        Ok(())
+    }
+
+    fn rowid_table(&self, name: &str) -> Result<TableSchema, String> {
+        let schema = self
+            .database
+            .table_schema(name)
+            .ok_or_else(|| format!("no such table: {name}"))?;
+        if schema.without_rowid_key.is_some() {
+            return Err(format!(
+                "{name} is a WITHOUT ROWID table, and cannot be changed in place"
+            ));
+        }
+        Ok(schema.clone())
+    }
+
+    /// The `CREATE TABLE` statement of `table`.
+    fn sql(&self, table: &str) -> Result<String, String> {
+        self.database
+            .scan(1)?
+            .into_iter()
+            .find_map(|record| match record.as_slice() {
+                [Value::Text(kind), Value::Text(name), _, _, Value::Text(sql)]
+                    if kind == "table" && name.eq_ignore_ascii_case(table) =>
+                {
+                    Some(sql.clone())
+                }
+                _ => None,
+            })
+            .ok_or_else(|| format!("no such table: {table}"))
+    }
+
+    /// The indexes of a table, read from their statements and its `UNIQUE` constraints.
+    fn indexes(&self, schema: &TableSchema) -> Result<Vec<Index>, String> {
+        let mut builder = Builder::default();
+        builder.table(&self.sql(&schema.name)?)?;
+        let mut roots = Vec::new();
+        for record in self.database.scan(1)? {
+            let [Value::Text(kind), Value::Text(name), Value::Text(table), root, sql] =
+                record.as_slice()
+            else {
+                continue;
+            };
+            if kind != "index" || !table.eq_ignore_ascii_case(&schema.name) {
+                continue;
+            }
+            if let Value::Text(sql) = sql {
+                builder.index(sql)?;
+            }
+            match root {
+                Value::Integer(root) if *root > 0 => roots.push((name.clone(), *root as u32)),
+                _ => return Err(format!("index {name} has no pages")),
+            }
+        }
+        if let Some((missing, _)) = roots
+            .iter()
+            .find(|(name, _)| !builder.indexes.iter().any(|index| &index.name == name))
+        {
+            return Err(format!(
+                "index {missing} of {} is not understood",
+                schema.name
+            ));
+        }
+        builder
+            .indexes
+            .iter()
+            .map(|index| {
+                let root = roots
+                    .iter()
+                    .find(|(name, _)| *name == index.name)
+                    .map(|(_, root)| *root)
+                    .ok_or_else(|| format!("index {} is missing", index.name))?;
+                let mut columns = index
+                    .columns
+                    .iter()
+                    .map(|(column, collation)| (Some(*column), *collation))
+                    .collect::<Vec<_>>();
+                columns.push((None, Collation::Binary));
+                Ok(Index {
+                    name: index.name.clone(),
+                    root,
+                    unique: if index.unique { index.columns.len() } else { 0 },
+                    columns,
+                })
+            })
+            .collect()
+    }
+
+    /// The leaf of a table b-tree where `rowid` is or would go, the way there, and the
+    /// position in the leaf; and whether the row is there.
+    fn find_row(&self, root: u32, rowid: i64) -> Result<(Trail, u32, Node, usize, bool), String> {
+        let mut path = Vec::new();
+        let mut number = root;
+        loop {
+            let node = self.node(number)?;
+            let keys = node
+                .cells
+                .iter()
+                .map(|cell| table_key(cell, node.kind))
+                .collect::<Result<Vec<_>, String>>()?;
+            let at = keys
+                .iter()
+                .position(|key| *key >= rowid)
+                .unwrap_or(keys.len());
+            match node.kind {
+                LEAF_TABLE_PAGE => {
+                    let exists = keys.get(at) == Some(&rowid);
+                    return Ok((path, number, node, at, exists));
+                }
+                INTERIOR_TABLE_PAGE => {
+                    path.push((number, at));
+                    number = child(&node, at)?;
+                }
+                kind => return Err(format!("page {number} is not a table page ({kind})")),
+            }
+            if path.len() > MAX_DEPTH {
+                return Err("b-tree has a cycle".to_string());
+            }
+        }
+    }
+
+    /// The leaf of `index` where `entry` goes, the way there, and the position in it.
+    fn find_entry(
+        &self,
+        index: &Index,
+        entry: &[Value],
+    ) -> Result<(Trail, u32, Node, usize), String> {
+        let mut path = Vec::new();
+        let mut number = index.root;
+        loop {
+            let node = self.node(number)?;
+            let mut at = node.cells.len();
+            for (position, cell) in node.cells.iter().enumerate() {
+                let key = self.index_key(cell, node.kind == INTERIOR_INDEX_PAGE)?;
+                if compare_keys(entry, &key, &index.columns).is_lt() {
+                    at = position;
+                    break;
+                }
+            }
+            match node.kind {
+                LEAF_INDEX_PAGE => return Ok((path, number, node, at)),
+                INTERIOR_INDEX_PAGE => {
+                    path.push((number, at));
+                    number = child(&node, at)?;
+                }
+                kind => return Err(format!("page {number} is not an index page ({kind})")),
+            }
+            if path.len() > MAX_DEPTH {
+                return Err("b-tree has a cycle".to_string());
+            }
+        }
+    }
+
+    /// Puts `node` on page `number`, which `path` leads to, splitting it when it does not
+    /// fit: all but the last part go to new pages, and a divider for each goes into the
+    /// parent, which may split in turn.
+    fn place(&mut self, mut path: Trail, number: u32, node: Node) -> Result<(), String> {
+        if self.fits(&node) {
+            self.write_node(number, &node);
+            return Ok(());
+        }
+        if number == 1 {
+            return Err("sqlite_master cannot grow in place".to_string());
+        }
+        let Some((parent, at)) = path.pop() else {
+            // The root keeps its page number, so its cells move to a new page under it.
+            let child = self.allocate();
+            let kind = match node.kind {
+                LEAF_TABLE_PAGE | INTERIOR_TABLE_PAGE => INTERIOR_TABLE_PAGE,
+                _ => INTERIOR_INDEX_PAGE,
+            };
+            let root = Node {
+                kind,
+                cells: Vec::new(),
+                right_child: Some(child),
+            };
+            self.write_node(number, &root);
+            return self.place(vec![(number, 0)], child, node);
+        };
+
+        let room = self.database.usable_size - header_size(node.kind);
+        let mut parts = Vec::new();
+        let mut dividers = Vec::new();
+        match node.kind {
+            LEAF_TABLE_PAGE => {
+                for cells in pack(&node.cells, |cell| cell.len() + 2, room) {
+                    let last = cells.last().expect("parts are never empty");
+                    dividers.push(varint(table_key(last, node.kind)? as u64));
+                    parts.push(Node {
+                        kind: node.kind,
+                        cells,
+                        right_child: None,
+                    });
+                }
+                dividers.pop();
+            }
+            LEAF_INDEX_PAGE => {
+                let (groups, ups) = split(node.cells, room);
+                dividers = ups;
+                parts = groups
+                    .into_iter()
+                    .map(|cells| Node {
+                        kind: node.kind,
+                        cells,
+                        right_child: None,
+                    })
+                    .collect();
+            }
+            _ => {
+                // An interior page's divider takes its left child along as the right child
+                // of the part before it.
+                let (groups, ups) = split(node.cells, room);
+                let mut right_children = ups
+                    .iter()
+                    .map(|divider| read_u32(divider, 0))
+                    .collect::<Result<Vec<_>, String>>()?;
+                right_children.push(
+                    node.right_child
+                        .ok_or("interior page without a right child")?,
+                );
+                dividers = ups
+                    .into_iter()
+                    .map(|mut divider| divider.split_off(4))
+                    .collect();
+                parts = groups
+                    .into_iter()
+                    .zip(right_children)
+                    .map(|(cells, right_child)| Node {
+                        kind: node.kind,
+                        cells,
+                        right_child: Some(right_child),
+                    })
+                    .collect();
+            }
+        }
+
+        let last = parts.pop().expect("a page splits into parts");
+        let mut cells = Vec::new();
+        for (part, divider) in parts.iter().zip(dividers) {
+            let page = self.allocate();
+            self.write_node(page, part);
+            let mut cell = page.to_be_bytes().to_vec();
+            cell.extend(divider);
+            cells.push(cell);
+        }
+        self.write_node(number, &last);
+        let mut parent_node = self.node(parent)?;
+        parent_node.cells.splice(at..at, cells);
+        self.place(path, parent, parent_node)
+    }
+
+    fn fits(&self, node: &Node) -> bool {
+        let cells = node.cells.iter().map(|cell| cell.len() + 2).sum::<usize>();
+        header_size(node.kind) + cells <= self.database.usable_size
+    }
+
+    fn node(&self, number: u32) -> Result<Node, String> {
+        let page = self.database.page(number)?;
+        let header = if number == 1 { HEADER_SIZE } else { 0 };
+        let kind = *page.get(header).ok_or("truncated page")?;
+        let interior = matches!(kind, INTERIOR_INDEX_PAGE | INTERIOR_TABLE_PAGE);
+        let count = read_u16(page, header + 3)? as usize;
+        let pointers = header + header_size(kind);
+        let mut cells = Vec::new();
+        for index in 0..count {
+            let offset = read_u16(page, pointers + index * 2)? as usize;
+            let size = self.cell_size(page, offset, kind)?;
+            cells.push(
+                page.get(offset..offset + size)
+                    .ok_or("cell runs past the end of its page")?
+                    .to_vec(),
+            );
+        }
+        Ok(Node {
+            kind,
+            cells,
+            right_child: interior.then(|| read_u32(page, header + 8)).transpose()?,
+        })
+    }
+
+    fn cell_size(&self, page: &[u8], offset: usize, kind: u8) -> Result<usize, String> {
+        let start = match kind {
+            INTERIOR_TABLE_PAGE => return Ok(4 + read_varint(page, offset + 4)?.1),
+            INTERIOR_INDEX_PAGE => offset + 4,
+            LEAF_TABLE_PAGE | LEAF_INDEX_PAGE => offset,
+            kind => return Err(format!("not a b-tree page ({kind})")),
+        };
+        let (size, mut read) = read_varint(page, start)?;
+        if kind == LEAF_TABLE_PAGE {
+            read += read_varint(page, start + read)?.1;
+        }
+        let size = size as usize;
+        let local = local_size(self.database.usable_size, size, kind != LEAF_TABLE_PAGE);
+        let overflow = if local < size { 4 } else { 0 };
+        Ok(start - offset + read + local + overflow)
+    }
+
+    /// Writes `node` onto page `number`, its cells packed at the end.
+    fn write_node(&mut self, number: u32, node: &Node) {
+        let page_size = self.database.page_size;
+        let usable = self.database.usable_size;
+        let start = (number as usize - 1) * page_size;
+        let page = &mut self.database.data[start..start + page_size];
+        let header = if number == 1 { HEADER_SIZE } else { 0 };
+        page[header..usable].fill(0);
+        page[header] = node.kind;
+        page[header + 3..header + 5].copy_from_slice(&(node.cells.len() as u16).to_be_bytes());
+        if let Some(child) = node.right_child {
+            page[header + 8..header + 12].copy_from_slice(&child.to_be_bytes());
+        }
+        let pointers = header + header_size(node.kind);
+        let mut content = usable;
+        for (index, cell) in node.cells.iter().enumerate() {
+            content -= cell.len();
+            page[content..content + cell.len()].copy_from_slice(cell);
+            let pointer = pointers + index * 2;
+            page[pointer..pointer + 2].copy_from_slice(&(content as u16).to_be_bytes());
+        }
+        // A 65536-byte page with nothing on it starts its content at 0.
+        page[header + 5..header + 7].copy_from_slice(&(content as u16).to_be_bytes());
+    }
+
+    /// A new page at the end of the file, skipping the one SQLite keeps its locks in.
+    fn allocate(&mut self) -> u32 {
+        let page_size = self.database.page_size;
+        let lock_page = PENDING_BYTE as usize / page_size + 1;
+        let mut number = self.database.data.len() / page_size + 1;
+        if number == lock_page {
+            number += 1;
+        }
+        self.database.data.resize(number * page_size, 0);
+        number as u32
+    }
+
+    /// Adds the pages of a cell's overflow chain to the freelist, each as a trunk page
+    /// listing no leaves.
+    fn free_overflow(&mut self, cell: &[u8]) -> Result<(), String> {
+        let (size, read) = read_varint(cell, 0)?;
+        let read = read + read_varint(cell, read)?.1;
+        let local = local_size(self.database.usable_size, size as usize, false);
+        if local == size as usize {
+            return Ok(());
+        }
+        let mut next = read_u32(cell, read + local)?;
+        let mut freed = 0;
+        while next != 0 {
+            freed += 1;
+            if freed > self.database.data.len() / self.database.page_size {
+                return Err("overflow chain has a cycle".to_string());
+            }
+            let number = next;
+            next = read_u32(self.database.page(number)?, 0)?;
+            let first_trunk = read_u32(&self.database.data, 32)?;
+            let free_pages = read_u32(&self.database.data, 36)?;
+            let start = (number as usize - 1) * self.database.page_size;
+            let page = &mut self.database.data[start..start + self.database.page_size];
+            page.fill(0);
+            page[..4].copy_from_slice(&first_trunk.to_be_bytes());
+            self.database.data[32..36].copy_from_slice(&number.to_be_bytes());
+            self.database.data[36..40].copy_from_slice(&(free_pages + 1).to_be_bytes());
+        }
+        Ok(())
+    }
+
+    fn table_cell(&mut self, rowid: i64, payload: &[u8]) -> Vec<u8> {
+        let mut cell = varint(payload.len() as u64);
+        cell.extend(varint(rowid as u64));
+        cell.extend(self.spill(payload, false));
+        cell
+    }
+
+    fn index_cell(&mut self, payload: &[u8]) -> Vec<u8> {
+        let mut cell = varint(payload.len() as u64);
+        cell.extend(self.spill(payload, true));
+        cell
+    }
+
+    /// The part of `payload` kept in its cell, followed by the first page of the overflow
+    /// chain the rest goes to, if it does not fit.
+    fn spill(&mut self, payload: &[u8], index: bool) -> Vec<u8> {
+        let usable = self.database.usable_size;
+        let local = local_size(usable, payload.len(), index);
+        let mut cell = payload[..local].to_vec();
+        let chunks = payload[local..].chunks(usable - 4).collect::<Vec<_>>();
+        let numbers = chunks.iter().map(|_| self.allocate()).collect::<Vec<_>>();
+        for (index, chunk) in chunks.iter().enumerate() {
+            let next = numbers.get(index + 1).copied().unwrap_or(0);
+            let start = (numbers[index] as usize - 1) * self.database.page_size;
+            let page = &mut self.database.data[start..start + self.database.page_size];
+            page[..4].copy_from_slice(&next.to_be_bytes());
+            page[4..4 + chunk.len()].copy_from_slice(chunk);
+        }
+        if let Some(first) = numbers.first() {
+            cell.extend_from_slice(&first.to_be_bytes());
+        }
+        cell
+    }
+
+    /// The record of an index cell, read through its overflow chain.
+    fn index_key(&self, cell: &[u8], interior: bool) -> Result<Vec<Value>, String> {
+        let start = if interior { 4 } else { 0 };
+        let (size, read) = read_varint(cell, start)?;
+        decode_record(
+            &self
+                .database
+                .payload(cell, start + read, size as usize, true)?,
+        )
+    }
+}
+
+/// Takes SQLite's exclusive lock, as a connection about to commit does: write locks on
+/// the RESERVED and PENDING bytes and on the shared range, which fail while anything
+/// else reads or writes the database. `false` where there are no such locks.
+#[cfg(unix)]
+fn lock(file: &File, path: &Path) -> Result<bool, String> {
+    let locked = set_lock(file, libc::F_WRLCK, RESERVED_BYTE, 1)
+        .and_then(|()| set_lock(file, libc::F_WRLCK, PENDING_BYTE, 1))
+        .and_then(|()| set_lock(file, libc::F_WRLCK, SHARED_FIRST, SHARED_SIZE));
+    match locked {
+        Ok(()) => Ok(true),
+        Err(err) => {
+            let _ = set_lock(file, libc::F_UNLCK, PENDING_BYTE, 2 + SHARED_SIZE);
+            Err(match err.kind() {
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::PermissionDenied => {
+                    format!(
+                        "Calibre or another program is using {}; try again when it is done",
+                        path.display()
+                    )
+                }
+                _ => format!("failed to lock {}: {err}", path.display()),
+            })
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_file: &File, _path: &Path) -> Result<bool, String> {
+    Ok(false)
+}
+
+#[cfg(unix)]
+fn set_lock(file: &File, kind: libc::c_int, start: u64, len: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: `flock` is plain data, for which all zeroes is a valid value.
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = kind as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = start as libc::off_t;
+    lock.l_len = len as libc::off_t;
+    // SAFETY: the descriptor is open for as long as `file` is, and `lock` outlives the call.
+    match unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock) } {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// The rollback journal of the database at `path`, e.g. `metadata.db-journal`.
+fn journal_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push("-journal");
+    PathBuf::from(name)
+}
+
+/// Makes the creation and deletion of files next to `path` durable, where the platform
+/// allows it.
+fn sync_directory(path: &Path) {
+    if let Some(Ok(directory)) = path.parent().map(File::open) {
+        let _ = directory.sync_all();
+    }
+}
+
+/// Page `index` of `data`, counting from 0.
+fn page(data: &[u8], index: usize, page_size: usize) -> Option<&[u8]> {
+    data.get(index * page_size..(index + 1) * page_size)
+}
+
+/// The size of a page header of `kind`, which for interior pages holds the right child.
+fn header_size(kind: u8) -> usize {
+    match kind {
+        INTERIOR_INDEX_PAGE | INTERIOR_TABLE_PAGE => 12,
+        _ => 8,
+    }
+}
+
+/// The rowid of a table leaf cell, or the key of a table interior cell.
+fn table_key(cell: &[u8], kind: u8) -> Result<i64, String> {
+    let at = match kind {
+        INTERIOR_TABLE_PAGE => 4,
+        _ => read_varint(cell, 0)?.1,
+    };
+    Ok(read_varint(cell, at)?.0 as i64)
+}
+
+/// The child of an interior page that the cell at `at` leads to, the right child past the
+/// last cell.
+fn child(node: &Node, at: usize) -> Result<u32, String> {
+    match node.cells.get(at) {
+        Some(cell) => read_u32(cell, 0),
+        None => node
+            .right_child
+            .ok_or_else(|| "interior page without a right child".to_string()),
+    }
+}
+
+/// Orders index entries by `columns`, in their collations.
+fn compare_keys(a: &[Value], b: &[Value], columns: &[(Option<usize>, Collation)]) -> Ordering {
+    columns
+        .iter()
+        .enumerate()
+        .map(|(at, (_, collation))| match (a.get(at), b.get(at)) {
+            (Some(a), Some(b)) => compare(a, b, *collation),
+            (a, b) => a.is_some().cmp(&b.is_some()),
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_rows_in_place_and_splits_full_pages() {
+        let scratch =
+            std::env::temp_dir().join(format!("calibre-transaction-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&scratch);
+        fs::create_dir_all(&scratch).unwrap();
+        let db = scratch.join("metadata.db");
+        fs::copy(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("../../tests/fixtures/test_library/metadata.db"),
+            &db,
+        )
+        .unwrap();
+        let before = Database::open(&db).unwrap();
+        let formats = |database: &Database| {
+            let data = database.table("data").unwrap();
+            data.rows()
+                .map(|row| {
+                    (
+                        row.integer("id").unwrap(),
+                        row.text("format").unwrap().to_string(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut expected = formats(&before);
+
+        // Enough rows to split the table's pages and its indexes' a few levels deep, some
+        // too long to fit on a page at all.
+        let mut transaction = Transaction::begin(&db, false).unwrap();
+        for id in 100..1100 {
+            let format = match id % 97 {
+                0 => format!("{}{id}", "X".repeat(9000)),
+                _ => format!("FORMAT{id}"),
+            };
+            let values = vec![
+                Value::Integer(id),
+                Value::Integer(id % 7),
+                Value::Text(format.clone()),
+                Value::Integer(id),
+                Value::Text("name".to_string()),
+            ];
+            transaction.insert("data", values).unwrap();
+            expected.push((id, format));
+        }
+        let err = transaction
+            .insert(
+                "data",
+                vec![
+                    Value::Integer(2000),
+                    Value::Integer(100 % 7),
+                    Value::Text("FORMAT100".into()),
+                    Value::Integer(0),
+                    Value::Null,
+                ],
+            )
+            .unwrap_err();
+        assert_eq!(err, "UNIQUE constraint failed: data.book, data.format");
+        assert_eq!(
+            transaction
+                .update("data", "book", Value::Integer(1), |_| true)
+                .unwrap_err(),
+            "data.book is in index sqlite_autoindex_data_1, and cannot be changed in place"
+        );
+        let long = "y".repeat(20_000);
+        let changed = transaction
+            .update("books", "isbn", Value::Text(long.clone()), |row| {
+                row.integer("id") == Some(1)
+            })
+            .unwrap();
+        assert_eq!(changed, 1);
+        transaction.commit().unwrap();
+        transaction
+            .update("books", "isbn", Value::Text("978".into()), |row| {
+                row.integer("id") == Some(1)
+            })
+            .unwrap();
+        transaction.commit().unwrap();
+        assert!(!journal_path(&db).exists());
+
+        let after = Database::open(&db).unwrap();
+        assert_eq!(formats(&after), expected);
+        let books = after.table("books").unwrap();
+        assert_eq!(books.rows().next().unwrap().text("isbn"), Some("978"));
+        // The four overflow pages of the long ISBN are free again.
+        assert_eq!(
+            read_u32(&after.data, 36).unwrap(),
+            read_u32(&before.data, 36).unwrap() + 4
+        );
+        assert_eq!(
+            read_u32(&after.data, 24).unwrap(),
+            read_u32(&before.data, 24).unwrap() + 2
+        );
+        for (index, count) in [
+            ("sqlite_autoindex_data_1", expected.len()),
+            ("idx_data_book", expected.len()),
+        ] {
+            let schema = after.table_schema("data").unwrap().clone();
+            let indexes = transaction.indexes(&schema).unwrap();
+            let index = indexes
+                .iter()
+                .find(|candidate| candidate.name == index)
+                .unwrap();
+            let mut entries = Vec::new();
+            after
+                .walk(index.root, &mut |_, payload| {
+                    entries.push(decode_record(payload)?);
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(entries.len(), count);
+            assert!(entries.windows(2).all(|pair| compare_keys(
+                &pair[0],
+                &pair[1],
+                &index.columns
+            )
+            .is_lt()));
+        }
+
+        transaction.undo().unwrap();
+        drop(transaction);
+        let undone = Database::open(&db).unwrap();
+        assert_eq!(formats(&undone), formats(&before));
+        assert_eq!(
+            format!("{:?}", undone.table("books")),
+            format!("{:?}", before.table("books"))
+        );
+
+        // Another connection reading the database keeps it from being locked.
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+            let reader = File::open(&db).unwrap();
+            // SAFETY: as in `set_lock`; an open file description lock stands in for another
+            // process's, as it conflicts with this process's own locks.
+            let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+            lock.l_type = libc::F_RDLCK as libc::c_short;
+            lock.l_whence = libc::SEEK_SET as libc::c_short;
+            lock.l_start = SHARED_FIRST as libc::off_t;
+            lock.l_len = SHARED_SIZE as libc::off_t;
+            assert_eq!(
+                unsafe { libc::fcntl(reader.as_raw_fd(), libc::F_OFD_SETLK, &lock) },
+                0
+            );
+            let err = Transaction::begin(&db, false).err().unwrap();
+            assert!(
+                err.starts_with("Calibre or another program is using"),
+                "{err}"
+            );
+            drop(reader);
+            Transaction::begin(&db, false).unwrap();
+        }
+
+        fs::remove_dir_all(&scratch).unwrap();
+    }
+}
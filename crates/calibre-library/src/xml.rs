@@ -0,0 +1,560 @@
+//! Just enough XML for the files inside an ebook: OPF packages, NCX and XHTML.
+//!
+//! Documents are read into an element tree and written back with [`Element::write`].
+//! DTDs are skipped, so only character references and the entities [`unescape`] knows
+//! are expanded.
+
+/// A problem with a document, at a byte offset into it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl Error {
+    fn new(offset: usize, message: impl Into<String>) -> Self {
+        Self {
+            offset,
+            message: message.into(),
+        }
+    }
+
+    /// The 1-based line and column of the problem in `source`.
+    pub fn line_column(&self, source: &str) -> (usize, usize) {
+        line_column(source, self.offset)
+    }
+}
+
+/// The 1-based line and column of a byte offset into `source`.
+pub fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let mut offset = offset.min(source.len());
+    while !source.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .count()
+        + 1;
+    (line, column)
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.offset)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Element {
+    /// The qualified name, prefix included.
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Node>,
+    /// Byte offset of the start tag in the document.
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Element {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            attributes: Vec::new(),
+            children: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    /// The name without its namespace prefix.
+    pub fn local_name(&self) -> &str {
+        local_name(&self.name)
+    }
+
+    pub fn is(&self, local: &str) -> bool {
+        self.local_name().eq_ignore_ascii_case(local)
+    }
+
+    /// An attribute by qualified name, or else by local name.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .or_else(|| {
+                self.attributes
+                    .iter()
+                    .find(|(key, _)| local_name(key) == name)
+            })
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Child elements.
+    pub fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|node| match node {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    /// The first child element with a local name.
+    pub fn child(&self, local: &str) -> Option<&Element> {
+        self.elements().find(|element| element.is(local))
+    }
+
+    /// Child elements with a local name.
+    pub fn children_named<'a>(&'a self, local: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.elements().filter(move |element| element.is(local))
+    }
+
+    /// This element and everything inside it, in document order.
+    pub fn descendants(&self) -> Vec<&Element> {
+        let mut found = vec![self];
+        for child in self.elements() {
+            found.extend(child.descendants());
+        }
+        found
+    }
+
+    /// The first element in document order with a local name, this one included.
+    pub fn find(&self, local: &str) -> Option<&Element> {
+        if self.is(local) {
+            return Some(self);
+        }
+        self.elements().find_map(|child| child.find(local))
+    }
+
+    /// All text inside the element, whitespace collapsed.
+    pub fn text(&self) -> String {
+        fn collect(element: &Element, text: &mut String) {
+            for node in &element.children {
+                match node {
+                    Node::Text(value) => text.push_str(value),
+                    Node::Element(child) => collect(child, text),
+                }
+            }
+        }
+        let mut text = String::new();
+        collect(self, &mut text);
+        text.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    /// Appends the element to `out` on lines of its own, indented four spaces per level
+    /// from `depth`. Elements holding only text keep it on their line; elsewhere text
+    /// that is only whitespace is dropped.
+    pub fn write(&self, out: &mut String, depth: usize) {
+        let indent = "    ".repeat(depth);
+        out.push_str(&indent);
+        out.push('<');
+        out.push_str(&self.name);
+        for (key, value) in &self.attributes {
+            out.push_str(&format!(" {key}=\"{}\"", escape(value)));
+        }
+        if self.children.is_empty() {
+            out.push_str("/>\n");
+            return;
+        }
+        out.push('>');
+        if self.elements().next().is_none() {
+            for node in &self.children {
+                if let Node::Text(value) = node {
+                    out.push_str(&escape(value));
+                }
+            }
+        } else {
+            out.push('\n');
+            for node in &self.children {
+                match node {
+                    Node::Element(child) => child.write(out, depth + 1),
+                    Node::Text(value) if !value.trim().is_empty() => {
+                        out.push_str(&format!("{indent}    {}\n", escape(value.trim())));
+                    }
+                    Node::Text(_) => {}
+                }
+            }
+            out.push_str(&indent);
+        }
+        out.push_str(&format!("</{}>\n", self.name));
+    }
+}
+
+/// `text` with `&`, `<`, `>` and `"` escaped, for element content and attribute values.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit_once(':').map_or(name, |(_, local)| local)
+}
+
+/// Parses a document into its root element. Tags must nest properly, and there must be
+/// exactly one root.
+pub fn parse(source: &str) -> Result<Element, Error> {
+    let mut reader = Reader::new(source);
+    // Open elements, innermost last; the bottom one collects the root.
+    let mut stack = vec![Element::new("")];
+    while let Some(token) = reader.next_token()? {
+        match token {
+            Token::Start(element, self_closing) => {
+                if stack.len() == 1 && stack[0].elements().next().is_some() {
+                    return Err(Error::new(element.offset, "more than one root element"));
+                }
+                if self_closing {
+                    push(&mut stack, Node::Element(element));
+                } else {
+                    stack.push(element);
+                }
+            }
+            Token::End(name, offset) => {
+                if stack.len() == 1 {
+                    return Err(Error::new(offset, format!("unexpected end tag </{name}>")));
+                }
+                let element = stack.pop().unwrap_or_else(|| Element::new(""));
+                if element.name != name {
+                    return Err(Error::new(
+                        offset,
+                        format!("end tag </{name}> does not match <{}>", element.name),
+                    ));
+                }
+                push(&mut stack, Node::Element(element));
+            }
+            Token::Text(value, offset) => {
+                if stack.len() == 1 {
+                    if !value.trim().is_empty() {
+                        return Err(Error::new(offset, "text outside the root element"));
+                    }
+                } else {
+                    push(&mut stack, Node::Text(value));
+                }
+            }
+        }
+    }
+    if stack.len() > 1 {
+        let open = stack.pop().unwrap_or_else(|| Element::new(""));
+        return Err(Error::new(
+            open.offset,
+            format!("<{}> is never closed", open.name),
+        ));
+    }
+    let root = stack.pop().unwrap_or_else(|| Element::new(""));
+    root.children
+        .into_iter()
+        .find_map(|node| match node {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+        .ok_or_else(|| Error::new(source.len(), "no root element"))
+}
+
+fn push(stack: &mut [Element], node: Node) {
+    if let Some(parent) = stack.last_mut() {
+        parent.children.push(node);
+    }
+}
+
+enum Token {
+    /// A start tag, and whether it closes itself.
+    Start(Element, bool),
+    End(String, usize),
+    Text(String, usize),
+}
+
+struct Reader<'a> {
+    source: &'a str,
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            source: source.strip_prefix('\u{feff}').unwrap_or(source),
+            position: 0,
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.position..]
+    }
+
+    /// Moves past `terminator`, which must come before the end of the document.
+    fn skip_past(&mut self, terminator: &str, what: &str) -> Result<&'a str, Error> {
+        let start = self.position;
+        let length = self
+            .rest()
+            .find(terminator)
+            .ok_or_else(|| Error::new(start, format!("unterminated {what}")))?;
+        self.position += length + terminator.len();
+        Ok(&self.source[start..start + length])
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>, Error> {
+        loop {
+            let rest = self.rest();
+            if rest.is_empty() {
+                return Ok(None);
+            }
+            let start = self.position;
+            if !rest.starts_with('<') {
+                let length = rest.find('<').unwrap_or(rest.len());
+                self.position += length;
+                let raw = &rest[..length];
+                for (amp, _) in raw.match_indices('&') {
+                    if !raw[amp + 1..]
+                        .split_once(';')
+                        .is_some_and(|(name, _)| is_reference(name))
+                    {
+                        return Err(Error::new(start + amp, "unescaped & in text"));
+                    }
+                }
+                return Ok(Some(Token::Text(unescape(raw), start)));
+            }
+
+            if rest.starts_with("<!--") {
+                self.position += 4;
+                self.skip_past("-->", "comment")?;
+            } else if rest.starts_with("<![CDATA[") {
+                self.position += 9;
+                let data = self.skip_past("]]>", "CDATA section")?;
+                return Ok(Some(Token::Text(data.to_string(), start)));
+            } else if rest.starts_with("<?") {
+                self.position += 2;
+                self.skip_past("?>", "processing instruction")?;
+            } else if rest.starts_with("<!") {
+                self.skip_declaration()?;
+            } else if let Some(rest) = rest.strip_prefix("</") {
+                let length = rest
+                    .find('>')
+                    .ok_or_else(|| Error::new(start, "unterminated end tag"))?;
+                let name = rest[..length].trim();
+                if !is_name(name) {
+                    return Err(Error::new(start, format!("invalid end tag </{name}>")));
+                }
+                self.position += 2 + length + 1;
+                return Ok(Some(Token::End(name.to_string(), start)));
+            } else {
+                return self.start_tag().map(Some);
+            }
+        }
+    }
+
+    /// Skips `<!DOCTYPE ...>`, including an internal subset in brackets.
+    fn skip_declaration(&mut self) -> Result<(), Error> {
+        let start = self.position;
+        let mut depth = 0usize;
+        for (index, c) in self.rest().char_indices() {
+            match c {
+                '[' => depth += 1,
+                ']' => depth = depth.saturating_sub(1),
+                '>' if depth == 0 => {
+                    self.position += index + 1;
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+        Err(Error::new(start, "unterminated declaration"))
+    }
+
+    fn start_tag(&mut self) -> Result<Token, Error> {
+        let start = self.position;
+        self.position += 1;
+        let name = self.name();
+        if name.is_empty() {
+            return Err(Error::new(start, "invalid start tag"));
+        }
+        let mut element = Element::new(name);
+        element.offset = start;
+        loop {
+            let skipped = self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("/>") {
+                self.position += 2;
+                return Ok(Token::Start(element, true));
+            }
+            if rest.starts_with('>') {
+                self.position += 1;
+                return Ok(Token::Start(element, false));
+            }
+            if rest.is_empty() {
+                return Err(Error::new(
+                    start,
+                    format!("unterminated start tag <{name}>"),
+                ));
+            }
+            if !skipped {
+                return Err(Error::new(
+                    self.position,
+                    format!("expected whitespace between attributes of <{name}>"),
+                ));
+            }
+
+            let attribute_start = self.position;
+            let key = self.name();
+            if key.is_empty() {
+                return Err(Error::new(
+                    attribute_start,
+                    format!("invalid attribute in <{name}>"),
+                ));
+            }
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return Err(Error::new(
+                    attribute_start,
+                    format!("attribute {key} of <{name}> has no value"),
+                ));
+            }
+            self.position += 1;
+            self.skip_whitespace();
+            let quote = self
+                .rest()
+                .chars()
+                .next()
+                .filter(|c| matches!(c, '"' | '\''))
+                .ok_or_else(|| {
+                    Error::new(
+                        attribute_start,
+                        format!("value of attribute {key} of <{name}> is not quoted"),
+                    )
+                })?;
+            self.position += 1;
+            let value = self.skip_past(&quote.to_string(), "attribute value")?;
+            if value.contains('<') {
+                return Err(Error::new(
+                    attribute_start,
+                    format!("< in value of attribute {key} of <{name}>"),
+                ));
+            }
+            if element
+                .attributes
+                .iter()
+                .any(|(existing, _)| existing == key)
+            {
+                return Err(Error::new(
+                    attribute_start,
+                    format!("attribute {key} appears twice in <{name}>"),
+                ));
+            }
+            element.attributes.push((key.to_string(), unescape(value)));
+        }
+    }
+
+    fn name(&mut self) -> &'a str {
+        let rest = self.rest();
+        let length = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+        self.position += length;
+        &rest[..length]
+    }
+
+    fn skip_whitespace(&mut self) -> bool {
+        let rest = self.rest();
+        let trimmed = rest.trim_start();
+        self.position += rest.len() - trimmed.len();
+        trimmed.len() < rest.len()
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, ':' | '_' | '-' | '.')
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(is_name_char)
+}
+
+/// Whether `name` is what can come between `&` and `;`.
+fn is_reference(name: &str) -> bool {
+    match name.strip_prefix('#') {
+        Some(number) => match number.strip_prefix('x') {
+            Some(hex) => !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()),
+            None => !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()),
+        },
+        None => is_name(name),
+    }
+}
+
+/// Character references, and the named entities that show up in Calibre comments.
+pub fn unescape(html: &str) -> String {
+    const NAMED: &[(&str, &str)] = &[
+        ("amp", "&"),
+        ("lt", "<"),
+        ("gt", ">"),
+        ("quot", "\""),
+        ("apos", "'"),
+        ("nbsp", "\u{a0}"),
+        ("ndash", "–"),
+        ("mdash", "—"),
+        ("hellip", "…"),
+        ("lsquo", "‘"),
+        ("rsquo", "’"),
+        ("ldquo", "“"),
+        ("rdquo", "”"),
+        ("laquo", "«"),
+        ("raquo", "»"),
+        ("copy", "©"),
+        ("reg", "®"),
+        ("trade", "™"),
+        ("eacute", "é"),
+        ("egrave", "è"),
+        ("aacute", "á"),
+        ("agrave", "à"),
+        ("ouml", "ö"),
+        ("uuml", "ü"),
+        ("auml", "ä"),
+        ("szlig", "ß"),
+    ];
+
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('&') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let reference = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 10)
+            .map(|end| &rest[1..end + 1]);
+        let replacement = reference.and_then(|name| {
+            if let Some(number) = name.strip_prefix('#') {
+                let code = match number.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => number.parse().ok(),
+                };
+                code.and_then(char::from_u32).map(String::from)
+            } else {
+                NAMED
+                    .iter()
+                    .find(|(entity, _)| *entity == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        });
+        match (reference, replacement) {
+            (Some(name), Some(replacement)) => {
+                text.push_str(&replacement);
+                rest = &rest[name.len() + 2..];
+            }
+            _ => {
+                text.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
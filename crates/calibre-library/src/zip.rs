@@ -0,0 +1,300 @@
+//! A reader and writer for zip archives, the container of EPUB and CBZ files.
+//!
+//! Entries are found through the central directory and may be stored or deflated. Zip64
+//! archives and encrypted entries are not supported; neither occurs in ebooks.
+
+use std::fs;
+use std::path::Path;
+
+use crate::deflate;
+use crate::inflate;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+/// Version 2.0, the first with deflate, as "version needed to extract".
+const VERSION: u16 = 20;
+/// General purpose flag: the entry name is UTF-8.
+const FLAG_UTF8: u16 = 1 << 11;
+/// MS-DOS date of 1980-01-01, the earliest there is. Written entries all carry it, so
+/// packing the same files twice gives the same archive.
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+pub const METHOD_STORED: u16 = 0;
+pub const METHOD_DEFLATED: u16 = 8;
+
+/// A file in the archive, as its central directory lists it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub method: u16,
+    pub crc32: u32,
+    pub compressed_size: u32,
+    pub size: u32,
+    /// Offset of the entry's local header.
+    pub offset: u32,
+}
+
+pub struct Archive {
+    data: Vec<u8>,
+    entries: Vec<Entry>,
+}
+
+impl Archive {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let data =
+            fs::read(path).map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+        Self::from_bytes(data).map_err(|err| format!("{}: {err}", path.display()))
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, String> {
+        let end = find_end_of_central_directory(&data).ok_or("not a zip archive")?;
+        let count = read_u16(&data, end + 10)? as usize;
+        let directory_offset = read_u32(&data, end + 16)? as usize;
+        if read_u32(&data, end + 16)? == u32::MAX {
+            return Err("Zip64 archives are not supported".to_string());
+        }
+
+        let mut entries = Vec::with_capacity(count);
+        let mut offset = directory_offset;
+        for _ in 0..count {
+            if read_u32(&data, offset)? != CENTRAL_HEADER_SIGNATURE {
+                return Err("corrupt zip central directory".to_string());
+            }
+            let flags = read_u16(&data, offset + 8)?;
+            let name_length = read_u16(&data, offset + 28)? as usize;
+            let extra_length = read_u16(&data, offset + 30)? as usize;
+            let comment_length = read_u16(&data, offset + 32)? as usize;
+            let name = data
+                .get(offset + 46..offset + 46 + name_length)
+                .ok_or("corrupt zip central directory")?;
+            if flags & 1 != 0 {
+                return Err(format!(
+                    "{} is encrypted, which is not supported",
+                    String::from_utf8_lossy(name)
+                ));
+            }
+            entries.push(Entry {
+                name: String::from_utf8_lossy(name).into_owned(),
+                method: read_u16(&data, offset + 10)?,
+                crc32: read_u32(&data, offset + 16)?,
+                compressed_size: read_u32(&data, offset + 20)?,
+                size: read_u32(&data, offset + 24)?,
+                offset: read_u32(&data, offset + 42)?,
+            });
+            offset += 46 + name_length + extra_length + comment_length;
+        }
+        Ok(Self { data, entries })
+    }
+
+    /// The entries in the order the central directory lists them.
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn entry(&self, name: &str) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// An entry's contents, decompressed and checked against its CRC.
+    pub fn read(&self, name: &str) -> Result<Vec<u8>, String> {
+        let entry = self
+            .entry(name)
+            .ok_or_else(|| format!("{name} is not in the archive"))?;
+        let compressed = self.raw(entry)?;
+        let contents = match entry.method {
+            METHOD_STORED => compressed.to_vec(),
+            METHOD_DEFLATED => inflate::inflate(compressed, entry.size as usize)
+                .map_err(|err| format!("{name}: {err}"))?,
+            method => {
+                return Err(format!(
+                    "{name} uses unsupported compression method {method}"
+                ))
+            }
+        };
+        if crc32(&contents) != entry.crc32 {
+            return Err(format!("{name} is corrupt: its checksum does not match"));
+        }
+        Ok(contents)
+    }
+
+    /// An entry's contents as UTF-8 text, dropping a byte order mark.
+    pub fn read_text(&self, name: &str) -> Result<String, String> {
+        let contents = self.read(name)?;
+        let contents = contents.strip_prefix(b"\xef\xbb\xbf").unwrap_or(&contents);
+        String::from_utf8(contents.to_vec()).map_err(|_| format!("{name} is not UTF-8 text"))
+    }
+
+    /// An entry's bytes as stored in the archive, after its local header.
+    fn raw(&self, entry: &Entry) -> Result<&[u8], String> {
+        let offset = entry.offset as usize;
+        if read_u32(&self.data, offset)? != LOCAL_HEADER_SIGNATURE {
+            return Err(format!("{}: corrupt local header", entry.name));
+        }
+        let name_length = read_u16(&self.data, offset + 26)? as usize;
+        let extra_length = read_u16(&self.data, offset + 28)? as usize;
+        let start = offset + 30 + name_length + extra_length;
+        self.data
+            .get(start..start + entry.compressed_size as usize)
+            .ok_or_else(|| format!("{} is truncated", entry.name))
+    }
+}
+
+/// An archive being written, entries in the order they are added.
+#[derive(Default)]
+pub struct Writer {
+    data: Vec<u8>,
+    entries: Vec<Entry>,
+}
+
+impl Writer {
+    /// Adds a file. Deflating falls back to storing when it would not make the file smaller.
+    pub fn add(&mut self, name: &str, contents: &[u8], method: u16) -> Result<(), String> {
+        let too_large = || format!("{name} is too large for a zip archive");
+        let deflated = match method {
+            METHOD_STORED => None,
+            METHOD_DEFLATED => {
+                Some(deflate::deflate(contents)).filter(|d| d.len() < contents.len())
+            }
+            method => return Err(format!("unsupported compression method {method}")),
+        };
+        let (method, stored) = match &deflated {
+            Some(deflated) => (METHOD_DEFLATED, deflated.as_slice()),
+            None => (METHOD_STORED, contents),
+        };
+        let entry = Entry {
+            name: name.to_string(),
+            method,
+            crc32: crc32(contents),
+            compressed_size: u32::try_from(stored.len()).map_err(|_| too_large())?,
+            size: u32::try_from(contents.len()).map_err(|_| too_large())?,
+            offset: u32::try_from(self.data.len()).map_err(|_| too_large())?,
+        };
+        self.data
+            .extend_from_slice(&LOCAL_HEADER_SIGNATURE.to_le_bytes());
+        self.header(&entry);
+        self.data.extend_from_slice(&0u16.to_le_bytes());
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.extend_from_slice(stored);
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// The archive, with its central directory.
+    pub fn finish(mut self) -> Result<Vec<u8>, String> {
+        let count = u16::try_from(self.entries.len())
+            .map_err(|_| "too many files for a zip archive".to_string())?;
+        let directory_offset = self.data.len();
+        for entry in std::mem::take(&mut self.entries) {
+            self.data
+                .extend_from_slice(&CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+            // Version made by, then the fields the local header has.
+            self.data.extend_from_slice(&VERSION.to_le_bytes());
+            self.header(&entry);
+            // Extra field, comment, disk number, internal and external attributes.
+            self.data.extend_from_slice(&[0; 12]);
+            self.data.extend_from_slice(&entry.offset.to_le_bytes());
+            self.data.extend_from_slice(entry.name.as_bytes());
+        }
+        let directory_size = u32::try_from(self.data.len() - directory_offset)
+            .map_err(|_| "zip central directory is too large".to_string())?;
+        let directory_offset =
+            u32::try_from(directory_offset).map_err(|_| "zip archive is too large".to_string())?;
+        self.data
+            .extend_from_slice(&END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+        self.data.extend_from_slice(&[0; 4]);
+        self.data.extend_from_slice(&count.to_le_bytes());
+        self.data.extend_from_slice(&count.to_le_bytes());
+        self.data.extend_from_slice(&directory_size.to_le_bytes());
+        self.data.extend_from_slice(&directory_offset.to_le_bytes());
+        self.data.extend_from_slice(&0u16.to_le_bytes());
+        Ok(self.data)
+    }
+
+    /// The fields local and central headers share, from "version needed to extract" to
+    /// the name length.
+    fn header(&mut self, entry: &Entry) {
+        let flags = if entry.name.is_ascii() { 0 } else { FLAG_UTF8 };
+        for field in [VERSION, flags, entry.method, 0, DOS_DATE] {
+            self.data.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [entry.crc32, entry.compressed_size, entry.size] {
+            self.data.extend_from_slice(&field.to_le_bytes());
+        }
+        self.data
+            .extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+    }
+}
+
+fn find_end_of_central_directory(data: &[u8]) -> Option<usize> {
+    if data.len() < END_OF_CENTRAL_DIRECTORY_SIZE {
+        return None;
+    }
+    // The record is followed by a comment of at most 65535 bytes.
+    let last = data.len() - END_OF_CENTRAL_DIRECTORY_SIZE;
+    let first = last.saturating_sub(u16::MAX as usize);
+    (first..=last)
+        .rev()
+        .find(|&offset| read_u32(data, offset).ok() == Some(END_OF_CENTRAL_DIRECTORY_SIGNATURE))
+}
+
+/// The CRC-32 checksum zip uses (IEEE 802.3, reflected).
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| "zip archive is truncated".to_string())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| "zip archive is truncated".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_archives_that_read_back() {
+        let chapter = "<p>Call me Ishmael.</p>\n".repeat(50);
+        let mut writer = Writer::default();
+        writer
+            .add("mimetype", b"application/epub+zip", METHOD_DEFLATED)
+            .unwrap();
+        writer
+            .add("OEBPS/chapter.xhtml", chapter.as_bytes(), METHOD_DEFLATED)
+            .unwrap();
+        writer.add("OEBPS/Émile.xhtml", b"", METHOD_STORED).unwrap();
+        let archive = Archive::from_bytes(writer.finish().unwrap()).unwrap();
+
+        let entries = archive.entries();
+        assert_eq!(entries.len(), 3);
+        // Too short to gain from deflating.
+        assert_eq!(entries[0].method, METHOD_STORED);
+        assert_eq!(entries[1].method, METHOD_DEFLATED);
+        assert!(entries[1].compressed_size < entries[1].size);
+        assert_eq!(
+            archive.read_text("mimetype").unwrap(),
+            "application/epub+zip"
+        );
+        assert_eq!(archive.read_text("OEBPS/chapter.xhtml").unwrap(), chapter);
+        assert_eq!(archive.read("OEBPS/Émile.xhtml").unwrap(), b"");
+    }
+}
@@ -0,0 +1,65 @@
+//! `calibre-recover`, which rebuilds a Calibre library's `metadata.db` from the
+//! `metadata.opf` sidecar and format files in each `Author/Title (id)/` directory, for
+//! when the database is lost or damaged.
+//!
+//! `calibre-recover <library> [<output>]` writes the new database to `<output>`, by
+//! default `metadata.recovered.db` in the library, and never to an existing file, so the
+//! original `metadata.db` is left as it was. Swap the new one in once it looks right.
+//! It exits with 2 when some books could not be rebuilt, and lists them.
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use calibre_library::recover;
+
+const USAGE: &str = "usage: calibre-recover <library> [<output>]";
+
+fn main() -> ExitCode {
+    let mut args = std::env::args_os().skip(1);
+    let (Some(library), output, None) = (args.next(), args.next(), args.next()) else {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    };
+    if library == "-h" || library == "--help" {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+    let library = PathBuf::from(library);
+    let output = output.map_or_else(|| library.join("metadata.recovered.db"), PathBuf::from);
+
+    let report = match recover::rebuild(&library, &output) {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("calibre-recover: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    println!(
+        "Rebuilt {} {} into {}.",
+        report.books,
+        if report.books == 1 { "book" } else { "books" },
+        output.display()
+    );
+    if !report.custom_columns.is_empty() {
+        println!(
+            "Values of custom columns were not rebuilt: {}.",
+            report.custom_columns.join(", ")
+        );
+    }
+    if report.failures.is_empty() {
+        return ExitCode::SUCCESS;
+    }
+    println!(
+        "Could not rebuild {} {}:",
+        report.failures.len(),
+        if report.failures.len() == 1 {
+            "book"
+        } else {
+            "books"
+        }
+    );
+    for (path, reason) in &report.failures {
+        println!("  {path}: {reason}");
+    }
+    ExitCode::from(2)
+}
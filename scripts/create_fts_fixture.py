@@ -0,0 +1,95 @@
+"""
+Create a test Calibre library with the full-text search tables Calibre keeps annotations in.
+
+Since Calibre 5, metadata.db holds an `annotations` table with two FTS5 indexes over it,
+`annotations_fts` and `annotations_fts_stemmed`, kept up to date by triggers. FTS5 stores
+each index in shadow tables, two of which are WITHOUT ROWID tables. This copies the test
+library's metadata.db, adds Calibre's annotation schema, and fills it with enough
+highlights, each committed on its own with merging off, that the shadow tables span
+several pages, interior ones included.
+
+The Rust writer's tests expect exactly this, so rerun it only to change them too.
+"""
+
+import json
+import shutil
+import sqlite3
+from pathlib import Path
+
+FIXTURES_DIR = Path(__file__).parent.parent / "tests" / "fixtures"
+FTS_LIBRARY_DIR = FIXTURES_DIR / "fts_library"
+
+# As in Calibre's resources/metadata_sqlite.sql.
+ANNOTATIONS_SCHEMA = """
+CREATE TABLE annotations ( id INTEGER PRIMARY KEY,
+    book INTEGER NOT NULL,
+    format TEXT NOT NULL COLLATE NOCASE,
+    user_type TEXT NOT NULL,
+    user TEXT NOT NULL,
+    timestamp REAL NOT NULL,
+    annot_id TEXT NOT NULL,
+    annot_type TEXT NOT NULL,
+    annot_data TEXT NOT NULL,
+    searchable_text TEXT NOT NULL DEFAULT "",
+    UNIQUE(book, user_type, user, format, annot_type, annot_id)
+);
+CREATE VIRTUAL TABLE annotations_fts USING fts5(searchable_text, content = 'annotations', content_rowid = 'id', tokenize = 'unicode61 remove_diacritics 2');
+CREATE VIRTUAL TABLE annotations_fts_stemmed USING fts5(searchable_text, content = 'annotations', content_rowid = 'id', tokenize = 'porter unicode61 remove_diacritics 2');
+CREATE TRIGGER annotations_fts_insert_trg AFTER INSERT ON annotations
+BEGIN
+    INSERT INTO annotations_fts(rowid, searchable_text) VALUES (NEW.id, NEW.searchable_text);
+    INSERT INTO annotations_fts_stemmed(rowid, searchable_text) VALUES (NEW.id, NEW.searchable_text);
+END;
+CREATE TRIGGER annotations_fts_delete_trg AFTER DELETE ON annotations
+BEGIN
+    INSERT INTO annotations_fts(annotations_fts, rowid, searchable_text) VALUES('delete', OLD.id, OLD.searchable_text);
+    INSERT INTO annotations_fts_stemmed(annotations_fts_stemmed, rowid, searchable_text) VALUES('delete', OLD.id, OLD.searchable_text);
+END;
+CREATE TRIGGER annotations_fts_update_trg AFTER UPDATE ON annotations
+BEGIN
+    INSERT INTO annotations_fts(annotations_fts, rowid, searchable_text) VALUES('delete', OLD.id, OLD.searchable_text);
+    INSERT INTO annotations_fts_stemmed(annotations_fts_stemmed, rowid, searchable_text) VALUES('delete', OLD.id, OLD.searchable_text);
+    INSERT INTO annotations_fts(rowid, searchable_text) VALUES (NEW.id, NEW.searchable_text);
+    INSERT INTO annotations_fts_stemmed(rowid, searchable_text) VALUES (NEW.id, NEW.searchable_text);
+END;
+"""
+
+HIGHLIGHTS = [
+    "It is a truth universally acknowledged",
+    "You have bewitched me, body and soul",
+    "There is nothing more deceptive than an obvious fact",
+    "The game is afoot",
+    "Education has for its object the formation of character",
+]
+
+
+def create_fts_fixture():
+    """Write tests/fixtures/fts_library/metadata.db."""
+    FTS_LIBRARY_DIR.mkdir(parents=True, exist_ok=True)
+    db_path = FTS_LIBRARY_DIR / "metadata.db"
+    shutil.copyfile(FIXTURES_DIR / "test_library" / "metadata.db", db_path)
+
+    conn = sqlite3.connect(str(db_path), isolation_level=None)
+    conn.executescript(ANNOTATIONS_SCHEMA)
+    for table in ("annotations_fts", "annotations_fts_stemmed"):
+        conn.execute(f"INSERT INTO {table}({table}, rank) VALUES ('automerge', 0)")
+        conn.execute(f"INSERT INTO {table}({table}, rank) VALUES ('crisismerge', 2000)")
+    # Small leaves give each segment several, and so `annotations_fts_idx` several pages.
+    conn.execute("INSERT INTO annotations_fts(annotations_fts, rank) VALUES ('pgsz', 32)")
+    conn.execute("PRAGMA user_version = 26")
+
+    for n in range(1, 301):
+        text = f"{HIGHLIGHTS[n % len(HIGHLIGHTS)]} (note {n}, word{n})"
+        data = {"type": "highlight", "uuid": f"annot-{n:04d}"}
+        conn.execute(
+            "INSERT INTO annotations (book, format, user_type, user, timestamp, annot_id,"
+            " annot_type, annot_data, searchable_text) VALUES (?, 'EPUB', 'local', 'viewer',"
+            " ?, ?, 'highlight', ?, ?)",
+            (n % 4 + 1, 1_700_000_000 + n, f"annot-{n:04d}", json.dumps(data), text),
+        )
+    conn.close()
+    print(f"Wrote {db_path}")
+
+
+if __name__ == "__main__":
+    create_fts_fixture()
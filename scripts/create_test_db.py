@@ -93,6 +93,26 @@ def create_test_database():
         )
     """)
 
+    # Publishers table
+    cursor.execute("""
+        CREATE TABLE publishers (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            sort TEXT
+        )
+    """)
+
+    # Identifiers table (ISBN, Project Gutenberg id, ...)
+    cursor.execute("""
+        CREATE TABLE identifiers (
+            id INTEGER PRIMARY KEY,
+            book INTEGER NOT NULL,
+            type TEXT NOT NULL DEFAULT 'isbn',
+            val TEXT NOT NULL,
+            UNIQUE(book, type)
+        )
+    """)
+
     # Data table (book formats)
     cursor.execute("""
         CREATE TABLE data (
@@ -133,6 +153,15 @@ def create_test_database():
         )
     """)
 
+    cursor.execute("""
+        CREATE TABLE books_publishers_link (
+            id INTEGER PRIMARY KEY,
+            book INTEGER NOT NULL,
+            publisher INTEGER NOT NULL,
+            UNIQUE(book)
+        )
+    """)
+
     cursor.execute("""
         CREATE TABLE books_ratings_link (
             id INTEGER PRIMARY KEY,
@@ -206,8 +235,18 @@ def create_test_database():
     ]
     cursor.executemany("INSERT INTO ratings (id, rating) VALUES (?, ?)", ratings)
 
+    # Insert sample publishers (first editions)
+    publishers = [
+        (1, "Ward Lock & Co", "Ward Lock & Co"),
+        (2, "Spencer Blackett", "Spencer Blackett"),
+        (3, "T. Egerton", "T. Egerton"),
+        (4, "American Publishing Company", "American Publishing Company"),
+    ]
+    cursor.executemany("INSERT INTO publishers (id, name, sort) VALUES (?, ?, ?)", publishers)
+
     # Insert sample books
-    # Format: (id, title, sort, path, flags, uuid, has_cover, series_index, author_sort, isbn, lccn)
+    # Format: (id, title, sort, path, flags, uuid, has_cover, pubdate, series_index, author_sort,
+    #          isbn, lccn)
     books = [
         (
             1,
@@ -217,6 +256,7 @@ def create_test_database():
             1,
             "test-uuid-1",
             0,
+            "1887-11-01 00:00:00+00:00",
             1.0,
             "Doyle, Arthur Conan",
             None,
@@ -230,6 +270,7 @@ def create_test_database():
             1,
             "test-uuid-2",
             0,
+            "1890-02-01 00:00:00+00:00",
             2.0,
             "Doyle, Arthur Conan",
             None,
@@ -243,6 +284,7 @@ def create_test_database():
             1,
             "test-uuid-3",
             0,
+            "1813-01-28 00:00:00+00:00",
             1.0,
             "Austen, Jane",
             None,
@@ -256,6 +298,7 @@ def create_test_database():
             1,
             "test-uuid-4",
             0,
+            "1876-06-01 00:00:00+00:00",
             1.0,
             "Twain, Mark",
             None,
@@ -265,7 +308,7 @@ def create_test_database():
     cursor.executemany(
         """
         INSERT INTO books (id, title, sort, path, flags, uuid, has_cover, pubdate, series_index, author_sort, isbn, lccn)
-        VALUES (?, ?, ?, ?, ?, ?, ?, CURRENT_TIMESTAMP, ?, ?, ?, ?)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
     """,
         books,
     )
@@ -307,6 +350,18 @@ def create_test_database():
         "INSERT INTO books_series_link (id, book, series) VALUES (?, ?, ?)", book_series
     )
 
+    # Link books to publishers
+    book_publishers = [
+        (1, 1, 1),  # A Study in Scarlet -> Ward Lock & Co
+        (2, 2, 2),  # The Sign of the Four -> Spencer Blackett
+        (3, 3, 3),  # Pride and Prejudice -> T. Egerton
+        (4, 4, 4),  # Tom Sawyer -> American Publishing Company
+    ]
+    cursor.executemany(
+        "INSERT INTO books_publishers_link (id, book, publisher) VALUES (?, ?, ?)",
+        book_publishers,
+    )
+
     # Link books to ratings
     book_ratings = [
         (1, 1, 5),  # A Study in Scarlet -> 5 stars
@@ -327,14 +382,26 @@ def create_test_database():
     ]
     cursor.executemany("INSERT INTO comments (id, book, text) VALUES (?, ?, ?)", comments)
 
-    # Insert format data (will match actual test files created by create_test_files.py)
+    # Insert identifiers (Project Gutenberg ebook numbers)
+    identifiers = [
+        (1, 1, "gutenberg", "244"),
+        (2, 2, "gutenberg", "2097"),
+        (3, 3, "gutenberg", "1342"),
+        (4, 4, "gutenberg", "74"),
+    ]
+    cursor.executemany(
+        "INSERT INTO identifiers (id, book, type, val) VALUES (?, ?, ?, ?)", identifiers
+    )
+
+    # Insert format data (will match actual test files created by create_test_files.py);
+    # name is the file name without extension, as in a real Calibre library
     format_data = [
-        (1, 1, "EPUB", 2456, ""),  # Minimal EPUB ~2KB
-        (2, 1, "PDF", 656, ""),  # Minimal PDF ~0.6KB
-        (3, 2, "EPUB", 2456, ""),  # Minimal EPUB ~2KB
-        (4, 3, "EPUB", 2456, ""),  # Minimal EPUB ~2KB
-        (5, 4, "EPUB", 2456, ""),  # Minimal EPUB ~2KB
-        (6, 4, "CBZ", 512, ""),  # Minimal CBZ ~0.5KB (comic format)
+        (1, 1, "EPUB", 2456, "1"),  # Minimal EPUB ~2KB
+        (2, 1, "PDF", 656, "1"),  # Minimal PDF ~0.6KB
+        (3, 2, "EPUB", 2456, "2"),  # Minimal EPUB ~2KB
+        (4, 3, "EPUB", 2456, "3"),  # Minimal EPUB ~2KB
+        (5, 4, "EPUB", 2456, "4"),  # Minimal EPUB ~2KB
+        (6, 4, "CBZ", 512, "4"),  # Minimal CBZ ~0.5KB (comic format)
     ]
     cursor.executemany(
         """
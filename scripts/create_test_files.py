@@ -4,10 +4,14 @@ Create minimal test book files (EPUB, PDF, CBZ) for testing.
 Creates very small but valid files that can be committed to GitHub.
 """
 
+import json
 import zipfile
 from pathlib import Path
+from xml.sax.saxutils import escape, quoteattr
 
 TEST_LIBRARY_DIR = Path(__file__).parent.parent / "tests" / "fixtures" / "test_library"
+# When the sidecars say the books were added
+TIMESTAMP = "2026-10-15T05:17:45+00:00"
 
 
 def create_minimal_epub(
@@ -240,6 +244,47 @@ def create_minimal_cbz(output_path: Path, title: str = "Test Comic", num_pages:
             cbz.writestr(page_filename, create_png_image())
 
 
+def create_metadata_opf(book_dir: Path, book: dict):
+    """Write the metadata.opf sidecar Calibre keeps beside a book's files (OPF 2.0)."""
+    lines = [
+        f'<dc:identifier opf:scheme="calibre" id="calibre_id">{book["id"]}</dc:identifier>',
+        f'<dc:identifier opf:scheme="uuid" id="uuid_id">{book["uuid"]}</dc:identifier>',
+        f"<dc:title>{escape(book['title'])}</dc:title>",
+        f'<dc:creator opf:file-as={quoteattr(book["author_sort"])} opf:role="aut">'
+        f"{escape(book['author'])}</dc:creator>",
+        f"<dc:date>{book['pubdate']}</dc:date>",
+        f"<dc:description>{escape(book['comments'])}</dc:description>",
+        f"<dc:publisher>{escape(book['publisher'])}</dc:publisher>",
+    ]
+    for scheme, value in book["identifiers"]:
+        lines.append(f'<dc:identifier opf:scheme="{scheme.upper()}">{value}</dc:identifier>')
+    lines.append("<dc:language>eng</dc:language>")
+    lines += [f"<dc:subject>{escape(tag)}</dc:subject>" for tag in book["tags"]]
+
+    metas = [("author_link_map", json.dumps({book["author"]: ""}))]
+    if book.get("series"):
+        metas += [("series", book["series"]), ("series_index", book["series_index"])]
+    metas += [
+        ("rating", book["rating"]),
+        ("timestamp", book["timestamp"]),
+        ("title_sort", book["sort"]),
+    ]
+    lines += [f'<meta name="calibre:{name}" content={quoteattr(value)}/>' for name, value in metas]
+
+    metadata = "\n".join(f"        {line}" for line in lines)
+    (book_dir / "metadata.opf").write_text(
+        f"""<?xml version='1.0' encoding='utf-8'?>
+<package xmlns="http://www.idpf.org/2007/opf" unique-identifier="uuid_id" version="2.0">
+    <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+{metadata}
+    </metadata>
+    <guide/>
+</package>
+""",
+        encoding="utf-8",
+    )
+
+
 def create_test_files():
     """Create all test files for the test library."""
 
@@ -253,6 +298,27 @@ def create_test_files():
     pdf1 = book1_dir / "1.pdf"
     create_minimal_pdf(pdf1, "A Study in Scarlet", num_pages=5)
 
+    create_metadata_opf(
+        book1_dir,
+        {
+            "id": 1,
+            "uuid": "test-uuid-1",
+            "title": "A Study in Scarlet",
+            "sort": "Study in Scarlet, A",
+            "author": "Arthur Conan Doyle",
+            "author_sort": "Doyle, Arthur Conan",
+            "pubdate": "1887-11-01T00:00:00+00:00",
+            "comments": "The first Sherlock Holmes novel, introducing the detective and Dr. Watson.",
+            "publisher": "Ward Lock & Co",
+            "identifiers": [("gutenberg", "244")],
+            "tags": ["mystery", "detective", "classic"],
+            "series": "Sherlock Holmes",
+            "series_index": "1",
+            "rating": "5.0",
+            "timestamp": TIMESTAMP,
+        },
+    )
+
     # Book 2: The Sign of the Four (EPUB)
     book2_dir = TEST_LIBRARY_DIR / "Arthur Conan Doyle" / "The Sign of the Four (2)"
     book2_dir.mkdir(parents=True, exist_ok=True)
@@ -260,6 +326,27 @@ def create_test_files():
     epub2 = book2_dir / "2.epub"
     create_minimal_epub(epub2, "The Sign of the Four", "Arthur Conan Doyle", num_pages=5)
 
+    create_metadata_opf(
+        book2_dir,
+        {
+            "id": 2,
+            "uuid": "test-uuid-2",
+            "title": "The Sign of the Four",
+            "sort": "Sign of the Four, The",
+            "author": "Arthur Conan Doyle",
+            "author_sort": "Doyle, Arthur Conan",
+            "pubdate": "1890-02-01T00:00:00+00:00",
+            "comments": "The second Sherlock Holmes novel.",
+            "publisher": "Spencer Blackett",
+            "identifiers": [("gutenberg", "2097")],
+            "tags": ["mystery", "detective", "classic"],
+            "series": "Sherlock Holmes",
+            "series_index": "2",
+            "rating": "4.0",
+            "timestamp": TIMESTAMP,
+        },
+    )
+
     # Book 3: Pride and Prejudice (EPUB)
     book3_dir = TEST_LIBRARY_DIR / "Jane Austen" / "Pride and Prejudice (3)"
     book3_dir.mkdir(parents=True, exist_ok=True)
@@ -267,6 +354,25 @@ def create_test_files():
     epub3 = book3_dir / "3.epub"
     create_minimal_epub(epub3, "Pride and Prejudice", "Jane Austen", num_pages=5)
 
+    create_metadata_opf(
+        book3_dir,
+        {
+            "id": 3,
+            "uuid": "test-uuid-3",
+            "title": "Pride and Prejudice",
+            "sort": "Pride and Prejudice",
+            "author": "Jane Austen",
+            "author_sort": "Austen, Jane",
+            "pubdate": "1813-01-28T00:00:00+00:00",
+            "comments": "A romantic novel of manners written by Jane Austen.",
+            "publisher": "T. Egerton",
+            "identifiers": [("gutenberg", "1342")],
+            "tags": ["classic", "romance"],
+            "rating": "5.0",
+            "timestamp": TIMESTAMP,
+        },
+    )
+
     # Book 4: Tom Sawyer (EPUB + CBZ for comic/manga test)
     book4_dir = TEST_LIBRARY_DIR / "Mark Twain" / "The Adventures of Tom Sawyer (4)"
     book4_dir.mkdir(parents=True, exist_ok=True)
@@ -278,6 +384,25 @@ def create_test_files():
     cbz4 = book4_dir / "4.cbz"
     create_minimal_cbz(cbz4, "The Adventures of Tom Sawyer", num_pages=5)
 
+    create_metadata_opf(
+        book4_dir,
+        {
+            "id": 4,
+            "uuid": "test-uuid-4",
+            "title": "The Adventures of Tom Sawyer",
+            "sort": "Adventures of Tom Sawyer, The",
+            "author": "Mark Twain",
+            "author_sort": "Twain, Mark",
+            "pubdate": "1876-06-01T00:00:00+00:00",
+            "comments": "A novel about a young boy growing up along the Mississippi River.",
+            "publisher": "American Publishing Company",
+            "identifiers": [("gutenberg", "74")],
+            "tags": ["classic", "adventure"],
+            "rating": "4.0",
+            "timestamp": TIMESTAMP,
+        },
+    )
+
     sum(f.stat().st_size for f in [epub1, pdf1, epub2, epub3, epub4, cbz4])
 
 
@@ -0,0 +1,89 @@
+"""
+Create a test Calibre library whose latest changes are still in its write-ahead log.
+
+Calibre and the server keep metadata.db in WAL mode, so committed transactions can sit
+in metadata.db-wal until the next checkpoint. This writes one book into metadata.db
+itself, then commits two more transactions that are only in the log, and copies both
+files out while the connection is still open, before SQLite checkpoints on close:
+
+1. renames book 1 and adds book 2 by a new author;
+2. adds 200 more books, enough to grow the books table past a single page.
+
+The Rust reader's tests expect exactly this, so rerun it only to change them too.
+"""
+
+import shutil
+import sqlite3
+import tempfile
+from pathlib import Path
+
+WAL_LIBRARY_DIR = Path(__file__).parent.parent / "tests" / "fixtures" / "wal_library"
+
+
+def create_wal_fixture():
+    """Write tests/fixtures/wal_library/metadata.db and metadata.db-wal."""
+    with tempfile.TemporaryDirectory() as scratch:
+        db_path = Path(scratch) / "metadata.db"
+        conn = sqlite3.connect(str(db_path), isolation_level=None)
+        conn.execute("PRAGMA page_size = 4096")
+        conn.execute("PRAGMA journal_mode = WAL")
+        conn.execute("PRAGMA wal_autocheckpoint = 0")
+        conn.executescript(
+            """
+            CREATE TABLE books (
+                id INTEGER PRIMARY KEY,
+                title TEXT NOT NULL DEFAULT 'Unknown',
+                sort TEXT,
+                timestamp TIMESTAMP,
+                pubdate TIMESTAMP,
+                series_index REAL NOT NULL DEFAULT 1.0,
+                author_sort TEXT,
+                path TEXT NOT NULL DEFAULT '',
+                uuid TEXT,
+                has_cover BOOL DEFAULT 0,
+                last_modified TIMESTAMP NOT NULL DEFAULT '2000-01-01 00:00:00+00:00'
+            );
+            CREATE TABLE authors (
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL COLLATE NOCASE,
+                sort TEXT COLLATE NOCASE,
+                link TEXT NOT NULL DEFAULT '',
+                UNIQUE(name)
+            );
+            CREATE TABLE books_authors_link (
+                id INTEGER PRIMARY KEY,
+                book INTEGER NOT NULL,
+                author INTEGER NOT NULL,
+                UNIQUE(book, author)
+            );
+            BEGIN;
+            INSERT INTO books (id, title, path) VALUES (1, 'Checkpointed Title', 'Ann (1)');
+            INSERT INTO authors (id, name, sort) VALUES (1, 'Ann Author', 'Author, Ann');
+            INSERT INTO books_authors_link (book, author) VALUES (1, 1);
+            COMMIT;
+            PRAGMA wal_checkpoint(TRUNCATE);
+
+            BEGIN;
+            UPDATE books SET title = 'Title From The WAL' WHERE id = 1;
+            INSERT INTO books (id, title, path) VALUES (2, 'Added In The WAL', 'Bea (2)');
+            INSERT INTO authors (id, name, sort) VALUES (2, 'Bea Writer', 'Writer, Bea');
+            INSERT INTO books_authors_link (book, author) VALUES (2, 2);
+            COMMIT;
+            """
+        )
+        conn.execute("BEGIN")
+        conn.executemany(
+            "INSERT INTO books (id, title, path) VALUES (?, ?, ?)",
+            [(n, f"Book {n} " + "padding " * 8, f"Bulk ({n})") for n in range(3, 203)],
+        )
+        conn.execute("COMMIT")
+
+        WAL_LIBRARY_DIR.mkdir(parents=True, exist_ok=True)
+        for name in ("metadata.db", "metadata.db-wal"):
+            shutil.copyfile(Path(scratch) / name, WAL_LIBRARY_DIR / name)
+        conn.close()
+    print(f"Wrote {WAL_LIBRARY_DIR}")
+
+
+if __name__ == "__main__":
+    create_wal_fixture()
@@ -0,0 +1,386 @@
+//! The project bibliography `/calibre-bibliography` keeps in the worktree: a BibTeX,
+//! CSL-JSON or RIS file of the books with a tag, in a series or matching a saved search,
+//! written again from `metadata.db` and compared entry by entry with what was there.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use calibre_library::cite::{KeyTemplate, Style};
+use calibre_library::metadata::{Book, MetadataDb};
+use serde_json::Value;
+
+use crate::search::{self, Query};
+
+/// File names by format, when `[bibliography]` gives a format but no file.
+pub const DEFAULT_FILES: &[(Style, &str)] = &[
+    (Style::Bibtex, "references.bib"),
+    (Style::CslJson, "references.json"),
+    (Style::Ris, "references.ris"),
+];
+
+/// What `[bibliography]` in the project config asks for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bibliography {
+    /// The file, resolved against the worktree root.
+    pub file: PathBuf,
+    /// BibTeX, CSL-JSON or RIS.
+    pub style: Style,
+    pub books: BookSet,
+}
+
+/// The books a bibliography holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BookSet {
+    /// Books with the tag or a tag under it, such as `Thesis.Sources` under `Thesis`.
+    Tag(String),
+    Series(String),
+    /// A search saved in Calibre, by name.
+    SavedSearch(String),
+}
+
+impl fmt::Display for BookSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tag(tag) => write!(f, "tag \"{tag}\""),
+            Self::Series(series) => write!(f, "series \"{series}\""),
+            Self::SavedSearch(name) => write!(f, "saved search \"{name}\""),
+        }
+    }
+}
+
+impl BookSet {
+    fn select<'a>(&self, books: &'a [Book], library: &Path) -> Result<Vec<&'a Book>, String> {
+        let same = |a: &str, b: &str| a.to_lowercase() == b.to_lowercase();
+        Ok(match self {
+            Self::Tag(tag) => {
+                let parent = format!("{}.", tag.to_lowercase());
+                books
+                    .iter()
+                    .filter(|book| {
+                        book.tags
+                            .iter()
+                            .any(|name| same(name, tag) || name.to_lowercase().starts_with(&parent))
+                    })
+                    .collect()
+            }
+            Self::Series(series) => books
+                .iter()
+                .filter(|book| {
+                    book.series
+                        .as_deref()
+                        .is_some_and(|name| same(name, series))
+                })
+                .collect(),
+            Self::SavedSearch(name) => {
+                let searches = MetadataDb::open(library)?.saved_searches()?;
+                let (_, query) = searches
+                    .iter()
+                    .find(|(saved, _)| same(saved, name))
+                    .ok_or_else(|| format!("the library has no saved search \"{name}\""))?;
+                let query =
+                    Query::parse(query).map_err(|err| format!("saved search \"{name}\": {err}"))?;
+                search::search(books, &query)
+            }
+        })
+    }
+}
+
+/// The bibliography as it should be now, and how it differs from the file on disk.
+pub struct Update {
+    pub text: String,
+    /// Entries in the new text.
+    pub count: usize,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added {
+        key: String,
+        entry: String,
+    },
+    Removed {
+        key: String,
+        entry: String,
+    },
+    /// The entry's lines, each marked ` `, `-` or `+`.
+    Changed {
+        key: String,
+        diff: Vec<String>,
+    },
+}
+
+impl Change {
+    /// `added`, `changed` or `removed`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Added { .. } => "added",
+            Self::Changed { .. } => "changed",
+            Self::Removed { .. } => "removed",
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Added { key, .. } | Self::Removed { key, .. } | Self::Changed { key, .. } => key,
+        }
+    }
+}
+
+impl Bibliography {
+    /// Builds the bibliography from `books` and compares it with `current`, the file's
+    /// text if it exists. Keys are made for the whole library, as `/calibre-cite` does,
+    /// so they are the same in both.
+    pub fn update(
+        &self,
+        books: &[Book],
+        library: &Path,
+        template: &KeyTemplate,
+        current: Option<&str>,
+    ) -> Result<Update, String> {
+        let selected = self.books.select(books, library)?;
+        if selected.is_empty() {
+            return Err(format!("no books match the {}", self.books));
+        }
+        let keys = template.keys(books);
+        let mut entries = selected
+            .into_iter()
+            .map(|book| {
+                let key = keys.get(&book.id).cloned().unwrap_or_default();
+                (book, key)
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|(_, a), (_, b)| a.cmp(b));
+        let entries = entries
+            .iter()
+            .map(|(book, key)| (*book, key.as_str()))
+            .collect::<Vec<_>>();
+        let mut text = self.style.document(&entries);
+        if self.style == Style::Bibtex {
+            // BibTeX ignores text outside entries; the other formats have no comments.
+            text.insert_str(
+                0,
+                &format!(
+                    "% Written by /calibre-bibliography from the Calibre {}.\n\
+                     % Edit the books in Calibre: changes here are overwritten.\n\n",
+                    self.books
+                ),
+            );
+        }
+
+        let new = split(self.style, &text);
+        let old = current
+            .map(|current| split(self.style, current))
+            .unwrap_or_default();
+        Ok(Update {
+            count: new.len(),
+            changes: changes(&old, &new),
+            text,
+        })
+    }
+}
+
+/// The entries of a bibliography file by key, in file order. Text outside entries, such
+/// as BibTeX comments, is left out; so is a CSL-JSON file that does not parse.
+fn split(style: Style, text: &str) -> Vec<(String, String)> {
+    match style {
+        Style::CslJson => {
+            let Ok(Value::Array(items)) = serde_json::from_str::<Value>(text) else {
+                return Vec::new();
+            };
+            items
+                .iter()
+                .map(|item| {
+                    let key = item["id"].as_str().unwrap_or_default().to_string();
+                    (key, serde_json::to_string_pretty(item).unwrap_or_default())
+                })
+                .collect()
+        }
+        Style::Ris => {
+            let mut entries = Vec::new();
+            let mut lines = Vec::new();
+            for line in text.lines() {
+                if lines.is_empty() && line.trim().is_empty() {
+                    continue;
+                }
+                lines.push(line);
+                if line.starts_with("ER  -") {
+                    let key = lines
+                        .iter()
+                        .find_map(|line| line.strip_prefix("ID  - "))
+                        .unwrap_or_default()
+                        .to_string();
+                    entries.push((key, lines.join("\n")));
+                    lines.clear();
+                }
+            }
+            entries
+        }
+        _ => {
+            let mut entries: Vec<(String, String)> = Vec::new();
+            for line in text.lines() {
+                if line.starts_with('@') {
+                    let key = line
+                        .split_once('{')
+                        .map(|(_, rest)| rest.split(',').next().unwrap_or_default().trim())
+                        .unwrap_or_default();
+                    entries.push((key.to_string(), line.to_string()));
+                } else if let Some((_, entry)) = entries.last_mut() {
+                    entry.push('\n');
+                    entry.push_str(line);
+                }
+            }
+            for (_, entry) in &mut entries {
+                entry.truncate(entry.trim_end().len());
+            }
+            entries
+        }
+    }
+}
+
+/// What turns `old` entries into `new` ones: additions and changes in the order of
+/// `new`, then removals.
+fn changes(old: &[(String, String)], new: &[(String, String)]) -> Vec<Change> {
+    let before: HashMap<&str, &str> = old
+        .iter()
+        .map(|(key, entry)| (key.as_str(), entry.as_str()))
+        .collect();
+    let after: HashMap<&str, &str> = new
+        .iter()
+        .map(|(key, entry)| (key.as_str(), entry.as_str()))
+        .collect();
+    let mut changes = Vec::new();
+    for (key, entry) in new {
+        match before.get(key.as_str()) {
+            None => changes.push(Change::Added {
+                key: key.clone(),
+                entry: entry.clone(),
+            }),
+            Some(old) if old != entry => changes.push(Change::Changed {
+                key: key.clone(),
+                diff: diff_lines(old, entry),
+            }),
+            Some(_) => {}
+        }
+    }
+    for (key, entry) in old {
+        if !after.contains_key(key.as_str()) {
+            changes.push(Change::Removed {
+                key: key.clone(),
+                entry: entry.clone(),
+            });
+        }
+    }
+    changes
+}
+
+/// A line diff of two short texts, from their longest common subsequence.
+fn diff_lines(old: &str, new: &str) -> Vec<String> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+    // common[i][j]: length of the longest common subsequence of old[i..] and new[j..].
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            diff.push(format!(" {}", old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            diff.push(format!("-{}", old[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+{}", new[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use calibre_library::metadata;
+
+    fn library() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/test_library")
+    }
+
+    fn bibliography(style: Style, books: BookSet) -> Bibliography {
+        Bibliography {
+            file: PathBuf::from("references"),
+            style,
+            books,
+        }
+    }
+
+    #[test]
+    fn builds_bibliographies_from_tags_and_series() {
+        let books = metadata::books(&library()).unwrap();
+        let template = KeyTemplate::default();
+        let holmes = bibliography(Style::Bibtex, BookSet::Tag("Mystery".to_string()));
+        let update = holmes.update(&books, &library(), &template, None).unwrap();
+        assert_eq!(update.count, 2);
+        assert!(update
+            .text
+            .starts_with("% Written by /calibre-bibliography from the Calibre tag \"Mystery\".\n"));
+        assert!(update.text.contains(".\n\n@book{doyle1887study,\n"));
+        assert!(update.text.contains("}\n\n@book{doyle1890sign,\n"));
+        let added = update.changes.iter().map(Change::key).collect::<Vec<_>>();
+        assert_eq!(added, ["doyle1887study", "doyle1890sign"]);
+
+        let unchanged = holmes
+            .update(&books, &library(), &template, Some(&update.text))
+            .unwrap();
+        assert!(unchanged.changes.is_empty());
+
+        let series = bibliography(
+            Style::CslJson,
+            BookSet::Series("sherlock holmes".to_string()),
+        );
+        let update = series.update(&books, &library(), &template, None).unwrap();
+        assert_eq!(update.count, 2);
+        assert!(update.text.starts_with("[\n  {\n"));
+
+        let nothing = bibliography(Style::Ris, BookSet::Tag("poetry".to_string()));
+        assert!(nothing.update(&books, &library(), &template, None).is_err());
+    }
+
+    #[test]
+    fn reports_changed_entries() {
+        let old = "% Thesis sources\n\
+                   @book{doyle1887study,\n  title = {A Study in Scarlet},\n  year = {1886},\n}\n\n\
+                   @book{stoker1897dracula,\n  title = {Dracula},\n}\n";
+        let new = "@book{doyle1887study,\n  title = {A Study in Scarlet},\n  year = {1887},\n}\n\n\
+                   @book{doyle1890sign,\n  title = {The Sign of the Four},\n}\n";
+        let changes = changes(&split(Style::Bibtex, old), &split(Style::Bibtex, new));
+        assert_eq!(changes.len(), 3);
+        assert_eq!(
+            changes[0],
+            Change::Changed {
+                key: "doyle1887study".to_string(),
+                diff: vec![
+                    " @book{doyle1887study,".to_string(),
+                    "   title = {A Study in Scarlet},".to_string(),
+                    "-  year = {1886},".to_string(),
+                    "+  year = {1887},".to_string(),
+                    " }".to_string(),
+                ],
+            }
+        );
+        assert_eq!(changes[1].key(), "doyle1890sign");
+        assert!(matches!(changes[1], Change::Added { .. }));
+        assert!(matches!(&changes[2], Change::Removed { key, .. } if key == "stoker1897dracula"));
+    }
+}
@@ -0,0 +1,234 @@
+"""
+Bootstrap for the calibre-mcp Zed extension.
+
+Written into the extension's work directory and run by Zed when the managed server
+install is missing or out of date, or comes from a bundle, which only this script can
+check for replacement. Creates the virtualenv, installs the requested
+calibre-mcp release into it, checks that `calibre_mcp` imports, records what was
+installed so the extension can launch the venv directly next time, and then replaces
+itself with the server process. A server that cannot open the library read-only is not
+started when read-only mode is on.
+
+Offline installs come from a wheel directory or a `.mcpb`/zip bundle. A bundle is
+unpacked and searched for wheels; a bundle that ships calibre-mcp as source, like the
+`mcpb/` package, is put on the venv's path with a .pth file instead.
+
+stdout belongs to the MCP stdio transport, so all progress goes to stderr.
+"""
+
+import argparse
+import json
+import os
+import re
+import shutil
+import subprocess
+import sys
+import venv
+import zipfile
+
+
+def bundle_stamp(bundle: str) -> str:
+    """Size and modification time of a bundle, so that a replaced bundle is reinstalled."""
+    stat = os.stat(bundle)
+    return f"{stat.st_size}-{int(stat.st_mtime)}"
+
+
+# Finds calibre_mcp/db/read_only.py in the venv without importing the server.
+READ_ONLY_CHECK = (
+    "import importlib.util, os, sys; "
+    "spec = importlib.util.find_spec('calibre_mcp'); "
+    "paths = (spec and spec.submodule_search_locations) or []; "
+    "sys.exit(not any(os.path.isfile(os.path.join(p, 'db', 'read_only.py')) for p in paths))"
+)
+
+
+def supports_read_only(python: str) -> bool:
+    """Whether the calibre-mcp installed for python has read-only mode."""
+    return subprocess.run([python, "-c", READ_ONLY_CHECK], stdout=sys.stderr).returncode == 0
+
+
+def read_only_requested() -> bool:
+    """Whether the extension asked for read-only mode, as calibre_mcp.db.read_only reads it."""
+    return os.environ.get("CALIBRE_READ_ONLY", "").strip().lower() in ("1", "true", "yes", "on")
+
+
+def venv_python(venv_dir: str) -> str:
+    """Path of the interpreter inside the managed virtualenv."""
+    if os.name == "nt":
+        return os.path.join(venv_dir, "Scripts", "python.exe")
+    return os.path.join(venv_dir, "bin", "python")
+
+
+def unpack_bundle(bundle: str, bundle_dir: str) -> str:
+    """Unpack a bundle into a fresh directory and return it."""
+    shutil.rmtree(bundle_dir, ignore_errors=True)
+    print(f"calibre-mcp: unpacking {bundle}", file=sys.stderr)
+    with zipfile.ZipFile(bundle) as archive:
+        archive.extractall(bundle_dir)
+    return bundle_dir
+
+
+def wheel_dirs(root: str) -> list[str]:
+    """Every directory under root that holds wheels or source distributions."""
+    return sorted(
+        dirpath
+        for dirpath, _, filenames in os.walk(root)
+        if any(name.endswith((".whl", ".tar.gz")) for name in filenames)
+    )
+
+
+def provides(dirs: list[str], requirement: str) -> bool:
+    """Whether any of dirs holds a distribution of the required project."""
+    project = re.split(r"[<>=!~ ;\[]", requirement, maxsplit=1)[0]
+    pattern = re.compile(re.escape(normalize(project)) + r"_\d")
+    return any(pattern.match(normalize(name)) for d in dirs for name in os.listdir(d))
+
+
+def normalize(name: str) -> str:
+    """PEP 503 normalization, also applied to file names so separators compare equal."""
+    return re.sub(r"[-_.]+", "_", name).lower()
+
+
+def source_paths(root: str) -> list[str]:
+    """Paths a source bundle needs on sys.path: its `src` and any vendored `lib` dirs.
+
+    Only the top two levels are searched, which covers the `src/` and `server/lib/`
+    layouts of MCP bundles without descending into the packages themselves.
+    """
+    paths = []
+    for dirpath, dirnames, _ in os.walk(root):
+        if os.path.relpath(dirpath, root).count(os.sep) >= 1:
+            dirnames.clear()
+        if os.path.isfile(os.path.join(dirpath, "calibre_mcp", "__init__.py")):
+            paths.append(dirpath)
+        paths.extend(os.path.join(dirpath, d) for d in dirnames if d == "lib")
+    return paths
+
+
+def install(args: argparse.Namespace, marker: dict) -> dict:
+    """
+    Create the virtualenv if needed, install the requested calibre-mcp release and return
+    the marker recording it, along with whether it has read-only mode.
+    """
+    python = venv_python(args.venv)
+    if not os.path.exists(python):
+        print(f"calibre-mcp: creating virtualenv in {args.venv}", file=sys.stderr)
+        venv.EnvBuilder(with_pip=True, clear=True).create(args.venv)
+
+    find_links = [args.wheel_dir] if args.wheel_dir else []
+    sources = []
+    if args.bundle:
+        root = unpack_bundle(args.bundle, args.bundle_dir)
+        find_links += wheel_dirs(root)
+        if not provides(find_links, args.requirement):
+            sources = source_paths(root)
+            if not sources:
+                raise RuntimeError(f"{args.bundle} has neither a calibre-mcp wheel nor its source")
+
+    command = [python, "-m", "pip", "install", "--disable-pip-version-check", "--upgrade"]
+    if args.index_url:
+        command += ["--index-url", args.index_url]
+    if args.wheel_dir or args.bundle:
+        command.append("--no-index")
+        for links in find_links:
+            command += ["--find-links", links]
+
+    if sources:
+        # calibre-mcp itself comes from source; only its bundled wheels need installing.
+        wheels = [
+            os.path.join(d, name)
+            for d in find_links
+            for name in os.listdir(d)
+            if name.endswith(".whl")
+        ]
+        if wheels:
+            print(f"calibre-mcp: installing {len(wheels)} bundled wheels", file=sys.stderr)
+            subprocess.run(command + wheels, check=True, stdout=sys.stderr)
+        site_packages = subprocess.run(
+            [python, "-c", "import sysconfig; print(sysconfig.get_path('purelib'))"],
+            check=True,
+            capture_output=True,
+            text=True,
+        ).stdout.strip()
+        pth = os.path.join(site_packages, "calibre-mcp-bundle.pth")
+        with open(pth, "w", encoding="utf-8") as f:
+            f.write("\n".join(sources) + "\n")
+    else:
+        print(f"calibre-mcp: installing {args.requirement}", file=sys.stderr)
+        subprocess.run(command + [args.requirement], check=True, stdout=sys.stderr)
+
+    verify = subprocess.run([python, "-c", "import calibre_mcp"], stdout=sys.stderr)
+    if verify.returncode != 0:
+        raise RuntimeError("calibre-mcp was installed, but `import calibre_mcp` failed")
+
+    marker = {**marker, "read_only": supports_read_only(python)}
+    with open(args.marker, "w", encoding="utf-8") as f:
+        json.dump(marker, f)
+    return marker
+
+
+def main() -> int:
+    parser = argparse.ArgumentParser(description="Install and launch the calibre-mcp server")
+    parser.add_argument("--venv", required=True, help="Virtualenv directory to manage")
+    parser.add_argument("--marker", required=True, help="Install marker shared with the extension")
+    parser.add_argument("--requirement", required=True, help="pip requirement to install")
+    parser.add_argument("--index-url", default=None, help="Package index to install from")
+    parser.add_argument("--wheel-dir", default=None, help="Local wheel directory to install from")
+    parser.add_argument("--bundle", default=None, help=".mcpb or zip bundle to install from")
+    parser.add_argument("--bundle-dir", default=None, help="Directory to unpack the bundle into")
+    args, server_args = parser.parse_known_args()
+
+    # The extension passes the settings on unchecked, as it cannot see outside its work dir.
+    if args.wheel_dir and not os.path.isdir(args.wheel_dir):
+        print(f"calibre-mcp: `wheel_dir` {args.wheel_dir} is not a directory", file=sys.stderr)
+        return 1
+    if args.bundle and not os.path.isfile(args.bundle):
+        print(f"calibre-mcp: `wheel_bundle` {args.bundle} does not exist", file=sys.stderr)
+        return 1
+
+    marker = {
+        "requirement": args.requirement,
+        "index_url": args.index_url,
+        "wheel_dir": args.wheel_dir,
+        "wheel_bundle": args.bundle,
+        "bundle_stamp": bundle_stamp(args.bundle) if args.bundle else None,
+    }
+
+    try:
+        with open(args.marker, encoding="utf-8") as f:
+            installed = json.load(f)
+    except (OSError, ValueError):
+        installed = None
+
+    # The marker also records what was found out about the install, like read_only.
+    if not isinstance(installed, dict) or any(installed.get(k) != v for k, v in marker.items()):
+        try:
+            installed = install(args, marker)
+        except (OSError, RuntimeError, subprocess.CalledProcessError, zipfile.BadZipFile) as e:
+            print(f"calibre-mcp: install of {args.requirement} failed: {e}", file=sys.stderr)
+            return 1
+
+    python = venv_python(args.venv)
+    if read_only_requested():
+        # Markers from before read_only was recorded do not say.
+        read_only = installed.get("read_only")
+        if read_only is None:
+            read_only = supports_read_only(python)
+        if not read_only:
+            print(
+                f"calibre-mcp: the installed {args.requirement} cannot open the library "
+                "read-only; install a release that can or set `read_only` to false",
+                file=sys.stderr,
+            )
+            return 1
+
+    command = [python, "-m", "calibre_mcp", *server_args]
+    if os.name == "nt":
+        # Windows has no exec: os.execv starts the server and exits, and Zed sees it stop.
+        return subprocess.call(command)
+    os.execv(python, command)
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
@@ -0,0 +1,175 @@
+"""
+stdio to Streamable HTTP bridge for the calibre-mcp Zed extension.
+
+Written into the extension's work directory and run by Zed in remote mode, when the
+server is a calibre-mcp instance started elsewhere with `--http`. Zed talks MCP over
+this process's stdin and stdout; every message is POSTed to the remote endpoint, and
+JSON or server-sent event responses are written back one message per line.
+
+The bearer token comes from CALIBRE_MCP_BEARER_TOKEN rather than argv, so it does not
+show up in process listings. Only the standard library is used, so any Python 3.9+ runs it.
+"""
+
+from __future__ import annotations
+
+import argparse
+import json
+import os
+import sys
+import threading
+import urllib.error
+import urllib.request
+
+PROTOCOL_VERSION_HEADER = "MCP-Protocol-Version"
+SESSION_HEADER = "Mcp-Session-Id"
+TOKEN_ENV = "CALIBRE_MCP_BEARER_TOKEN"
+
+
+class Bridge:
+    """One MCP session with the remote endpoint."""
+
+    def __init__(self, url: str, token: str | None) -> None:
+        self.url = url
+        self.token = token
+        self.session_id: str | None = None
+        self.protocol_version: str | None = None
+        self.stdout_lock = threading.Lock()
+
+    def headers(self, accept: str) -> dict:
+        headers = {"Accept": accept, "Content-Type": "application/json"}
+        if self.token:
+            headers["Authorization"] = f"Bearer {self.token}"
+        if self.session_id:
+            headers[SESSION_HEADER] = self.session_id
+        if self.protocol_version:
+            headers[PROTOCOL_VERSION_HEADER] = self.protocol_version
+        return headers
+
+    def write(self, message) -> None:
+        """Write one message to Zed; stdout is shared by every request thread."""
+        line = json.dumps(message, separators=(",", ":"))
+        with self.stdout_lock:
+            sys.stdout.write(line + "\n")
+            sys.stdout.flush()
+
+    def post(self, message) -> None:
+        """Forward a message and relay whatever the endpoint answers with."""
+        request = urllib.request.Request(
+            self.url,
+            data=json.dumps(message).encode("utf-8"),
+            headers=self.headers("application/json, text/event-stream"),
+            method="POST",
+        )
+        try:
+            with urllib.request.urlopen(request) as response:
+                session_id = response.headers.get(SESSION_HEADER)
+                if session_id:
+                    self.session_id = session_id
+                content_type = response.headers.get("Content-Type", "")
+                if response.status == 202:
+                    return
+                if content_type.startswith("text/event-stream"):
+                    self.relay_events(response, message)
+                else:
+                    body = response.read().decode("utf-8").strip()
+                    if body:
+                        self.relay(json.loads(body), message)
+        except urllib.error.HTTPError as e:
+            if e.code == 401:
+                reason = f"{self.url} rejected the bearer token (401)"
+            elif e.code == 404 and self.session_id:
+                reason = f"{self.url} no longer knows session {self.session_id}"
+            else:
+                reason = f"{self.url} answered {e.code} {e.reason}"
+            self.fail(message, reason)
+        except (OSError, ValueError) as e:
+            self.fail(message, f"request to {self.url} failed: {e}")
+
+    def relay(self, reply, message) -> None:
+        if is_request(message, "initialize") and isinstance(reply, dict):
+            version = reply.get("result", {}).get("protocolVersion")
+            if version:
+                self.protocol_version = version
+        self.write(reply)
+
+    def relay_events(self, response, message) -> None:
+        """Relay each `data:` event of a server-sent event stream as one message."""
+        data: list[str] = []
+        for raw in response:
+            line = raw.decode("utf-8").rstrip("\r\n")
+            if line.startswith("data:"):
+                data.append(line[5:].removeprefix(" "))
+            elif not line and data:
+                self.relay(json.loads("\n".join(data)), message)
+                data = []
+        if data:
+            self.relay(json.loads("\n".join(data)), message)
+
+    def fail(self, message, reason: str) -> None:
+        """Answer a failed request with a JSON-RPC error so Zed does not wait forever."""
+        print(f"calibre-mcp bridge: {reason}", file=sys.stderr)
+        if is_request(message) and "id" in message:
+            self.write(
+                {
+                    "jsonrpc": "2.0",
+                    "id": message["id"],
+                    "error": {"code": -32603, "message": reason},
+                }
+            )
+
+    def close(self) -> None:
+        """End the session so the remote server can free it."""
+        if not self.session_id:
+            return
+        request = urllib.request.Request(
+            self.url, headers=self.headers("application/json"), method="DELETE"
+        )
+        try:
+            urllib.request.urlopen(request).close()
+        except (OSError, ValueError):
+            pass
+
+
+def is_request(message, method: str | None = None) -> bool:
+    """Whether a message is a single JSON-RPC request or notification, optionally of `method`."""
+    if not isinstance(message, dict) or "method" not in message:
+        return False
+    return method is None or message["method"] == method
+
+
+def main() -> int:
+    parser = argparse.ArgumentParser(description="Bridge MCP stdio to a Streamable HTTP server")
+    parser.add_argument("--url", required=True, help="Streamable HTTP endpoint of the server")
+    args = parser.parse_args()
+
+    bridge = Bridge(args.url, os.environ.get(TOKEN_ENV) or None)
+    threads = []
+    for line in sys.stdin:
+        line = line.strip()
+        if not line:
+            continue
+        try:
+            message = json.loads(line)
+        except ValueError as e:
+            print(f"calibre-mcp bridge: ignoring malformed message: {e}", file=sys.stderr)
+            continue
+
+        # The session id arrives with the initialize response, so nothing else may go out
+        # before it. Notifications and responses go out in order, `notifications/initialized`
+        # first; requests run concurrently so a slow tool call does not hold up the rest.
+        if is_request(message, "initialize") or not (is_request(message) and "id" in message):
+            bridge.post(message)
+        else:
+            threads = [thread for thread in threads if thread.is_alive()]
+            thread = threading.Thread(target=bridge.post, args=(message,), daemon=True)
+            thread.start()
+            threads.append(thread)
+
+    for thread in threads:
+        thread.join()
+    bridge.close()
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
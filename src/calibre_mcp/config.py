@@ -23,6 +23,23 @@ from .logging_config import get_logger, log_error, log_operation
 logger = get_logger("calibremcp.config")
 
 
+def accept_library_as_base_path() -> None:
+    """
+    Let CALIBRE_BASE_PATH name a library itself, not only a directory of libraries.
+
+    The Zed extension cannot always look inside the path it is configured with, so it
+    passes a library there as it is. Such a path is turned into its parent directory plus
+    CALIBRE_LIBRARY_NAME before anything reads the environment.
+    """
+    base_path = os.environ.get("CALIBRE_BASE_PATH", "").strip().strip('"')
+    if not base_path or os.environ.get("CALIBRE_LIBRARY_NAME"):
+        return
+    path = Path(base_path)
+    if (path / "metadata.db").is_file():
+        os.environ["CALIBRE_BASE_PATH"] = str(path.parent)
+        os.environ["CALIBRE_LIBRARY_NAME"] = path.name
+
+
 class RemoteServerConfig(BaseModel):
     """Configuration for a remote Calibre content server"""
 
@@ -11,6 +11,7 @@ from sqlalchemy import create_engine, event
 from sqlalchemy.engine import Engine
 from sqlalchemy.orm import Session, scoped_session, sessionmaker
 
+from . import read_only
 from .models import Base
 from .repositories import AuthorRepository, BookRepository, LibraryRepository
 
@@ -72,7 +73,10 @@ class DatabaseService:
         try:
             if "://" not in db_url and os.path.exists(db_url):
                 abs_path = os.path.abspath(db_url).replace("\\", "/")
-                db_url = f"sqlite:///{abs_path}"
+                if read_only.is_enabled():
+                    db_url = read_only.sqlalchemy_url(abs_path)
+                else:
+                    db_url = f"sqlite:///{abs_path}"
                 self._current_db_path = abs_path
             else:
                 # Extract path from SQLite URL if it's already a URL
@@ -104,6 +108,13 @@ class DatabaseService:
             pool_recycle=3600,
         )
 
+        if read_only.is_enabled() and "sqlite" in db_url:
+            raw = self._engine.raw_connection()
+            try:
+                read_only.assert_read_only(raw)
+            finally:
+                raw.close()
+
         # Create session factory
         self._session_factory = scoped_session(
             sessionmaker(autocommit=False, autoflush=False, bind=self._engine)
@@ -116,13 +127,16 @@ class DatabaseService:
             "library": LibraryRepository(self),
         }
 
-        # Enable WAL mode for SQLite
+        # Enable WAL mode for SQLite; a read-only database keeps its journal mode
         if "sqlite" in db_url:
 
             @event.listens_for(Engine, "connect")
             def set_sqlite_pragma(dbapi_connection, connection_record):
                 cursor = dbapi_connection.cursor()
-                cursor.execute("PRAGMA journal_mode=WAL")
+                if read_only.is_enabled():
+                    cursor.execute("PRAGMA query_only=ON")
+                else:
+                    cursor.execute("PRAGMA journal_mode=WAL")
                 cursor.execute("PRAGMA synchronous=NORMAL")
                 cursor.execute("PRAGMA cache_size=-2000")
                 cursor.execute("PRAGMA temp_store=MEMORY")
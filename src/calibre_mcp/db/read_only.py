@@ -0,0 +1,102 @@
+"""
+Read-only mode for metadata.db.
+
+The Zed extension turns this on with CALIBRE_READ_ONLY=1, by default for shared
+libraries, and passes the library's database as a read-only SQLite URI in
+CALIBRE_METADATA_DB_URI. Every metadata.db is then opened with `mode=ro` and
+`query_only`, and the server refuses to start if a write still gets through.
+"""
+
+import os
+import sqlite3
+import sys
+from urllib.parse import quote, unquote
+
+ENABLED_ENV = "CALIBRE_READ_ONLY"
+URI_ENV = "CALIBRE_METADATA_DB_URI"
+
+
+class ReadOnlyError(RuntimeError):
+    """Read-only mode was requested but cannot be honoured."""
+
+
+def is_enabled() -> bool:
+    """Whether read-only mode was requested."""
+    return os.environ.get(ENABLED_ENV, "").strip().lower() in ("1", "true", "yes", "on")
+
+
+def sqlite_uri(db_path: str) -> str:
+    """Read-only SQLite URI for a database file, reusing the extension's URI for it."""
+    given = os.environ.get(URI_ENV)
+    if given and os.path.normcase(uri_path(given)) == os.path.normcase(os.path.abspath(db_path)):
+        return given
+    path = os.path.abspath(db_path).replace("\\", "/")
+    if not path.startswith("/"):
+        path = "/" + path
+    return f"file://{quote(path, safe='/:')}?mode=ro"
+
+
+def uri_path(uri: str) -> str:
+    """The file path of a `file:` URI."""
+    path = unquote(uri.split("?", 1)[0].removeprefix("file:"))
+    if path.startswith("//"):
+        path = path[2:]
+    if len(path) > 2 and path[0] == "/" and path[2] == ":":
+        path = path[1:]
+    return os.path.abspath(path)
+
+
+def sqlalchemy_url(db_path: str) -> str:
+    """SQLAlchemy URL opening a database file read-only."""
+    return f"sqlite:///{sqlite_uri(db_path)}&uri=true"
+
+
+def assert_read_only(connection) -> None:
+    """Raise ReadOnlyError if a write on a DB-API connection would succeed."""
+    try:
+        version = connection.execute("PRAGMA user_version").fetchone()[0]
+        connection.execute(f"PRAGMA user_version = {int(version)}")
+    except sqlite3.OperationalError:
+        return
+    raise ReadOnlyError("metadata.db accepted a write although read-only mode is on")
+
+
+def _configured_uri() -> str | None:
+    """
+    The read-only URI of the library in CALIBRE_BASE_PATH and CALIBRE_LIBRARY_NAME.
+
+    The extension builds CALIBRE_METADATA_DB_URI from the library path without looking
+    inside it, so when that path is a directory of libraries the URI names no file, and
+    the check falls back to the library that will be opened.
+    """
+    base_path = os.environ.get("CALIBRE_BASE_PATH", "").strip().strip('"')
+    name = os.environ.get("CALIBRE_LIBRARY_NAME", "").strip()
+    if not base_path or not name:
+        return None
+    db_path = os.path.join(base_path, name, "metadata.db")
+    return sqlite_uri(db_path) if os.path.isfile(db_path) else None
+
+
+def ensure_supported() -> None:
+    """
+    Check at startup that read-only mode works for the extension's database.
+
+    Exits with a message on the original stderr, which the stdio transport leaves alone,
+    so Zed reports why the server did not start.
+    """
+    if not is_enabled():
+        return
+    uri = os.environ.get(URI_ENV)
+    if not uri or not os.path.isfile(uri_path(uri)):
+        uri = _configured_uri()
+    if not uri:
+        return
+    try:
+        connection = sqlite3.connect(uri, uri=True)
+        try:
+            assert_read_only(connection)
+        finally:
+            connection.close()
+    except (sqlite3.Error, ReadOnlyError) as e:
+        print(f"calibre-mcp: cannot honour read-only mode for {uri}: {e}", file=sys.__stderr__)
+        raise SystemExit(2) from e
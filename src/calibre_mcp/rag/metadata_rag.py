@@ -14,6 +14,7 @@ from typing import Any
 
 from calibre_mcp.db.database import get_database
 from calibre_mcp.db.models import Book, Comment
+from calibre_mcp.rag.storage_paths import index_root
 from calibre_mcp.rag.text_utils import (
     get_comment_max_chars,
     should_strip_html_metadata,
@@ -188,10 +189,7 @@ def _get_extended_metadata_text(book_id: int, library_path: str) -> str:
 
 def get_metadata_rag_path(metadata_db_path: str | Path) -> Path:
     """Return LanceDB path for metadata RAG (next to library or in configurable dir)."""
-    p = Path(metadata_db_path).resolve()
-    if p.is_file():
-        p = p.parent
-    return p / "lancedb_metadata"
+    return index_root(metadata_db_path) / "lancedb_metadata"
 
 
 def build_metadata_index(
@@ -2,19 +2,27 @@
 
 from __future__ import annotations
 
+import os
 from pathlib import Path
 
+# Set by the Zed extension from a project's `.calibre-mcp.toml` `rag_index`.
+INDEX_DIR_ENV = "CALIBRE_RAG_INDEX_DIR"
 
-def _library_dir(metadata_db_path: str | Path) -> Path:
+
+def index_root(metadata_db_path: str | Path) -> Path:
+    """Directory the indexes go in: CALIBRE_RAG_INDEX_DIR if set, else the library's."""
+    index_dir = os.environ.get(INDEX_DIR_ENV, "").strip()
+    if index_dir:
+        return Path(index_dir)
     p = Path(metadata_db_path).resolve()
     return p.parent if p.is_file() else p
 
 
 def fts_chunks_lancedb_dir(metadata_db_path: str | Path) -> Path:
     """Calibre FTS text chunks → semantic index (table ``books_rag``)."""
-    return _library_dir(metadata_db_path) / "lancedb"
+    return index_root(metadata_db_path) / "lancedb"
 
 
 def portmanteau_lancedb_dir(metadata_db_path: str | Path) -> Path:
     """Neural ingest: tables ``calibre_media``, ``calibre_fulltext``."""
-    return _library_dir(metadata_db_path) / "lancedb_calibre"
+    return index_root(metadata_db_path) / "lancedb_calibre"
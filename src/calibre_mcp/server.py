@@ -710,6 +710,16 @@ async def main():
             logger.exception(f"ERROR: MCP instance verification failed: {mcp_error}")
             raise
 
+        # The Zed extension may pass a library itself as CALIBRE_BASE_PATH
+        from calibre_mcp.config import accept_library_as_base_path
+
+        accept_library_as_base_path()
+
+        # Read-only mode requested by the Zed extension must be honoured, or nothing runs
+        from calibre_mcp.db import read_only
+
+        read_only.ensure_supported()
+
         # PHASE 5: Register tools with comprehensive error handling
         logger.info("PHASE 5: Registering tools...")
         try:
@@ -718,11 +728,12 @@ async def main():
 
             async def register_tools_with_timeout():
                 logger.info("Starting tool registration...")
-                from calibre_mcp.tools import register_tools
+                from calibre_mcp.tools import apply_tool_selection, register_tools
 
                 logger.info("Tools module imported, calling register_tools...")
                 register_tools(mcp)
                 logger.info("register_tools() completed")
+                await apply_tool_selection(mcp)
 
             await asyncio.wait_for(register_tools_with_timeout(), timeout=30.0)
             logger.info("SUCCESS: Tool registration completed")
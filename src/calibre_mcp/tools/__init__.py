@@ -292,3 +292,31 @@ def register_tools(mcp: Any) -> None:
         logger.warning(f"{error_count} tool modules failed to load - check logs above for details")
     else:
         logger.info("SUCCESS: All tool modules loaded successfully")
+
+
+async def apply_tool_selection(mcp: Any) -> None:
+    """
+    Remove every registered tool not listed in CALIBRE_MCP_TOOLS.
+
+    The Zed extension resolves its tool group setting into this comma-separated list of
+    tool names. Without the variable all registered tools are kept.
+    """
+    import os
+
+    selected = {name.strip() for name in os.environ.get("CALIBRE_MCP_TOOLS", "").split(",")}
+    selected.discard("")
+    if not selected:
+        return
+
+    if hasattr(mcp, "list_tools"):
+        registered = [tool.name for tool in await mcp.list_tools()]
+    else:
+        registered = list(await mcp.get_tools())
+
+    removed = [name for name in registered if name not in selected]
+    for name in removed:
+        mcp.remove_tool(name)
+    logger.info(
+        f"Tool selection: kept {len(registered) - len(removed)} tools, removed {len(removed)} "
+        "not listed in CALIBRE_MCP_TOOLS"
+    )
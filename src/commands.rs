@@ -0,0 +1,1247 @@
+//! Slash commands registered in `extension.toml`.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use calibre_library::cite::{KeyTemplate, Style};
+use calibre_library::edit;
+use calibre_library::epub::Epub;
+use calibre_library::epubcheck::{self, Severity, Source};
+use calibre_library::metadata::{self, Book, MetadataDb};
+use calibre_library::zip::crc32;
+use zed_extension_api::{
+    self as zed, SlashCommandArgumentCompletion, SlashCommandOutput, SlashCommandOutputSection,
+};
+
+use crate::bibliography::Change;
+use crate::completion::{self, Catalog, Entry, Kind};
+use crate::discovery;
+use crate::library;
+use crate::project_config;
+use crate::quote::Text;
+use crate::search::{self, Query};
+use crate::settings::CalibreSettings;
+use crate::text::CommentOptions;
+use crate::unpacked::{self, Marker};
+use crate::worktree::WorktreeInfo;
+
+/// Most rows `/calibre-search` inserts; the rest are counted but left out.
+const SEARCH_RESULT_LIMIT: usize = 50;
+/// Most passages `/calibre-quote` inserts.
+const QUOTE_LIMIT: usize = 5;
+
+/// Runs a slash command. `settings` are those the context server was last started with,
+/// if it has been.
+pub fn run(
+    command: &str,
+    args: &[String],
+    worktree: Option<&WorktreeInfo>,
+    settings: Option<&CalibreSettings>,
+) -> zed::Result<SlashCommandOutput> {
+    match command {
+        "calibre-libraries" => libraries(worktree),
+        "calibre-search" => search(&args.join(" "), &library(worktree, settings)?),
+        "calibre-book" => {
+            let comments = match settings {
+                Some(settings) => settings.comment_options(&env_lookup(worktree)),
+                None => CommentOptions::from_env(&env_lookup(worktree)),
+            };
+            book(&args.join(" "), &library(worktree, settings)?, comments)
+        }
+        "calibre-quote" => quote(args, &library(worktree, settings)?),
+        "calibre-toc" => toc(&args.join(" "), &library(worktree, settings)?),
+        "calibre-chapter" => chapter(args, &library(worktree, settings)?),
+        "calibre-validate" => validate(&args.join(" "), &library(worktree, settings)?),
+        "calibre-unpack" => {
+            let worktree =
+                worktree.ok_or("/calibre-unpack writes into a project; open one first")?;
+            unpack(
+                &args.join(" "),
+                worktree,
+                &library(Some(worktree), settings)?,
+            )
+        }
+        "calibre-repack" => {
+            let worktree =
+                worktree.ok_or("/calibre-repack reads from a project; open one first")?;
+            repack(args, worktree, settings)
+        }
+        "calibre-cite" => cite(
+            args,
+            &library(worktree, settings)?,
+            &key_template(worktree, settings)?,
+        ),
+        "calibre-bibliography" => {
+            let worktree =
+                worktree.ok_or("/calibre-bibliography writes into a project; open one first")?;
+            bibliography(
+                args,
+                worktree,
+                &library(Some(worktree), settings)?,
+                &key_template(Some(worktree), settings)?,
+            )
+        }
+        command => Err(format!("unknown slash command: \"{command}\"")),
+    }
+}
+
+/// Appends a note that the context server runs without the worktree's `.calibre-mcp.toml`,
+/// having been started before the extension could read it.
+pub fn add_restart_note(output: &mut SlashCommandOutput) {
+    let start = output.text.len();
+    output.text.push_str(
+        "\nThe calibre-mcp context server was started before Zed showed the extension this \
+         project, so it is not using the project's .calibre-mcp.toml. Restart the server, \
+         e.g. by turning it off and on in the Agent Panel settings, to apply it.\n",
+    );
+    output.sections.push(SlashCommandOutputSection {
+        range: (start..output.text.len()).into(),
+        label: "calibre-mcp needs a restart".to_string(),
+    });
+}
+
+/// Completions for the argument being typed, the last of `args`.
+pub fn complete(
+    command: &str,
+    args: &[String],
+    catalog: &Catalog,
+) -> Vec<SlashCommandArgumentCompletion> {
+    let typed = args.last().map(String::as_str).unwrap_or_default();
+    match command {
+        "calibre-search" => search_completions(typed, catalog),
+        // The whole argument is the book, so match on all of it.
+        "calibre-book" => book_completions(&args.join(" "), catalog, true),
+        // The book comes first; the phrase after it is not completed.
+        "calibre-quote" | "calibre-chapter" if args.len() <= 1 => {
+            book_completions(typed, catalog, false)
+        }
+        "calibre-toc" | "calibre-validate" | "calibre-unpack" | "calibre-repack" => {
+            book_completions(&args.join(" "), catalog, true)
+        }
+        "calibre-cite" => cite_completions(args, catalog),
+        "calibre-bibliography" if "--check".starts_with(typed) => {
+            vec![SlashCommandArgumentCompletion {
+                label: "--check (show what would change without writing)".to_string(),
+                new_text: "--check".to_string(),
+                run_command: true,
+            }]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Completes a book argument with its id, and runs the command if the book is all it takes.
+fn book_completions(
+    typed: &str,
+    catalog: &Catalog,
+    run_command: bool,
+) -> Vec<SlashCommandArgumentCompletion> {
+    catalog
+        .rank(typed, &[Kind::Book])
+        .into_iter()
+        .map(|entry| completion(entry, entry.id.to_string(), run_command))
+        .collect()
+}
+
+/// Completes `--format` and its value, and otherwise the book, which runs the command.
+fn cite_completions(args: &[String], catalog: &Catalog) -> Vec<SlashCommandArgumentCompletion> {
+    let typed = args.last().map(String::as_str).unwrap_or_default();
+    let previous = args.len().checked_sub(2).map(|index| args[index].as_str());
+    if previous == Some("--format") {
+        return Style::NAMES
+            .iter()
+            .filter(|name| name.starts_with(&typed.to_ascii_lowercase()))
+            .map(|name| SlashCommandArgumentCompletion {
+                label: name.to_string(),
+                new_text: name.to_string(),
+                run_command: args.len() > 2,
+            })
+            .collect();
+    }
+    if typed.starts_with('-') {
+        return vec![SlashCommandArgumentCompletion {
+            label: format!("--format {}", Style::NAMES.join("|")),
+            new_text: "--format".to_string(),
+            run_command: false,
+        }];
+    }
+    book_completions(typed, catalog, true)
+}
+
+/// Search fields whose values can be completed.
+const COMPLETED_FIELDS: &[(&str, Kind)] = &[
+    ("title", Kind::Book),
+    ("author", Kind::Author),
+    ("authors", Kind::Author),
+    ("series", Kind::Series),
+    ("tag", Kind::Tag),
+    ("tags", Kind::Tag),
+    ("publisher", Kind::Publisher),
+];
+
+/// Completes `field:value` with that field's values, and a bare word with anything, as a
+/// term that finds it: `id:3` for a book, `author:"Jane Austen"` for an author.
+fn search_completions(typed: &str, catalog: &Catalog) -> Vec<SlashCommandArgumentCompletion> {
+    let field = typed.split_once(':').and_then(|(name, value)| {
+        COMPLETED_FIELDS
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(field, kind)| (*field, *kind, value))
+    });
+    let (query, kinds) = match field {
+        Some((_, kind, value)) => (value.trim_start_matches(['"', '=']), vec![kind]),
+        None => (typed, Kind::ALL.to_vec()),
+    };
+
+    catalog
+        .rank(query, &kinds)
+        .into_iter()
+        .map(|entry| {
+            let new_text = match (field, entry.kind) {
+                (Some((name, ..)), _) => format!("{name}:{}", search_value(&entry.name)),
+                (None, Kind::Book) => format!("id:{}", entry.id),
+                (None, kind) => format!("{}:{}", search_field(kind), search_value(&entry.name)),
+            };
+            completion(entry, new_text, false)
+        })
+        .collect()
+}
+
+fn search_field(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Book => "title",
+        Kind::Author => "author",
+        Kind::Series => "series",
+        Kind::Tag => "tag",
+        Kind::Publisher => "publisher",
+    }
+}
+
+/// A search value, quoted unless it is a single plain word.
+fn search_value(value: &str) -> String {
+    if !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+fn completion(
+    entry: &Entry,
+    new_text: String,
+    run_command: bool,
+) -> SlashCommandArgumentCompletion {
+    SlashCommandArgumentCompletion {
+        label: entry.label(),
+        new_text,
+        run_command,
+    }
+}
+
+/// The library slash commands read: the worktree's `.calibre-mcp.toml` binding, else the
+/// context server's library, else the active discovered library.
+///
+/// A directory of libraries stands for the first library in it, by name.
+pub fn library(
+    worktree: Option<&WorktreeInfo>,
+    settings: Option<&CalibreSettings>,
+) -> zed::Result<PathBuf> {
+    let path = match chosen_library(worktree, settings)? {
+        Some(path) => path,
+        None => discovery::active(&discovery::discover(&env_lookup(worktree)))
+            .map(|library| library.path.clone())
+            .ok_or_else(|| {
+                "no Calibre library found; set `library_path` or CALIBRE_LIBRARY_PATH".to_string()
+            })?,
+    };
+    if library::is_library(&path) {
+        return Ok(path);
+    }
+
+    let mut children = fs::read_dir(&path)
+        .map_err(|err| format!("failed to read {}: {err}", path.display()))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|child| library::is_library(child))
+        .collect::<Vec<_>>();
+    children.sort();
+    children
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("{} is not a Calibre library", path.display()))
+}
+
+/// The worktree's `.calibre-mcp.toml` binding, else the context server's library, as they
+/// are set: either may be a directory of libraries, or not exist.
+pub fn chosen_library(
+    worktree: Option<&WorktreeInfo>,
+    settings: Option<&CalibreSettings>,
+) -> zed::Result<Option<PathBuf>> {
+    let bound = worktree
+        .map(WorktreeInfo::project_config)
+        .transpose()?
+        .flatten()
+        .and_then(|config| config.library);
+    let configured = settings
+        .and_then(|settings| settings.library_path.as_ref())
+        .map(PathBuf::from);
+    Ok(bound.or(configured))
+}
+
+/// The citation key template: the worktree's `.calibre-mcp.toml`, else the context
+/// server's `citation_key` setting, else the default.
+pub fn key_template(
+    worktree: Option<&WorktreeInfo>,
+    settings: Option<&CalibreSettings>,
+) -> zed::Result<KeyTemplate> {
+    let project = worktree
+        .map(WorktreeInfo::project_config)
+        .transpose()?
+        .flatten()
+        .and_then(|config| config.citation_key);
+    if let Some(template) = project {
+        return Ok(template);
+    }
+    match settings.and_then(|settings| settings.citation_key.as_deref()) {
+        Some(template) => {
+            KeyTemplate::parse(template).map_err(|err| format!("invalid `citation_key`: {err}"))
+        }
+        None => Ok(KeyTemplate::default()),
+    }
+}
+
+/// Looks variables up in the worktree's shell environment, or the extension's own without one.
+pub fn env_lookup(worktree: Option<&WorktreeInfo>) -> impl Fn(&str) -> Option<String> + '_ {
+    move |name| match worktree {
+        Some(worktree) => worktree.env_var(name),
+        None => std::env::var(name).ok(),
+    }
+}
+
+/// `/calibre-libraries`: every discovered library with its book count and database.
+fn libraries(worktree: Option<&WorktreeInfo>) -> zed::Result<SlashCommandOutput> {
+    let libraries = discovery::discover(&env_lookup(worktree));
+    if libraries.is_empty() {
+        return Err(
+            "no Calibre libraries found; set `library_path` or CALIBRE_LIBRARY_PATH".to_string(),
+        );
+    }
+
+    let mut text = String::new();
+    let mut sections = Vec::new();
+    for library in &libraries {
+        let start = text.len();
+        let books = match library.book_count() {
+            Ok(1) => "1 book".to_string(),
+            Ok(count) => format!("{count} books"),
+            Err(err) => format!("book count unavailable ({err})"),
+        };
+        let active = if library.is_active { " (active)" } else { "" };
+        let _ = writeln!(text, "{}{active}", library.name);
+        let _ = writeln!(text, "  {books}");
+        let _ = writeln!(text, "  {}", library.metadata_db().display());
+        sections.push(SlashCommandOutputSection {
+            range: (start..text.len()).into(),
+            label: format!("{}{active}", library.name),
+        });
+    }
+
+    Ok(SlashCommandOutput { text, sections })
+}
+
+/// `/calibre-search <query>`: books matching a Calibre search, as a Markdown table.
+fn search(query: &str, library: &Path) -> zed::Result<SlashCommandOutput> {
+    let parsed = Query::parse(query).map_err(|err| format!("invalid search: {err}"))?;
+    let books = metadata::books(library)?;
+    let found = search::search(&books, &parsed);
+
+    let mut text = format!(
+        "Calibre search `{}` in {}: ",
+        query.trim(),
+        library.display()
+    );
+    let _ = match found.len() {
+        1 => writeln!(text, "1 book"),
+        count => writeln!(text, "{count} books"),
+    };
+    if !found.is_empty() {
+        text.push_str("\n| id | title | authors | series | tags | formats |\n");
+        text.push_str("|---:|---|---|---|---|---|\n");
+    }
+    for book in found.iter().take(SEARCH_RESULT_LIMIT) {
+        let series = book
+            .series
+            .as_ref()
+            .map(|series| format!("{series} [{}]", book.series_index))
+            .unwrap_or_default();
+        let _ = writeln!(
+            text,
+            "| {} | {} | {} | {} | {} | {} |",
+            book.id,
+            cell(&book.title),
+            cell(&book.authors.join(" & ")),
+            cell(&series),
+            cell(&book.tags.join(", ")),
+            book.formats.join(", "),
+        );
+    }
+    if found.len() > SEARCH_RESULT_LIMIT {
+        let _ = writeln!(
+            text,
+            "\n{} more not shown; narrow the search to see them.",
+            found.len() - SEARCH_RESULT_LIMIT
+        );
+    }
+
+    let label = format!("Calibre search: {} ({})", query.trim(), found.len());
+    Ok(SlashCommandOutput {
+        sections: vec![SlashCommandOutputSection {
+            range: (0..text.len()).into(),
+            label,
+        }],
+        text,
+    })
+}
+
+/// Text safe to put in a Markdown table cell.
+fn cell(text: &str) -> String {
+    text.replace('|', "\\|").replace(['\n', '\r'], " ")
+}
+
+/// Finds the book an argument names: an id, optionally written `#3`, or a title. A title
+/// matches exactly, ignoring case, or else as the best fuzzy match. Otherwise an id ending
+/// the argument names the book, as completing `pride and` leaves `pride 3`: Zed replaces
+/// only the last word with the completion.
+pub fn find_book<'a>(books: &'a [Book], argument: &str) -> zed::Result<&'a Book> {
+    let argument = argument.trim();
+    if argument.is_empty() {
+        return Err("name a book by id or title".to_string());
+    }
+    if let Ok(id) = argument.trim_start_matches('#').parse::<i64>() {
+        return books
+            .iter()
+            .find(|book| book.id == id)
+            .ok_or_else(|| format!("no book with id {id}"));
+    }
+
+    let exact = books
+        .iter()
+        .filter(|book| book.title.eq_ignore_ascii_case(argument))
+        .collect::<Vec<_>>();
+    match exact.as_slice() {
+        [book] => return Ok(book),
+        [] => {}
+        several => {
+            let ids = several
+                .iter()
+                .map(|book| format!("#{} by {}", book.id, book.authors.join(" & ")))
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(format!(
+                "several books are titled \"{argument}\" ({ids}); use an id"
+            ));
+        }
+    }
+
+    let last = argument
+        .rsplit(char::is_whitespace)
+        .next()
+        .unwrap_or_default();
+    if let Ok(id) = last.trim_start_matches('#').parse::<i64>() {
+        if let Some(book) = books.iter().find(|book| book.id == id) {
+            return Ok(book);
+        }
+    }
+
+    books
+        .iter()
+        .filter_map(|book| Some((completion::fuzzy_score(argument, &book.title)?, book)))
+        .max_by_key(|(score, book)| (*score, std::cmp::Reverse(book.id)))
+        .map(|(_, book)| book)
+        .ok_or_else(|| format!("no book matches \"{argument}\""))
+}
+
+/// `/calibre-book <id|title>`: everything the library knows about a book, in folding
+/// sections.
+fn book(
+    argument: &str,
+    library: &Path,
+    comments: CommentOptions,
+) -> zed::Result<SlashCommandOutput> {
+    let db = MetadataDb::open(library)?;
+    let books = db.library()?;
+    let book = find_book(&books, argument)?;
+
+    let mut dossier = Dossier::default();
+    let title = match book.authors.as_slice() {
+        [] => book.title.clone(),
+        authors => format!("{} — {}", book.title, authors.join(" & ")),
+    };
+    let _ = writeln!(dossier.text, "# {} (#{})\n", book.title, book.id);
+
+    dossier.section("Metadata", |text| {
+        let mut field = |name: &str, value: &str| {
+            if !value.is_empty() {
+                let _ = writeln!(text, "- {name}: {value}");
+            }
+        };
+        field("Title", &book.title);
+        field("Title sort", book.sort.as_deref().unwrap_or_default());
+        field("Authors", &book.authors.join(" & "));
+        field(
+            "Author sort",
+            book.author_sort.as_deref().unwrap_or_default(),
+        );
+        if let Some(series) = &book.series {
+            field("Series", &format!("{series} [{}]", book.series_index));
+        }
+        field("Publisher", book.publisher.as_deref().unwrap_or_default());
+        field("Published", date(book.pubdate.as_deref()));
+        if let Some(rating) = book.rating.filter(|rating| *rating > 0) {
+            field("Rating", &format!("{} stars", rating as f64 / 2.0));
+        }
+        field("Tags", &book.tags.join(", "));
+        field("Added", date(book.timestamp.as_deref()));
+        field("Modified", date(book.last_modified.as_deref()));
+        field("UUID", book.uuid.as_deref().unwrap_or_default());
+        field("Cover", if book.has_cover { "yes" } else { "no" });
+        field("Folder", &library.join(&book.path).to_string_lossy());
+    });
+
+    if !book.identifiers.is_empty() {
+        dossier.section("Identifiers", |text| {
+            for (kind, value) in &book.identifiers {
+                let _ = match identifier_url(kind, value) {
+                    Some(url) => writeln!(text, "- {}: {value} ({url})", identifier_name(kind)),
+                    None => writeln!(text, "- {}: {value}", identifier_name(kind)),
+                };
+            }
+        });
+    }
+
+    if !book.files.is_empty() {
+        dossier.section("Formats", |text| {
+            for data in &book.files {
+                let file = library.join(&book.path).join(format!(
+                    "{}.{}",
+                    data.name,
+                    data.format.to_lowercase()
+                ));
+                let _ = writeln!(
+                    text,
+                    "- {}: {} ({})",
+                    data.format,
+                    file.display(),
+                    size(data.uncompressed_size)
+                );
+            }
+        });
+    }
+
+    if let Some(comment) = book
+        .comments
+        .as_deref()
+        .map(|comment| comments.apply(comment))
+    {
+        if !comment.is_empty() {
+            dossier.section("Comments", |text| {
+                let _ = writeln!(text, "{comment}");
+            });
+        }
+    }
+
+    let mut custom = Vec::new();
+    for column in db.custom_columns()? {
+        if let Some(values) = db.custom_values(&column)?.remove(&book.id) {
+            let separator = if column.is_multiple { ", " } else { "; " };
+            let value = match column.datatype.as_str() {
+                "comments" => values
+                    .iter()
+                    .map(|value| comments.apply(value))
+                    .collect::<Vec<_>>()
+                    .join(separator),
+                _ => values.join(separator),
+            };
+            custom.push((column.name, column.label, value));
+        }
+    }
+    if !custom.is_empty() {
+        dossier.section("Custom columns", |text| {
+            for (name, label, value) in &custom {
+                let _ = writeln!(text, "- {name} (#{label}): {value}");
+            }
+        });
+    }
+
+    Ok(dossier.finish(title))
+}
+
+/// `/calibre-quote <book> <phrase>`: passages of the book's EPUB, else its PDF, containing
+/// a phrase, with where in the book each one is.
+fn quote(args: &[String], library: &Path) -> zed::Result<SlashCommandOutput> {
+    let usage = "usage: /calibre-quote <book id or title> <phrase>";
+    let (argument, phrase) = book_and_rest(args, usage)?;
+    let books = metadata::books(library)?;
+    let book = find_book(&books, &argument)?;
+    let text = match (book.file(library, "EPUB"), book.file(library, "PDF")) {
+        (Some(epub), _) => Text::epub(&epub)?,
+        (None, Some(pdf)) => Text::pdf(&pdf)?,
+        (None, None) => {
+            return Err(format!(
+                "{} (#{}) has no EPUB or PDF to quote from",
+                book.title, book.id
+            ))
+        }
+    };
+
+    let quotes = text.find(&phrase);
+    if quotes.is_empty() {
+        return Err(format!(
+            "\"{phrase}\" does not occur in the {} of {} (#{})",
+            text.format, book.title, book.id
+        ));
+    }
+
+    let mut dossier = Dossier::default();
+    let _ = writeln!(
+        dossier.text,
+        "# \"{phrase}\" in {} (#{})\n",
+        book.title, book.id
+    );
+    let _ = match quotes.len() {
+        1 => writeln!(dossier.text, "1 passage in the {}.\n", text.format),
+        count if count > QUOTE_LIMIT => writeln!(
+            dossier.text,
+            "{count} passages in the {}; the first {QUOTE_LIMIT} follow.\n",
+            text.format
+        ),
+        count => writeln!(dossier.text, "{count} passages in the {}.\n", text.format),
+    };
+    let parts = text.parts.len();
+    for quote in quotes.iter().take(QUOTE_LIMIT) {
+        let title = &text.parts[quote.part].title;
+        let label = match text.format {
+            "PDF" => format!("{title} of {parts}, {:.0}%", quote.percent),
+            _ => format!(
+                "{title} — chapter {} of {parts}, {:.0}%",
+                quote.part + 1,
+                quote.percent
+            ),
+        };
+        dossier.section(&label, |text| {
+            let _ = writeln!(text, "> {}", quote.context);
+        });
+    }
+
+    Ok(dossier.finish(format!("\"{phrase}\" — {}", book.title)))
+}
+
+/// Splits `/calibre-quote` and `/calibre-chapter` arguments into the book and the rest.
+/// The book is the first argument, or several wrapped in double quotes; quotes around the
+/// rest are dropped.
+fn book_and_rest(args: &[String], usage: &str) -> zed::Result<(String, String)> {
+    let (book, rest) = match args.first() {
+        Some(first) if first.starts_with('"') => {
+            let closing = args
+                .iter()
+                .position(|arg| arg.len() > 1 && arg.ends_with('"'))
+                .ok_or(usage)?;
+            let book = args[..=closing].join(" ");
+            (book.trim_matches('"').to_string(), &args[closing + 1..])
+        }
+        Some(first) => (first.clone(), &args[1..]),
+        None => return Err(usage.to_string()),
+    };
+    let phrase = rest.join(" ");
+    let phrase = phrase.trim();
+    let phrase = phrase
+        .strip_prefix('"')
+        .and_then(|phrase| phrase.strip_suffix('"'))
+        .unwrap_or(phrase)
+        .trim();
+    if phrase.is_empty() {
+        return Err(usage.to_string());
+    }
+    Ok((book, phrase.to_string()))
+}
+
+/// `/calibre-toc <book>`: the table of contents of the book's EPUB, with the number
+/// `/calibre-chapter` takes for each entry.
+fn toc(argument: &str, library: &Path) -> zed::Result<SlashCommandOutput> {
+    let books = metadata::books(library)?;
+    let book = find_book(&books, argument)?;
+    let epub = open_epub(book, library)?;
+    let sections = epub.sections()?;
+
+    let mut text = format!("# Contents of {} (#{})\n\n", book.title, book.id);
+    let _ = writeln!(
+        text,
+        "EPUB {}: {} documents in reading order and {} resources, listed by {}.\n",
+        if epub.version.is_empty() {
+            "2.0"
+        } else {
+            &epub.version
+        },
+        epub.spine.len(),
+        epub.manifest.len(),
+        epub.package_path
+    );
+    if epub.toc.is_empty() {
+        text.push_str("The book has no table of contents; its chapters are:\n\n");
+        for (index, section) in sections.iter().enumerate() {
+            let _ = writeln!(text, "{}. {}", index + 1, section.title);
+        }
+    } else {
+        for entry in &epub.toc {
+            let indent = "  ".repeat(entry.depth);
+            let title = if entry.title.is_empty() {
+                "Untitled"
+            } else {
+                &entry.title
+            };
+            let _ = match sections
+                .iter()
+                .position(|section| section.path == entry.path)
+            {
+                Some(index) => writeln!(text, "{indent}- {title} (chapter {})", index + 1),
+                None => writeln!(text, "{indent}- {title}"),
+            };
+        }
+    }
+    let _ = writeln!(
+        text,
+        "\nInsert a chapter with `/calibre-chapter {} <number or title>`.",
+        book.id
+    );
+    Ok(SlashCommandOutput {
+        sections: vec![SlashCommandOutputSection {
+            range: (0..text.len()).into(),
+            label: format!("Contents — {}", book.title),
+        }],
+        text,
+    })
+}
+
+/// `/calibre-chapter <book> <chapter>`: a chapter of the book's EPUB as Markdown. The
+/// chapter is its number in `/calibre-toc`, or its title.
+fn chapter(args: &[String], library: &Path) -> zed::Result<SlashCommandOutput> {
+    let usage = "usage: /calibre-chapter <book id or title> <chapter number or title>";
+    let (argument, wanted) = book_and_rest(args, usage)?;
+    let books = metadata::books(library)?;
+    let book = find_book(&books, &argument)?;
+    let sections = open_epub(book, library)?.markdown_sections()?;
+
+    let section = match wanted.parse::<usize>() {
+        Ok(number) => number
+            .checked_sub(1)
+            .and_then(|index| sections.get(index))
+            .ok_or_else(|| {
+                format!(
+                    "{} (#{}) has {} chapters; there is no chapter {number}",
+                    book.title,
+                    book.id,
+                    sections.len()
+                )
+            })?,
+        Err(_) => sections
+            .iter()
+            .find(|section| section.title.eq_ignore_ascii_case(&wanted))
+            .or_else(|| {
+                sections
+                    .iter()
+                    .filter_map(|section| {
+                        Some((completion::fuzzy_score(&wanted, &section.title)?, section))
+                    })
+                    .max_by_key(|(score, _)| *score)
+                    .map(|(_, section)| section)
+            })
+            .ok_or_else(|| {
+                format!(
+                    "no chapter of {} (#{}) matches \"{wanted}\"",
+                    book.title, book.id
+                )
+            })?,
+    };
+
+    let text = format!("{}\n", section.text);
+    Ok(SlashCommandOutput {
+        sections: vec![SlashCommandOutputSection {
+            range: (0..text.len()).into(),
+            label: format!("{} — {}", section.title, book.title),
+        }],
+        text,
+    })
+}
+
+/// `/calibre-validate <book>`: the problems epubcheck would find in the book's EPUB,
+/// errors first.
+fn validate(argument: &str, library: &Path) -> zed::Result<SlashCommandOutput> {
+    let books = metadata::books(library)?;
+    let book = find_book(&books, argument)?;
+    let path = epub_path(book, library)?;
+    let problems = epubcheck::check(&Source::packed(&path)?);
+
+    let mut text = format!("# Validation of {} (#{})\n\n", book.title, book.id);
+    let file = path.file_name().unwrap_or_default().to_string_lossy();
+    if problems.is_empty() {
+        let _ = writeln!(text, "No problems found in {file}.");
+    } else {
+        let errors = problems
+            .iter()
+            .filter(|problem| problem.severity == Severity::Error)
+            .count();
+        let warnings = problems.len() - errors;
+        let _ = writeln!(
+            text,
+            "{errors} {} and {warnings} {} in {file}:\n",
+            if errors == 1 { "error" } else { "errors" },
+            if warnings == 1 { "warning" } else { "warnings" },
+        );
+        for problem in &problems {
+            let _ = writeln!(text, "- {problem}");
+        }
+    }
+    Ok(SlashCommandOutput {
+        sections: vec![SlashCommandOutputSection {
+            range: (0..text.len()).into(),
+            label: format!("Validation — {}", book.title),
+        }],
+        text,
+    })
+}
+
+/// `/calibre-unpack <book>`: writes the files of the book's EPUB into a folder of the
+/// worktree for editing, with a marker `/calibre-repack` finds it by.
+fn unpack(
+    argument: &str,
+    worktree: &WorktreeInfo,
+    library: &Path,
+) -> zed::Result<SlashCommandOutput> {
+    let books = metadata::books(library)?;
+    let book = find_book(&books, argument)?;
+    if let Some((folder, _)) = unpacked::find(&worktree.root, book.id) {
+        return Err(format!(
+            "{} (#{}) is unpacked in {} already; put it back with `/calibre-repack {}`, or \
+             delete the folder to start over",
+            book.title,
+            book.id,
+            relative(&folder, worktree),
+            book.id
+        ));
+    }
+    let epub = epub_path(book, library)?;
+    let folder = unpacked::folder(&worktree.root, book);
+    let count = edit::unpack(&epub, &folder)?;
+    let contents =
+        fs::read(&epub).map_err(|err| format!("failed to read {}: {err}", epub.display()))?;
+    let marker = Marker {
+        library: library.to_path_buf(),
+        book: book.id,
+        crc32: crc32(&contents),
+    };
+    marker.write(&folder)?;
+
+    let mut text = format!("# Unpacked {} (#{})\n\n", book.title, book.id);
+    let _ = writeln!(
+        text,
+        "The {count} files of its EPUB are in `{}`. Problems in the book show up as \
+         diagnostics once one of its files is open.\n\n\
+         When done, `/calibre-repack {}` checks the folder and puts it back in the library, \
+         keeping the EPUB as it is now as ORIGINAL_EPUB.",
+        relative(&folder, worktree),
+        book.id
+    );
+    Ok(SlashCommandOutput {
+        sections: vec![SlashCommandOutputSection {
+            range: (0..text.len()).into(),
+            label: format!("Unpacked — {}", book.title),
+        }],
+        text,
+    })
+}
+
+/// `/calibre-repack <book> [--calibre-closed]`: checks the folder `/calibre-unpack` made,
+/// and unless it has errors packs it into an EPUB that replaces the book's in the library.
+/// `--calibre-closed` vouches that Calibre is closed where the library cannot be locked.
+fn repack(
+    args: &[String],
+    worktree: &WorktreeInfo,
+    settings: Option<&CalibreSettings>,
+) -> zed::Result<SlashCommandOutput> {
+    let (flags, words): (Vec<_>, Vec<_>) = args
+        .iter()
+        .map(String::as_str)
+        .partition(|arg| *arg == "--calibre-closed");
+    let library = library(Some(worktree), settings)?;
+    let books = metadata::books(&library)?;
+    let book = find_book(&books, &words.join(" "))?;
+    let (folder, mut marker) = unpacked::find(&worktree.root, book.id).ok_or_else(|| {
+        format!(
+            "{} (#{}) is not unpacked in this project; unpack it with `/calibre-unpack {}`",
+            book.title, book.id, book.id
+        )
+    })?;
+    if marker.library != library {
+        return Err(format!(
+            "{} was unpacked from the library at {}, not this project's at {}",
+            relative(&folder, worktree),
+            marker.library.display(),
+            library.display()
+        ));
+    }
+    let read_only = settings
+        .and_then(|settings| settings.read_only)
+        .unwrap_or_else(|| library::is_shared(&library));
+    if read_only {
+        return Err(format!(
+            "the library at {} is read-only; set `read_only` to false to repack into it",
+            library.display()
+        ));
+    }
+    let epub = epub_path(book, &library)?;
+    let current =
+        fs::read(&epub).map_err(|err| format!("failed to read {}: {err}", epub.display()))?;
+    if crc32(&current) != marker.crc32 {
+        return Err(format!(
+            "the EPUB of {} (#{}) changed in the library since it was unpacked; unpack it \
+             again into a new folder and carry the edits over",
+            book.title, book.id
+        ));
+    }
+
+    let name = relative(&folder, worktree);
+    let problems = epubcheck::check(&Source::unpacked(&folder));
+    let errors = problems
+        .iter()
+        .filter(|problem| problem.severity == Severity::Error)
+        .count();
+    let mut text = if errors > 0 {
+        format!(
+            "# Not repacked: {} (#{})\n\n{errors} {} in `{name}` must be fixed first:\n\n",
+            book.title,
+            book.id,
+            if errors == 1 { "error" } else { "errors" },
+        )
+    } else {
+        let packed = edit::pack(&folder)?;
+        if !edit::CAN_LOCK && flags.is_empty() {
+            return Err(format!(
+                "the library cannot be locked against Calibre on this platform; close Calibre \
+                 and run `/calibre-repack {} --calibre-closed`",
+                book.id
+            ));
+        }
+        let replaced = edit::replace_format(&library, book.id, "EPUB", &packed, !flags.is_empty())?;
+        marker.crc32 = crc32(&packed);
+        marker.write(&folder)?;
+        let mut text = format!("# Repacked {} (#{})\n\n", book.title, book.id);
+        let _ = write!(
+            text,
+            "`{name}` is the book's EPUB now, {} bytes. ",
+            packed.len()
+        );
+        text.push_str(match replaced.original {
+            Some(_) => "The EPUB it replaced is kept as ORIGINAL_EPUB.\n",
+            None => "The book's ORIGINAL_EPUB, from before the first repack, is left as it was.\n",
+        });
+        if !problems.is_empty() {
+            text.push_str("\nWarnings:\n\n");
+        }
+        text
+    };
+    for problem in &problems {
+        let _ = writeln!(text, "- {problem}");
+    }
+    let label = match errors {
+        0 => format!("Repacked — {}", book.title),
+        _ => format!("Not repacked — {}", book.title),
+    };
+    Ok(SlashCommandOutput {
+        sections: vec![SlashCommandOutputSection {
+            range: (0..text.len()).into(),
+            label,
+        }],
+        text,
+    })
+}
+
+/// `path` relative to the worktree root, for showing.
+fn relative(path: &Path, worktree: &WorktreeInfo) -> String {
+    path.strip_prefix(&worktree.root)
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+fn epub_path(book: &Book, library: &Path) -> zed::Result<PathBuf> {
+    book.file(library, "EPUB")
+        .ok_or_else(|| format!("{} (#{}) has no EPUB", book.title, book.id))
+}
+
+fn open_epub(book: &Book, library: &Path) -> zed::Result<Epub> {
+    Epub::open(&epub_path(book, library)?)
+}
+
+/// `/calibre-cite <book> [--format bibtex|csl-json|ris|apa|chicago]`: a citation of the
+/// book, BibTeX by default.
+fn cite(
+    args: &[String],
+    library: &Path,
+    template: &KeyTemplate,
+) -> zed::Result<SlashCommandOutput> {
+    let mut style = Style::Bibtex;
+    let mut words = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if let Some(name) = arg.strip_prefix("--format=") {
+            style = Style::parse(name)?;
+        } else if arg == "--format" {
+            let name = args
+                .next()
+                .ok_or_else(|| format!("--format needs one of {}", Style::NAMES.join(", ")))?;
+            style = Style::parse(name)?;
+        } else {
+            words.push(arg.as_str());
+        }
+    }
+    let books = metadata::books(library)?;
+    let book = find_book(&books, words.join(" ").trim_matches('"'))?;
+    // Keys are made for the whole library so that clashes resolve the same way every time.
+    let key = template
+        .keys(&books)
+        .remove(&book.id)
+        .unwrap_or_else(|| template.key(book));
+
+    let citation = style.format(book, &key);
+    let text = match style.code_language() {
+        Some(language) => format!("```{language}\n{citation}\n```\n"),
+        None => format!("{citation}\n"),
+    };
+    Ok(SlashCommandOutput {
+        sections: vec![SlashCommandOutputSection {
+            range: (0..text.len()).into(),
+            label: format!("{} — {} ({key})", style.name(), book.title),
+        }],
+        text,
+    })
+}
+
+/// Writes the project's `[bibliography]` from the library, or with `--check` only shows
+/// what would change, entry by entry.
+fn bibliography(
+    args: &[String],
+    worktree: &WorktreeInfo,
+    library: &Path,
+    template: &KeyTemplate,
+) -> zed::Result<SlashCommandOutput> {
+    let check = match args {
+        [] => false,
+        [flag] if flag == "--check" => true,
+        _ => return Err("usage: /calibre-bibliography [--check]".to_string()),
+    };
+    let config = worktree
+        .project_config()?
+        .and_then(|config| config.bibliography)
+        .ok_or_else(|| {
+            format!(
+                "no bibliography is configured; add a [bibliography] table with `tag`, \
+                 `series` or `saved_search` to {}",
+                project_config::FILE_NAMES[0]
+            )
+        })?;
+    let file = &config.file;
+    let current = match fs::read_to_string(file) {
+        Ok(text) => Some(text),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(format!("failed to read {}: {err}", file.display())),
+    };
+    let books = metadata::books(library)?;
+    let update = config.update(&books, library, template, current.as_deref())?;
+    let stale = current.as_deref() != Some(update.text.as_str());
+    if stale && !check {
+        if let Some(directory) = file.parent() {
+            fs::create_dir_all(directory)
+                .map_err(|err| format!("failed to create {}: {err}", directory.display()))?;
+        }
+        fs::write(file, &update.text)
+            .map_err(|err| format!("failed to write {}: {err}", file.display()))?;
+    }
+
+    let name = relative(file, worktree);
+    let mut dossier = Dossier::default();
+    let _ = writeln!(
+        dossier.text,
+        "# {} bibliography: {name}\n",
+        config.style.name()
+    );
+    let count = |kind: &str| {
+        let changes = update.changes.iter().filter(|change| change.kind() == kind);
+        format!("{} {kind}", changes.count())
+    };
+    let summary = format!(
+        "{}, {}, {}",
+        count("added"),
+        count("changed"),
+        count("removed")
+    );
+    let entries = match update.count {
+        1 => "1 entry".to_string(),
+        count => format!("{count} entries"),
+    };
+    let _ = match (stale, check) {
+        (false, _) => writeln!(
+            dossier.text,
+            "Up to date with the Calibre {}: {entries}.\n",
+            config.books
+        ),
+        (true, true) => writeln!(
+            dossier.text,
+            "Out of date with the Calibre {}; writing it would give {entries}: {summary}.\n",
+            config.books
+        ),
+        (true, false) => writeln!(
+            dossier.text,
+            "Written from the Calibre {}: {entries}, {summary}.\n",
+            config.books
+        ),
+    };
+    let language = config.style.code_language().unwrap_or_default();
+    for change in &update.changes {
+        let block = match change {
+            Change::Added { entry, .. } => format!("```{language}\n{entry}\n```"),
+            Change::Changed { diff, .. } => format!("```diff\n{}\n```", diff.join("\n")),
+            Change::Removed { entry, .. } => {
+                let lines = entry.lines().map(|line| format!("-{line}"));
+                format!("```diff\n{}\n```", lines.collect::<Vec<_>>().join("\n"))
+            }
+        };
+        let label = format!("{} ({})", change.key(), change.kind());
+        dossier.section(&label, |text| {
+            let _ = writeln!(text, "{block}");
+        });
+    }
+
+    Ok(dossier.finish(format!("Bibliography — {name}")))
+}
+
+/// Output built from labelled sections, all inside one section for the whole command.
+#[derive(Default)]
+struct Dossier {
+    text: String,
+    sections: Vec<SlashCommandOutputSection>,
+}
+
+impl Dossier {
+    fn section(&mut self, label: &str, write: impl FnOnce(&mut String)) {
+        let start = self.text.len();
+        let _ = writeln!(self.text, "## {label}\n");
+        write(&mut self.text);
+        self.text.push('\n');
+        self.sections.push(SlashCommandOutputSection {
+            range: (start..self.text.len()).into(),
+            label: label.to_string(),
+        });
+    }
+
+    fn finish(mut self, label: String) -> SlashCommandOutput {
+        self.sections.insert(
+            0,
+            SlashCommandOutputSection {
+                range: (0..self.text.len()).into(),
+                label,
+            },
+        );
+        SlashCommandOutput {
+            text: self.text,
+            sections: self.sections,
+        }
+    }
+}
+
+/// The date part of a Calibre timestamp; Calibre's placeholder for unknown dates reads
+/// as nothing.
+fn date(timestamp: Option<&str>) -> &str {
+    match timestamp {
+        Some(timestamp) if !timestamp.starts_with("0101-") => {
+            timestamp.get(..10).unwrap_or(timestamp)
+        }
+        _ => "",
+    }
+}
+
+fn identifier_name(kind: &str) -> &str {
+    match kind.to_ascii_lowercase().as_str() {
+        "isbn" => "ISBN",
+        "doi" => "DOI",
+        "arxiv" => "arXiv",
+        "issn" => "ISSN",
+        "asin" | "amazon" => "ASIN",
+        "gutenberg" => "Project Gutenberg",
+        _ => kind,
+    }
+}
+
+fn identifier_url(kind: &str, value: &str) -> Option<String> {
+    match kind.to_ascii_lowercase().as_str() {
+        "doi" => Some(format!("https://doi.org/{value}")),
+        "arxiv" => Some(format!("https://arxiv.org/abs/{value}")),
+        "gutenberg" => Some(format!("https://www.gutenberg.org/ebooks/{value}")),
+        "url" | "uri" => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn size(bytes: i64) -> String {
+    match bytes {
+        bytes if bytes < 1024 => format!("{bytes} B"),
+        bytes if bytes < 1024 * 1024 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        bytes => format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_book_a_completed_argument_names() {
+        let library = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/test_library");
+        let catalog = Catalog::load(&library).unwrap();
+        let mut books = metadata::books(&library).unwrap();
+        let args = |text: &str| text.split(' ').map(str::to_string).collect::<Vec<_>>();
+        let title = |argument: &str| find_book(&books, argument).map(|book| book.title.clone());
+
+        // Zed swaps the completion in for the last word only.
+        for command in ["calibre-book", "calibre-cite", "calibre-toc"] {
+            let mut typed = args("pride and");
+            let completions = complete(command, &typed, &catalog);
+            assert_eq!(completions[0].new_text, "3", "{command}");
+            *typed.last_mut().unwrap() = completions[0].new_text.clone();
+            assert_eq!(typed.join(" "), "pride 3");
+            assert_eq!(title(&typed.join(" ")).unwrap(), "Pride and Prejudice");
+        }
+        assert_eq!(title("3").unwrap(), "Pride and Prejudice");
+        assert_eq!(title(" #3 ").unwrap(), "Pride and Prejudice");
+        assert_eq!(title("sign #2").unwrap(), "The Sign of the Four");
+        assert_eq!(
+            title("the sign of the four").unwrap(),
+            "The Sign of the Four"
+        );
+        assert_eq!(title("sawyer").unwrap(), "The Adventures of Tom Sawyer");
+        assert_eq!(title("99").unwrap_err(), "no book with id 99");
+        assert_eq!(
+            title("pride 99").unwrap_err(),
+            "no book matches \"pride 99\""
+        );
+        assert_eq!(title("  ").unwrap_err(), "name a book by id or title");
+
+        // A title that ends in a number is still found by its name.
+        books[0].record.title = "Catch 3".to_string();
+        assert_eq!(find_book(&books, "catch 3").unwrap().id, books[0].id);
+    }
+}
@@ -0,0 +1,286 @@
+//! Slash command argument completion from the library's books, authors, series, tags and
+//! publishers, ranked by a fuzzy match on what has been typed so far.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use calibre_library::metadata::MetadataDb;
+use serde::{Deserialize, Serialize};
+
+/// Most completions offered at once.
+pub const LIMIT: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Kind {
+    Book,
+    Author,
+    Series,
+    Tag,
+    Publisher,
+}
+
+impl Kind {
+    pub const ALL: &'static [Kind] = &[
+        Kind::Book,
+        Kind::Author,
+        Kind::Series,
+        Kind::Tag,
+        Kind::Publisher,
+    ];
+
+    fn noun(self) -> &'static str {
+        match self {
+            Self::Book => "book",
+            Self::Author => "author",
+            Self::Series => "series",
+            Self::Tag => "tag",
+            Self::Publisher => "publisher",
+        }
+    }
+}
+
+/// Something that can be completed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub kind: Kind,
+    pub id: i64,
+    /// Title of a book, or name of anything else; what typed text is matched against.
+    pub name: String,
+    /// A book's authors, or how many books anything else has.
+    detail: String,
+}
+
+impl Entry {
+    /// What the completion menu shows, e.g. `Pride and Prejudice (#3) — Jane Austen` or
+    /// `Arthur Conan Doyle — author, 2 books`.
+    pub fn label(&self) -> String {
+        match self.kind {
+            Kind::Book if self.detail.is_empty() => format!("{} (#{})", self.name, self.id),
+            Kind::Book => format!("{} (#{}) — {}", self.name, self.id, self.detail),
+            kind => format!("{} — {}, {}", self.name, kind.noun(), self.detail),
+        }
+    }
+}
+
+/// The completable entries of one library, reloaded when its `metadata.db` changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Catalog {
+    stamp: Stamp,
+    entries: Vec<Entry>,
+}
+
+/// The library a catalog was loaded from, and how its `metadata.db` was then.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stamp {
+    library: PathBuf,
+    /// `metadata.db`'s size and modification time, which change with every Calibre edit.
+    db: Option<(u64, SystemTime)>,
+}
+
+impl Stamp {
+    fn of(library: &Path) -> Self {
+        let db = fs::metadata(library.join("metadata.db"))
+            .ok()
+            .and_then(|metadata| Some((metadata.len(), metadata.modified().ok()?)));
+        Self {
+            library: library.to_path_buf(),
+            db,
+        }
+    }
+
+    /// Whether a catalog with this stamp is still an accurate catalog of `library`.
+    pub fn is_current(&self, library: &Path) -> bool {
+        self.db.is_some() && *self == Self::of(library)
+    }
+}
+
+impl Catalog {
+    pub fn load(library: &Path) -> Result<Self, String> {
+        let db = MetadataDb::open(library)?;
+        let mut entries = Vec::new();
+
+        let books = db.library()?;
+        for book in &books {
+            entries.push(Entry {
+                kind: Kind::Book,
+                id: book.id,
+                name: book.title.clone(),
+                detail: book.authors.join(" & "),
+            });
+        }
+
+        let lookups = [
+            (
+                Kind::Author,
+                db.authors()?
+                    .into_iter()
+                    .map(|author| (author.id, author.name))
+                    .collect::<Vec<_>>(),
+                db.books_authors_link()?,
+            ),
+            (
+                Kind::Series,
+                db.series()?
+                    .into_iter()
+                    .map(|series| (series.id, series.name))
+                    .collect(),
+                db.books_series_link()?,
+            ),
+            (
+                Kind::Tag,
+                db.tags()?
+                    .into_iter()
+                    .map(|tag| (tag.id, tag.name))
+                    .collect(),
+                db.books_tags_link()?,
+            ),
+            (
+                Kind::Publisher,
+                db.publishers()?
+                    .into_iter()
+                    .map(|publisher| (publisher.id, publisher.name))
+                    .collect(),
+                db.books_publishers_link()?,
+            ),
+        ];
+        for (kind, names, links) in lookups {
+            let mut counts: HashMap<i64, usize> = HashMap::new();
+            for link in links {
+                *counts.entry(link.item).or_default() += 1;
+            }
+            for (id, name) in names {
+                let count = counts.get(&id).copied().unwrap_or(0);
+                entries.push(Entry {
+                    kind,
+                    id,
+                    name,
+                    detail: match count {
+                        1 => "1 book".to_string(),
+                        count => format!("{count} books"),
+                    },
+                });
+            }
+        }
+
+        Ok(Self {
+            stamp: Stamp::of(library),
+            entries,
+        })
+    }
+
+    pub fn stamp(&self) -> &Stamp {
+        &self.stamp
+    }
+
+    /// The best matches for `query` among entries of the given kinds, best first and at
+    /// most `LIMIT` of them. An empty query lists entries in catalog order.
+    pub fn rank(&self, query: &str, kinds: &[Kind]) -> Vec<&Entry> {
+        let mut scored = self
+            .entries
+            .iter()
+            .filter(|entry| kinds.contains(&entry.kind))
+            .filter_map(|entry| Some((fuzzy_score(query, &entry.name)?, entry)))
+            .collect::<Vec<_>>();
+        // Stable, so equal scores keep catalog order: books by id, then names.
+        scored.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+        scored
+            .into_iter()
+            .take(LIMIT)
+            .map(|(_, entry)| entry)
+            .collect()
+    }
+}
+
+/// How well `query` matches `candidate`, or `None` if its characters do not all appear in
+/// order. Case is ignored. Matches at the start, at word starts and in runs score higher,
+/// skipped characters lower, so `pride` ranks "Pride and Prejudice" above "Spiders".
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<i64> {
+    let query = query.trim().to_lowercase();
+    if query.is_empty() {
+        return Some(0);
+    }
+    let query: Vec<char> = query.chars().collect();
+    let candidate: Vec<char> = candidate.to_lowercase().chars().collect();
+
+    let mut score: i64 = 0;
+    let mut next = 0;
+    let mut previous_match: Option<usize> = None;
+    for (index, c) in candidate.iter().enumerate() {
+        if next == query.len() {
+            break;
+        }
+        if *c != query[next] {
+            continue;
+        }
+        score += 1;
+        if index == 0 {
+            score += 8;
+        } else if !candidate[index - 1].is_alphanumeric() {
+            score += 5;
+        }
+        match previous_match {
+            Some(previous) if previous + 1 == index => score += 4,
+            Some(previous) => score -= (index - previous - 1).min(5) as i64,
+            None => score -= index.min(10) as i64,
+        }
+        previous_match = Some(index);
+        next += 1;
+    }
+    if next < query.len() {
+        return None;
+    }
+    // Among equal matches, prefer the shorter candidate.
+    Some(score * 100 - candidate.len() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture() -> Catalog {
+        let library = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/test_library");
+        Catalog::load(&library).unwrap()
+    }
+
+    #[test]
+    fn completes_books_with_id_and_authors() {
+        let catalog = fixture();
+        let labels = catalog
+            .rank("pride", &[Kind::Book])
+            .iter()
+            .map(|entry| entry.label())
+            .collect::<Vec<_>>();
+        assert_eq!(labels, ["Pride and Prejudice (#3) — Jane Austen"]);
+
+        // As calibre-commands hands it to the extension, which asks again with its stamp.
+        let json = serde_json::to_string(&catalog).unwrap();
+        let catalog = serde_json::from_str::<Catalog>(&json).unwrap();
+        assert_eq!(catalog.rank("pride", &[Kind::Book])[0].id, 3);
+        let stamp: Stamp =
+            serde_json::from_str(&serde_json::to_string(catalog.stamp()).unwrap()).unwrap();
+        let library = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/test_library");
+        assert!(stamp.is_current(&library));
+        assert!(!catalog
+            .stamp()
+            .is_current(&library.with_file_name("fts_library")));
+    }
+
+    #[test]
+    fn ranks_word_starts_above_scattered_matches() {
+        let catalog = fixture();
+        let names = catalog
+            .rank("s", Kind::ALL)
+            .iter()
+            .map(|entry| entry.name.as_str())
+            .take(2)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["Sherlock Holmes", "Spencer Blackett"]);
+        assert_eq!(
+            catalog.rank("doyle", Kind::ALL)[0].label(),
+            "Arthur Conan Doyle — author, 2 books"
+        );
+        assert!(fuzzy_score("xyz", "Pride and Prejudice").is_none());
+    }
+}
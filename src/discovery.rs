@@ -0,0 +1,342 @@
+//! Finds the Calibre libraries on this machine, the way `config_discovery.py` does on the
+//! server side: Calibre's own config files first, then the `CALIBRE_LIBRARY_PATH` and
+//! `CALIBRE_LIBRARIES` overrides, then the places Calibre suggests for new libraries.
+//!
+//! This only works natively, in `calibre-commands`: Zed lets the extension itself see
+//! nothing outside its work directory, neither Calibre's config nor the libraries.
+
+use std::cmp::Reverse;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use calibre_library::sqlite::Database;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::library;
+
+/// A Calibre library found on disk.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Library {
+    pub name: String,
+    pub path: PathBuf,
+    /// Whether this is the library Calibre opens by default.
+    pub is_active: bool,
+}
+
+impl Library {
+    fn new(path: PathBuf) -> Self {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.to_string_lossy().into_owned());
+        Self {
+            name,
+            path,
+            is_active: false,
+        }
+    }
+
+    pub fn metadata_db(&self) -> PathBuf {
+        self.path.join("metadata.db")
+    }
+
+    pub fn book_count(&self) -> Result<usize, String> {
+        Database::open(&self.metadata_db())?.row_count("books")
+    }
+}
+
+/// Calibre's `global.py.json`, which holds the library Calibre last opened.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct GlobalPrefs {
+    library_path: Option<String>,
+}
+
+/// Calibre's `gui.json`, which counts how often each known library has been opened.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct GuiPrefs {
+    library_usage_stats: serde_json::Map<String, Value>,
+}
+
+/// Discovers libraries, active library first, then in the order they were found.
+///
+/// `env` looks up variables in the user's shell environment, which for a worktree is its
+/// `shell_env` rather than the extension's own.
+pub fn discover(env: &dyn Fn(&str) -> Option<String>) -> Vec<Library> {
+    let mut found = Discovered::default();
+
+    if let Some(config_dir) = calibre_config_dir(env) {
+        found.read_config_dir(&config_dir);
+    }
+
+    if let Some(path) = env("CALIBRE_LIBRARY_PATH").filter(|path| !path.trim().is_empty()) {
+        found.add_active(PathBuf::from(path.trim()));
+    }
+    if let Some(paths) = env("CALIBRE_LIBRARIES") {
+        for path in paths
+            .split(',')
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+        {
+            found.add(PathBuf::from(path));
+        }
+    }
+
+    if let Some(home) = env("HOME").or_else(|| env("USERPROFILE")) {
+        let home = PathBuf::from(home);
+        for location in [
+            home.join("Calibre Library"),
+            home.join("Documents").join("Calibre Library"),
+            home.join("Books").join("Calibre Library"),
+            home.join("Library").join("Calibre Library"),
+        ] {
+            found.add_location(&location);
+        }
+    }
+    found.add_location(Path::new("/opt/calibre/library"));
+
+    found.into_libraries()
+}
+
+/// The active library, or else the first one found.
+pub fn active(libraries: &[Library]) -> Option<&Library> {
+    libraries
+        .iter()
+        .find(|library| library.is_active)
+        .or(libraries.first())
+}
+
+/// Calibre's config directory; `CALIBRE_CONFIG_DIRECTORY` overrides it as in Calibre.
+fn calibre_config_dir(env: &dyn Fn(&str) -> Option<String>) -> Option<PathBuf> {
+    if let Some(dir) = env("CALIBRE_CONFIG_DIRECTORY") {
+        return Some(PathBuf::from(dir));
+    }
+    match std::env::consts::OS {
+        "windows" => env("APPDATA").map(|dir| PathBuf::from(dir).join("calibre")),
+        "macos" => env("HOME").map(|home| PathBuf::from(home).join("Library/Preferences/calibre")),
+        _ => env("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .map(|dir| dir.join("calibre")),
+    }
+}
+
+#[derive(Default)]
+struct Discovered {
+    libraries: Vec<Library>,
+}
+
+impl Discovered {
+    fn read_config_dir(&mut self, config_dir: &Path) {
+        if let Some(prefs) = read_json::<GlobalPrefs>(&config_dir.join("global.py.json")) {
+            if let Some(path) = prefs.library_path {
+                self.add_active(PathBuf::from(path));
+            }
+        }
+
+        // Most used first, so the list follows how the user actually works.
+        if let Some(prefs) = read_json::<GuiPrefs>(&config_dir.join("gui.json")) {
+            let mut stats: Vec<_> = prefs
+                .library_usage_stats
+                .into_iter()
+                .map(|(path, count)| (count.as_u64().unwrap_or(0), path))
+                .collect();
+            stats.sort_by_key(|(count, _)| Reverse(*count));
+            for (_, path) in stats {
+                self.add(PathBuf::from(path));
+            }
+        }
+
+        if let Some(Value::Object(infos)) =
+            read_json::<Value>(&config_dir.join("library_infos.json"))
+        {
+            for info in infos.values() {
+                if let Some(path) = info.get("path").and_then(Value::as_str) {
+                    if info.get("is_active").and_then(Value::as_bool) == Some(true) {
+                        self.add_active(PathBuf::from(path));
+                    } else {
+                        self.add(PathBuf::from(path));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Adds a directory that is a library, or whose subdirectories are libraries.
+    fn add_location(&mut self, dir: &Path) {
+        if library::is_library(dir) {
+            self.add(dir.to_path_buf());
+        } else if let Ok(entries) = fs::read_dir(dir) {
+            let mut children: Vec<_> = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .collect();
+            children.sort();
+            for child in children {
+                self.add(child);
+            }
+        }
+    }
+
+    fn add(&mut self, path: PathBuf) {
+        self.insert(path, false);
+    }
+
+    /// Adds a library as the active one. Later sources override earlier ones, so an
+    /// explicit `CALIBRE_LIBRARY_PATH` wins over what Calibre last opened.
+    fn add_active(&mut self, path: PathBuf) {
+        self.insert(path, true);
+    }
+
+    fn insert(&mut self, path: PathBuf, is_active: bool) {
+        if !library::is_library(&path) {
+            return;
+        }
+        if is_active {
+            for library in &mut self.libraries {
+                library.is_active = false;
+            }
+        }
+        match self
+            .libraries
+            .iter_mut()
+            .find(|library| library.path == path)
+        {
+            Some(library) => library.is_active |= is_active,
+            None => self.libraries.push(Library {
+                is_active,
+                ..Library::new(path)
+            }),
+        }
+    }
+
+    fn into_libraries(mut self) -> Vec<Library> {
+        // Stable, so everything else keeps discovery order.
+        self.libraries.sort_by_key(|library| !library.is_active);
+        self.libraries
+    }
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Option<T> {
+    let text = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discovers_libraries_in_calibre_order() {
+        let root = std::env::temp_dir().join(format!("calibre-discovery-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let library = |name: &str| {
+            let path = root.join(name);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("metadata.db"), "").unwrap();
+            path
+        };
+        let (last_opened, rare, frequent, info, explicit, listed) = (
+            library("Last Opened"),
+            library("Rare"),
+            library("Frequent"),
+            library("Info"),
+            library("Explicit"),
+            library("Listed"),
+        );
+        let home = root.join("home");
+        // Created out of order, as read_dir may list them.
+        library("home/Calibre Library/B");
+        library("home/Calibre Library/A");
+        fs::create_dir_all(home.join("Calibre Library/Not a library")).unwrap();
+        let config = root.join("config");
+        fs::create_dir_all(&config).unwrap();
+        let json = |path: &Path| serde_json::to_string(path).unwrap();
+        fs::write(
+            config.join("global.py.json"),
+            format!(r#"{{"library_path": {}}}"#, json(&last_opened)),
+        )
+        .unwrap();
+        fs::write(
+            config.join("gui.json"),
+            format!(
+                r#"{{"library_usage_stats": {{{}: 2, {}: 9, {}: 5}}}}"#,
+                json(&rare),
+                json(&frequent),
+                json(&last_opened)
+            ),
+        )
+        .unwrap();
+        fs::write(
+            config.join("library_infos.json"),
+            format!(
+                r#"{{"a": {{"path": {}}}, "b": {{"path": {}}}}}"#,
+                json(&info),
+                json(&root.join("Deleted"))
+            ),
+        )
+        .unwrap();
+
+        let mut vars = vec![
+            (
+                "CALIBRE_CONFIG_DIRECTORY",
+                config.to_string_lossy().into_owned(),
+            ),
+            ("HOME", home.to_string_lossy().into_owned()),
+            (
+                "CALIBRE_LIBRARIES",
+                format!(" {} ,, {} ", listed.display(), rare.display()),
+            ),
+        ];
+        let discover_with = |vars: &[(&str, String)]| {
+            let libraries = discover(&|name| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.clone())
+            });
+            libraries
+                .into_iter()
+                .map(|library| (library.name, library.is_active))
+                .collect::<Vec<_>>()
+        };
+        let named = |names: &[(&str, bool)]| {
+            names
+                .iter()
+                .map(|(name, active)| (name.to_string(), *active))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            discover_with(&vars),
+            named(&[
+                ("Last Opened", true),
+                ("Frequent", false),
+                ("Rare", false),
+                ("Info", false),
+                ("Listed", false),
+                ("A", false),
+                ("B", false),
+            ])
+        );
+
+        vars.push((
+            "CALIBRE_LIBRARY_PATH",
+            explicit.to_string_lossy().into_owned(),
+        ));
+        let libraries = discover_with(&vars);
+        assert_eq!(libraries[0], ("Explicit".to_string(), true));
+        assert_eq!(libraries.iter().filter(|(_, active)| *active).count(), 1);
+
+        let libraries = discover(&|name| {
+            (name == "CALIBRE_CONFIG_DIRECTORY").then(|| config.to_string_lossy().into_owned())
+        });
+        assert_eq!(
+            active(&libraries).map(|library| &library.path),
+            Some(&last_opened)
+        );
+        assert_eq!(libraries.len(), 4);
+        assert_eq!(active(&[]), None);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+//! The `calibre` indexed docs provider behind `/docs calibre <book>`: each book with an
+//! EPUB is a package named after its title, such as `pride-and-prejudice`, and its
+//! entries are the sections its table of contents lists.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use calibre_library::cite;
+use calibre_library::epub::{Chapter, Epub};
+use calibre_library::metadata::{self, Book};
+use serde::{Deserialize, Serialize};
+use zed_extension_api::KeyValueStore;
+
+pub const PROVIDER: &str = "calibre";
+
+/// Packages indexed this session, by name.
+pub type Indexed = HashMap<String, Version>;
+
+/// Which library a package was indexed from, and its book's `last_modified` then.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Version {
+    library: PathBuf,
+    last_modified: Option<String>,
+}
+
+/// A package as `calibre-commands` indexed it, without its entries if its book has not
+/// been modified since the version it was told about.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Indexing {
+    version: Version,
+    entries: Option<Vec<(String, String)>>,
+}
+
+/// Package names of the books in the library at `library`.
+pub fn suggest(library: &Path) -> Result<Vec<String>, String> {
+    let books = metadata::books(library)?;
+    let mut names = packages(&books)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    names.sort();
+    Ok(names)
+}
+
+/// The entries of `package`, unless its book is unchanged since `known`, the version of
+/// it indexed before.
+pub fn index(library: &Path, package: &str, known: Option<&Version>) -> Result<Indexing, String> {
+    let books = metadata::books(library)?;
+    let packages = packages(&books);
+    let (_, book) = packages
+        .iter()
+        .find(|(name, _)| name == package)
+        .ok_or_else(|| format!("no book with an EPUB is called \"{package}\""))?;
+    let version = Version {
+        library: library.to_path_buf(),
+        last_modified: book.last_modified.clone(),
+    };
+    if known == Some(&version) {
+        return Ok(Indexing {
+            version,
+            entries: None,
+        });
+    }
+
+    let path = book
+        .file(library, "EPUB")
+        .ok_or_else(|| format!("{} (#{}) has no EPUB", book.title, book.id))?;
+    let sections = Epub::open(&path)?.sections()?;
+    Ok(Indexing {
+        version,
+        entries: Some(entries(package, book, &sections)),
+    })
+}
+
+/// Stores the entries of an indexed `package` in `database`, and records its version.
+pub fn store(
+    package: &str,
+    indexing: Indexing,
+    database: &KeyValueStore,
+    indexed: &mut Indexed,
+) -> Result<(), String> {
+    for (key, text) in indexing.entries.unwrap_or_default() {
+        database.insert(&key, &text)?;
+    }
+    indexed.insert(package.to_string(), indexing.version);
+    Ok(())
+}
+
+/// Each book with an EPUB under its package name: its title as a slug, with the book id
+/// appended when an earlier book already has that name.
+fn packages(books: &[Book]) -> Vec<(String, &Book)> {
+    let mut books = books
+        .iter()
+        .filter(|book| book.formats.iter().any(|format| format == "EPUB"))
+        .collect::<Vec<_>>();
+    books.sort_by_key(|book| book.id);
+    let mut taken = HashSet::new();
+    books
+        .into_iter()
+        .map(|book| {
+            let mut name = slug(&book.title);
+            if name.is_empty() || taken.contains(&name) {
+                name = format!(
+                    "{}-{}",
+                    if name.is_empty() { "book" } else { &name },
+                    book.id
+                );
+            }
+            taken.insert(name.clone());
+            (name, book)
+        })
+        .collect()
+}
+
+/// A package's entries: an overview listing its sections under the package name, then
+/// each section under `package/section-title`.
+fn entries(package: &str, book: &Book, sections: &[Chapter]) -> Vec<(String, String)> {
+    let mut byline = book.authors.join(" & ");
+    if let Some(year) = cite::year(book) {
+        byline = match byline.is_empty() {
+            true => year.to_string(),
+            false => format!("{byline}, {year}"),
+        };
+    }
+    let mut taken = HashSet::new();
+    let keys = sections
+        .iter()
+        .enumerate()
+        .map(|(index, section)| {
+            let number = index + 1;
+            let mut key = match slug(&section.title) {
+                title if title.is_empty() => format!("{package}/section-{number}"),
+                title => format!("{package}/{title}"),
+            };
+            if taken.contains(&key) {
+                key = format!("{key}-{number}");
+            }
+            taken.insert(key.clone());
+            key
+        })
+        .collect::<Vec<_>>();
+
+    let mut overview = format!("# {}\n\n", book.title);
+    if !byline.is_empty() {
+        let _ = writeln!(overview, "{byline}\n");
+    }
+    overview.push_str("## Contents\n\n");
+    for (key, section) in keys.iter().zip(sections) {
+        let _ = writeln!(overview, "- {} (`{key}`)", section.title);
+    }
+
+    let mut entries = vec![(package.to_string(), overview)];
+    for (key, section) in keys.into_iter().zip(sections) {
+        let mut text = format!("# {}\n\n*{}*", section.title, book.title);
+        if !byline.is_empty() {
+            let _ = write!(text, ", {byline}");
+        }
+        let _ = write!(text, "\n\n{}\n", section.text);
+        entries.push((key, text));
+    }
+    entries
+}
+
+/// Lowercase words joined by `-`: `Pride and Prejudice` is `pride-and-prejudice`.
+fn slug(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if matches!(c, '\'' | '’') {
+            continue;
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/test_library")
+    }
+
+    #[test]
+    fn names_packages_after_titles() {
+        assert_eq!(
+            suggest(&library()).unwrap(),
+            [
+                "a-study-in-scarlet",
+                "pride-and-prejudice",
+                "the-adventures-of-tom-sawyer",
+                "the-sign-of-the-four"
+            ]
+        );
+        assert_eq!(
+            slug("Alice’s Adventures — in Wonderland!"),
+            "alices-adventures-in-wonderland"
+        );
+    }
+
+    #[test]
+    fn makes_an_entry_per_section() {
+        let books = metadata::books(&library()).unwrap();
+        let book = &books[0];
+        let epub = book.file(&library(), "EPUB").unwrap();
+        let sections = Epub::open(&epub).unwrap().sections().unwrap();
+        let entries = entries("a-study-in-scarlet", book, &sections);
+        let keys = entries
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                "a-study-in-scarlet",
+                "a-study-in-scarlet/page-1",
+                "a-study-in-scarlet/page-2",
+                "a-study-in-scarlet/page-3",
+                "a-study-in-scarlet/page-4",
+                "a-study-in-scarlet/page-5"
+            ]
+        );
+        assert!(entries[0]
+            .1
+            .starts_with("# A Study in Scarlet\n\nArthur Conan Doyle, 1887\n\n## Contents\n\n- Page 1 (`a-study-in-scarlet/page-1`)\n"));
+        assert!(entries[3]
+            .1
+            .starts_with("# Page 3\n\n*A Study in Scarlet*, Arthur Conan Doyle, 1887\n\n"));
+        assert!(entries[3].1.contains("page 3 of a minimal test EPUB file"));
+
+        let indexing = index(&library(), "a-study-in-scarlet", None).unwrap();
+        assert_eq!(indexing.entries.map(|entries| entries.len()), Some(6));
+        let unchanged = index(&library(), "a-study-in-scarlet", Some(&indexing.version)).unwrap();
+        assert!(unchanged.entries.is_none());
+    }
+}
@@ -0,0 +1,510 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use zed_extension_api as zed;
+
+use crate::interpreter::{Interpreter, InterpreterKind};
+use crate::server::PACKAGE_NAME;
+use crate::settings::CalibreSettings;
+use crate::version::SUPPORTED;
+
+/// Distribution name calibre-mcp is published under.
+pub const DISTRIBUTION: &str = "schip-mcp-calibre";
+
+/// calibre-mcp release the extension installs when it manages the server itself. Has to lie
+/// within `version::SUPPORTED`, and so has read-only mode.
+pub const PINNED_VERSION: &str = "1.9.0";
+
+const VENV_DIR: &str = "calibre-mcp-venv";
+const MARKER_FILE: &str = "calibre-mcp-install.json";
+const BOOTSTRAP_FILE: &str = "calibre-mcp-bootstrap.py";
+const BOOTSTRAP_SCRIPT: &str = include_str!("bootstrap.py");
+const BUNDLE_DIR: &str = "calibre-mcp-bundle";
+
+/// What the managed virtualenv should contain. Mirrors the marker the bootstrap script
+/// writes after a successful install, so a matching marker means there is nothing to do.
+///
+/// The marker also fingerprints `wheel_bundle`, so that a replaced bundle is reinstalled,
+/// but only the bootstrap script can take that fingerprint: Zed does not let the extension
+/// look at files outside its work directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct InstallSpec {
+    requirement: String,
+    index_url: Option<String>,
+    wheel_dir: Option<String>,
+    wheel_bundle: Option<String>,
+}
+
+/// The marker itself: the spec that was installed, and whether the install turned out to
+/// have read-only mode, which markers from older bootstrap scripts do not record.
+#[derive(Debug, Deserialize)]
+struct Marker {
+    #[serde(flatten)]
+    spec: InstallSpec,
+    read_only: Option<bool>,
+}
+
+/// A calibre-mcp install kept in a virtualenv under the extension's work directory.
+#[derive(Debug, Clone)]
+pub struct ManagedInstall {
+    work_dir: PathBuf,
+    spec: InstallSpec,
+    /// Whether the server must open the library read-only.
+    read_only: bool,
+}
+
+impl ManagedInstall {
+    /// Online installs pin `PINNED_VERSION`. Offline installs from `wheel_dir` or
+    /// `wheel_bundle` accept any supported release, since the wheelhouse decides which one
+    /// is available.
+    pub fn new(work_dir: PathBuf, settings: &CalibreSettings) -> zed::Result<Self> {
+        let sources = [
+            ("package_index", settings.package_index.is_some()),
+            ("wheel_dir", settings.wheel_dir.is_some()),
+            ("wheel_bundle", settings.wheel_bundle.is_some()),
+        ];
+        let set = sources
+            .iter()
+            .filter(|(_, is_set)| *is_set)
+            .map(|(key, _)| format!("`{key}`"))
+            .collect::<Vec<_>>();
+        if set.len() > 1 {
+            return Err(format!(
+                "set only one of `package_index`, `wheel_dir` and `wheel_bundle`, not {}",
+                set.join(" and ")
+            ));
+        }
+
+        if let Some(bundle) = &settings.wheel_bundle {
+            check_bundle_name(Path::new(bundle))?;
+        }
+
+        let offline = settings.wheel_dir.is_some() || settings.wheel_bundle.is_some();
+        let requirement = if offline {
+            format!("{DISTRIBUTION}{SUPPORTED}")
+        } else {
+            format!("{DISTRIBUTION}=={PINNED_VERSION}")
+        };
+
+        Ok(Self {
+            work_dir,
+            spec: InstallSpec {
+                requirement,
+                index_url: settings.package_index.clone(),
+                wheel_dir: settings.wheel_dir.clone(),
+                wheel_bundle: settings.wheel_bundle.clone(),
+            },
+            read_only: settings.read_only(),
+        })
+    }
+
+    fn venv_dir(&self) -> PathBuf {
+        self.work_dir.join(VENV_DIR)
+    }
+
+    fn marker_path(&self) -> PathBuf {
+        self.venv_dir().join(MARKER_FILE)
+    }
+
+    fn venv_python(&self, os: zed::Os) -> PathBuf {
+        match os {
+            zed::Os::Windows => self.venv_dir().join("Scripts").join("python.exe"),
+            zed::Os::Mac | zed::Os::Linux => self.venv_dir().join("bin").join("python"),
+        }
+    }
+
+    /// Launches the venv directly when the install is current, and otherwise goes through
+    /// the bootstrap script, which installs first and then execs the same server.
+    ///
+    /// With only `uvx` available there is no Python to run the bootstrap with, so the pinned
+    /// release runs as a uv tool environment instead, which uv caches just the same. Every
+    /// release it can install has read-only mode, which the server checks at startup.
+    pub fn command(
+        &self,
+        interpreter: zed::Result<Interpreter>,
+        server_args: &[String],
+    ) -> zed::Result<zed::Command> {
+        self.command_on(zed::current_platform().0, interpreter, server_args)
+    }
+
+    /// [`Self::command`] on `os`, which decides where the venv keeps its interpreter.
+    fn command_on(
+        &self,
+        os: zed::Os,
+        interpreter: zed::Result<Interpreter>,
+        server_args: &[String],
+    ) -> zed::Result<zed::Command> {
+        let marker = fs::read_to_string(self.marker_path()).ok();
+        let launch = plan(
+            &self.spec,
+            self.read_only,
+            marker.as_deref(),
+            self.venv_python(os).exists(),
+            interpreter
+                .as_ref()
+                .map(|interpreter| interpreter.kind)
+                .map_err(Clone::clone),
+        )?;
+        if launch == Launch::Venv {
+            let mut args = vec!["-m".to_string(), PACKAGE_NAME.to_string()];
+            args.extend_from_slice(server_args);
+            return Ok(zed::Command {
+                command: self.venv_python(os).to_string_lossy().into_owned(),
+                args,
+                env: Default::default(),
+            });
+        }
+
+        let interpreter = interpreter?;
+        if launch == Launch::UvxTool {
+            let mut args = self.index_args();
+            args.extend([
+                "--from".to_string(),
+                self.spec.requirement.clone(),
+                DISTRIBUTION.to_string(),
+            ]);
+            args.extend_from_slice(server_args);
+            return Ok(zed::Command {
+                command: interpreter.path.clone(),
+                args,
+                env: Default::default(),
+            });
+        }
+
+        let bootstrap = self.work_dir.join(BOOTSTRAP_FILE);
+        fs::write(&bootstrap, BOOTSTRAP_SCRIPT)
+            .map_err(|err| format!("failed to write {}: {err}", bootstrap.display()))?;
+
+        let mut args = match interpreter.kind {
+            InterpreterKind::Uv => vec![
+                "run".to_string(),
+                "--no-project".to_string(),
+                "python".to_string(),
+            ],
+            InterpreterKind::Uvx | InterpreterKind::Python => Vec::new(),
+        };
+        args.extend([
+            bootstrap.to_string_lossy().into_owned(),
+            "--venv".to_string(),
+            self.venv_dir().to_string_lossy().into_owned(),
+            "--marker".to_string(),
+            self.marker_path().to_string_lossy().into_owned(),
+            "--requirement".to_string(),
+            self.spec.requirement.clone(),
+        ]);
+        if let Some(index_url) = &self.spec.index_url {
+            args.extend(["--index-url".to_string(), index_url.clone()]);
+        }
+        if let Some(wheel_dir) = &self.spec.wheel_dir {
+            args.extend(["--wheel-dir".to_string(), wheel_dir.clone()]);
+        }
+        if let Some(bundle) = &self.spec.wheel_bundle {
+            args.extend([
+                "--bundle".to_string(),
+                bundle.clone(),
+                "--bundle-dir".to_string(),
+                self.work_dir
+                    .join(BUNDLE_DIR)
+                    .to_string_lossy()
+                    .into_owned(),
+            ]);
+        }
+        args.extend_from_slice(server_args);
+
+        Ok(zed::Command {
+            command: interpreter.path.clone(),
+            args,
+            env: Default::default(),
+        })
+    }
+
+    /// Index selection in the flag syntax shared by pip and uv.
+    fn index_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(index_url) = &self.spec.index_url {
+            args.extend(["--index-url".to_string(), index_url.clone()]);
+        }
+        if let Some(wheel_dir) = &self.spec.wheel_dir {
+            args.extend([
+                "--no-index".to_string(),
+                "--find-links".to_string(),
+                wheel_dir.clone(),
+            ]);
+        }
+        args
+    }
+}
+
+/// Checks that a wheel bundle is named as a `.mcpb` or `.zip` file; the bootstrap script
+/// checks that it exists.
+fn check_bundle_name(bundle: &Path) -> zed::Result<()> {
+    let is_archive = bundle
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            extension.eq_ignore_ascii_case("mcpb") || extension.eq_ignore_ascii_case("zip")
+        });
+    if !is_archive {
+        return Err(format!(
+            "`wheel_bundle` {} must be a .mcpb or .zip file",
+            bundle.display()
+        ));
+    }
+    Ok(())
+}
+
+/// How the managed install is launched.
+#[derive(Debug, PartialEq, Eq)]
+enum Launch {
+    /// Straight from the virtualenv, which holds exactly the requested install.
+    Venv,
+    /// Through the bootstrap script, which installs or reinstalls first.
+    Bootstrap,
+    /// As a uv tool environment, when `uvx` is all there is to run Python with.
+    UvxTool,
+}
+
+/// Decides how to launch `spec`, given the marker the bootstrap script left after the
+/// last successful install, whether the venv's interpreter is still there, and the
+/// interpreter found on PATH, which a current install does not need.
+///
+/// An install from `wheel_bundle` always goes through the bootstrap script, which alone
+/// can tell whether the bundle has been replaced and launches the venv if it has not. So
+/// does a `read_only` launch of an install whose marker does not say it has read-only
+/// mode, as the bootstrap script then checks; one whose marker says it lacks it is refused.
+fn plan(
+    spec: &InstallSpec,
+    read_only: bool,
+    marker: Option<&str>,
+    venv_python_exists: bool,
+    interpreter: zed::Result<InterpreterKind>,
+) -> zed::Result<Launch> {
+    let installed = marker
+        .and_then(|marker| serde_json::from_str::<Marker>(marker).ok())
+        .filter(|installed| installed.spec == *spec && venv_python_exists);
+    if let Some(installed) = installed {
+        if read_only && installed.read_only == Some(false) {
+            return Err(format!(
+                "the managed {} cannot open the library read-only; set `read_only` to false, \
+                 or point `wheel_dir` or `wheel_bundle` at a release that can",
+                spec.requirement
+            ));
+        }
+        if spec.wheel_bundle.is_none() && (!read_only || installed.read_only == Some(true)) {
+            return Ok(Launch::Venv);
+        }
+    }
+    if interpreter? == InterpreterKind::Uvx {
+        if spec.wheel_bundle.is_some() {
+            return Err(
+                "`wheel_bundle` is unpacked with Python, but only `uvx` was found on PATH"
+                    .to_string(),
+            );
+        }
+        return Ok(Launch::UvxTool);
+    }
+    Ok(Launch::Bootstrap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::version::{self, Version};
+
+    fn spec(wheel_dir: Option<&str>) -> InstallSpec {
+        InstallSpec {
+            requirement: format!("{DISTRIBUTION}=={PINNED_VERSION}"),
+            index_url: None,
+            wheel_dir: wheel_dir.map(str::to_string),
+            wheel_bundle: None,
+        }
+    }
+
+    #[test]
+    fn reuses_the_venv_only_when_its_marker_matches() {
+        let spec = spec(None);
+        // As bootstrap.py's `json.dump` writes it.
+        let marker = format!(
+            "{{\"requirement\": \"{DISTRIBUTION}=={PINNED_VERSION}\", \"index_url\": null, \
+             \"wheel_dir\": null, \"wheel_bundle\": null, \"bundle_stamp\": null}}"
+        );
+        let python = Ok(InterpreterKind::Python);
+        assert_eq!(
+            plan(&spec, false, Some(&marker), true, python.clone()),
+            Ok(Launch::Venv)
+        );
+        assert_eq!(
+            plan(&spec, false, Some(&marker), false, python.clone()),
+            Ok(Launch::Bootstrap)
+        );
+        assert_eq!(
+            plan(&spec, false, None, true, python.clone()),
+            Ok(Launch::Bootstrap)
+        );
+        assert_eq!(
+            plan(&spec, false, Some("{not json"), true, python.clone()),
+            Ok(Launch::Bootstrap)
+        );
+
+        // A changed setting is a different install.
+        let other = InstallSpec {
+            index_url: Some("https://pypi.example/simple".to_string()),
+            ..spec.clone()
+        };
+        assert_eq!(
+            plan(&other, false, Some(&marker), true, python.clone()),
+            Ok(Launch::Bootstrap)
+        );
+        // Only the bootstrap script can tell whether a bundle has been replaced.
+        let bundle = InstallSpec {
+            wheel_bundle: Some("/srv/calibre-mcp.mcpb".to_string()),
+            ..spec.clone()
+        };
+        let bundle_marker = format!(
+            "{{\"requirement\": \"{DISTRIBUTION}=={PINNED_VERSION}\", \"index_url\": null, \
+             \"wheel_dir\": null, \"wheel_bundle\": \"/srv/calibre-mcp.mcpb\", \
+             \"bundle_stamp\": \"1024-1700000000\"}}"
+        );
+        assert_eq!(
+            plan(&bundle, false, Some(&bundle_marker), true, python),
+            Ok(Launch::Bootstrap)
+        );
+
+        let uvx = || Ok(InterpreterKind::Uvx);
+        assert_eq!(
+            plan(&spec, false, Some(&marker), true, uvx()),
+            Ok(Launch::Venv)
+        );
+        assert_eq!(plan(&spec, false, None, false, uvx()), Ok(Launch::UvxTool));
+        assert!(plan(&bundle, false, Some(&bundle_marker), true, uvx())
+            .unwrap_err()
+            .contains("only `uvx` was found"));
+
+        // Read-only launches need an install known to have read-only mode.
+        let python = || Ok(InterpreterKind::Python);
+        let checked =
+            |read_only: bool| marker.replace('}', &format!(", \"read_only\": {read_only}}}"));
+        assert_eq!(
+            plan(&spec, true, Some(&checked(true)), true, python()),
+            Ok(Launch::Venv)
+        );
+        assert_eq!(
+            plan(&spec, true, Some(&marker), true, python()),
+            Ok(Launch::Bootstrap)
+        );
+        assert!(plan(&spec, true, Some(&checked(false)), true, python())
+            .unwrap_err()
+            .contains("cannot open the library read-only"));
+        assert_eq!(
+            plan(&spec, false, Some(&checked(false)), true, python()),
+            Ok(Launch::Venv)
+        );
+        assert!(version::SUPPORTED.contains(Version::parse(PINNED_VERSION).unwrap()));
+
+        // Without an interpreter, only a current install can be launched.
+        let none = || Err("no interpreter".to_string());
+        assert_eq!(
+            plan(&spec, false, Some(&marker), true, none()),
+            Ok(Launch::Venv)
+        );
+        assert_eq!(
+            plan(&spec, false, None, true, none()),
+            Err("no interpreter".to_string())
+        );
+    }
+
+    #[test]
+    fn installs_from_a_wheel_dir_without_an_index() {
+        let work_dir = std::env::temp_dir().join(format!("calibre-install-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&work_dir);
+        fs::create_dir_all(&work_dir).unwrap();
+        // Whether it exists is for the bootstrap script, or uv, to find out.
+        let wheels = "/srv/wheels".to_string();
+        let settings = CalibreSettings {
+            wheel_dir: Some(wheels.clone()),
+            ..CalibreSettings::default()
+        };
+        let install = ManagedInstall::new(work_dir.clone(), &settings).unwrap();
+        assert_eq!(
+            install.spec.requirement,
+            format!("{DISTRIBUTION}{SUPPORTED}")
+        );
+
+        let uvx = Interpreter {
+            kind: InterpreterKind::Uvx,
+            path: "uvx".to_string(),
+        };
+        let command = install
+            .command_on(zed::Os::Linux, Ok(uvx), &["--stdio".to_string()])
+            .unwrap();
+        assert_eq!(
+            command.args,
+            [
+                "--no-index",
+                "--find-links",
+                &wheels,
+                "--from",
+                &install.spec.requirement,
+                DISTRIBUTION,
+                "--stdio"
+            ]
+        );
+
+        let python = Interpreter {
+            kind: InterpreterKind::Python,
+            path: "python3".to_string(),
+        };
+        let command = install
+            .command_on(zed::Os::Linux, Ok(python), &["--stdio".to_string()])
+            .unwrap();
+        let wheel_dir = command.args.iter().position(|arg| arg == "--wheel-dir");
+        assert_eq!(command.args[wheel_dir.unwrap() + 1], wheels);
+        assert!(work_dir.join(BOOTSTRAP_FILE).is_file());
+
+        let bundle = |name: &str| CalibreSettings {
+            wheel_bundle: Some(name.to_string()),
+            ..CalibreSettings::default()
+        };
+        let install = ManagedInstall::new(work_dir.clone(), &bundle("/srv/Calibre.MCPB")).unwrap();
+        let command = install
+            .command_on(
+                zed::Os::Windows,
+                Ok(Interpreter {
+                    kind: InterpreterKind::Python,
+                    path: "python3".to_string(),
+                }),
+                &[],
+            )
+            .unwrap();
+        let flag = command
+            .args
+            .iter()
+            .position(|arg| arg == "--bundle")
+            .unwrap();
+        assert_eq!(
+            command.args[flag..],
+            [
+                "--bundle".to_string(),
+                "/srv/Calibre.MCPB".to_string(),
+                "--bundle-dir".to_string(),
+                work_dir.join(BUNDLE_DIR).to_string_lossy().into_owned(),
+            ]
+        );
+        assert_eq!(
+            ManagedInstall::new(work_dir.clone(), &bundle("/srv/wheels.tar")).unwrap_err(),
+            "`wheel_bundle` /srv/wheels.tar must be a .mcpb or .zip file"
+        );
+
+        let settings = CalibreSettings {
+            package_index: Some("https://pypi.example/simple".to_string()),
+            ..settings
+        };
+        assert_eq!(
+            ManagedInstall::new(work_dir.clone(), &settings).unwrap_err(),
+            "set only one of `package_index`, `wheel_dir` and `wheel_bundle`, not \
+             `package_index` and `wheel_dir`"
+        );
+        fs::remove_dir_all(&work_dir).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use zed_extension_api as zed;
+
+/// How a binary found on the PATH can run calibre-mcp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterpreterKind {
+    /// `uv run`, which resolves the project's dependencies itself.
+    Uv,
+    /// `uvx --from`, which runs the server as a cached tool environment.
+    Uvx,
+    /// A plain Python interpreter with calibre-mcp's dependencies installed.
+    Python,
+}
+
+/// Binaries tried in order of preference.
+pub const CANDIDATES: [(&str, InterpreterKind); 4] = [
+    ("uv", InterpreterKind::Uv),
+    ("uvx", InterpreterKind::Uvx),
+    ("python3", InterpreterKind::Python),
+    ("python", InterpreterKind::Python),
+];
+
+/// Where candidates were found on PATH, keyed by binary name.
+pub type Found = HashMap<String, String>;
+
+/// File in the extension's work directory recording where the candidates were found the
+/// last time Zed handed the extension a worktree. The context server is usually launched
+/// before any worktree has passed through a hook, and a `Project` cannot answer `which`.
+const FOUND_FILE: &str = "calibre-mcp-interpreters.json";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interpreter {
+    pub kind: InterpreterKind,
+    pub path: String,
+}
+
+impl Interpreter {
+    /// Picks the first candidate `which` can find.
+    pub fn find(which: impl Fn(&str) -> Option<String>) -> zed::Result<Self> {
+        Self::find_among(&CANDIDATES, which)
+    }
+
+    /// Picks the first plain Python interpreter `which` can find.
+    pub fn find_python(which: impl Fn(&str) -> Option<String>) -> zed::Result<Self> {
+        let pythons = CANDIDATES
+            .into_iter()
+            .filter(|(_, kind)| *kind == InterpreterKind::Python)
+            .collect::<Vec<_>>();
+        Self::find_among(&pythons, which)
+    }
+
+    fn find_among(
+        candidates: &[(&str, InterpreterKind)],
+        which: impl Fn(&str) -> Option<String>,
+    ) -> zed::Result<Self> {
+        candidates
+            .iter()
+            .find_map(|(binary, kind)| which(binary).map(|path| Self { kind: *kind, path }))
+            .ok_or_else(|| {
+                format!(
+                    "no interpreter for calibre-mcp found on PATH (tried {}); {}, then restart \
+                     the server",
+                    tried(candidates),
+                    install_hint(candidates)
+                )
+            })
+    }
+}
+
+/// The error for a launch before any worktree has been seen, when PATH cannot be searched.
+pub fn not_searched() -> String {
+    format!(
+        "Zed has not handed the extension a worktree yet, so PATH could not be searched for \
+         {}; open a file of the project or run a /calibre slash command, then restart the \
+         server. If none of them is installed, {} first",
+        tried(&CANDIDATES),
+        install_hint(&CANDIDATES)
+    )
+}
+
+fn tried(candidates: &[(&str, InterpreterKind)]) -> String {
+    candidates
+        .iter()
+        .map(|(binary, _)| format!("`{binary}`"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn install_hint(candidates: &[(&str, InterpreterKind)]) -> &'static str {
+    if candidates
+        .iter()
+        .any(|(_, kind)| *kind == InterpreterKind::Uv)
+    {
+        "install uv (https://docs.astral.sh/uv/) or Python 3"
+    } else {
+        "install Python 3"
+    }
+}
+
+/// Records where the candidates were found in a worktree, for launches that have none.
+pub fn save_found(work_dir: &Path, found: &Found) -> Result<(), String> {
+    let path = work_dir.join(FOUND_FILE);
+    let json = serde_json::to_string(found).map_err(|err| err.to_string())?;
+    fs::write(&path, json).map_err(|err| format!("failed to write {}: {err}", path.display()))
+}
+
+/// Where the candidates were found in the last worktree seen, in this session or an
+/// earlier one.
+pub fn load_found(work_dir: &Path) -> Option<Found> {
+    let json = fs::read_to_string(work_dir.join(FOUND_FILE)).ok()?;
+    serde_json::from_str(&json).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_uv_then_uvx_then_python() {
+        let on_path = |binaries: &'static [&'static str]| {
+            move |binary: &str| {
+                binaries
+                    .contains(&binary)
+                    .then(|| format!("/usr/bin/{binary}"))
+            }
+        };
+        let kind = |binaries| Interpreter::find(on_path(binaries)).map(|found| found.kind);
+        assert_eq!(kind(&["python", "uvx", "uv"]), Ok(InterpreterKind::Uv));
+        assert_eq!(kind(&["python3", "uvx"]), Ok(InterpreterKind::Uvx));
+        assert_eq!(
+            Interpreter::find(on_path(&["python", "python3"])),
+            Ok(Interpreter {
+                kind: InterpreterKind::Python,
+                path: "/usr/bin/python3".to_string()
+            })
+        );
+        assert_eq!(
+            Interpreter::find_python(on_path(&["uv", "python"])).map(|found| found.path),
+            Ok("/usr/bin/python".to_string())
+        );
+
+        assert_eq!(
+            kind(&[]).unwrap_err(),
+            "no interpreter for calibre-mcp found on PATH (tried `uv`, `uvx`, `python3`, \
+             `python`); install uv (https://docs.astral.sh/uv/) or Python 3, then restart the \
+             server"
+        );
+        assert_eq!(
+            Interpreter::find_python(on_path(&["uv"])).unwrap_err(),
+            "no interpreter for calibre-mcp found on PATH (tried `python3`, `python`); install \
+             Python 3, then restart the server"
+        );
+        assert!(not_searched().contains("open a file of the project"));
+    }
+
+    #[test]
+    fn records_where_candidates_were_found() {
+        let work_dir = std::env::temp_dir().join(format!("calibre-found-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&work_dir);
+        fs::create_dir_all(&work_dir).unwrap();
+        assert_eq!(load_found(&work_dir), None);
+
+        let found = Found::from([("python3".to_string(), "/usr/bin/python3".to_string())]);
+        save_found(&work_dir, &found).unwrap();
+        let loaded = load_found(&work_dir).unwrap();
+        assert_eq!(
+            Interpreter::find(|binary| loaded.get(binary).cloned())
+                .unwrap()
+                .path,
+            "/usr/bin/python3"
+        );
+        fs::remove_dir_all(&work_dir).unwrap();
+    }
+}
@@ -0,0 +1,58 @@
+//! Launching `calibre-cite-ls`, the citation language server in `crates/calibre-cite-ls`,
+//! for Markdown, LaTeX and Typst files, and for the HTML and XML files of an unpacked
+//! EPUB, which it checks.
+//!
+//! The server is a native binary, so it is not downloaded: it is found on the worktree's
+//! PATH after `cargo install --path crates/calibre-cite-ls`, or wherever
+//! `lsp.calibre-cite.binary.path` points.
+
+use zed_extension_api::{self as zed, settings::LspSettings};
+
+use crate::commands;
+use crate::settings::CalibreSettings;
+use crate::worktree::WorktreeInfo;
+
+const BINARY: &str = "calibre-cite-ls";
+
+pub fn command(
+    id: &zed::LanguageServerId,
+    worktree: &zed::Worktree,
+    info: &WorktreeInfo,
+    settings: Option<&CalibreSettings>,
+) -> zed::Result<zed::Command> {
+    let binary = LspSettings::for_worktree(id.as_ref(), worktree)?.binary;
+    let path = binary
+        .as_ref()
+        .and_then(|binary| binary.path.clone())
+        .or_else(|| worktree.which(BINARY))
+        .ok_or_else(|| {
+            format!(
+                "{BINARY} is not on PATH; install it with `cargo install --path \
+                 crates/{BINARY}` from the calibre-mcp checkout, or set \
+                 `lsp.{}.binary.path`",
+                id.as_ref()
+            )
+        })?;
+
+    // EPUB checks need no library, so without one the server still starts, and says
+    // citations are unavailable. The extension cannot look at the library, so the server
+    // checks it, and picks one from a directory of libraries.
+    let template = commands::key_template(Some(info), settings)?;
+    let mut env = vec![("CALIBRE_CITATION_KEY".to_string(), template.to_string())];
+    if let Some(library) = commands::chosen_library(Some(info), settings)? {
+        env.push((
+            "CALIBRE_LIBRARY_PATH".to_string(),
+            library.to_string_lossy().into_owned(),
+        ));
+    }
+    let mut args = Vec::new();
+    if let Some(binary) = binary {
+        env.extend(binary.env.unwrap_or_default());
+        args = binary.arguments.unwrap_or_default();
+    }
+    Ok(zed::Command {
+        command: path,
+        args,
+        env,
+    })
+}
@@ -1,10 +1,120 @@
+mod bibliography;
+mod commands;
+mod completion;
+mod discovery;
+mod docs;
+mod install;
+mod interpreter;
+mod language_server;
+mod library;
+pub mod native;
+mod project_config;
+mod quote;
+mod remote;
+mod search;
+mod server;
+mod settings;
+mod text;
+mod tool_groups;
+mod unpacked;
+mod version;
+mod worktree;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
 use zed_extension_api as zed;
 
-struct CalibreMcpExtension;
+use crate::completion::Catalog;
+use crate::settings::{CalibreSettings, ToolSelection};
+use crate::worktree::WorktreeInfo;
+
+struct CalibreMcpExtension {
+    /// Worktrees Zed has handed to the extension, keyed by worktree id. Behind a mutex
+    /// because slash command hooks only get `&self`.
+    worktrees: Mutex<HashMap<u64, WorktreeInfo>>,
+    /// Id of the worktree last handed to a hook, for completions, which get none.
+    recent_worktree: Mutex<Option<u64>>,
+    /// Settings the context server was last started with, its library resolved, for
+    /// slash commands, which cannot read them.
+    server_settings: Mutex<Option<CalibreSettings>>,
+    /// Worktrees of the project the context server was last started for that no hook had
+    /// been handed yet, so that their `.calibre-mcp.toml` could not be applied.
+    unseen_at_launch: Mutex<Vec<u64>>,
+    /// Completion entries of the library last completed from.
+    catalog: Mutex<Option<Catalog>>,
+    /// Books indexed for `/docs` this session, so unmodified ones are not read again.
+    indexed_docs: Mutex<docs::Indexed>,
+}
 
 impl CalibreMcpExtension {
     fn new() -> Self {
-        Self
+        Self {
+            worktrees: Mutex::new(HashMap::new()),
+            recent_worktree: Mutex::new(None),
+            server_settings: Mutex::new(None),
+            unseen_at_launch: Mutex::new(Vec::new()),
+            catalog: Mutex::new(None),
+            indexed_docs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records what the launcher needs to know about a worktree for later hooks, and where
+    /// it found the interpreters for launches that have no worktree at hand.
+    fn remember(&self, worktree: &zed::Worktree) -> WorktreeInfo {
+        let info = WorktreeInfo::from(worktree);
+        if let Ok(work_dir) = server::work_dir() {
+            // A launch that finds nothing recorded says how to get there itself.
+            let _ = interpreter::save_found(&work_dir, info.binaries());
+        }
+        if let Ok(mut worktrees) = self.worktrees.lock() {
+            worktrees.insert(worktree.id(), info.clone());
+        }
+        if let Ok(mut recent) = self.recent_worktree.lock() {
+            *recent = Some(worktree.id());
+        }
+        info
+    }
+
+    fn recent_worktree(&self) -> Option<WorktreeInfo> {
+        let id = (*self.recent_worktree.lock().ok()?)?;
+        self.worktrees.lock().ok()?.get(&id).cloned()
+    }
+
+    fn server_settings(&self) -> Option<CalibreSettings> {
+        self.server_settings
+            .lock()
+            .ok()
+            .and_then(|settings| settings.clone())
+    }
+
+    /// The project's worktrees the extension has seen, and the ids of those it has not.
+    fn project_worktrees(&self, project: &zed::Project) -> (Vec<WorktreeInfo>, Vec<u64>) {
+        let Ok(worktrees) = self.worktrees.lock() else {
+            return (Vec::new(), project.worktree_ids());
+        };
+        let (seen, unseen): (Vec<u64>, Vec<u64>) = project
+            .worktree_ids()
+            .into_iter()
+            .partition(|id| worktrees.contains_key(id));
+        let seen = seen
+            .into_iter()
+            .filter_map(|id| worktrees.get(&id).cloned())
+            .collect();
+        (seen, unseen)
+    }
+
+    /// Whether the running context server is missing the binding of a worktree it was
+    /// started without.
+    fn misses_binding(&self, id: u64, worktree: &WorktreeInfo) -> bool {
+        self.unseen_at_launch
+            .lock()
+            .is_ok_and(|unseen| unseen.contains(&id))
+            && worktree
+                .project_config()
+                .ok()
+                .flatten()
+                .is_some_and(|config| config.affects_server())
     }
 }
 
@@ -15,19 +125,147 @@ impl zed::Extension for CalibreMcpExtension {
 
     fn context_server_command(
         &mut self,
-        _id: &zed::ContextServerId,
-        _project: &zed::Project,
+        id: &zed::ContextServerId,
+        project: &zed::Project,
     ) -> zed::Result<zed::Command> {
-        Ok(zed::Command {
-            command: "uv".to_string(),
-            args: vec![
-                "run".to_string(),
-                "--project".to_string(),
-                ".".to_string(),
-                "--mcp".to_string(),
-            ],
-            env: Default::default(),
-        })
+        let mut settings = CalibreSettings::for_project(id.as_ref(), project)?;
+        let (worktrees, unseen) = self.project_worktrees(project);
+        if let Ok(mut unseen_at_launch) = self.unseen_at_launch.lock() {
+            *unseen_at_launch = unseen;
+        }
+        let worktrees: Vec<&WorktreeInfo> = worktrees.iter().collect();
+        let work_dir = server::work_dir()?;
+        let interpreter = server::interpreter(&worktrees, &work_dir);
+        if let Some(remote_url) = &settings.remote_url {
+            return remote::command(
+                remote_url,
+                settings.bearer_token.as_deref(),
+                &interpreter?,
+                &work_dir,
+            );
+        }
+
+        let project_config = worktrees
+            .iter()
+            .find_map(|worktree| worktree.project_config().transpose())
+            .transpose()?;
+        if let Some(library) = project_config
+            .as_ref()
+            .and_then(|config| config.library.as_ref())
+        {
+            settings.library_path = Some(library.to_string_lossy().into_owned());
+        }
+        if let Some(tool_groups) = project_config
+            .as_ref()
+            .and_then(|config| config.tool_groups.clone())
+        {
+            settings.tool_groups = Some(ToolSelection::Many(tool_groups));
+        }
+        // Without calibre-commands to look, the server discovers the library itself.
+        if settings.library_path.is_none() && settings.server_url.is_none() {
+            if let Ok(libraries) = native::discover(worktrees.first().copied()) {
+                settings.library_path = discovery::active(&libraries)
+                    .map(|library| library.path.to_string_lossy().into_owned());
+            }
+        }
+
+        if let Ok(mut server_settings) = self.server_settings.lock() {
+            *server_settings = Some(settings.clone());
+        }
+
+        let location = server::locate(&settings, &worktrees)?;
+        let mut command = location.command(interpreter)?;
+        command.env.extend(settings.server_env()?);
+        if let Some(project_config) = &project_config {
+            command.env.extend(project_config.server_env());
+        }
+        Ok(command)
+    }
+
+    fn language_server_command(
+        &mut self,
+        id: &zed::LanguageServerId,
+        worktree: &zed::Worktree,
+    ) -> zed::Result<zed::Command> {
+        let info = self.remember(worktree);
+        language_server::command(id, worktree, &info, self.server_settings().as_ref())
+    }
+
+    fn complete_slash_command_argument(
+        &self,
+        command: zed::SlashCommand,
+        args: Vec<String>,
+    ) -> zed::Result<Vec<zed::SlashCommandArgumentCompletion>> {
+        let mut catalog = self
+            .catalog
+            .lock()
+            .map_err(|_| "the completion cache is unavailable".to_string())?;
+        if let Some(fresh) = native::catalog(
+            catalog.as_ref(),
+            self.recent_worktree().as_ref(),
+            self.server_settings().as_ref(),
+        )? {
+            *catalog = Some(fresh);
+        }
+        Ok(catalog
+            .as_ref()
+            .map(|catalog| commands::complete(&command.name, &args, catalog))
+            .unwrap_or_default())
+    }
+
+    fn suggest_docs_packages(&self, provider: String) -> zed::Result<Vec<String>> {
+        if provider != docs::PROVIDER {
+            return Ok(Vec::new());
+        }
+        native::suggest_docs(
+            self.recent_worktree().as_ref(),
+            self.server_settings().as_ref(),
+        )
+    }
+
+    fn index_docs(
+        &self,
+        provider: String,
+        package: String,
+        database: &zed::KeyValueStore,
+    ) -> zed::Result<()> {
+        if provider != docs::PROVIDER {
+            return Err(format!("unknown docs provider \"{provider}\""));
+        }
+        let mut indexed = self
+            .indexed_docs
+            .lock()
+            .map_err(|_| "the docs index is unavailable".to_string())?;
+        let indexing = native::index_docs(
+            &package,
+            indexed.get(&package),
+            self.recent_worktree().as_ref(),
+            self.server_settings().as_ref(),
+        )?;
+        docs::store(&package, indexing, database, &mut indexed)
+    }
+
+    fn run_slash_command(
+        &self,
+        command: zed::SlashCommand,
+        args: Vec<String>,
+        worktree: Option<&zed::Worktree>,
+    ) -> zed::Result<zed::SlashCommandOutput> {
+        let id = worktree.map(zed::Worktree::id);
+        let worktree = worktree.map(|worktree| self.remember(worktree));
+        let server_settings = self.server_settings();
+        let mut output = native::run(
+            &command.name,
+            &args,
+            worktree.as_ref(),
+            server_settings.as_ref(),
+        )?;
+        if let (Some(id), Some(worktree)) = (id, &worktree) {
+            if self.misses_binding(id, worktree) {
+                commands::add_restart_note(&mut output);
+            }
+        }
+        Ok(output)
     }
 }
 
@@ -0,0 +1,164 @@
+use std::path::Path;
+
+use zed_extension_api as zed;
+
+/// Whether `dir` is a Calibre library.
+pub fn is_library(dir: &Path) -> bool {
+    dir.join("metadata.db").is_file()
+}
+
+/// Environment that points the server at a library, or at a directory of libraries.
+///
+/// `CALIBRE_BASE_PATH` names a directory of libraries, so a path that is a library itself
+/// is passed as its parent plus `CALIBRE_LIBRARY_NAME`. Zed only lets the extension see
+/// its own work directory, though, so a path it cannot look inside is passed as it is:
+/// the server takes a library there as well, and checks that the path exists.
+pub fn server_env(path: &Path) -> Result<zed::EnvVars, String> {
+    if !is_library(path) {
+        return Ok(vec![(
+            "CALIBRE_BASE_PATH".to_string(),
+            path.to_string_lossy().into_owned(),
+        )]);
+    }
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(format!("{} cannot be a filesystem root", path.display()));
+    };
+    Ok(vec![
+        (
+            "CALIBRE_BASE_PATH".to_string(),
+            parent.to_string_lossy().into_owned(),
+        ),
+        (
+            "CALIBRE_LIBRARY_NAME".to_string(),
+            name.to_string_lossy().into_owned(),
+        ),
+    ])
+}
+
+/// Whether `path` looks like it lives on a network share or removable volume, where the
+/// library may be open in Calibre on another machine at the same time.
+pub fn is_shared(path: &Path) -> bool {
+    let path = path.to_string_lossy().replace('\\', "/");
+    if path.starts_with("//") {
+        return true;
+    }
+    if ["/Volumes/", "/mnt/", "/media/", "/net/", "/smb/", "/nfs/"]
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+    {
+        return true;
+    }
+    path.strip_prefix("/run/user/")
+        .and_then(|rest| rest.split_once('/'))
+        .is_some_and(|(_, rest)| rest.starts_with("gvfs/"))
+}
+
+/// A SQLite URI opening `db` read-only, e.g. `file:///srv/books/metadata.db?mode=ro`.
+///
+/// Windows drive paths become `file:///C:/...` and UNC paths `file:////server/share/...`;
+/// anything SQLite would read as URI syntax is percent-encoded.
+pub fn read_only_uri(db: &Path) -> String {
+    let mut path = db.to_string_lossy().replace('\\', "/");
+    if !path.starts_with('/') {
+        path.insert(0, '/');
+    }
+    let mut uri = String::from("file://");
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"/:-._~".contains(&byte) {
+            uri.push(char::from(byte));
+        } else {
+            uri.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    uri.push_str("?mode=ro");
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn splits_a_library_from_its_parent() {
+        let root = std::env::temp_dir().join(format!("calibre-split-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let library = root.join("Calibre Library");
+        fs::create_dir_all(&library).unwrap();
+        fs::write(library.join("metadata.db"), "").unwrap();
+        fs::create_dir_all(root.join("Empty")).unwrap();
+
+        let env = |path: &Path| {
+            server_env(path)
+                .unwrap()
+                .into_iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect::<Vec<_>>()
+        };
+        assert!(is_library(&library) && !is_library(&root) && !is_library(&root.join("Empty")));
+        assert_eq!(
+            env(&library),
+            [
+                format!("CALIBRE_BASE_PATH={}", root.display()),
+                "CALIBRE_LIBRARY_NAME=Calibre Library".to_string(),
+            ]
+        );
+        for base in [root.clone(), root.join("Empty"), root.join("Missing")] {
+            assert_eq!(
+                env(&base),
+                [format!("CALIBRE_BASE_PATH={}", base.display())]
+            );
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn recognises_shared_paths() {
+        for shared in [
+            "\\\\nas\\books\\Calibre Library",
+            "//nas/books",
+            "/Volumes/USB/Calibre Library",
+            "/mnt/nas/books",
+            "/media/ann/BOOKS",
+            "/net/server/books",
+            "/run/user/1000/gvfs/smb-share:server=nas,share=books/Calibre Library",
+        ] {
+            assert!(is_shared(Path::new(shared)), "{shared}");
+        }
+        for local in [
+            "/home/ann/Calibre Library",
+            "C:\\Users\\Ann\\Calibre Library",
+            "/mntx/books",
+            "/run/user/1000/doc/books",
+            "/srv/Volumes/books",
+        ] {
+            assert!(!is_shared(Path::new(local)), "{local}");
+        }
+    }
+
+    #[test]
+    fn percent_encodes_read_only_uris() {
+        let uri = |path: &str| read_only_uri(Path::new(path));
+        assert_eq!(
+            uri("/srv/books/metadata.db"),
+            "file:///srv/books/metadata.db?mode=ro"
+        );
+        assert_eq!(
+            uri("/srv/My Books #1? 100%/metadata.db"),
+            "file:///srv/My%20Books%20%231%3F%20100%25/metadata.db?mode=ro"
+        );
+        assert_eq!(
+            uri("/srv/Bücher&Co=+/metadata.db"),
+            "file:///srv/B%C3%BCcher%26Co%3D%2B/metadata.db?mode=ro"
+        );
+        assert_eq!(
+            uri("C:\\Users\\Ann\\Calibre Library\\metadata.db"),
+            "file:///C:/Users/Ann/Calibre%20Library/metadata.db?mode=ro"
+        );
+        assert_eq!(
+            uri("\\\\nas\\books\\metadata.db"),
+            "file:////nas/books/metadata.db?mode=ro"
+        );
+    }
+}
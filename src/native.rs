@@ -0,0 +1,240 @@
+//! The extension's side of `calibre-commands`, the binary in `crates/calibre-commands`,
+//! and that binary's side of it.
+//!
+//! Zed only lets the extension see its own work directory, so slash commands, their
+//! completions, `/docs` indexing and library discovery, which read Calibre's config and
+//! libraries, run in `calibre-commands` instead.
+//! It is found on the worktree's PATH after `cargo install --path crates/calibre-commands`,
+//! gets the worktree's shell environment and root, and answers one request per run as
+//! JSON on stdout, or with an error on stderr.
+
+use serde::{Deserialize, Serialize};
+use zed_extension_api::{self as zed, SlashCommandOutput, SlashCommandOutputSection};
+
+use crate::commands;
+use crate::completion::{Catalog, Stamp};
+use crate::discovery::{self, Library};
+use crate::docs::{self, Indexing};
+use crate::settings::CalibreSettings;
+use crate::worktree::WorktreeInfo;
+
+const BINARY: &str = "calibre-commands";
+
+/// The root of the worktree a request is for, if it is for one.
+const WORKTREE_VAR: &str = "CALIBRE_COMMANDS_WORKTREE";
+/// The settings the context server was last started with, as JSON, if it has been.
+const SETTINGS_VAR: &str = "CALIBRE_COMMANDS_SETTINGS";
+
+/// Runs a slash command in `calibre-commands`.
+pub fn run(
+    command: &str,
+    args: &[String],
+    worktree: Option<&WorktreeInfo>,
+    settings: Option<&CalibreSettings>,
+) -> zed::Result<SlashCommandOutput> {
+    let mut request = vec!["run".to_string(), command.to_string()];
+    request.extend_from_slice(args);
+    let output: Output = call(&request, worktree, settings)?;
+    Ok(output.into())
+}
+
+/// A catalog of the library slash commands read, unless `known` is still one.
+pub fn catalog(
+    known: Option<&Catalog>,
+    worktree: Option<&WorktreeInfo>,
+    settings: Option<&CalibreSettings>,
+) -> zed::Result<Option<Catalog>> {
+    let mut request = vec!["catalog".to_string()];
+    if let Some(known) = known {
+        request.push(to_json(known.stamp())?);
+    }
+    call(&request, worktree, settings)
+}
+
+/// The `/docs` packages of the library slash commands read.
+pub fn suggest_docs(
+    worktree: Option<&WorktreeInfo>,
+    settings: Option<&CalibreSettings>,
+) -> zed::Result<Vec<String>> {
+    call(&["docs-suggest".to_string()], worktree, settings)
+}
+
+/// `package` indexed for `/docs`, unless it is unchanged since `known`.
+pub fn index_docs(
+    package: &str,
+    known: Option<&docs::Version>,
+    worktree: Option<&WorktreeInfo>,
+    settings: Option<&CalibreSettings>,
+) -> zed::Result<Indexing> {
+    let mut request = vec!["docs-index".to_string(), package.to_string()];
+    if let Some(known) = known {
+        request.push(to_json(known)?);
+    }
+    call(&request, worktree, settings)
+}
+
+/// The libraries `calibre-commands` discovers, active library first.
+pub fn discover(worktree: Option<&WorktreeInfo>) -> zed::Result<Vec<Library>> {
+    call(&["discover".to_string()], worktree, None)
+}
+
+fn call<T: for<'de> Deserialize<'de>>(
+    request: &[String],
+    worktree: Option<&WorktreeInfo>,
+    settings: Option<&CalibreSettings>,
+) -> zed::Result<T> {
+    let mut command = zed::process::Command::new(BINARY).args(request.iter().cloned());
+    if let Some(worktree) = worktree {
+        // Its PATH is also where the binary is looked for.
+        command = command
+            .envs(worktree.shell_env().clone())
+            .env(WORKTREE_VAR, worktree.root.to_string_lossy());
+    }
+    if let Some(settings) = settings {
+        command = command.env(SETTINGS_VAR, to_json(settings)?);
+    }
+    let output = command.output().map_err(|err| {
+        format!(
+            "failed to run {BINARY} ({err}); install it with `cargo install --path \
+             crates/{BINARY}` from the calibre-mcp checkout"
+        )
+    })?;
+    if output.status != Some(0) {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(match stderr.trim() {
+            "" => format!("{BINARY} failed with status {:?}", output.status),
+            stderr => stderr
+                .strip_prefix(&format!("{BINARY}: "))
+                .unwrap_or(stderr)
+                .to_string(),
+        });
+    }
+    serde_json::from_slice(&output.stdout)
+        .map_err(|err| format!("{BINARY} answered with invalid JSON: {err}"))
+}
+
+/// Answers the request in `args`, as `calibre-commands` run by the extension.
+pub fn serve(args: &[String]) -> Result<String, String> {
+    let worktree = std::env::var_os(WORKTREE_VAR).map(|root| WorktreeInfo::open(root.into()));
+    let worktree = worktree.as_ref();
+    let settings = match std::env::var(SETTINGS_VAR) {
+        Ok(json) => Some(
+            serde_json::from_str::<CalibreSettings>(&json)
+                .map_err(|err| format!("invalid {SETTINGS_VAR}: {err}"))?,
+        ),
+        Err(_) => None,
+    };
+    let settings = settings.as_ref();
+    match args {
+        [operation, command, args @ ..] if operation == "run" => {
+            let output = commands::run(command, args, worktree, settings)?;
+            to_json(&Output::from(output))
+        }
+        [operation, known @ ..] if operation == "catalog" && known.len() <= 1 => {
+            let library = commands::library(worktree, settings)?;
+            let known = known
+                .first()
+                .map(|json| serde_json::from_str::<Stamp>(json))
+                .transpose()
+                .map_err(|err| format!("invalid catalog stamp: {err}"))?;
+            if known.is_some_and(|stamp| stamp.is_current(&library)) {
+                return to_json(&None::<Catalog>);
+            }
+            to_json(&Some(Catalog::load(&library)?))
+        }
+        [operation] if operation == "docs-suggest" => {
+            to_json(&docs::suggest(&commands::library(worktree, settings)?)?)
+        }
+        [operation, package, known @ ..] if operation == "docs-index" && known.len() <= 1 => {
+            let known = known
+                .first()
+                .map(|json| serde_json::from_str::<docs::Version>(json))
+                .transpose()
+                .map_err(|err| format!("invalid docs version: {err}"))?;
+            let library = commands::library(worktree, settings)?;
+            to_json(&docs::index(&library, package, known.as_ref())?)
+        }
+        [operation] if operation == "discover" => {
+            to_json(&discovery::discover(&commands::env_lookup(worktree)))
+        }
+        _ => Err(format!(
+            "unknown request \"{}\"; {BINARY} is run by the calibre-mcp Zed extension",
+            args.join(" ")
+        )),
+    }
+}
+
+fn to_json(value: &impl Serialize) -> Result<String, String> {
+    serde_json::to_string(value).map_err(|err| format!("failed to write the answer: {err}"))
+}
+
+/// A [`SlashCommandOutput`] as JSON.
+#[derive(Debug, Serialize, Deserialize)]
+struct Output {
+    text: String,
+    sections: Vec<Section>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Section {
+    start: u32,
+    end: u32,
+    label: String,
+}
+
+impl From<SlashCommandOutput> for Output {
+    fn from(output: SlashCommandOutput) -> Self {
+        Self {
+            text: output.text,
+            sections: output
+                .sections
+                .into_iter()
+                .map(|section| Section {
+                    start: section.range.start,
+                    end: section.range.end,
+                    label: section.label,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<Output> for SlashCommandOutput {
+    fn from(output: Output) -> Self {
+        Self {
+            text: output.text,
+            sections: output
+                .sections
+                .into_iter()
+                .map(|section| SlashCommandOutputSection {
+                    range: (section.start..section.end).into(),
+                    label: section.label,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn carries_output_sections_through_json() {
+        let output = SlashCommandOutput {
+            text: "Calibre Library (active)\n".to_string(),
+            sections: vec![SlashCommandOutputSection {
+                range: (0..25_u32).into(),
+                label: "Calibre Library".to_string(),
+            }],
+        };
+        let json = to_json(&Output::from(output)).unwrap();
+        assert_eq!(
+            json,
+            r#"{"text":"Calibre Library (active)\n","sections":[{"start":0,"end":25,"label":"Calibre Library"}]}"#
+        );
+        let output = SlashCommandOutput::from(serde_json::from_str::<Output>(&json).unwrap());
+        assert_eq!(output.sections[0].range.end, 25);
+        assert!(serve(&["index".to_string()]).is_err());
+    }
+}
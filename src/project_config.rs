@@ -0,0 +1,373 @@
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use calibre_library::cite::{KeyTemplate, Style};
+use serde::Deserialize;
+use serde_path_to_error::Segment;
+use toml::Spanned;
+use zed_extension_api as zed;
+
+use crate::bibliography::{Bibliography, BookSet, DEFAULT_FILES};
+use crate::library;
+use crate::settings::ToolSelection;
+use crate::tool_groups;
+
+/// Files binding a worktree to a library, relative to the worktree root, in lookup order.
+pub const FILE_NAMES: [&str; 2] = [".calibre-mcp.toml", ".zed/calibre.toml"];
+
+/// The per-project library binding from a worktree's `.calibre-mcp.toml`.
+///
+/// ```toml
+/// library = "/srv/books/Research"
+/// rag_index = ".calibre/rag"
+/// tool_groups = ["search", "metadata"]  # or a preset: tool_groups = "librarian"
+/// citation_key = "{author}{year}{title}"
+///
+/// [bibliography]
+/// file = "references.bib"  # .json for CSL-JSON, .ris for RIS, or set `format`
+/// tag = "Thesis"           # or series = "...", or saved_search = "..."
+/// ```
+///
+/// Relative paths are resolved against the worktree root.
+///
+/// Zed launches the context server with the project's worktree ids only, so the file is
+/// read once a hook has been handed the worktree itself: a slash command run in the
+/// project, or the citation language server starting for one of its files. A server
+/// launched before then runs without the binding until it is restarted, and slash
+/// commands say so meanwhile.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProjectConfig {
+    /// A Calibre library, or a directory of libraries; overrides `library_path`.
+    pub library: Option<PathBuf>,
+    /// Directory holding the project's RAG index.
+    pub rag_index: Option<PathBuf>,
+    /// Tool groups or a preset the server registers; overrides `tool_groups`.
+    pub tool_groups: Option<Vec<String>>,
+    /// Citation key template for the project's papers; overrides `citation_key`.
+    pub citation_key: Option<KeyTemplate>,
+    /// The bibliography `/calibre-bibliography` writes.
+    pub bibliography: Option<Bibliography>,
+}
+
+/// The file as written, with where each value is so errors can point at its line.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    library: Option<Spanned<String>>,
+    rag_index: Option<Spanned<String>>,
+    tool_groups: Option<Spanned<ToolSelection>>,
+    citation_key: Option<Spanned<String>>,
+    #[serde(default)]
+    bibliography: BibliographyTable,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BibliographyTable {
+    file: Option<Spanned<String>>,
+    format: Option<Spanned<String>>,
+    tag: Option<Spanned<String>>,
+    series: Option<Spanned<String>>,
+    saved_search: Option<Spanned<String>>,
+}
+
+impl ProjectConfig {
+    /// Parses and validates a config file; errors name the file, line and key.
+    pub fn parse(file_name: &str, text: &str, root: &Path) -> Result<Self, String> {
+        let line = |span: Range<usize>| text[..span.start].matches('\n').count() + 1;
+        let file: File =
+            serde_path_to_error::deserialize(toml::Deserializer::new(text)).map_err(|err| {
+                // `Spanned` adds a private field of its own to the path.
+                let key = err
+                    .path()
+                    .iter()
+                    .filter_map(|segment| match segment {
+                        Segment::Map { key } if !key.starts_with("$__") => Some(key.as_str()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join(".");
+                let at = match err.inner().span() {
+                    Some(span) => format!("{file_name}:{}", line(span)),
+                    None => file_name.to_string(),
+                };
+                match key.as_str() {
+                    "" => format!("{at}: {}", err.inner().message()),
+                    key => format!("{at}: `{key}`: {}", err.inner().message()),
+                }
+            })?;
+        let error = |key: &str, span: Range<usize>, message: String| {
+            format!("{file_name}:{}: `{key}` {message}", line(span))
+        };
+
+        let mut config = Self::default();
+        if let Some(library) = &file.library {
+            let path = root.join(library.get_ref());
+            library::server_env(&path).map_err(|err| error("library", library.span(), err))?;
+            config.library = Some(path);
+        }
+        if let Some(rag_index) = &file.rag_index {
+            config.rag_index = Some(root.join(rag_index.get_ref()));
+        }
+        if let Some(tool_groups) = &file.tool_groups {
+            let groups = tool_groups.get_ref().names();
+            tool_groups::resolve(&groups)
+                .map_err(|err| error("tool_groups", tool_groups.span(), err))?;
+            config.tool_groups = Some(groups);
+        }
+        if let Some(template) = &file.citation_key {
+            let parsed = KeyTemplate::parse(template.get_ref())
+                .map_err(|err| error("citation_key", template.span(), err))?;
+            config.citation_key = Some(parsed);
+        }
+
+        // `[bibliography]` is checked as a whole.
+        let table = &file.bibliography;
+        if let Some(file) = &table.file {
+            if file.get_ref().trim().is_empty() {
+                return Err(error(
+                    "bibliography.file",
+                    file.span(),
+                    "must not be empty".to_string(),
+                ));
+            }
+        }
+        let bibliography_style = match &table.format {
+            Some(format) => {
+                let style = Style::parse(format.get_ref())
+                    .map_err(|err| error("bibliography.format", format.span(), err))?;
+                if !DEFAULT_FILES
+                    .iter()
+                    .any(|(file_style, _)| *file_style == style)
+                {
+                    return Err(error(
+                        "bibliography.format",
+                        format.span(),
+                        "must be bibtex, csl-json or ris for a bibliography file".to_string(),
+                    ));
+                }
+                Some(style)
+            }
+            None => None,
+        };
+        let mut book_sets = Vec::new();
+        for (key, name, book_set) in [
+            (
+                "bibliography.tag",
+                &table.tag,
+                BookSet::Tag as fn(String) -> BookSet,
+            ),
+            ("bibliography.series", &table.series, BookSet::Series),
+            (
+                "bibliography.saved_search",
+                &table.saved_search,
+                BookSet::SavedSearch,
+            ),
+        ] {
+            let Some(name) = name else { continue };
+            if name.get_ref().trim().is_empty() {
+                return Err(error(key, name.span(), "must not be empty".to_string()));
+            }
+            book_sets.push((key, name.span(), book_set(name.get_ref().clone())));
+        }
+        // In the order they are written, to point at the second.
+        book_sets.sort_by_key(|(_, span, _)| span.start);
+
+        let bibliography_file = table.file.as_ref().map(|file| file.get_ref().as_str());
+        let configured = bibliography_file.is_some() || bibliography_style.is_some();
+        match book_sets.len() {
+            0 if configured => {
+                return Err(format!(
+                    "{file_name}: [bibliography] needs one of `tag`, `series` or `saved_search`"
+                ))
+            }
+            0 => {}
+            1 => {
+                let (_, _, books) = book_sets.remove(0);
+                let (file, style) = match (bibliography_file, bibliography_style) {
+                    (Some(file), Some(style)) => (file, style),
+                    (Some(file), None) => {
+                        let style = match Path::new(file).extension().and_then(|ext| ext.to_str()) {
+                            Some("json") => Style::CslJson,
+                            Some("ris") => Style::Ris,
+                            Some("bib") => Style::Bibtex,
+                            _ => {
+                                return Err(format!(
+                                    "{file_name}: [bibliography] `file` {file} is not .bib, \
+                                     .json or .ris; set `format`"
+                                ))
+                            }
+                        };
+                        (file, style)
+                    }
+                    (None, style) => {
+                        let style = style.unwrap_or(Style::Bibtex);
+                        let file = DEFAULT_FILES
+                            .iter()
+                            .find(|(file_style, _)| *file_style == style)
+                            .map_or("references.bib", |(_, file)| file);
+                        (file, style)
+                    }
+                };
+                config.bibliography = Some(Bibliography {
+                    file: root.join(file),
+                    style,
+                    books,
+                });
+            }
+            _ => {
+                let (key, span, _) = &book_sets[1];
+                return Err(error(
+                    key,
+                    span.clone(),
+                    "cannot be combined with another of `tag`, `series` and `saved_search`"
+                        .to_string(),
+                ));
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Whether the file changes how the context server runs, as opposed to only what the
+    /// extension's own commands do.
+    pub fn affects_server(&self) -> bool {
+        self.library.is_some() || self.tool_groups.is_some() || self.rag_index.is_some()
+    }
+
+    /// Environment for everything in the file except `library` and `tool_groups`, which
+    /// are applied through the regular settings they override, and `citation_key` and
+    /// `bibliography`, which only the extension itself uses.
+    pub fn server_env(&self) -> zed::EnvVars {
+        let mut env = Vec::new();
+        if let Some(rag_index) = &self.rag_index {
+            env.push((
+                "CALIBRE_RAG_INDEX_DIR".to_string(),
+                rag_index.to_string_lossy().into_owned(),
+            ));
+        }
+        env
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = ".calibre-mcp.toml";
+
+    fn parse(text: &str) -> Result<ProjectConfig, String> {
+        ProjectConfig::parse(FILE, text, Path::new("/work/thesis"))
+    }
+
+    #[test]
+    fn reads_the_documented_example() {
+        let config = parse(
+            "library = \"/srv/books/Research\"\n\
+             rag_index = \".calibre/rag\"\n\
+             tool_groups = [\"search\", \"metadata\"]\n\
+             citation_key = \"{author}{year}{title}\"\n\
+             \n\
+             [bibliography]\n\
+             file = \"refs.json\"\n\
+             tag = \"Thesis\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.library, Some(PathBuf::from("/srv/books/Research")));
+        assert_eq!(
+            config.rag_index,
+            Some(PathBuf::from("/work/thesis/.calibre/rag"))
+        );
+        assert_eq!(
+            config.tool_groups,
+            Some(vec!["search".to_string(), "metadata".to_string()])
+        );
+        assert!(config.citation_key.is_some());
+        assert_eq!(
+            config.bibliography,
+            Some(Bibliography {
+                file: PathBuf::from("/work/thesis/refs.json"),
+                style: Style::CslJson,
+                books: BookSet::Tag("Thesis".to_string()),
+            })
+        );
+        assert!(config.affects_server());
+        assert_eq!(
+            config.server_env(),
+            [(
+                "CALIBRE_RAG_INDEX_DIR".to_string(),
+                "/work/thesis/.calibre/rag".to_string()
+            )]
+        );
+
+        let config =
+            parse("tool_groups = \"librarian\"\n[bibliography]\nseries = \"S\"\n").unwrap();
+        assert_eq!(config.tool_groups, Some(vec!["librarian".to_string()]));
+        let bibliography = config.bibliography.unwrap();
+        assert_eq!(
+            (bibliography.file, bibliography.style),
+            (PathBuf::from("/work/thesis/references.bib"), Style::Bibtex)
+        );
+
+        let config = parse("citation_key = \"{author}{year}\"\n").unwrap();
+        assert!(!config.affects_server());
+        assert_eq!(parse("# nothing yet\n").unwrap(), ProjectConfig::default());
+    }
+
+    #[test]
+    fn names_the_line_and_key_of_each_error() {
+        let error = |text: &str| parse(text).unwrap_err();
+        assert_eq!(
+            error("library = \"/srv/books\"\nvirtual_library = \"Thesis\"\n"),
+            ".calibre-mcp.toml:2: `virtual_library`: unknown field `virtual_library`, expected \
+             one of `library`, `rag_index`, `tool_groups`, `citation_key`, `bibliography`"
+        );
+        assert_eq!(
+            error("\nlibrary = 7\n"),
+            ".calibre-mcp.toml:2: `library`: invalid type: integer `7`, expected a string"
+        );
+        assert_eq!(
+            error("tool_groups = [\"search\", 1]\n"),
+            ".calibre-mcp.toml:1: `tool_groups`: expected a string or an array of strings"
+        );
+        assert_eq!(
+            error("tool_groups = true\n"),
+            ".calibre-mcp.toml:1: `tool_groups`: expected a string or an array of strings"
+        );
+        assert_eq!(
+            error("tool_groups = []\n"),
+            ".calibre-mcp.toml:1: `tool_groups` must name at least one tool group or preset"
+        );
+        assert!(error("tool_groups = [\"search\", \"everything\"]\n")
+            .starts_with(".calibre-mcp.toml:1: `tool_groups` "));
+        assert!(error("\n\ncitation_key = \"{author\"\n")
+            .starts_with(".calibre-mcp.toml:3: `citation_key` unclosed {"));
+        assert_eq!(
+            error("library = \"/srv\"\nlibrary = \"/srv\"\n"),
+            ".calibre-mcp.toml:2: duplicate key `library` in document root"
+        );
+        assert_eq!(
+            error("[bibliography]\nfile = \"refs.bib\"\n"),
+            ".calibre-mcp.toml: [bibliography] needs one of `tag`, `series` or `saved_search`"
+        );
+        assert_eq!(
+            error("[bibliography]\ntag = \"A\"\nseries = \"B\"\n"),
+            ".calibre-mcp.toml:3: `bibliography.series` cannot be combined with another of \
+             `tag`, `series` and `saved_search`"
+        );
+        assert_eq!(
+            error("[bibliography]\ntag = \" \"\n"),
+            ".calibre-mcp.toml:2: `bibliography.tag` must not be empty"
+        );
+        assert_eq!(
+            error("[bibliography]\ntag = \"A\"\nfile = \"refs.txt\"\n"),
+            ".calibre-mcp.toml: [bibliography] `file` refs.txt is not .bib, .json or .ris; \
+             set `format`"
+        );
+        assert_eq!(
+            error("[bibliography]\ntag = \"A\"\nformat = \"apa\"\n"),
+            ".calibre-mcp.toml:3: `bibliography.format` must be bibtex, csl-json or ris for a \
+             bibliography file"
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! Finding a phrase in a book's text, for `/calibre-quote`.
+//!
+//! Matching ignores case and runs of whitespace, and treats curly and straight quotes
+//! alike, so a phrase typed from memory finds the typeset text.
+
+use std::path::Path;
+
+use calibre_library::epub::Epub;
+use calibre_library::pdf;
+
+/// Characters of context kept on each side of a match.
+const CONTEXT_CHARS: usize = 240;
+
+/// A chapter of an EPUB or a page of a PDF.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    pub title: String,
+    pub text: String,
+}
+
+/// A book's text in reading order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Text {
+    /// `EPUB` or `PDF`.
+    pub format: &'static str,
+    pub parts: Vec<Part>,
+}
+
+impl Text {
+    pub fn epub(path: &Path) -> Result<Self, String> {
+        let parts = Epub::open(path)?
+            .chapters()?
+            .into_iter()
+            .map(|chapter| Part {
+                title: chapter.title,
+                text: chapter.text,
+            })
+            .collect();
+        Ok(Self {
+            format: "EPUB",
+            parts,
+        })
+    }
+
+    pub fn pdf(path: &Path) -> Result<Self, String> {
+        let parts = pdf::pages(path)?
+            .into_iter()
+            .enumerate()
+            .map(|(index, text)| Part {
+                title: format!("Page {}", index + 1),
+                text,
+            })
+            .collect();
+        Ok(Self {
+            format: "PDF",
+            parts,
+        })
+    }
+
+    /// Every occurrence of `phrase`, in reading order.
+    pub fn find(&self, phrase: &str) -> Vec<Quote> {
+        let (phrase, _) = fold(phrase.trim());
+        if phrase.is_empty() {
+            return Vec::new();
+        }
+        let total = self
+            .parts
+            .iter()
+            .map(|part| part.text.len())
+            .sum::<usize>()
+            .max(1);
+        let mut quotes = Vec::new();
+        let mut before = 0;
+        for (index, part) in self.parts.iter().enumerate() {
+            let (folded, offsets) = fold(&part.text);
+            let mut start = 0;
+            while start + phrase.len() <= folded.len() {
+                if folded[start..start + phrase.len()] != phrase[..] {
+                    start += 1;
+                    continue;
+                }
+                let end_char = start + phrase.len();
+                let range =
+                    offsets[start]..offsets.get(end_char).copied().unwrap_or(part.text.len());
+                let range = range.start..part.text[..range.end].trim_end().len();
+                quotes.push(Quote {
+                    part: index,
+                    percent: (before + range.start) as f64 * 100.0 / total as f64,
+                    context: context(&part.text, range),
+                });
+                start = end_char;
+            }
+            before += part.text.len();
+        }
+        quotes
+    }
+}
+
+/// One occurrence of a phrase.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quote {
+    /// Index of the part it is in.
+    pub part: usize,
+    /// How far into the book it is, from 0 to 100.
+    pub percent: f64,
+    /// The paragraph around it, cut to length, the match in bold.
+    pub context: String,
+}
+
+/// Characters of `text` folded for matching, each with the byte offset it came from.
+fn fold(text: &str) -> (Vec<char>, Vec<usize>) {
+    let mut folded = Vec::with_capacity(text.len());
+    let mut offsets = Vec::with_capacity(text.len());
+    for (offset, c) in text.char_indices() {
+        let c = match c {
+            '‘' | '’' | '‚' | '‛' | '′' | '`' => '\'',
+            '“' | '”' | '„' | '‟' | '″' | '«' | '»' => '"',
+            '\u{ad}' => continue,
+            c if c.is_whitespace() => {
+                if folded.last() != Some(&' ') {
+                    folded.push(' ');
+                    offsets.push(offset);
+                }
+                continue;
+            }
+            c => c,
+        };
+        for lower in c.to_lowercase() {
+            folded.push(lower);
+            offsets.push(offset);
+        }
+    }
+    (folded, offsets)
+}
+
+/// The paragraph around `range`, at most `CONTEXT_CHARS` either side of it and cut at a
+/// word, with the match in bold.
+fn context(text: &str, range: std::ops::Range<usize>) -> String {
+    let paragraph_start = text[..range.start]
+        .rfind("\n\n")
+        .map_or(0, |index| index + 2);
+    let paragraph_end = text[range.end..]
+        .find("\n\n")
+        .map_or(text.len(), |index| range.end + index);
+
+    let before = &text[paragraph_start..range.start];
+    let (before, cut_before) = match before.char_indices().rev().nth(CONTEXT_CHARS) {
+        Some((cut, _)) => {
+            let rest = &before[cut..];
+            let word = rest.find(char::is_whitespace).map_or(0, |space| space + 1);
+            (&rest[word..], true)
+        }
+        None => (before, false),
+    };
+    let after = &text[range.end..paragraph_end];
+    let (after, cut_after) = match after.char_indices().nth(CONTEXT_CHARS) {
+        Some((cut, _)) => {
+            let rest = &after[..cut];
+            let word = rest.rfind(char::is_whitespace).unwrap_or(rest.len());
+            (&rest[..word], true)
+        }
+        None => (after, false),
+    };
+
+    let line = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut context = String::new();
+    if cut_before {
+        context.push('…');
+    }
+    let before = line(before);
+    context.push_str(&before);
+    if text[..range.start].ends_with(char::is_whitespace) && !before.is_empty() {
+        context.push(' ');
+    }
+    context.push_str(&format!("**{}**", line(&text[range.clone()])));
+    let after_line = line(after);
+    if after.starts_with(char::is_whitespace) && !after_line.is_empty() {
+        context.push(' ');
+    }
+    context.push_str(&after_line);
+    if cut_after {
+        context.push('…');
+    }
+    context
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(file: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/test_library/Arthur Conan Doyle/A Study in Scarlet (1)")
+            .join(file)
+    }
+
+    #[test]
+    fn finds_phrases_in_epub_chapters() {
+        let text = Text::epub(&fixture("1.epub")).unwrap();
+        let titles = text
+            .parts
+            .iter()
+            .map(|part| part.title.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(titles, ["Page 1", "Page 2", "Page 3", "Page 4", "Page 5"]);
+
+        assert_eq!(text.find("minimal   TEST epub").len(), 5);
+        let quotes = text.find("page 3 of");
+        assert_eq!(quotes.len(), 2);
+        assert_eq!(quotes[0].part, 2);
+        assert_eq!(quotes[0].context, "**Page 3 of** 5");
+        assert_eq!(
+            quotes[1].context,
+            "This is **page 3 of** a minimal test EPUB file for automated testing."
+        );
+        assert!(quotes[0].percent > 40.0 && quotes[0].percent < 60.0);
+        assert!(text.find("the hound").is_empty());
+    }
+
+    #[test]
+    fn finds_phrases_on_pdf_pages() {
+        let text = Text::pdf(&fixture("1.pdf")).unwrap();
+        assert_eq!(text.parts.len(), 5);
+        assert_eq!(text.parts[0].text, "Page 1 of 5 - A Study in Scarlet");
+
+        let quotes = text.find("page 3 of 5");
+        assert_eq!(quotes.len(), 1);
+        assert_eq!(text.parts[quotes[0].part].title, "Page 3");
+        assert_eq!(quotes[0].context, "**Page 3 of 5** - A Study in Scarlet");
+    }
+
+    #[test]
+    fn treats_curly_and_straight_quotes_alike() {
+        let text = Text {
+            format: "EPUB",
+            parts: vec![Part {
+                title: "Chapter 1".to_string(),
+                text: "“You have been in Afghanistan, I perceive.”\n\n“How on earth did you know that?”".to_string(),
+            }],
+        };
+        let quotes = text.find("\"how on earth");
+        assert_eq!(quotes.len(), 1);
+        assert_eq!(quotes[0].context, "**“How on earth** did you know that?”");
+    }
+}
@@ -0,0 +1,62 @@
+use std::fs;
+use std::path::Path;
+
+use zed_extension_api as zed;
+
+use crate::interpreter::{Interpreter, InterpreterKind};
+
+const BRIDGE_FILE: &str = "calibre-mcp-bridge.py";
+const BRIDGE_SCRIPT: &str = include_str!("bridge.py");
+
+/// Environment variable the bridge reads the bearer token from.
+const TOKEN_ENV: &str = "CALIBRE_MCP_BEARER_TOKEN";
+
+/// Launches the stdio bridge to a calibre-mcp instance serving Streamable HTTP elsewhere.
+pub fn command(
+    url: &str,
+    bearer_token: Option<&str>,
+    interpreter: &Interpreter,
+    work_dir: &Path,
+) -> zed::Result<zed::Command> {
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        return Err(format!(
+            "`remote_url` {url} must start with http:// or https://"
+        ));
+    }
+    if bearer_token.is_some_and(|token| token.trim().is_empty()) {
+        return Err("`bearer_token` must not be empty".to_string());
+    }
+
+    let bridge = work_dir.join(BRIDGE_FILE);
+    fs::write(&bridge, BRIDGE_SCRIPT)
+        .map_err(|err| format!("failed to write {}: {err}", bridge.display()))?;
+
+    let mut args = match interpreter.kind {
+        InterpreterKind::Uv => vec![
+            "run".to_string(),
+            "--no-project".to_string(),
+            "python".to_string(),
+        ],
+        InterpreterKind::Python => Vec::new(),
+        InterpreterKind::Uvx => {
+            return Err(
+                "remote mode runs its bridge with Python, but only `uvx` was found on PATH"
+                    .to_string(),
+            )
+        }
+    };
+    args.extend([
+        bridge.to_string_lossy().into_owned(),
+        "--url".to_string(),
+        url.to_string(),
+    ]);
+
+    let env = bearer_token
+        .map(|token| vec![(TOKEN_ENV.to_string(), token.to_string())])
+        .unwrap_or_default();
+    Ok(zed::Command {
+        command: interpreter.path.clone(),
+        args,
+        env,
+    })
+}
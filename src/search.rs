@@ -0,0 +1,655 @@
+//! Calibre's search syntax, evaluated against books read from `metadata.db`.
+//!
+//! Supports the parts people type into Calibre's search bar: bare words, `field:value`
+//! with `=` for exact matches, `true`/`false` for presence, `<`, `>`, `<=`, `>=` and `=`
+//! comparisons on ratings, numbers and dates, quoted values, and `and`, `or`, `not` with
+//! parentheses. Words next to each other are combined with `and`.
+
+use calibre_library::metadata::Book;
+
+/// A parsed search.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Term(Term),
+    Not(Box<Query>),
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+}
+
+/// A single `field:value`, or a bare value when `field` is `None`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Term {
+    pub field: Option<Field>,
+    pub matcher: Matcher,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Title,
+    Authors,
+    Series,
+    Tags,
+    Publisher,
+    Comments,
+    Formats,
+    Identifiers,
+    Rating,
+    SeriesIndex,
+    Id,
+    Pubdate,
+    Timestamp,
+    LastModified,
+    Cover,
+}
+
+/// Field names Calibre accepts, including its singular aliases.
+const FIELDS: &[(&str, Field)] = &[
+    ("title", Field::Title),
+    ("authors", Field::Authors),
+    ("author", Field::Authors),
+    ("series", Field::Series),
+    ("tags", Field::Tags),
+    ("tag", Field::Tags),
+    ("publisher", Field::Publisher),
+    ("comments", Field::Comments),
+    ("comment", Field::Comments),
+    ("formats", Field::Formats),
+    ("format", Field::Formats),
+    ("identifiers", Field::Identifiers),
+    ("identifier", Field::Identifiers),
+    ("rating", Field::Rating),
+    ("series_index", Field::SeriesIndex),
+    ("id", Field::Id),
+    ("pubdate", Field::Pubdate),
+    ("date", Field::Timestamp),
+    ("timestamp", Field::Timestamp),
+    ("last_modified", Field::LastModified),
+    ("cover", Field::Cover),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Matcher {
+    /// Case-insensitive substring match, already lower-cased.
+    Contains(String),
+    /// Case-insensitive match of a whole value, already lower-cased.
+    Exact(String),
+    /// Whether the field has any value.
+    Present(bool),
+    Number(Comparison, f64),
+    /// A date prefix such as `2001` or `2001-09-11`.
+    Date(Comparison, String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Comparison {
+    fn holds(self, ordering: std::cmp::Ordering) -> bool {
+        use std::cmp::Ordering::*;
+        match self {
+            Self::Less => ordering == Less,
+            Self::LessOrEqual => ordering != Greater,
+            Self::Equal => ordering == Equal,
+            Self::GreaterOrEqual => ordering != Less,
+            Self::Greater => ordering == Greater,
+        }
+    }
+}
+
+impl Query {
+    /// Parses a search; errors say what is wrong and where.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let tokens = tokenize(text)?;
+        if tokens.is_empty() {
+            return Err("the search is empty".to_string());
+        }
+        let mut parser = Parser { tokens, next: 0 };
+        let query = parser.or()?;
+        match parser.peek() {
+            None => Ok(query),
+            Some(Token::Close) => Err("unmatched `)`".to_string()),
+            Some(token) => Err(format!("unexpected {token}")),
+        }
+    }
+
+    pub fn matches(&self, book: &Book) -> bool {
+        match self {
+            Self::Term(term) => term.matches(book),
+            Self::Not(query) => !query.matches(book),
+            Self::And(left, right) => left.matches(book) && right.matches(book),
+            Self::Or(left, right) => left.matches(book) || right.matches(book),
+        }
+    }
+}
+
+/// The books matching `query`, in the order given.
+pub fn search<'a>(books: &'a [Book], query: &Query) -> Vec<&'a Book> {
+    books.iter().filter(|book| query.matches(book)).collect()
+}
+
+impl Term {
+    fn parse(field: Option<Field>, value: &str) -> Result<Self, String> {
+        let matcher = match field {
+            Some(field) => match field {
+                Field::Rating | Field::SeriesIndex | Field::Id => number(field, value)?,
+                Field::Pubdate | Field::Timestamp | Field::LastModified => date(field, value)?,
+                Field::Cover => match presence(value) {
+                    Some(present) => Matcher::Present(present),
+                    None => return Err(format!("`cover` takes true or false, got `{value}`")),
+                },
+                _ => text(Some(field), value)?,
+            },
+            None => text(None, value)?,
+        };
+        Ok(Self { field, matcher })
+    }
+
+    fn matches(&self, book: &Book) -> bool {
+        let Some(field) = self.field else {
+            return [
+                Field::Title,
+                Field::Authors,
+                Field::Series,
+                Field::Tags,
+                Field::Publisher,
+                Field::Comments,
+                Field::Formats,
+            ]
+            .into_iter()
+            .any(|field| self.matcher.matches_text(&texts(book, field)));
+        };
+
+        match (&self.matcher, field) {
+            (Matcher::Present(present), Field::Cover) => book.has_cover == *present,
+            (Matcher::Present(present), Field::Rating) => {
+                book.rating.is_some_and(|rating| rating > 0) == *present
+            }
+            (
+                Matcher::Present(present),
+                Field::Pubdate | Field::Timestamp | Field::LastModified,
+            ) => date_value(book, field).is_some() == *present,
+            (Matcher::Number(comparison, value), field) => number_value(book, field)
+                .and_then(|number| number.partial_cmp(value))
+                .is_some_and(|ordering| comparison.holds(ordering)),
+            (Matcher::Date(comparison, prefix), field) => date_value(book, field)
+                .and_then(|date| date.get(..prefix.len()))
+                .is_some_and(|date| comparison.holds(date.cmp(prefix.as_str()))),
+            (matcher, Field::Identifiers) => {
+                let identifiers = book
+                    .identifiers
+                    .iter()
+                    .map(|(kind, value)| format!("{kind}:{value}"))
+                    .collect::<Vec<_>>();
+                matcher.matches_text(&identifiers.iter().map(String::as_str).collect::<Vec<_>>())
+            }
+            (matcher, field) => matcher.matches_text(&texts(book, field)),
+        }
+    }
+}
+
+impl Matcher {
+    fn matches_text(&self, values: &[&str]) -> bool {
+        match self {
+            Self::Contains(needle) => values
+                .iter()
+                .any(|value| value.to_lowercase().contains(needle.as_str())),
+            Self::Exact(wanted) => values.iter().any(|value| value.to_lowercase() == *wanted),
+            Self::Present(present) => values.iter().any(|value| !value.is_empty()) == *present,
+            Self::Number(..) | Self::Date(..) => false,
+        }
+    }
+}
+
+fn texts(book: &Book, field: Field) -> Vec<&str> {
+    match field {
+        Field::Title => vec![book.title.as_str()],
+        Field::Authors => book.authors.iter().map(String::as_str).collect(),
+        Field::Series => book.series.as_deref().into_iter().collect(),
+        Field::Tags => book.tags.iter().map(String::as_str).collect(),
+        Field::Publisher => book.publisher.as_deref().into_iter().collect(),
+        Field::Comments => book.comments.as_deref().into_iter().collect(),
+        Field::Formats => book.formats.iter().map(String::as_str).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn number_value(book: &Book, field: Field) -> Option<f64> {
+    match field {
+        // Stored as half-stars; searches are in stars.
+        Field::Rating => book.rating.map(|rating| rating as f64 / 2.0),
+        Field::SeriesIndex => book.series.as_ref().map(|_| book.series_index),
+        Field::Id => Some(book.id as f64),
+        _ => None,
+    }
+}
+
+fn date_value(book: &Book, field: Field) -> Option<&str> {
+    match field {
+        Field::Pubdate => book.pubdate.as_deref(),
+        Field::Timestamp => book.timestamp.as_deref(),
+        Field::LastModified => book.last_modified.as_deref(),
+        _ => None,
+    }
+    // Calibre stores an unknown publication date as the year 101.
+    .filter(|date| !date.starts_with("0101-"))
+}
+
+fn presence(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" => Some(true),
+        "false" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+fn text(field: Option<Field>, value: &str) -> Result<Matcher, String> {
+    // An empty value is contained in everything, so it would match every book.
+    if value.trim_start_matches('=').trim().is_empty() {
+        return Err(match field {
+            Some(field) => format!(
+                "`{0}:` needs a value; search `{0}:false` for books without one",
+                field_name(field)
+            ),
+            None => "an empty quoted term matches every book".to_string(),
+        });
+    }
+    if value.starts_with('~') {
+        return Err("regular expression searches (`~`) are not supported here".to_string());
+    }
+    if let Some(exact) = value.strip_prefix('=') {
+        return Ok(Matcher::Exact(exact.to_lowercase()));
+    }
+    Ok(match presence(value) {
+        Some(present) => Matcher::Present(present),
+        None => Matcher::Contains(value.to_lowercase()),
+    })
+}
+
+fn comparison(value: &str) -> (Comparison, &str) {
+    for (prefix, comparison) in [
+        (">=", Comparison::GreaterOrEqual),
+        ("<=", Comparison::LessOrEqual),
+        (">", Comparison::Greater),
+        ("<", Comparison::Less),
+        ("=", Comparison::Equal),
+    ] {
+        if let Some(rest) = value.strip_prefix(prefix) {
+            return (comparison, rest.trim());
+        }
+    }
+    (Comparison::Equal, value.trim())
+}
+
+fn number(field: Field, value: &str) -> Result<Matcher, String> {
+    if let Some(present) = presence(value) {
+        return Ok(Matcher::Present(present));
+    }
+    let (comparison, number) = comparison(value);
+    // Calibre accepts `rating:4stars` and `rating:"4 stars"`.
+    let number = number
+        .trim_end_matches("stars")
+        .trim_end_matches("star")
+        .trim();
+    number
+        .parse()
+        .map(|number| Matcher::Number(comparison, number))
+        .map_err(|_| format!("`{}` needs a number, got `{value}`", field_name(field)))
+}
+
+fn date(field: Field, value: &str) -> Result<Matcher, String> {
+    if let Some(present) = presence(value) {
+        return Ok(Matcher::Present(present));
+    }
+    let (comparison, date) = comparison(value);
+    let valid = matches!(date.len(), 4 | 7 | 10)
+        && date.char_indices().all(|(index, c)| {
+            if index == 4 || index == 7 {
+                c == '-'
+            } else {
+                c.is_ascii_digit()
+            }
+        });
+    if !valid {
+        return Err(format!(
+            "`{}` needs a date as YYYY, YYYY-MM or YYYY-MM-DD, got `{value}`",
+            field_name(field)
+        ));
+    }
+    Ok(Matcher::Date(comparison, date.to_string()))
+}
+
+fn field_name(field: Field) -> &'static str {
+    FIELDS
+        .iter()
+        .find(|(_, candidate)| *candidate == field)
+        .map(|(name, _)| *name)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Term(Term),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Open => f.write_str("`(`"),
+            Self::Close => f.write_str("`)`"),
+            Self::And => f.write_str("`and`"),
+            Self::Or => f.write_str("`or`"),
+            Self::Not => f.write_str("`not`"),
+            Self::Term(_) => f.write_str("search term"),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            _ => {
+                // A word runs to whitespace or a parenthesis outside of quotes. Quotes
+                // are removed, and only an unquoted word can be a keyword or a field.
+                let mut word = String::new();
+                let mut quoted = false;
+                let mut field_end = None;
+                let mut in_quotes = false;
+                while let Some(&c) = chars.peek() {
+                    if !in_quotes && (c.is_whitespace() || c == '(' || c == ')') {
+                        break;
+                    }
+                    chars.next();
+                    match c {
+                        '"' => {
+                            in_quotes = !in_quotes;
+                            quoted = true;
+                        }
+                        '\\' if in_quotes => {
+                            if let Some(escaped) = chars.next() {
+                                word.push(escaped);
+                            }
+                        }
+                        ':' if !in_quotes && !quoted && field_end.is_none() => {
+                            field_end = Some(word.len());
+                            word.push(c);
+                        }
+                        c => word.push(c),
+                    }
+                }
+                if in_quotes {
+                    return Err(format!("unclosed quote in `{word}`"));
+                }
+                if !quoted {
+                    match word.to_ascii_lowercase().as_str() {
+                        "and" => {
+                            tokens.push(Token::And);
+                            continue;
+                        }
+                        "or" => {
+                            tokens.push(Token::Or);
+                            continue;
+                        }
+                        "not" => {
+                            tokens.push(Token::Not);
+                            continue;
+                        }
+                        _ => {}
+                    }
+                }
+                let field = field_end.and_then(|end| {
+                    let name = word[..end].to_ascii_lowercase();
+                    FIELDS
+                        .iter()
+                        .find(|(candidate, _)| *candidate == name)
+                        .map(|(_, field)| (*field, end))
+                });
+                let term = match field {
+                    Some((field, end)) => Term::parse(Some(field), &word[end + 1..])?,
+                    None => Term::parse(None, &word)?,
+                };
+                tokens.push(Token::Term(term));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    fn or(&mut self) -> Result<Query, String> {
+        let mut query = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next += 1;
+            query = Query::Or(Box::new(query), Box::new(self.and()?));
+        }
+        Ok(query)
+    }
+
+    fn and(&mut self) -> Result<Query, String> {
+        let mut query = self.not()?;
+        loop {
+            match self.peek() {
+                Some(Token::And) => self.next += 1,
+                Some(Token::Open | Token::Not | Token::Term(_)) => {}
+                _ => return Ok(query),
+            }
+            query = Query::And(Box::new(query), Box::new(self.not()?));
+        }
+    }
+
+    fn not(&mut self) -> Result<Query, String> {
+        if self.peek() == Some(&Token::Not) {
+            self.next += 1;
+            return Ok(Query::Not(Box::new(self.not()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Query, String> {
+        let token = self.tokens.get(self.next).cloned();
+        self.next += 1;
+        match token {
+            Some(Token::Term(term)) => Ok(Query::Term(term)),
+            Some(Token::Open) => {
+                let query = self.or()?;
+                if self.peek() != Some(&Token::Close) {
+                    return Err("unmatched `(`".to_string());
+                }
+                self.next += 1;
+                Ok(query)
+            }
+            Some(token) => Err(format!("expected a search term, got {token}")),
+            None => Err("the search ends with an operator".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use calibre_library::metadata::BookRecord;
+
+    use super::*;
+
+    fn books() -> Vec<Book> {
+        let book = |id: i64, title: &str| Book {
+            record: BookRecord {
+                id,
+                title: title.to_string(),
+                series_index: 1.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        vec![
+            Book {
+                record: BookRecord {
+                    pubdate: Some("1813-01-28T00:00:00+00:00".to_string()),
+                    has_cover: true,
+                    ..book(1, "Pride and Prejudice").record
+                },
+                authors: vec!["Jane Austen".to_string()],
+                tags: vec!["Fiction".to_string(), "Romance".to_string()],
+                rating: Some(10),
+                formats: vec!["EPUB".to_string()],
+                identifiers: vec![("isbn".to_string(), "9780141439518".to_string())],
+                ..Default::default()
+            },
+            Book {
+                record: BookRecord {
+                    series_index: 2.0,
+                    pubdate: Some("0101-01-01T00:00:00+00:00".to_string()),
+                    ..book(2, "The Sign of the Four").record
+                },
+                authors: vec!["Arthur Conan Doyle".to_string()],
+                series: Some("Sherlock Holmes".to_string()),
+                tags: vec!["Fiction".to_string(), "Mystery".to_string()],
+                rating: Some(6),
+                formats: vec!["EPUB".to_string(), "PDF".to_string()],
+                ..Default::default()
+            },
+            Book {
+                record: BookRecord {
+                    pubdate: Some("1859-11-24T00:00:00+00:00".to_string()),
+                    ..book(3, "On the Origin of Species").record
+                },
+                authors: vec!["Charles Darwin".to_string()],
+                tags: vec!["Science".to_string()],
+                publisher: Some("John Murray".to_string()),
+                comments: Some("<p>Natural selection &amp; more.</p>".to_string()),
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn matches_fields_quotes_and_boolean_operators() {
+        let books = books();
+        for (search, expected) in [
+            // Bare words search the text fields; neighbours are combined with `and`.
+            ("austen", &[1][..]),
+            ("PRIDE prejudice", &[1]),
+            ("the", &[2, 3]),
+            ("pdf", &[2]),
+            ("selection", &[3]),
+            // Fields, their aliases and exact matches.
+            ("title:sign", &[2]),
+            ("author:doyle", &[2]),
+            ("tag:fiction", &[1, 2]),
+            ("tags:=fiction", &[1, 2]),
+            ("tags:=fict", &[]),
+            ("series:sherlock", &[2]),
+            ("publisher:murray", &[3]),
+            ("identifier:isbn:978", &[1]),
+            ("format:=pdf", &[2]),
+            ("unknown:austen", &[]),
+            // Quotes keep spaces, colons and keywords together.
+            ("\"pride and prejudice\"", &[1]),
+            ("title:\"origin of\"", &[3]),
+            ("\"or\"", &[3]),
+            ("\"title:sign\"", &[]),
+            ("title:\"say \\\"hi\\\"\"", &[]),
+            // Presence.
+            ("series:true", &[2]),
+            ("series:false", &[1, 3]),
+            ("cover:yes", &[1]),
+            ("rating:false", &[3]),
+            ("pubdate:false", &[2]),
+            // Numbers and dates.
+            ("rating:5", &[1]),
+            ("rating:>=3", &[1, 2]),
+            ("rating:\"3 stars\"", &[2]),
+            ("rating:<4", &[2]),
+            ("series_index:>1.5", &[2]),
+            ("id:<=2", &[1, 2]),
+            ("pubdate:<1850", &[1]),
+            ("pubdate:>=1859-11", &[3]),
+            ("pubdate:1813-01-28", &[1]),
+            // Negation, `or`, precedence and parentheses.
+            ("not fiction", &[3]),
+            ("NOT not fiction", &[1, 2]),
+            ("austen or darwin", &[1, 3]),
+            ("fiction and not mystery or science", &[1, 3]),
+            ("fiction and (mystery or romance)", &[1, 2]),
+            ("not (austen or doyle)", &[3]),
+            ("(((darwin)))", &[3]),
+        ] {
+            let query = Query::parse(search).unwrap_or_else(|err| panic!("{search}: {err}"));
+            let found = search_ids(&books, &query);
+            assert_eq!(found, expected, "{search}");
+        }
+    }
+
+    fn search_ids(books: &[Book], query: &Query) -> Vec<i64> {
+        search(books, query).iter().map(|book| book.id).collect()
+    }
+
+    #[test]
+    fn rejects_malformed_searches() {
+        for (search, error) in [
+            ("", "the search is empty"),
+            ("   ", "the search is empty"),
+            (
+                "title:",
+                "`title:` needs a value; search `title:false` for books without one",
+            ),
+            ("author:\"\"", "`authors:` needs a value"),
+            ("tags:=", "`tags:` needs a value"),
+            ("\"\"", "an empty quoted term matches every book"),
+            ("title:\"open", "unclosed quote in `title:open`"),
+            ("(austen", "unmatched `(`"),
+            ("austen)", "unmatched `)`"),
+            ("austen or", "the search ends with an operator"),
+            ("not", "the search ends with an operator"),
+            ("and austen", "expected a search term, got `and`"),
+            ("austen or or darwin", "expected a search term, got `or`"),
+            ("()", "expected a search term, got `)`"),
+            ("rating:lots", "`rating` needs a number, got `lots`"),
+            ("id:>", "`id` needs a number, got `>`"),
+            (
+                "pubdate:18xx",
+                "`pubdate` needs a date as YYYY, YYYY-MM or YYYY-MM-DD",
+            ),
+            ("date:2001-9-11", "`date` needs a date"),
+            ("cover:maybe", "`cover` takes true or false, got `maybe`"),
+            (
+                "title:~^Pride",
+                "regular expression searches (`~`) are not supported",
+            ),
+        ] {
+            let err = Query::parse(search).unwrap_err();
+            assert!(err.starts_with(error), "{search}: {err}");
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use zed_extension_api as zed;
+
+use crate::install::{ManagedInstall, DISTRIBUTION};
+use crate::interpreter::{self, Found, Interpreter, InterpreterKind};
+use crate::settings::CalibreSettings;
+use crate::version::{self, Version, SUPPORTED};
+use crate::worktree::WorktreeInfo;
+
+/// Python package the server runs as, with `python -m`.
+pub const PACKAGE_NAME: &str = "calibre_mcp";
+
+/// The server's read-only mode, relative to a checkout, which older checkouts of a
+/// supported version may predate.
+pub const READ_ONLY_MODULE: &str = "src/calibre_mcp/db/read_only.py";
+
+const VERSION_CHECK_FILE: &str = "calibre-mcp-version-check.py";
+const VERSION_CHECK_SCRIPT: &str = include_str!("version_check.py");
+
+/// Where the calibre-mcp server is run from.
+#[derive(Debug, Clone)]
+pub enum ServerLocation {
+    /// A calibre-mcp checkout.
+    Checkout(PathBuf),
+    /// The virtualenv the extension installs calibre-mcp into on first use.
+    Managed(ManagedInstall),
+    /// calibre-mcp already installed into a Python interpreter, e.g. with pip. Its version
+    /// is only known once that interpreter runs, so it is launched through a version check.
+    Installed(Interpreter),
+}
+
+impl ServerLocation {
+    /// The command launching the server. `interpreter` is only needed, and so its error
+    /// only returned, when the server is not run by a Python of its own.
+    pub fn command(&self, interpreter: zed::Result<Interpreter>) -> zed::Result<zed::Command> {
+        let server_args = ["--stdio".to_string()];
+        match self {
+            Self::Checkout(project_dir) => {
+                Ok(checkout_command(project_dir, &interpreter?, &server_args))
+            }
+            Self::Managed(install) => install.command(interpreter, &server_args),
+            Self::Installed(python) => {
+                let script = work_dir()?.join(VERSION_CHECK_FILE);
+                fs::write(&script, VERSION_CHECK_SCRIPT)
+                    .map_err(|err| format!("failed to write {}: {err}", script.display()))?;
+                let mut args = vec![
+                    script.to_string_lossy().into_owned(),
+                    "--distribution".to_string(),
+                    DISTRIBUTION.to_string(),
+                    "--min".to_string(),
+                    SUPPORTED.min.to_string(),
+                    "--below".to_string(),
+                    SUPPORTED.below.to_string(),
+                ];
+                args.extend(server_args);
+                Ok(zed::Command {
+                    command: python.path.clone(),
+                    args,
+                    env: Default::default(),
+                })
+            }
+        }
+    }
+}
+
+fn checkout_command(
+    project_dir: &Path,
+    interpreter: &Interpreter,
+    server_args: &[String],
+) -> zed::Command {
+    let project_dir = project_dir.to_string_lossy().into_owned();
+    let (mut args, env) = match interpreter.kind {
+        InterpreterKind::Uv => (
+            vec![
+                "run".to_string(),
+                "--project".to_string(),
+                project_dir,
+                "python".to_string(),
+                "-m".to_string(),
+                PACKAGE_NAME.to_string(),
+            ],
+            Vec::new(),
+        ),
+        InterpreterKind::Uvx => (
+            vec!["--from".to_string(), project_dir, DISTRIBUTION.to_string()],
+            Vec::new(),
+        ),
+        InterpreterKind::Python => {
+            let source_dir = Path::new(&project_dir).join("src");
+            (
+                vec!["-m".to_string(), PACKAGE_NAME.to_string()],
+                vec![(
+                    "PYTHONPATH".to_string(),
+                    source_dir.to_string_lossy().into_owned(),
+                )],
+            )
+        }
+    };
+    args.extend_from_slice(server_args);
+
+    zed::Command {
+        command: interpreter.path.clone(),
+        args,
+        env,
+    }
+}
+
+/// Picks how to run Python for the project.
+pub fn interpreter(worktrees: &[&WorktreeInfo], work_dir: &Path) -> zed::Result<Interpreter> {
+    let found = found(worktrees, work_dir)?;
+    Interpreter::find(|binary| found.get(binary).cloned())
+}
+
+/// Where the interpreter candidates are on PATH, as the first worktree of the project the
+/// extension has seen answers `which`. Before any has been seen, as on the launch when the
+/// project opens, the answers recorded from the last worktree seen are used, and without
+/// those the launch fails rather than guess an interpreter.
+fn found(worktrees: &[&WorktreeInfo], work_dir: &Path) -> zed::Result<Found> {
+    match worktrees.first() {
+        Some(worktree) => Ok(worktree.binaries().clone()),
+        None => interpreter::load_found(work_dir).ok_or_else(interpreter::not_searched),
+    }
+}
+
+/// Finds the server to launch.
+///
+/// The `server_path` setting wins and is taken as it is: the extension cannot look outside
+/// its work directory, so a path that is not a calibre-mcp checkout fails when the server
+/// starts. Otherwise a worktree whose root is a checkout is used. Without one the extension
+/// falls back to its managed install, or, with `auto_install` turned off, to a calibre-mcp
+/// installed into the system Python.
+///
+/// A worktree checkout of an unsupported version, or one without read-only mode when that
+/// is on, is passed over for the managed install; with `auto_install` off it is an error
+/// instead. The managed install pins a supported release unless `wheel_dir` or
+/// `wheel_bundle` decides which one is available, and is refused in read-only mode if
+/// it turns out not to have read-only mode.
+pub fn locate(
+    settings: &CalibreSettings,
+    worktrees: &[&WorktreeInfo],
+) -> zed::Result<ServerLocation> {
+    if let Some(server_path) = &settings.server_path {
+        return Ok(ServerLocation::Checkout(PathBuf::from(server_path)));
+    }
+
+    for worktree in worktrees {
+        let Some(pyproject) = worktree.pyproject().filter(|text| declares_server(text)) else {
+            continue;
+        };
+        let project_dir = &worktree.root;
+        if settings.read_only() && !worktree.has_read_only() {
+            if settings.auto_install {
+                continue;
+            }
+            return Err(format!(
+                "the calibre-mcp checkout at {} cannot open the library read-only; update \
+                 it, turn `auto_install` on, or set `read_only` to false",
+                project_dir.display()
+            ));
+        }
+        match unsupported_version(pyproject) {
+            None => return Ok(ServerLocation::Checkout(project_dir.clone())),
+            Some(version) if !settings.auto_install => {
+                return Err(format!(
+                    "the calibre-mcp checkout at {} is version {version}, but this \
+                     extension supports {SUPPORTED}; check out a supported release or \
+                     turn `auto_install` on to use the managed install",
+                    project_dir.display()
+                ))
+            }
+            Some(_) => {}
+        }
+    }
+
+    if !settings.auto_install {
+        let found = found(worktrees, &work_dir()?)?;
+        let python = Interpreter::find_python(|binary| found.get(binary).cloned())
+            .map_err(|err| format!("`auto_install` is off and {err}"))?;
+        return Ok(ServerLocation::Installed(python));
+    }
+
+    Ok(ServerLocation::Managed(ManagedInstall::new(
+        work_dir()?,
+        settings,
+    )?))
+}
+
+/// The extension's work directory, where it keeps its scripts and the managed install.
+pub fn work_dir() -> zed::Result<PathBuf> {
+    std::env::current_dir()
+        .map_err(|err| format!("failed to resolve the extension work directory: {err}"))
+}
+
+/// The checkout's version if it is known and outside the supported range. A checkout whose
+/// `pyproject.toml` has no static version is given the benefit of the doubt.
+fn unsupported_version(pyproject: &str) -> Option<Version> {
+    version::pyproject_version(pyproject).filter(|version| !SUPPORTED.contains(*version))
+}
+
+/// Whether a `pyproject.toml` is calibre-mcp's own, going by its `[project] name`. A
+/// project that merely depends on calibre-mcp names it too, but only among its
+/// dependencies.
+fn declares_server(pyproject: &str) -> bool {
+    version::pyproject_name(pyproject)
+        .is_some_and(|name| normalize_name(&name) == normalize_name(DISTRIBUTION))
+}
+
+/// A distribution name as PEP 503 compares them: lowercase, with runs of `-`, `_` and `.`
+/// as a single `-`.
+fn normalize_name(name: &str) -> String {
+    let mut normalized = String::new();
+    for c in name.trim().chars() {
+        if matches!(c, '-' | '_' | '.') {
+            if !normalized.ends_with('-') {
+                normalized.push('-');
+            }
+        } else {
+            normalized.push(c.to_ascii_lowercase());
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_checkouts_by_project_name() {
+        assert!(declares_server(
+            "[project]\nname = \"schip-mcp-calibre\"\nversion = \"1.9.0\"\n"
+        ));
+        assert!(declares_server("[project]\nname = 'Schip_MCP.Calibre'\n"));
+        // A project built on calibre-mcp mentions it everywhere but in its name.
+        assert!(!declares_server(
+            "[project]\nname = \"thesis-tools\"\ndependencies = [\"schip-mcp-calibre>=1.9\"]\n\n\
+             [tool.hatch.build.targets.wheel]\npackages = [\"src/calibre_mcp\"]\n"
+        ));
+        assert!(!declares_server("# name = \"schip-mcp-calibre\"\n"));
+
+        // Zed does not let the extension look at it, so it is not checked.
+        let settings = CalibreSettings {
+            server_path: Some("/home/jane/src/calibre-mcp".to_string()),
+            ..CalibreSettings::default()
+        };
+        assert!(matches!(
+            locate(&settings, &[]),
+            Ok(ServerLocation::Checkout(path)) if path == Path::new("/home/jane/src/calibre-mcp")
+        ));
+    }
+}
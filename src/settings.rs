@@ -0,0 +1,335 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use zed_extension_api::{self as zed, settings::ContextServerSettings};
+
+use crate::library;
+use crate::text::{CommentOptions, MAX_COMMENT_MAX_CHARS};
+use crate::tool_groups;
+
+/// The `settings` object of the `calibre-mcp` context server in Zed's settings.json.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CalibreSettings {
+    /// Directory of a calibre-mcp checkout to run instead of searching for one.
+    pub server_path: Option<String>,
+    /// Install calibre-mcp into the extension's work directory when no checkout is found.
+    /// When off, the server is run as `python -m calibre_mcp` from the system Python.
+    pub auto_install: bool,
+    /// Package index URL the managed install pulls calibre-mcp from.
+    pub package_index: Option<String>,
+    /// Local directory of wheels the managed install uses instead of a package index.
+    pub wheel_dir: Option<String>,
+    /// A `.mcpb` or zip bundle the managed install unpacks and installs from, offline.
+    pub wheel_bundle: Option<String>,
+    /// A Calibre library, or a directory whose subdirectories are Calibre libraries.
+    pub library_path: Option<String>,
+    /// URL of a running Calibre content server.
+    pub server_url: Option<String>,
+    /// Where the server keeps user comments, auth data and RAG indexes.
+    pub user_data_dir: Option<String>,
+    /// JSON configuration file for the server.
+    pub config_path: Option<String>,
+    /// Streamable HTTP endpoint of a calibre-mcp started elsewhere with `--http`, e.g.
+    /// `http://nas.local:8000/mcp`. When set, Zed connects to it through a local stdio
+    /// bridge and every setting about the local server is ignored.
+    pub remote_url: Option<String>,
+    /// Bearer token sent to `remote_url`.
+    pub bearer_token: Option<String>,
+    /// Tool groups the server registers, as a list of group names or a preset such as
+    /// `"read-only research"`, `"librarian"` or `"full"`. All tools when unset.
+    pub tool_groups: Option<ToolSelection>,
+    /// Open `metadata.db` read-only and only register tools that read the library. On by
+    /// default for libraries on network shares and removable volumes, where Calibre may
+    /// have the same library open elsewhere.
+    pub read_only: Option<bool>,
+    /// Remove HTML from book comments, as `CALIBRE_METADATA_STRIP_HTML` does. On by default.
+    pub strip_html: Option<bool>,
+    /// Characters of a book's comments to use, as `CALIBRE_METADATA_COMMENT_MAX_CHARS`
+    /// does. 20480 by default.
+    pub comment_max_chars: Option<usize>,
+    /// Template for the citation keys of `/calibre-cite`, e.g. `"{author}{year}{title}"`,
+    /// the default.
+    pub citation_key: Option<String>,
+}
+
+/// A single preset or group name, or a list of them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged, expecting = "expected a string or an array of strings")]
+pub enum ToolSelection {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ToolSelection {
+    pub fn names(&self) -> Vec<String> {
+        match self {
+            Self::One(name) => vec![name.clone()],
+            Self::Many(names) => names.clone(),
+        }
+    }
+}
+
+impl Default for CalibreSettings {
+    fn default() -> Self {
+        Self {
+            server_path: None,
+            auto_install: true,
+            package_index: None,
+            wheel_dir: None,
+            wheel_bundle: None,
+            library_path: None,
+            server_url: None,
+            user_data_dir: None,
+            config_path: None,
+            remote_url: None,
+            bearer_token: None,
+            tool_groups: None,
+            read_only: None,
+            strip_html: None,
+            comment_max_chars: None,
+            citation_key: None,
+        }
+    }
+}
+
+impl CalibreSettings {
+    pub fn for_project(context_server_id: &str, project: &zed::Project) -> zed::Result<Self> {
+        let settings = ContextServerSettings::for_project(context_server_id, project)?;
+        match settings.settings {
+            Some(value) => serde_json::from_value(value)
+                .map_err(|err| format!("invalid `{context_server_id}` settings: {err}")),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Whether the server is started in read-only mode, defaulting to on for a shared
+    /// `library_path`.
+    pub fn read_only(&self) -> bool {
+        self.read_only.unwrap_or_else(|| {
+            self.library_path
+                .as_deref()
+                .is_some_and(|path| library::is_shared(Path::new(path)))
+        })
+    }
+
+    /// How slash commands render comments: these settings, else the server's environment
+    /// variables in `env`.
+    pub fn comment_options(&self, env: &dyn Fn(&str) -> Option<String>) -> CommentOptions {
+        let mut options = CommentOptions::from_env(env);
+        if let Some(strip_html) = self.strip_html {
+            options.strip_html = strip_html;
+        }
+        if let Some(max_chars) = self.comment_max_chars {
+            options.max_chars = max_chars;
+        }
+        options
+    }
+
+    /// Validates the library settings and maps them onto the environment variables the
+    /// server reads at startup.
+    ///
+    /// Paths are only checked for being absolute. Zed lets the extension see no more than
+    /// its own work directory, so whether they exist is for the server to find out.
+    pub fn server_env(&self) -> zed::Result<zed::EnvVars> {
+        let mut env = Vec::new();
+        let read_only = self.read_only();
+        if read_only {
+            env.push(("CALIBRE_READ_ONLY".to_string(), "1".to_string()));
+        }
+
+        if let Some(library_path) = &self.library_path {
+            let path = absolute_path("library_path", library_path)?;
+            env.extend(library::server_env(path).map_err(|err| format!("`library_path`: {err}"))?);
+            // A directory of libraries has no metadata.db, and the server then checks the
+            // library it opens instead.
+            if read_only {
+                env.push((
+                    "CALIBRE_METADATA_DB_URI".to_string(),
+                    library::read_only_uri(&path.join("metadata.db")),
+                ));
+            }
+        }
+
+        if let Some(server_url) = &self.server_url {
+            if !(server_url.starts_with("http://") || server_url.starts_with("https://")) {
+                return Err(format!(
+                    "`server_url` {server_url} must start with http:// or https://"
+                ));
+            }
+            env.push(("CALIBRE_SERVER_URL".to_string(), server_url.clone()));
+        }
+
+        if let Some(user_data_dir) = &self.user_data_dir {
+            absolute_path("user_data_dir", user_data_dir)?;
+            env.push((
+                "CALIBRE_MCP_USER_DATA_DIR".to_string(),
+                user_data_dir.clone(),
+            ));
+        }
+
+        let selection = match &self.tool_groups {
+            Some(tool_groups) => Some(tool_groups.names()),
+            None if read_only => Some(vec![tool_groups::READ_ONLY_PRESET.to_string()]),
+            None => None,
+        };
+        if let Some(selection) = selection {
+            let groups =
+                tool_groups::resolve(&selection).map_err(|err| format!("`tool_groups`: {err}"))?;
+            if read_only {
+                if let Some(group) = tool_groups::first_writing(&groups) {
+                    return Err(format!(
+                        "`tool_groups`: `{}` can change the library, but read-only mode is on; \
+                         pick groups from `{}` or set `read_only` to false",
+                        group.name,
+                        tool_groups::READ_ONLY_PRESET
+                    ));
+                }
+            }
+            env.extend(tool_groups::server_env(&groups));
+        }
+
+        if let Some(strip_html) = self.strip_html {
+            env.push((
+                "CALIBRE_METADATA_STRIP_HTML".to_string(),
+                if strip_html { "1" } else { "0" }.to_string(),
+            ));
+        }
+
+        if let Some(max_chars) = self.comment_max_chars {
+            if !(1..=MAX_COMMENT_MAX_CHARS).contains(&max_chars) {
+                return Err(format!(
+                    "`comment_max_chars` must be between 1 and {MAX_COMMENT_MAX_CHARS}, got {max_chars}"
+                ));
+            }
+            env.push((
+                "CALIBRE_METADATA_COMMENT_MAX_CHARS".to_string(),
+                max_chars.to_string(),
+            ));
+        }
+
+        if let Some(config_path) = &self.config_path {
+            absolute_path("config_path", config_path)?;
+            env.push(("CALIBRE_CONFIG_PATH".to_string(), config_path.clone()));
+        }
+
+        Ok(env)
+    }
+}
+
+/// The server is started from an unspecified working directory, so relative paths would
+/// resolve differently there than here.
+fn absolute_path<'a>(key: &str, value: &'a str) -> zed::Result<&'a Path> {
+    let path = Path::new(value);
+    if path.is_absolute() {
+        Ok(path)
+    } else {
+        Err(format!("`{key}` must be an absolute path, got {value}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(settings: &CalibreSettings) -> zed::EnvVars {
+        settings.server_env().unwrap()
+    }
+
+    fn var<'a>(env: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        env.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn checks_paths_and_urls_by_their_syntax_only() {
+        let settings = |library_path: &str| CalibreSettings {
+            library_path: Some(library_path.to_string()),
+            ..CalibreSettings::default()
+        };
+        assert_eq!(
+            settings("Calibre Library").server_env().unwrap_err(),
+            "`library_path` must be an absolute path, got Calibre Library"
+        );
+        // Nothing the extension cannot see is refused; the server checks it exists.
+        let env = vars(&settings("/srv/books/Calibre Library"));
+        assert_eq!(
+            var(&env, "CALIBRE_BASE_PATH"),
+            Some("/srv/books/Calibre Library")
+        );
+        assert_eq!(var(&env, "CALIBRE_LIBRARY_NAME"), None);
+
+        let unseen = CalibreSettings {
+            user_data_dir: Some("/srv/calibre-mcp".to_string()),
+            config_path: Some("/etc/calibre-mcp/config.json".to_string()),
+            server_url: Some("https://books.example".to_string()),
+            ..CalibreSettings::default()
+        };
+        let env = vars(&unseen);
+        assert_eq!(
+            var(&env, "CALIBRE_MCP_USER_DATA_DIR"),
+            Some("/srv/calibre-mcp")
+        );
+        assert_eq!(
+            var(&env, "CALIBRE_CONFIG_PATH"),
+            Some("/etc/calibre-mcp/config.json")
+        );
+        assert_eq!(
+            var(&env, "CALIBRE_SERVER_URL"),
+            Some("https://books.example")
+        );
+
+        let invalid = |settings: CalibreSettings| settings.server_env().unwrap_err();
+        assert_eq!(
+            invalid(CalibreSettings {
+                config_path: Some("config.json".to_string()),
+                ..CalibreSettings::default()
+            }),
+            "`config_path` must be an absolute path, got config.json"
+        );
+        assert_eq!(
+            invalid(CalibreSettings {
+                server_url: Some("books.example:8080".to_string()),
+                ..CalibreSettings::default()
+            }),
+            "`server_url` books.example:8080 must start with http:// or https://"
+        );
+        assert!(invalid(CalibreSettings {
+            comment_max_chars: Some(0),
+            ..CalibreSettings::default()
+        })
+        .starts_with("`comment_max_chars` must be between 1 and"));
+    }
+
+    #[test]
+    fn defaults_shared_libraries_to_read_only_tools() {
+        let shared = CalibreSettings {
+            library_path: Some("/Volumes/NAS/Calibre Library".to_string()),
+            ..CalibreSettings::default()
+        };
+        assert!(shared.read_only());
+        let env = vars(&shared);
+        assert_eq!(var(&env, "CALIBRE_READ_ONLY"), Some("1"));
+        assert_eq!(
+            var(&env, "CALIBRE_METADATA_DB_URI"),
+            Some("file:///Volumes/NAS/Calibre%20Library/metadata.db?mode=ro")
+        );
+        assert!(var(&env, "CALIBRE_MCP_TOOLS")
+            .is_some_and(|tools| tools.contains("query_books") && !tools.contains("manage_books")));
+
+        let writing = CalibreSettings {
+            tool_groups: Some(ToolSelection::One("librarian".to_string())),
+            ..shared.clone()
+        };
+        assert!(writing
+            .server_env()
+            .unwrap_err()
+            .starts_with("`tool_groups`: `books` can change the library"));
+        let allowed = CalibreSettings {
+            read_only: Some(false),
+            ..writing
+        };
+        assert_eq!(var(&vars(&allowed), "CALIBRE_READ_ONLY"), None);
+    }
+}
@@ -0,0 +1,86 @@
+//! Plain text from Calibre comments, the way `calibre_mcp.rag.text_utils` makes it, so a
+//! book reads the same in a slash command as in the server's metadata search.
+
+use calibre_library::xml::unescape;
+
+/// Comment length used when `comment_max_chars` and `CALIBRE_METADATA_COMMENT_MAX_CHARS`
+/// are unset or invalid: 20 KiB.
+pub const DEFAULT_COMMENT_MAX_CHARS: usize = 20 * 1024;
+/// Largest accepted comment length: 16 MiB.
+pub const MAX_COMMENT_MAX_CHARS: usize = 16_777_216;
+
+/// How comments are turned into text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommentOptions {
+    pub strip_html: bool,
+    pub max_chars: usize,
+}
+
+impl Default for CommentOptions {
+    fn default() -> Self {
+        Self {
+            strip_html: true,
+            max_chars: DEFAULT_COMMENT_MAX_CHARS,
+        }
+    }
+}
+
+impl CommentOptions {
+    /// Reads `CALIBRE_METADATA_STRIP_HTML` and `CALIBRE_METADATA_COMMENT_MAX_CHARS` like the
+    /// server does: stripping is on unless turned off, and a missing, invalid or
+    /// non-positive length falls back to the default while a large one is clamped.
+    pub fn from_env(env: &dyn Fn(&str) -> Option<String>) -> Self {
+        let strip_html = env("CALIBRE_METADATA_STRIP_HTML").is_none_or(|value| {
+            !matches!(
+                value.trim().to_lowercase().as_str(),
+                "0" | "false" | "no" | "off"
+            )
+        });
+        let max_chars = env("CALIBRE_METADATA_COMMENT_MAX_CHARS")
+            .and_then(|value| value.trim().parse::<i64>().ok())
+            .filter(|max_chars| *max_chars >= 1)
+            .map(|max_chars| (max_chars as u64).min(MAX_COMMENT_MAX_CHARS as u64) as usize)
+            .unwrap_or(DEFAULT_COMMENT_MAX_CHARS);
+        Self {
+            strip_html,
+            max_chars,
+        }
+    }
+
+    /// The comment as text, cut to `max_chars` characters with a trailing `…`.
+    pub fn apply(&self, comment: &str) -> String {
+        let text = if self.strip_html {
+            strip_html(comment)
+        } else {
+            comment.trim().to_string()
+        };
+        match text.char_indices().nth(self.max_chars) {
+            Some((end, _)) => format!("{}…", &text[..end]),
+            None => text,
+        }
+    }
+}
+
+/// Unescapes entities, removes tags and collapses whitespace, as `strip_html_for_embedding`
+/// does.
+pub fn strip_html(html: &str) -> String {
+    let unescaped = unescape(html);
+    let mut text = String::with_capacity(unescaped.len());
+    let mut rest = unescaped.as_str();
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        // Like the server's `<[^>]+>`, a `<` only opens a tag if a `>` follows it.
+        match rest[start + 1..].find('>') {
+            Some(length) if length > 0 => {
+                text.push(' ');
+                rest = &rest[start + 1 + length + 1..];
+            }
+            _ => {
+                text.push('<');
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
@@ -0,0 +1,319 @@
+use zed_extension_api as zed;
+
+use crate::version::Version;
+
+/// First calibre-mcp release that registers only the tools in `CALIBRE_MCP_TOOLS`, and so
+/// the start of `version::SUPPORTED`.
+pub const SINCE: Version = Version::new(1, 9, 0);
+
+/// A set of related calibre-mcp tools that can be enabled together.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ToolGroup {
+    pub name: &'static str,
+    pub tools: &'static [&'static str],
+    /// Whether the tools are only registered with `CALIBRE_BETA_TOOLS` on.
+    pub beta: bool,
+}
+
+const fn group(name: &'static str, tools: &'static [&'static str]) -> ToolGroup {
+    ToolGroup {
+        name,
+        tools,
+        beta: false,
+    }
+}
+
+const fn beta(name: &'static str, tools: &'static [&'static str]) -> ToolGroup {
+    ToolGroup {
+        name,
+        tools,
+        beta: true,
+    }
+}
+
+/// Every tool group, following how `calibre_mcp.tools.register_tools` loads them.
+pub const GROUPS: &[ToolGroup] = &[
+    group("libraries", &["manage_libraries", "library_discovery"]),
+    group("search", &["query_books", "search_fulltext"]),
+    group("books", &["manage_books"]),
+    group(
+        "metadata",
+        &[
+            "manage_metadata",
+            "manage_authors",
+            "manage_comments",
+            "manage_publishers",
+            "manage_series",
+            "manage_tags",
+        ],
+    ),
+    group(
+        "rag_search",
+        &[
+            "calibre_metadata_search",
+            "rag_retrieve",
+            "media_research_book",
+            "media_synopsis",
+            "media_critical_reception",
+            "media_deep_research",
+        ],
+    ),
+    // These write LanceDB indexes, and the JSON export, next to the library.
+    group(
+        "rag_index",
+        &[
+            "calibre_metadata_index_build",
+            "calibre_metadata_export_json",
+            "rag_index_build",
+            "calibre_rag",
+        ],
+    ),
+    group("files", &["manage_files", "export_books"]),
+    group(
+        "analysis",
+        &[
+            "manage_analysis",
+            "get_tag_statistics",
+            "find_duplicate_books",
+            "get_series_analysis",
+            "analyze_library_health",
+            "unread_priority_list",
+            "reading_statistics",
+        ],
+    ),
+    group("library_operations", &["manage_library_operations"]),
+    group("viewer", &["manage_viewer"]),
+    group("system", &["manage_system", "help_tool"]),
+    group(
+        "cards",
+        &["show_book_prefab_card", "show_libraries_prefab_card"],
+    ),
+    group("ocr", &["calibre_ocr"]),
+    beta("import", &["manage_import"]),
+    beta("bulk", &["manage_bulk_operations", "manage_content_sync"]),
+    beta(
+        "descriptions",
+        &[
+            "manage_descriptions",
+            "manage_extended_metadata",
+            "manage_times",
+            "manage_user_comments",
+        ],
+    ),
+    beta(
+        "organization",
+        &[
+            "manage_organization",
+            "manage_specialized",
+            "manage_smart_collections",
+        ],
+    ),
+    beta(
+        "ai",
+        &[
+            "manage_ai_operations",
+            "agentic_library_workflow",
+            "agentic_calibre_workflow",
+            "intelligent_library_processing",
+            "conversational_calibre_assistant",
+        ],
+    ),
+    beta("users", &["manage_users"]),
+];
+
+/// Preset of the groups that only read the library, and the default in read-only mode.
+pub const READ_ONLY_PRESET: &str = "read-only research";
+
+/// Named selections of groups. `full` lists none and stands for all of them.
+pub const PRESETS: &[(&str, &[&str])] = &[
+    (
+        READ_ONLY_PRESET,
+        &[
+            "libraries",
+            "search",
+            "rag_search",
+            "analysis",
+            "viewer",
+            "system",
+            "cards",
+        ],
+    ),
+    (
+        "librarian",
+        &[
+            "libraries",
+            "search",
+            "rag_search",
+            "rag_index",
+            "analysis",
+            "viewer",
+            "system",
+            "cards",
+            "books",
+            "metadata",
+            "files",
+            "library_operations",
+            "ocr",
+            "import",
+        ],
+    ),
+    ("rag", &["rag_search", "rag_index"]),
+    ("full", &[]),
+];
+
+/// Resolves a selection of group and preset names into groups, in `GROUPS` order.
+///
+/// Unknown names are an error that lists what is available, and suggests the closest
+/// name when the unknown one looks like a typo.
+pub fn resolve(selection: &[String]) -> Result<Vec<&'static ToolGroup>, String> {
+    if selection.is_empty() {
+        return Err("must name at least one tool group or preset".to_string());
+    }
+
+    let mut names = Vec::new();
+    for name in selection {
+        let name = name.trim();
+        if let Some((_, groups)) = PRESETS.iter().find(|(preset, _)| *preset == name) {
+            if groups.is_empty() {
+                names.extend(GROUPS.iter().map(|group| group.name));
+            } else {
+                names.extend(groups.iter().copied());
+            }
+        } else if let Some(group) = GROUPS.iter().find(|group| group.name == name) {
+            names.push(group.name);
+        } else {
+            return Err(unknown(name));
+        }
+    }
+
+    Ok(GROUPS
+        .iter()
+        .filter(|group| names.contains(&group.name))
+        .collect())
+}
+
+/// The first group in `groups` that can change the library.
+pub fn first_writing<'a>(groups: &[&'a ToolGroup]) -> Option<&'a ToolGroup> {
+    let read_only = PRESETS
+        .iter()
+        .find(|(preset, _)| *preset == READ_ONLY_PRESET)
+        .map(|(_, groups)| *groups)
+        .unwrap_or_default();
+    groups
+        .iter()
+        .copied()
+        .find(|group| !read_only.contains(&group.name))
+}
+
+/// Environment telling the server which tools to register.
+pub fn server_env(groups: &[&ToolGroup]) -> zed::EnvVars {
+    let tools = groups
+        .iter()
+        .flat_map(|group| group.tools.iter().copied())
+        .collect::<Vec<_>>();
+    let mut env = vec![("CALIBRE_MCP_TOOLS".to_string(), tools.join(","))];
+    if groups.iter().any(|group| group.beta) {
+        env.push(("CALIBRE_BETA_TOOLS".to_string(), "true".to_string()));
+    }
+    env
+}
+
+fn unknown(name: &str) -> String {
+    let known = GROUPS
+        .iter()
+        .map(|group| group.name)
+        .chain(PRESETS.iter().map(|(preset, _)| *preset));
+    let suggestion = known
+        .clone()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| format!("; did you mean `{candidate}`?"))
+        .unwrap_or_default();
+    let known = known
+        .map(|name| format!("`{name}`"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("unknown tool group `{name}`{suggestion} (known groups and presets: {known})")
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(selection: &[&str]) -> Result<Vec<&'static str>, String> {
+        let selection = selection
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        resolve(&selection).map(|groups| groups.iter().map(|group| group.name).collect())
+    }
+
+    #[test]
+    fn resolves_groups_and_presets_into_server_tools() {
+        assert_eq!(
+            names(&["rag", " search "]),
+            Ok(vec!["search", "rag_search", "rag_index"])
+        );
+        assert_eq!(
+            names(&[READ_ONLY_PRESET]),
+            Ok(vec![
+                "libraries",
+                "search",
+                "rag_search",
+                "analysis",
+                "viewer",
+                "system",
+                "cards"
+            ])
+        );
+        assert_eq!(names(&["full"]).unwrap().len(), GROUPS.len());
+        assert!(names(&[]).is_err());
+        assert!(names(&["serach"])
+            .unwrap_err()
+            .starts_with("unknown tool group `serach`; did you mean `search`?"));
+        assert!(!names(&["everything"]).unwrap_err().contains("did you mean"));
+
+        let groups = resolve(&["librarian".to_string()]).unwrap();
+        assert_eq!(
+            first_writing(&groups).map(|group| group.name),
+            Some("books")
+        );
+        let read_only = resolve(&[READ_ONLY_PRESET.to_string()]).unwrap();
+        assert_eq!(first_writing(&read_only), None);
+        let rag = resolve(&["rag".to_string()]).unwrap();
+        assert_eq!(
+            first_writing(&rag).map(|group| group.name),
+            Some("rag_index")
+        );
+
+        let search = resolve(&["search".to_string()]).unwrap();
+        assert_eq!(
+            server_env(&search),
+            [(
+                "CALIBRE_MCP_TOOLS".to_string(),
+                "query_books,search_fulltext".to_string()
+            )]
+        );
+        let env = server_env(&groups);
+        assert_eq!(
+            env.last(),
+            Some(&("CALIBRE_BETA_TOOLS".to_string(), "true".to_string()))
+        );
+        assert!(env[0].1.split(',').any(|tool| tool == "manage_import"));
+    }
+}
@@ -0,0 +1,113 @@
+//! EPUBs `/calibre-unpack` writes into the worktree for editing, one folder per book under
+//! `epub/`, and the marker file in each that tells `/calibre-repack` which book of which
+//! library the folder belongs to.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use calibre_library::metadata::Book;
+use serde::{Deserialize, Serialize};
+
+/// Folder of the worktree the books are unpacked into.
+pub const DIRECTORY: &str = "epub";
+/// The marker, hidden so that neither EPUB checks nor packing see it.
+pub const MARKER: &str = ".calibre-book.toml";
+
+/// Which book a folder was unpacked from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Marker {
+    pub library: PathBuf,
+    pub book: i64,
+    /// CRC-32 of the book's EPUB as it was unpacked, or last repacked, to tell whether the
+    /// library's copy has changed since.
+    pub crc32: u32,
+}
+
+impl Marker {
+    pub fn read(folder: &Path) -> Result<Self, String> {
+        let path = folder.join(MARKER);
+        let text = fs::read_to_string(&path)
+            .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+        toml::from_str(&text).map_err(|err| format!("{}: {}", path.display(), err.message()))
+    }
+
+    pub fn write(&self, folder: &Path) -> Result<(), String> {
+        let path = folder.join(MARKER);
+        let marker = toml::to_string(self)
+            .map_err(|err| format!("failed to write {}: {err}", path.display()))?;
+        let text = format!(
+            "# Written by /calibre-unpack. `/calibre-repack {}` puts this folder back into the\n\
+             # library in place of the book's EPUB.\n\
+             {marker}",
+            self.book
+        );
+        fs::write(&path, text).map_err(|err| format!("failed to write {}: {err}", path.display()))
+    }
+}
+
+/// Where `book` is unpacked in the worktree at `root`: `epub/<title> (<id>)`, like the
+/// book's directory in the library.
+pub fn folder(root: &Path, book: &Book) -> PathBuf {
+    let title = book
+        .title
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    let title = title.trim().trim_end_matches('.');
+    root.join(DIRECTORY).join(format!("{title} ({})", book.id))
+}
+
+/// The folder `book` was unpacked into, found by its marker, since the book may have
+/// been renamed since.
+pub fn find(root: &Path, book: i64) -> Option<(PathBuf, Marker)> {
+    let mut folders = fs::read_dir(root.join(DIRECTORY))
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    folders.sort();
+    folders.into_iter().find_map(|folder| {
+        let marker = Marker::read(&folder).ok()?;
+        (marker.book == book).then_some((folder, marker))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_markers() {
+        let root = std::env::temp_dir().join(format!("calibre-unpacked-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let book = Book {
+            record: calibre_library::metadata::BookRecord {
+                id: 7,
+                title: "Who? What: \"Why\"".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let folder = folder(&root, &book);
+        assert_eq!(folder, root.join("epub/Who_ What_ _Why_ (7)"));
+
+        fs::create_dir_all(&folder).unwrap();
+        let marker = Marker {
+            library: PathBuf::from("C:\\Books\\Calibre \"Library\""),
+            book: 7,
+            crc32: 0xdead_beef,
+        };
+        marker.write(&folder).unwrap();
+        assert_eq!(find(&root, 7), Some((folder.clone(), marker)));
+        assert_eq!(find(&root, 8), None);
+        fs::write(folder.join(MARKER), "library = \"/srv/books\"\nbook = 7\n").unwrap();
+        assert!(Marker::read(&folder)
+            .unwrap_err()
+            .ends_with(": missing field `crc32`"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt;
+
+use serde::Deserialize;
+
+use crate::tool_groups;
+
+/// calibre-mcp releases this extension knows how to launch and configure: from the first
+/// with tool selection, which the `tool_groups` setting relies on.
+pub const SUPPORTED: VersionRange = VersionRange {
+    min: tool_groups::SINCE,
+    below: Version::new(2, 0, 0),
+};
+
+/// A release number, compared on its first three components.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    major: u64,
+    minor: u64,
+    patch: u64,
+}
+
+impl Version {
+    pub const fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Parses the leading `X[.Y[.Z]]` of a version, ignoring pre-release and local
+    /// suffixes such as `1.9.0.dev3+g1234`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut parts = text.trim().trim_start_matches('v').split('.').map(|part| {
+            let digits: String = part.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse::<u64>().ok()
+        });
+        let major = parts.next()??;
+        let minor = parts.next().flatten().unwrap_or(0);
+        let patch = parts.next().flatten().unwrap_or(0);
+        Some(Self::new(major, minor, patch))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Versions from `min` up to, but not including, `below`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionRange {
+    pub min: Version,
+    pub below: Version,
+}
+
+impl VersionRange {
+    pub fn contains(&self, version: Version) -> bool {
+        self.min <= version && version < self.below
+    }
+}
+
+impl fmt::Display for VersionRange {
+    /// PEP 440 specifier syntax, so the range can be pasted into pip.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, ">={},<{}", self.min, self.below)
+    }
+}
+
+/// The static `version` of a `pyproject.toml`'s `[project]` table, if it declares one.
+pub fn pyproject_version(pyproject: &str) -> Option<Version> {
+    Version::parse(&project(pyproject)?.version?)
+}
+
+/// The `name` of a `pyproject.toml`'s `[project]` table, if it declares one.
+pub fn pyproject_name(pyproject: &str) -> Option<String> {
+    project(pyproject)?.name
+}
+
+#[derive(Deserialize)]
+struct Pyproject {
+    project: Option<Project>,
+}
+
+#[derive(Deserialize)]
+struct Project {
+    name: Option<String>,
+    version: Option<String>,
+}
+
+fn project(pyproject: &str) -> Option<Project> {
+    toml::from_str::<Pyproject>(pyproject).ok()?.project
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_project_table_of_pyproject() {
+        let pyproject = r#"
+[build-system]
+requires = ["hatchling"]
+name = "not-this-one"
+
+[project]
+name = "schip-mcp-calibre"  # published name
+version = '1.9.0.dev3+g1234'
+dependencies = ["fastmcp"]
+
+[tool.hatch.build.targets.wheel]
+packages = ["src/calibre_mcp"]
+"#;
+        assert_eq!(
+            pyproject_name(pyproject).as_deref(),
+            Some("schip-mcp-calibre")
+        );
+        assert_eq!(pyproject_version(pyproject), Some(Version::new(1, 9, 0)));
+        assert_eq!(pyproject_name("[tool.uv]\nname = \"x\"\n"), None);
+        assert_eq!(
+            pyproject_version("[project]\ndynamic = [\"version\"]\n"),
+            None
+        );
+
+        assert_eq!(Version::parse("v2"), Some(Version::new(2, 0, 0)));
+        assert_eq!(Version::parse("1.10"), Some(Version::new(1, 10, 0)));
+        assert_eq!(Version::parse("dev"), None);
+    }
+
+    #[test]
+    fn supports_releases_with_read_only_mode_and_tool_selection() {
+        let supported = |version: &str| SUPPORTED.contains(Version::parse(version).unwrap());
+        assert!(!supported("1.8.0"));
+        assert!(!supported("1.8.9"));
+        assert!(supported("1.9.0.dev3"));
+        assert!(supported("1.9.0"));
+        assert!(supported("1.12.1"));
+        assert!(!supported("2.0.0"));
+        assert!(!supported("2.0.0rc1"));
+        assert_eq!(SUPPORTED.to_string(), ">=1.9.0,<2.0.0");
+    }
+}
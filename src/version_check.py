@@ -0,0 +1,90 @@
+"""
+Version check for the calibre-mcp Zed extension.
+
+Written into the extension's work directory and run by Zed when the server comes from
+a calibre-mcp installed into the system Python, whose version the extension cannot read
+itself. Looks up the installed distribution's version, refuses to start a release
+outside the range the extension supports or one that cannot honour read-only mode, and
+otherwise replaces itself with the server.
+
+stdout belongs to the MCP stdio transport, so all messages go to stderr.
+"""
+
+import argparse
+import os
+import subprocess
+import sys
+from importlib import metadata, util
+
+
+def parse_version(text: str) -> tuple[int, int, int]:
+    """The leading X.Y.Z of a version, ignoring pre-release and local suffixes."""
+    parts = []
+    for part in text.split(".")[:3]:
+        digits = ""
+        for c in part:
+            if not c.isdigit():
+                break
+            digits += c
+        if not digits:
+            break
+        parts.append(int(digits))
+    while len(parts) < 3:
+        parts.append(0)
+    return tuple(parts)
+
+
+def supports_read_only() -> bool:
+    """Whether the installed server has read-only mode, found without importing it."""
+    spec = util.find_spec("calibre_mcp")
+    locations = (spec and spec.submodule_search_locations) or []
+    return any(os.path.isfile(os.path.join(p, "db", "read_only.py")) for p in locations)
+
+
+def main() -> int:
+    parser = argparse.ArgumentParser(description="Check the calibre-mcp version and launch it")
+    parser.add_argument("--distribution", required=True, help="Distribution to look up")
+    parser.add_argument("--min", required=True, help="Lowest supported version")
+    parser.add_argument("--below", required=True, help="First unsupported version")
+    args, server_args = parser.parse_known_args()
+
+    supported = f">={args.min},<{args.below}"
+    try:
+        installed = metadata.version(args.distribution)
+    except metadata.PackageNotFoundError:
+        print(
+            f"calibre-mcp: {args.distribution} is not installed for {sys.executable}; "
+            f'run `{sys.executable} -m pip install "{args.distribution}{supported}"` '
+            "or turn `auto_install` on",
+            file=sys.stderr,
+        )
+        return 1
+
+    if not parse_version(args.min) <= parse_version(installed) < parse_version(args.below):
+        print(
+            f"calibre-mcp: {args.distribution} {installed} is installed, but the Zed extension "
+            f'supports {supported}; run `{sys.executable} -m pip install "{args.distribution}'
+            f'{supported}"` or turn `auto_install` on',
+            file=sys.stderr,
+        )
+        return 1
+
+    if os.environ.get("CALIBRE_READ_ONLY") and not supports_read_only():
+        print(
+            f"calibre-mcp: {args.distribution} {installed} cannot open the library read-only; "
+            f'run `{sys.executable} -m pip install --upgrade "{args.distribution}{supported}"` '
+            "or set `read_only` to false",
+            file=sys.stderr,
+        )
+        return 1
+
+    command = [sys.executable, "-m", "calibre_mcp", *server_args]
+    if os.name == "nt":
+        # Windows has no exec: os.execv starts the server and exits, and Zed sees it stop.
+        return subprocess.call(command)
+    os.execv(sys.executable, command)
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
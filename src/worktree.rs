@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use zed_extension_api as zed;
+
+use crate::interpreter::{Found, CANDIDATES};
+use crate::project_config::{self, ProjectConfig};
+use crate::server;
+
+/// What the extension knows about a worktree after Zed handed it one.
+///
+/// `context_server_command` only receives a `Project`, which lists worktree ids but not
+/// their roots or shell environment, so the parts the launcher needs are captured whenever
+/// a `Worktree` passes through a hook.
+#[derive(Debug, Clone)]
+pub struct WorktreeInfo {
+    pub root: PathBuf,
+    /// Interpreter candidates found by `Worktree::which`.
+    binaries: Found,
+    /// Name and contents of the worktree's library binding file, if it has one.
+    config_file: Option<(String, String)>,
+    /// The worktree's `pyproject.toml`, which says whether it is a calibre-mcp checkout.
+    pyproject: Option<String>,
+    /// Whether the worktree has the server's read-only mode, should it be a checkout.
+    has_read_only: bool,
+    /// The user's shell environment in the worktree.
+    env: HashMap<String, String>,
+}
+
+impl WorktreeInfo {
+    pub fn binaries(&self) -> &Found {
+        &self.binaries
+    }
+
+    pub fn env_var(&self, name: &str) -> Option<String> {
+        self.env.get(name).cloned()
+    }
+
+    pub fn shell_env(&self) -> &HashMap<String, String> {
+        &self.env
+    }
+
+    pub fn pyproject(&self) -> Option<&str> {
+        self.pyproject.as_deref()
+    }
+
+    pub fn has_read_only(&self) -> bool {
+        self.has_read_only
+    }
+
+    pub fn project_config(&self) -> Result<Option<ProjectConfig>, String> {
+        self.config_file
+            .as_ref()
+            .map(|(file_name, text)| ProjectConfig::parse(file_name, text, &self.root))
+            .transpose()
+    }
+}
+
+impl WorktreeInfo {
+    /// The worktree at `root` as `calibre-commands` sees it, natively: its files read from
+    /// disk, and its shell environment the one the extension started it with.
+    pub fn open(root: PathBuf) -> Self {
+        let read = |file_name: &str| fs::read_to_string(root.join(file_name)).ok();
+        let config_file = project_config::FILE_NAMES.iter().find_map(|file_name| {
+            let text = read(file_name)?;
+            Some((file_name.to_string(), text))
+        });
+        Self {
+            binaries: Found::default(),
+            config_file,
+            pyproject: read("pyproject.toml"),
+            has_read_only: root.join(server::READ_ONLY_MODULE).is_file(),
+            env: std::env::vars().collect(),
+            root,
+        }
+    }
+}
+
+impl From<&zed::Worktree> for WorktreeInfo {
+    fn from(worktree: &zed::Worktree) -> Self {
+        let binaries = CANDIDATES
+            .iter()
+            .filter_map(|(binary, _)| Some((binary.to_string(), worktree.which(binary)?)))
+            .collect();
+        let config_file = project_config::FILE_NAMES.iter().find_map(|file_name| {
+            let text = worktree.read_text_file(file_name).ok()?;
+            Some((file_name.to_string(), text))
+        });
+        Self {
+            root: PathBuf::from(worktree.root_path()),
+            binaries,
+            config_file,
+            pyproject: worktree.read_text_file("pyproject.toml").ok(),
+            has_read_only: worktree.read_text_file(server::READ_ONLY_MODULE).is_ok(),
+            env: worktree.shell_env().into_iter().collect(),
+        }
+    }
+}
@@ -0,0 +1,43 @@
+"""
+Tests that every tool calibre-mcp registers belongs to a tool group of the Zed extension.
+
+The extension selects tools by group (src/tool_groups.rs) and the server removes every
+tool it was not given, so a tool in no group is gone even with the `full` preset.
+"""
+
+import asyncio
+import re
+from pathlib import Path
+
+import pytest
+
+TOOL_GROUPS = Path(__file__).parent.parent.parent / "src" / "tool_groups.rs"
+
+
+def grouped_tools() -> set[str]:
+    """The tools of every group in the extension's GROUPS, beta ones included."""
+    source = TOOL_GROUPS.read_text(encoding="utf-8")
+    start = source.index("pub const GROUPS")
+    groups = source[start : source.index("\n];", start)]
+    tools = set()
+    for names in re.findall(r'(?:group|beta)\(\s*"[a-z_]+",\s*&\[(.*?)\]', groups, re.DOTALL):
+        tools.update(re.findall(r'"([a-z0-9_]+)"', names))
+    return tools
+
+
+def test_every_registered_tool_is_grouped(monkeypatch):
+    pytest.importorskip("fastmcp")
+    monkeypatch.setenv("CALIBRE_BETA_TOOLS", "true")
+    monkeypatch.delenv("CALIBRE_MCP_TOOLS", raising=False)
+
+    from calibre_mcp.server import mcp
+    from calibre_mcp.tools import register_tools
+
+    register_tools(mcp)
+    if hasattr(mcp, "list_tools"):
+        registered = {tool.name for tool in asyncio.run(mcp.list_tools())}
+    else:
+        registered = set(asyncio.run(mcp.get_tools()))
+
+    assert "query_books" in registered
+    assert sorted(registered - grouped_tools()) == []
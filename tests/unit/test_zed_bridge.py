@@ -0,0 +1,162 @@
+"""
+Tests for the Zed extension's stdio to Streamable HTTP bridge (src/bridge.py).
+
+The bridge runs against a stand-in MCP server on localhost that speaks just enough of
+the Streamable HTTP transport: JSON and server-sent event responses, a session id handed
+out on initialize, 202 for notifications, DELETE to end the session, and bearer auth.
+"""
+
+import json
+import os
+import subprocess
+import sys
+import threading
+import time
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+from pathlib import Path
+
+import pytest
+
+BRIDGE = Path(__file__).parent.parent.parent / "src" / "bridge.py"
+TOKEN = "s3cret"
+SESSION_ID = "session-1"
+
+
+class StandInServer(BaseHTTPRequestHandler):
+    """Answers initialize with JSON, tools/list as an event stream, notifications with 202."""
+
+    received: list = []
+
+    def log_message(self, *args):
+        pass
+
+    def do_POST(self):
+        if self.headers.get("Authorization") != f"Bearer {TOKEN}":
+            self.send_response(401)
+            self.end_headers()
+            return
+
+        message = json.loads(self.rfile.read(int(self.headers["Content-Length"])))
+        if message.get("method") == "notifications/initialized":
+            # Slow enough that a request sent right after it would overtake it.
+            time.sleep(0.2)
+        self.received.append((self.headers, message))
+        method = message.get("method")
+
+        if method == "initialize":
+            self.reply_json(
+                {
+                    "jsonrpc": "2.0",
+                    "id": message["id"],
+                    "result": {"protocolVersion": "2025-03-26", "capabilities": {}},
+                },
+                session_id=SESSION_ID,
+            )
+        elif "id" not in message:
+            self.send_response(202)
+            self.end_headers()
+        elif self.headers.get("Mcp-Session-Id") != SESSION_ID:
+            self.send_response(404)
+            self.end_headers()
+        else:
+            progress = {"jsonrpc": "2.0", "method": "notifications/progress", "params": {}}
+            result = {"jsonrpc": "2.0", "id": message["id"], "result": {"tools": []}}
+            body = "".join(f"event: message\ndata: {json.dumps(m)}\n\n" for m in (progress, result))
+            self.send_response(200)
+            self.send_header("Content-Type", "text/event-stream")
+            self.end_headers()
+            self.wfile.write(body.encode("utf-8"))
+
+    def do_DELETE(self):
+        self.received.append((self.headers, "DELETE"))
+        self.send_response(200)
+        self.end_headers()
+
+    def reply_json(self, message, session_id=None):
+        body = json.dumps(message).encode("utf-8")
+        self.send_response(200)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(body)))
+        if session_id:
+            self.send_header("Mcp-Session-Id", session_id)
+        self.end_headers()
+        self.wfile.write(body)
+
+
+@pytest.fixture
+def server_url():
+    StandInServer.received = []
+    server = ThreadingHTTPServer(("127.0.0.1", 0), StandInServer)
+    thread = threading.Thread(target=server.serve_forever, daemon=True)
+    thread.start()
+    yield f"http://127.0.0.1:{server.server_address[1]}/mcp"
+    server.shutdown()
+
+
+def run_bridge(url: str, messages: list, token: str | None = TOKEN) -> list:
+    env = {k: v for k, v in os.environ.items() if k != "CALIBRE_MCP_BEARER_TOKEN"}
+    if token:
+        env["CALIBRE_MCP_BEARER_TOKEN"] = token
+    result = subprocess.run(
+        [sys.executable, str(BRIDGE), "--url", url],
+        input="".join(json.dumps(m) + "\n" for m in messages),
+        capture_output=True,
+        text=True,
+        timeout=30,
+        env=env,
+    )
+    assert result.returncode == 0, result.stderr
+    return [json.loads(line) for line in result.stdout.splitlines()]
+
+
+INITIALIZE = {"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}
+INITIALIZED = {"jsonrpc": "2.0", "method": "notifications/initialized"}
+TOOLS_LIST = {"jsonrpc": "2.0", "id": 2, "method": "tools/list"}
+
+
+def test_relays_json_and_event_stream_responses(server_url):
+    replies = run_bridge(server_url, [INITIALIZE, INITIALIZED, TOOLS_LIST])
+
+    assert replies[0]["result"]["protocolVersion"] == "2025-03-26"
+    assert [r.get("method") for r in replies[1:]] == ["notifications/progress", None]
+    assert replies[2] == {"jsonrpc": "2.0", "id": 2, "result": {"tools": []}}
+
+
+def test_sends_session_and_protocol_headers_after_initialize(server_url):
+    run_bridge(server_url, [INITIALIZE, TOOLS_LIST])
+
+    headers, message = StandInServer.received[1]
+    assert message["method"] == "tools/list"
+    assert headers["Mcp-Session-Id"] == SESSION_ID
+    assert headers["MCP-Protocol-Version"] == "2025-03-26"
+    assert "text/event-stream" in headers["Accept"]
+
+
+def test_sends_notifications_before_later_requests(server_url):
+    run_bridge(server_url, [INITIALIZE, INITIALIZED, TOOLS_LIST])
+
+    methods = [message["method"] for _, message in StandInServer.received[:3]]
+    assert methods == ["initialize", "notifications/initialized", "tools/list"]
+
+
+def test_ends_session_on_eof(server_url):
+    run_bridge(server_url, [INITIALIZE])
+
+    headers, message = StandInServer.received[-1]
+    assert message == "DELETE"
+    assert headers["Mcp-Session-Id"] == SESSION_ID
+
+
+def test_rejected_token_becomes_a_jsonrpc_error(server_url):
+    replies = run_bridge(server_url, [INITIALIZE, INITIALIZED], token="wrong")
+
+    assert len(replies) == 1
+    assert replies[0]["id"] == 1
+    assert "401" in replies[0]["error"]["message"]
+
+
+def test_unreachable_server_becomes_a_jsonrpc_error():
+    replies = run_bridge("http://127.0.0.1:9/mcp", [INITIALIZE])
+
+    assert replies[0]["id"] == 1
+    assert "failed" in replies[0]["error"]["message"]